@@ -0,0 +1,46 @@
+//! Times a call site that always calls the same function, which its call cache remembers,
+//! against one that alternates between two identical functions, which misses the cache on
+//! every call and so looks the callee up the way an uncached call would.
+//!
+//! cargo bench --bench calls
+
+use std::time::{Duration, Instant};
+
+use rusp::compiler::compile;
+use rusp::vm::VM;
+
+const RUNS: u32 = 10;
+
+/// `pick` chooses the function that the call site in `count` calls for each `n`
+const PROGRAM: &str = "
+(defun (one n) (not (= n 0)))
+(defun (two n) (not (= n 0)))
+(defun (count pick n acc)
+    (if (= n 0)
+        acc
+        (count pick (- n 1) (if ((pick n) n) (+ acc 1) acc))))
+(defun (repeat pick times)
+    (if (= times 0) 0 (+ (count pick 200 0) (repeat pick (- times 1)))))
+";
+
+/// the fastest of `RUNS` runs, which is the least affected by whatever else the machine is doing
+fn time(pick: &str) -> Duration {
+    let chunk = compile(&format!("{PROGRAM}(repeat {pick} 500)"));
+    (0..RUNS)
+        .map(|_| {
+            let chunk = chunk.clone();
+            let start = Instant::now();
+            VM::default().run(chunk);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let hits = time("(fn (n) (if (= (% n 2) 0) one one))");
+    let misses = time("(fn (n) (if (= (% n 2) 0) one two))");
+    println!("same callee:        {hits:?} per run");
+    println!("alternating callee: {misses:?} per run");
+    println!("speedup: {:.2}x", misses.as_secs_f64() / hits.as_secs_f64());
+}
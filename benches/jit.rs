@@ -0,0 +1,56 @@
+//! Times hot numeric functions run by the interpreter against the same functions compiled by
+//! the JIT:
+//!
+//! cargo bench --bench jit --features jit
+//! cargo bench --bench jit --features jit,nan_boxing
+
+use std::time::{Duration, Instant};
+
+use rusp::compiler::compile;
+use rusp::vm::VM;
+
+const RUNS: u32 = 10;
+
+/// mostly calls and returns, which go back through the interpreter
+const FIB_REC: &str = "
+(defun (fib-rec n)
+    (if (< n 2)
+        n
+        (+ (fib-rec (- n 1))
+           (fib-rec (- n 2)))))
+(fib-rec 25)";
+
+/// mostly arithmetic on locals, which native code does inline
+const POLYNOMIAL: &str = "
+(defun (poly x)
+    (+ (* x (* x (* x x)))
+       (- (* 3 (* x (* x x)))
+          (+ (* 5 (* x x))
+             (- (* 7 x) 11)))))
+(defun (sum n acc) (if (= n 0) acc (sum (- n 1) (+ acc (poly (- n (* 2 (- n 100))))))))
+(defun (repeat times) (if (= times 0) 0 (+ (sum 200 0) (repeat (- times 1)))))
+(repeat 300)";
+
+/// the fastest of `RUNS` runs, which is the least affected by whatever else the machine is doing
+fn time(src: &str, threshold: Option<u32>) -> Duration {
+    let chunk = compile(&src.to_string());
+    (0..RUNS)
+        .map(|_| {
+            let chunk = chunk.clone();
+            let mut vm = VM::default();
+            vm.set_jit_threshold(threshold);
+            let start = Instant::now();
+            vm.run(chunk);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    for (name, src) in [("fib-rec", FIB_REC), ("polynomial", POLYNOMIAL)] {
+        let interpreted = time(src, None);
+        let compiled = time(src, Some(100));
+        println!("{name}: interpreted {interpreted:?}, compiled {compiled:?}");
+    }
+}
@@ -0,0 +1,34 @@
+//! Times `demo/input.risp` with the arithmetic and comparison opcodes, and again with the
+//! operators rebound so that every `+`, `-` and `<` is a global lookup and a builtin call.
+//!
+//! cargo bench --bench operators
+
+use std::time::{Duration, Instant};
+
+use rusp::compiler::compile;
+use rusp::vm::VM;
+
+const RUNS: u32 = 10;
+
+fn time(src: &str) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        VM::default().run(compile(&src.to_string()));
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let demo = std::fs::read_to_string("demo/input.risp").expect("failed to read the demo");
+    // redefining any operator makes the VM look them all up
+    let rebound = format!("(define + +)\n{demo}");
+
+    let opcodes = time(&demo);
+    let calls = time(&rebound);
+    println!("opcodes: {opcodes:?} per run");
+    println!("calls:   {calls:?} per run");
+    println!(
+        "speedup: {:.2}x",
+        calls.as_secs_f64() / opcodes.as_secs_f64()
+    );
+}
@@ -0,0 +1,564 @@
+//! Reads the text format written by `disassembler::disassemble` back into a `BytecodeChunk`.
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! .const ten 10                 ; a named constant
+//! .func add "add" arity=2 locals=0 upvalues=0
+//!   .locals a b                 ; names for the debugger, like `.upvalues`
+//!   ReferenceLocal 1            ; a nested function, assembled like the top level
+//!   ReferenceLocal 2
+//!   Add
+//!   Return
+//! .end
+//!   Closure add                 ; captures are written `local N` or `upvalue N`
+//!   DeclareGlobal "add"         ; constant operands can also be literals
+//!   Constant true
+//!   CondJump then               ; jumps name a label
+//!   Constant 'else
+//!   Jump end
+//! then:
+//! .line 3                       ; the source line of the next instruction
+//!   Constant ten
+//! end:
+//!   DebugEnd
+//! ```
+//!
+//! Literals are integers, floats, `true`, `false`, `nil`, `"strings"`, `'quoted` values and
+//! `(lists)`. Inside `.const`, quotes and lists, a bare word is a symbol.
+
+use std::collections::HashMap;
+
+use crate::vm::{
+    BytecodeChunk, CaptureType, Closure, ConstantObject, ConstantValue, DebugInfo, Function, Op,
+};
+
+struct Line<'a> {
+    number: usize,
+    text: &'a str,
+}
+
+/// The line that opens a nested function, which its body follows
+struct FunctionHeader {
+    name: String,
+    arity: usize,
+    locals: usize,
+    upvalues: usize,
+}
+
+struct Instruction<'a> {
+    line: usize,
+    op: Op,
+    operands: &'a str,
+    offset: usize,
+}
+
+/// Assembles a chunk, or describes the first problem with the line it's on
+pub fn assemble(src: &str) -> Result<BytecodeChunk, String> {
+    let lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, text)| Line {
+            number: i + 1,
+            text: strip_comment(text).trim(),
+        })
+        .filter(|line| !line.text.is_empty())
+        .collect::<Vec<_>>();
+    let mut rest = &lines[..];
+    let chunk = assemble_block(&mut rest, None)?;
+    Ok(chunk)
+}
+
+/// Assembles lines up to the end of the input, or the `.end` of a nested function opened on
+/// line `opened_at`
+fn assemble_block(lines: &mut &[Line], opened_at: Option<usize>) -> Result<BytecodeChunk, String> {
+    let mut constants = vec![];
+    let mut names: HashMap<String, usize> = HashMap::new();
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut instructions = vec![];
+    let mut debug = DebugInfo::default();
+    let mut pending_line = None;
+    let mut offset = 0;
+
+    loop {
+        let Some((line, rest)) = lines.split_first() else {
+            if let Some(opened_at) = opened_at {
+                return Err(format!(
+                    "line {opened_at}: expected .end before the end of the input"
+                ));
+            }
+            break;
+        };
+        *lines = rest;
+        let error = |message: String| format!("line {}: {message}", line.number);
+        let (word, args) = split_word(line.text);
+
+        match word {
+            ".end" if opened_at.is_some() => break,
+            ".end" => return Err(error("unexpected .end".to_string())),
+            ".const" => {
+                let (name, value) = split_word(args);
+                let value = parse_literal(value).map_err(error)?;
+                define(&mut names, name, constants.len()).map_err(error)?;
+                constants.push(value);
+            }
+            ".func" => {
+                let (name, header) = split_word(args);
+                let header = parse_function_header(header).map_err(error)?;
+                define(&mut names, name, constants.len()).map_err(error)?;
+                // the body's errors are on its own lines
+                let bytecode = assemble_block(lines, Some(line.number))?;
+                let function = Function::new(header.name, header.arity, header.locals, bytecode);
+                let closure = Closure::new(function, header.upvalues);
+                constants.push(ConstantValue::Object(ConstantObject::Closure(closure)));
+            }
+            ".line" => {
+                let source_line = args
+                    .parse::<usize>()
+                    .map_err(|_| error(format!("expected a line number, got {args}")))?;
+                pending_line = Some(source_line);
+            }
+            ".locals" => debug.locals = args.split_whitespace().map(String::from).collect(),
+            ".upvalues" => debug.upvalues = args.split_whitespace().map(String::from).collect(),
+            label if label.ends_with(':') => {
+                let label = label.trim_end_matches(':');
+                if labels.insert(label.to_string(), offset).is_some() {
+                    return Err(error(format!("duplicate label {label}")));
+                }
+            }
+            name => {
+                let op = parse_op(name).map_err(error)?;
+                if let Some(source_line) = pending_line.take() {
+                    debug.lines.push((offset, source_line));
+                }
+                instructions.push(Instruction {
+                    line: line.number,
+                    op,
+                    operands: args,
+                    offset,
+                });
+                offset += instruction_len(op, args);
+            }
+        }
+    }
+
+    let mut code = vec![];
+    for instruction in instructions {
+        encode(&instruction, &mut code, &mut constants, &names, &labels)
+            .map_err(|message| format!("line {}: {message}", instruction.line))?;
+    }
+
+    Ok(BytecodeChunk::new(code, constants).with_debug_info(debug))
+}
+
+fn define(names: &mut HashMap<String, usize>, name: &str, idx: usize) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("expected a name for the constant".to_string());
+    }
+    if names.insert(name.to_string(), idx).is_some() {
+        return Err(format!("duplicate constant {name}"));
+    }
+    Ok(())
+}
+
+/// `"name" arity=N locals=N upvalues=N`
+fn parse_function_header(header: &str) -> Result<FunctionHeader, String> {
+    let mut chars = header.chars().peekable();
+    let name = match parse_value(&mut chars)? {
+        ConstantValue::Object(ConstantObject::String(name)) => name,
+        got => {
+            return Err(format!(
+                "expected a string for the function name, got {got:?}"
+            ))
+        }
+    };
+    let mut fields: HashMap<&str, usize> = HashMap::new();
+    for field in chars.collect::<String>().split_whitespace() {
+        let (key, value) = field
+            .split_once('=')
+            .ok_or(format!("expected key=value, got {field}"))?;
+        let key = match key {
+            "arity" => "arity",
+            "locals" => "locals",
+            "upvalues" => "upvalues",
+            _ => return Err(format!("unknown function field {key}")),
+        };
+        fields.insert(key, parse_number(value)? as usize);
+    }
+    let field = |key| fields.get(key).copied().unwrap_or(0);
+    Ok(FunctionHeader {
+        name,
+        arity: field("arity"),
+        locals: field("locals"),
+        upvalues: field("upvalues"),
+    })
+}
+
+fn parse_op(name: &str) -> Result<Op, String> {
+    (0..=u8::MAX)
+        .filter_map(|byte| Op::try_from(byte).ok())
+        .find(|op| format!("{op:?}") == name)
+        .ok_or(format!("unknown instruction {name}"))
+}
+
+/// the number of bytes an instruction takes up, before its operands are checked
+fn instruction_len(op: Op, operands: &str) -> usize {
+    match op {
+        Op::Closure => 2 + operands.split_whitespace().skip(1).count(),
+        Op::Constant
+        | Op::DeclareGlobal
+        | Op::ReferenceGlobal
+        | Op::Jump
+        | Op::CondJump
+        | Op::PushHandler
+        | Op::FuncCall
+        | Op::ReferenceLocal
+        | Op::Define
+        | Op::SetLocal
+        | Op::ReferenceUpvalue
+        | Op::SetUpvalue => 2,
+        _ => 1,
+    }
+}
+
+fn encode(
+    instruction: &Instruction,
+    code: &mut Vec<u8>,
+    constants: &mut Vec<ConstantValue>,
+    names: &HashMap<String, usize>,
+    labels: &HashMap<String, usize>,
+) -> Result<(), String> {
+    let operands = instruction.operands;
+    let op = instruction.op;
+    code.push(op.into());
+    match op {
+        Op::Constant | Op::DeclareGlobal | Op::ReferenceGlobal => {
+            let idx = match names.get(operands) {
+                Some(idx) => *idx,
+                None => {
+                    constants.push(parse_literal(operands)?);
+                    constants.len() - 1
+                }
+            };
+            if op != Op::Constant
+                && !matches!(
+                    constants[idx],
+                    ConstantValue::Object(ConstantObject::String(_))
+                )
+            {
+                return Err(format!(
+                    "expected a string for the global's name, got {operands}"
+                ));
+            }
+            code.push(byte(idx, "constant index")?);
+        }
+        Op::Jump | Op::CondJump | Op::PushHandler => {
+            let operand_idx = instruction.offset + 1;
+            let jump = match labels.get(operands) {
+                // jumps are relative to their operand
+                Some(target) if *target > operand_idx => target - operand_idx,
+                Some(_) => return Err(format!("can't jump backwards to {operands}")),
+                None if operands.parse::<u8>().is_ok() => parse_number(operands)? as usize,
+                None => return Err(format!("unknown label {operands}")),
+            };
+            code.push(byte(jump, "jump")?);
+        }
+        Op::Closure => {
+            let mut words = operands.split_whitespace();
+            let name = words.next().ok_or("expected a function")?;
+            let idx = *names.get(name).ok_or(format!("unknown function {name}"))?;
+            let num_upvalues = match &constants[idx] {
+                ConstantValue::Object(ConstantObject::Closure(closure)) => closure.num_upvalues,
+                _ => return Err(format!("{name} isn't a function")),
+            };
+            code.push(byte(idx, "constant index")?);
+            let captures = words.collect::<Vec<_>>();
+            if captures.len() != num_upvalues * 2 {
+                return Err(format!(
+                    "{name} has {num_upvalues} upvalues, expected a capture for each"
+                ));
+            }
+            for capture in captures.chunks(2) {
+                let capture_type = match capture[0] {
+                    "local" => CaptureType::SurroundingLocal,
+                    "upvalue" => CaptureType::SurroundingUpvalue,
+                    got => return Err(format!("expected local or upvalue, got {got}")),
+                };
+                code.push(capture_type.into());
+                code.push(parse_number(capture[1])?);
+            }
+        }
+        Op::FuncCall
+        | Op::ReferenceLocal
+        | Op::Define
+        | Op::SetLocal
+        | Op::ReferenceUpvalue
+        | Op::SetUpvalue => code.push(parse_number(operands)?),
+        _ if !operands.is_empty() => return Err(format!("{op:?} doesn't take operands")),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn byte(n: usize, what: &str) -> Result<u8, String> {
+    u8::try_from(n).map_err(|_| format!("{what} too large: {n}"))
+}
+
+fn parse_number(s: &str) -> Result<u8, String> {
+    s.trim()
+        .parse()
+        .map_err(|_| format!("expected a number from 0 to 255, got {s}"))
+}
+
+/// the first word, and the rest of the line
+fn split_word(s: &str) -> (&str, &str) {
+    match s.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (s, ""),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_literal(s: &str) -> Result<ConstantValue, String> {
+    let mut chars = s.chars().peekable();
+    let value = parse_value(&mut chars)?;
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected {c:?} after {s}")),
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_value(chars: &mut Chars) -> Result<ConstantValue, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        None => Err("expected a value".to_string()),
+        Some('\'') => {
+            chars.next();
+            Ok(ConstantValue::Quote(Box::new(parse_value(chars)?)))
+        }
+        Some('(') => {
+            chars.next();
+            let mut items = vec![];
+            loop {
+                skip_whitespace(chars);
+                if chars.next_if_eq(&')').is_some() {
+                    return Ok(ConstantValue::List(items));
+                }
+                items.push(parse_value(chars)?);
+            }
+        }
+        Some('"') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated string".to_string()),
+                    Some('"') => return Ok(ConstantValue::Object(ConstantObject::String(s))),
+                    Some('\\') => match chars.next() {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some(c @ ('\\' | '"')) => s.push(c),
+                        got => return Err(format!("unknown escape {got:?}")),
+                    },
+                    Some(c) => s.push(c),
+                }
+            }
+        }
+        Some(')') => Err("unexpected )".to_string()),
+        Some(_) => {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"()\"'".contains(*c)) {
+                word.push(c);
+            }
+            Ok(parse_word(word))
+        }
+    }
+}
+
+fn parse_word(word: String) -> ConstantValue {
+    match word.as_str() {
+        "true" => return ConstantValue::Boolean(true),
+        "false" => return ConstantValue::Boolean(false),
+        "nil" => return ConstantValue::Nil,
+        _ => {}
+    }
+    if let Ok(i) = word.parse() {
+        return ConstantValue::Integer(i);
+    }
+    match word.parse() {
+        Ok(f) if word.contains(|c: char| c.is_ascii_digit()) => ConstantValue::Float(f),
+        _ => ConstantValue::Object(ConstantObject::Symbol(word)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::compile,
+        disassembler::disassemble,
+        vm::{SmallVal, VM},
+    };
+
+    fn round_trip(src: &str) {
+        let chunk = compile(&src.to_string());
+        let text = disassemble(&chunk).unwrap();
+        assert_eq!(assemble(&text), Ok(chunk), "{text}");
+    }
+
+    #[test]
+    fn labels_and_named_constants() {
+        let chunk = assemble(
+            "
+.const eleven 11
+  Constant eleven
+  CondJump then   ; skip the else branch
+  Constant 13
+  Jump end
+then:
+  Constant 12
+end:
+  DebugEnd
+",
+        )
+        .unwrap();
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Constant.into(),
+                0,
+                Op::CondJump.into(),
+                5,
+                Op::Constant.into(),
+                1,
+                Op::Jump.into(),
+                3,
+                Op::Constant.into(),
+                2,
+                Op::DebugEnd.into(),
+            ]
+        );
+        assert_eq!(
+            chunk.constants,
+            vec![
+                ConstantValue::Integer(11),
+                ConstantValue::Integer(13),
+                ConstantValue::Integer(12)
+            ]
+        );
+
+        let mut vm = VM::default();
+        vm.run(chunk);
+        assert_eq!(vm.stack.at(0), Some(SmallVal::Integer(12)));
+    }
+
+    #[test]
+    fn nested_functions() {
+        let chunk = assemble(
+            r#"
+.func add "add" arity=2
+  ReferenceLocal 1
+  ReferenceLocal 2
+  Add
+  Return
+.end
+  Closure add
+  DeclareGlobal "add"
+  ReferenceGlobal "add"
+  Constant 1
+  Constant 2
+  FuncCall 2
+  DebugEnd
+"#,
+        )
+        .unwrap();
+
+        let mut vm = VM::default();
+        vm.run(chunk);
+        assert_eq!(vm.stack.at(0), Some(SmallVal::Integer(3)));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse_literal("-4"), Ok(ConstantValue::Integer(-4)));
+        assert_eq!(parse_literal("1.0"), Ok(ConstantValue::Float(1.0)));
+        assert_eq!(parse_literal("nil"), Ok(ConstantValue::Nil));
+        assert_eq!(
+            parse_literal(r#"'(a "b \"c\"" (1))"#),
+            Ok(ConstantValue::Quote(Box::new(ConstantValue::List(vec![
+                ConstantValue::Object(ConstantObject::Symbol("a".to_string())),
+                ConstantValue::Object(ConstantObject::String("b \"c\"".to_string())),
+                ConstantValue::List(vec![ConstantValue::Integer(1)]),
+            ]))))
+        );
+        assert!(parse_literal("(1 2").is_err());
+    }
+
+    #[test]
+    fn round_trips_with_the_disassembler() {
+        round_trip("(define x (+ 1 2))\n(print x)");
+        round_trip("(if (< 1 2) \"yes; really\" 'no)");
+        round_trip("(print '(1 2.5 \"three\" four))");
+        round_trip(
+            "
+(defun (make-counter)
+  (define n 0)
+  (fn () (set n (+ n 1)) n))
+(define counter (make-counter))",
+        );
+        round_trip("(try (raise 'oops) (catch e e) (finally (print 1)))");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            assemble("  Constant 1\n  Frobnicate"),
+            Err("line 2: unknown instruction Frobnicate".to_string())
+        );
+        assert_eq!(
+            assemble("  Jump nowhere"),
+            Err("line 1: unknown label nowhere".to_string())
+        );
+        assert_eq!(
+            assemble("  ReferenceLocal 300"),
+            Err("line 1: expected a number from 0 to 255, got 300".to_string())
+        );
+        assert_eq!(
+            assemble(".func f \"f\" upvalues=1\n  Return\n.end\n  Closure f"),
+            Err("line 4: f has 1 upvalues, expected a capture for each".to_string())
+        );
+        assert_eq!(
+            assemble(".func f \"f\"\n  Return"),
+            Err("line 1: expected .end before the end of the input".to_string())
+        );
+        // errors inside a function are only on their own line
+        assert_eq!(
+            assemble(".func f \"f\"\n  Return\n.func g \"g\"\n  Frobnicate\n.end\n.end"),
+            Err("line 4: unknown instruction Frobnicate".to_string())
+        );
+        assert_eq!(
+            assemble(".func f \"f\" arity=x\n  Return\n.end"),
+            Err("line 1: expected a number from 0 to 255, got x".to_string())
+        );
+    }
+}
@@ -1,15 +1,37 @@
+use std::cell::RefCell;
 use std::io::Write;
+use std::rc::Rc;
 
-use rusp::compiler::compile;
+use rusp::cfg;
+use rusp::compiler::{compile, compile_optimised};
+use rusp::coverage::Coverage;
+use rusp::debugger::Debugger;
+use rusp::disassembler::listing;
+use rusp::emit_c;
+use rusp::profiler::Profiler;
+use rusp::trace::Tracer;
 use rusp::vm::VM;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
     match args[..] {
+        [_, ref command, ref file] if command == "debug" => debug(file),
+        [_, ref command, ref file] if command == "disassemble" => disassemble(file),
+        [_, ref command, ref file] if command == "cfg" => control_flow_graph(file),
+        [_, ref command, ref file] if command == "emit-c" => emit_c(file),
+        [_, ref command, ref file] if command == "trace" => trace(file),
+        [_, ref command, ref file] if command == "profile" => profile(file, None),
+        [_, ref command, ref file] if command == "coverage" => coverage(file, None),
+        [_, ref command, ref file, ref lcov] if command == "coverage" => coverage(file, Some(lcov)),
+        [_, ref command, ref file, ref folded] if command == "profile" => {
+            profile(file, Some(folded))
+        }
         [_, ref file] => interpret(file),
         [_] => repl(),
-        _ => panic!("Usage: ruspc [filename]"),
+        _ => panic!(
+            "Usage: ruspc [debug|disassemble|cfg|emit-c|trace|profile|coverage] [filename] [output]"
+        ),
     }
 }
 
@@ -17,7 +39,92 @@ fn interpret(filename: &str) {
     let contents =
         std::fs::read_to_string(filename).expect("Something went wrong reading the file");
 
-    VM::default().run(compile(&contents))
+    VM::default().run(compile_optimised(&contents))
+}
+
+/// prints the bytecode a file compiles to
+fn disassemble(filename: &str) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+
+    match listing(&compile(&contents)) {
+        Ok(listing) => print!("{listing}"),
+        Err(e) => panic!("Malformed bytecode: {e}"),
+    }
+}
+
+/// prints a Graphviz graph of the basic blocks of each function in a file
+fn control_flow_graph(filename: &str) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+
+    match cfg::dot(&compile(&contents)) {
+        Ok(dot) => print!("{dot}"),
+        Err(e) => panic!("Malformed bytecode: {e}"),
+    }
+}
+
+/// prints a C program that does what a file does, see `emit_c`
+fn emit_c(filename: &str) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+
+    match emit_c::emit_c(&compile(&contents)) {
+        Ok(c) => print!("{c}"),
+        Err(e) => panic!("Can't emit C: {e}"),
+    }
+}
+
+/// runs a file, printing every instruction, call, allocation and collection
+fn trace(filename: &str) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+
+    let mut vm = VM::default();
+    vm.set_trace_hook(Some(Box::new(Tracer::stdout())));
+    vm.run(compile(&contents))
+}
+
+/// runs a file and prints where it spent its instructions,
+/// optionally writing folded stacks for a flamegraph
+fn profile(filename: &str, folded_filename: Option<&str>) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+
+    let profiler = Rc::new(RefCell::new(Profiler::new()));
+    let mut vm = VM::default();
+    vm.set_trace_hook(Some(Box::new(profiler.clone())));
+    vm.run(compile(&contents));
+
+    let profiler = profiler.borrow();
+    println!("{} instructions", profiler.instructions());
+    print!("{}", profiler.table());
+    if let Some(folded_filename) = folded_filename {
+        std::fs::write(folded_filename, profiler.folded())
+            .expect("Something went wrong writing the folded stacks");
+    }
+}
+
+/// runs a file and prints which lines and branches ran, optionally writing an lcov tracefile
+fn coverage(filename: &str, lcov_filename: Option<&str>) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+
+    let chunk = compile(&contents);
+    let coverage = match Coverage::new(&chunk) {
+        Ok(coverage) => Rc::new(RefCell::new(coverage)),
+        Err(e) => panic!("Malformed bytecode: {e}"),
+    };
+    let mut vm = VM::default();
+    vm.set_trace_hook(Some(Box::new(coverage.clone())));
+    vm.run(chunk);
+
+    let coverage = coverage.borrow();
+    print!("{}", coverage.summary());
+    if let Some(lcov_filename) = lcov_filename {
+        std::fs::write(lcov_filename, coverage.lcov(filename))
+            .expect("Something went wrong writing the lcov file");
+    }
 }
 
 fn repl() {
@@ -34,3 +141,24 @@ fn repl() {
         vm.run(compile(&input));
     }
 }
+
+fn debug(filename: &str) {
+    let contents =
+        std::fs::read_to_string(filename).expect("Something went wrong reading the file");
+    let mut debugger = Debugger::new(compile(&contents));
+
+    println!("debugging {filename}, type `help` for commands");
+    loop {
+        print!("(debug) ");
+        std::io::stdout().flush().unwrap();
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input).unwrap() == 0 {
+            break;
+        }
+        match input.trim() {
+            "" => continue,
+            "quit" | "q" => break,
+            command => println!("{}", debugger.command(command).trim_end()),
+        }
+    }
+}
@@ -1,20 +1,40 @@
-use crate::vm::{ConsCell, ObjectValue, SmallVal, VM};
+use crate::vm::{
+    Channel, ConsCell, Coroutine, CoroutineStatus, ErrorValue, ObjectValue, SmallVal, TaskStatus,
+    VM,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct BuiltIn {
     pub name: &'static str,
+    /// the number of arguments it must be called with
     pub arity: usize,
-    pub func: fn(Vec<SmallVal>, &mut VM) -> SmallVal, // This signature is probably wrong, if we want cons to be able to return a &mut SmallVal for example
+    /// how many more arguments it can be called with
+    pub optional: usize,
+    pub func: fn(Vec<SmallVal>, &mut VM) -> Result<SmallVal, ErrorValue>, // This signature is probably wrong, if we want cons to be able to return a &mut SmallVal for example
+}
+
+impl BuiltIn {
+    /// whether it can be called with `given_arity` arguments
+    pub fn accepts(&self, given_arity: usize) -> bool {
+        (self.arity..=self.arity + self.optional).contains(&given_arity)
+    }
+}
+
+fn type_error(message: String) -> ErrorValue {
+    ErrorValue::new("type-error", message, SmallVal::Nil)
 }
 
 const ADD: BuiltIn = BuiltIn {
     name: "+",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Integer(i + j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Integer(i + j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Float(i + j),
-            _ => panic!("add must be called with two integers"),
+            _ => Err(type_error(
+                "add must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -22,11 +42,14 @@ const ADD: BuiltIn = BuiltIn {
 const SUB: BuiltIn = BuiltIn {
     name: "-",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Integer(i - j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Integer(i - j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Float(i - j),
-            _ => panic!("sub must be called with two integers"),
+            _ => Err(type_error(
+                "sub must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -34,11 +57,14 @@ const SUB: BuiltIn = BuiltIn {
 const MUL: BuiltIn = BuiltIn {
     name: "*",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Integer(i * j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Integer(i * j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Float(i * j),
-            _ => panic!("mul must be called with two integers"),
+            _ => Err(type_error(
+                "mul must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -46,11 +72,19 @@ const MUL: BuiltIn = BuiltIn {
 const DIV: BuiltIn = BuiltIn {
     name: "/",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Integer(i / j),
+            [SmallVal::Integer(_), SmallVal::Integer(0)] => Err(ErrorValue::new(
+                "division-by-zero",
+                "attempted to divide by zero".to_string(),
+                SmallVal::Nil,
+            )),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Integer(i / j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Float(i / j),
-            _ => panic!("div must be called with two integers"),
+            _ => Err(type_error(
+                "div must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -58,11 +92,19 @@ const DIV: BuiltIn = BuiltIn {
 const MOD: BuiltIn = BuiltIn {
     name: "%",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Integer(i % j),
+            [SmallVal::Integer(_), SmallVal::Integer(0)] => Err(ErrorValue::new(
+                "division-by-zero",
+                "attempted to take a remainder by zero".to_string(),
+                SmallVal::Nil,
+            )),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Integer(i % j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Float(i % j),
-            _ => panic!("mod must be called with two integers"),
+            _ => Err(type_error(
+                "mod must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -70,30 +112,38 @@ const MOD: BuiltIn = BuiltIn {
 const INC: BuiltIn = BuiltIn {
     name: "inc",
     arity: 1,
+    optional: 0,
     func: |args, _vm| match &args[0] {
-        SmallVal::Integer(i) => SmallVal::Integer(i + 1),
-        got => panic!("must be called with an integer, got {:?}, {}", got, got),
+        SmallVal::Integer(i) => Ok(SmallVal::Integer(i + 1)),
+        got => Err(type_error(format!(
+            "inc must be called with an integer, got {}",
+            got
+        ))),
     },
 };
 
 const PRINT: BuiltIn = BuiltIn {
     name: "print",
     arity: 1,
+    optional: 0,
     func: |args, _vm| {
         println!("{}", args[0]);
-        SmallVal::Nil
+        Ok(SmallVal::Nil)
     },
 };
 
 const EQ: BuiltIn = BuiltIn {
     name: "=",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Bool(i == j),
-            [SmallVal::Float(i), SmallVal::Float(j)] => SmallVal::Bool(i == j),
-            [SmallVal::ObjectPtr(a), SmallVal::ObjectPtr(b)] => SmallVal::Bool(a == b), // todo watch out for this
-            _ => panic!(),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Bool(i == j)),
+            [SmallVal::Float(i), SmallVal::Float(j)] => Ok(SmallVal::Bool(i == j)),
+            [SmallVal::ObjectPtr(a), SmallVal::ObjectPtr(b)] => Ok(SmallVal::Bool(a == b)), // todo watch out for this
+            _ => Err(type_error(
+                "= must be called with two values of the same type".to_string(),
+            )),
         }
     },
 };
@@ -101,11 +151,12 @@ const EQ: BuiltIn = BuiltIn {
 const GT: BuiltIn = BuiltIn {
     name: ">",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Bool(i > j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Bool(i > j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Bool(i == j),
-            _ => panic!(),
+            _ => Err(type_error("> must be called with two integers".to_string())),
         }
     },
 };
@@ -113,11 +164,12 @@ const GT: BuiltIn = BuiltIn {
 const LT: BuiltIn = BuiltIn {
     name: "<",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Bool(i < j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Bool(i < j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Bool(i == j),
-            _ => panic!(),
+            _ => Err(type_error("< must be called with two integers".to_string())),
         }
     },
 };
@@ -125,11 +177,14 @@ const LT: BuiltIn = BuiltIn {
 const GTE: BuiltIn = BuiltIn {
     name: ">=",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Bool(i >= j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Bool(i >= j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Bool(i == j),
-            _ => panic!(),
+            _ => Err(type_error(
+                ">= must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -137,11 +192,14 @@ const GTE: BuiltIn = BuiltIn {
 const LTE: BuiltIn = BuiltIn {
     name: "<=",
     arity: 2,
+    optional: 0,
     func: |args, _vm| {
         match args[..] {
-            [SmallVal::Integer(i), SmallVal::Integer(j)] => SmallVal::Bool(i <= j),
+            [SmallVal::Integer(i), SmallVal::Integer(j)] => Ok(SmallVal::Bool(i <= j)),
             // (Sexpr::Float(i), Sexpr::Float(j)) => Sexpr::Bool(i == j),
-            _ => panic!(),
+            _ => Err(type_error(
+                "<= must be called with two integers".to_string(),
+            )),
         }
     },
 };
@@ -149,57 +207,79 @@ const LTE: BuiltIn = BuiltIn {
 const AND: BuiltIn = BuiltIn {
     name: "and",
     arity: 2,
+    optional: 0,
     func: |args, _vm| match args[..] {
-        [SmallVal::Bool(i), SmallVal::Bool(j)] => SmallVal::Bool(i && j),
-        _ => panic!(),
+        [SmallVal::Bool(i), SmallVal::Bool(j)] => Ok(SmallVal::Bool(i && j)),
+        _ => Err(type_error(
+            "and must be called with two booleans".to_string(),
+        )),
     },
 };
 
 const OR: BuiltIn = BuiltIn {
     name: "or",
     arity: 2,
+    optional: 0,
     func: |args, _vm| match args[..] {
-        [SmallVal::Bool(i), SmallVal::Bool(j)] => SmallVal::Bool(i || j),
-        _ => panic!(),
+        [SmallVal::Bool(i), SmallVal::Bool(j)] => Ok(SmallVal::Bool(i || j)),
+        _ => Err(type_error(
+            "or must be called with two booleans".to_string(),
+        )),
     },
 };
 
 const NOT: BuiltIn = BuiltIn {
     name: "not",
     arity: 1,
+    optional: 0,
     func: |args, _vm| match args[0] {
-        SmallVal::Bool(i) => SmallVal::Bool(!i),
-        _ => panic!(),
+        SmallVal::Bool(i) => Ok(SmallVal::Bool(!i)),
+        _ => Err(type_error("not must be called with a boolean".to_string())),
     },
 };
 
 const CAR: BuiltIn = BuiltIn {
     name: "car",
     arity: 1,
+    optional: 0,
     func: |args, _vm| match args[0] {
         SmallVal::ObjectPtr(ptr) => match &unsafe { &*ptr }.value {
-            &ObjectValue::ConsCell(ConsCell(val_ptr, _cdr_ptr)) => SmallVal::ObjectPtr(val_ptr),
-            _ => panic!("car must be called with a cons cell"),
+            &ObjectValue::ConsCell(ConsCell(val_ptr, _cdr_ptr)) => Ok(SmallVal::ObjectPtr(val_ptr)),
+            got => Err(type_error(format!(
+                "car must be called with a cons cell, got {}",
+                got
+            ))),
         },
-        _ => panic!(),
+        ref got => Err(type_error(format!(
+            "car must be called with a cons cell, got {}",
+            got
+        ))),
     },
 };
 
 const CDR: BuiltIn = BuiltIn {
     name: "cdr",
     arity: 1,
+    optional: 0,
     func: |args, _vm| match args[0] {
-        SmallVal::ObjectPtr(ptr) => match unsafe { &*ptr }.value {
-            ObjectValue::ConsCell(ConsCell(_val_ptr, cdr_ptr)) => SmallVal::ObjectPtr(cdr_ptr),
-            _ => panic!("car must be called with a cons cell"),
+        SmallVal::ObjectPtr(ptr) => match &unsafe { &*ptr }.value {
+            &ObjectValue::ConsCell(ConsCell(_val_ptr, cdr_ptr)) => Ok(SmallVal::ObjectPtr(cdr_ptr)),
+            got => Err(type_error(format!(
+                "cdr must be called with a cons cell, got {}",
+                got
+            ))),
         },
-        _ => panic!(),
+        ref got => Err(type_error(format!(
+            "cdr must be called with a cons cell, got {}",
+            got
+        ))),
     },
 };
 
 const CONS: BuiltIn = BuiltIn {
     name: "cons",
     arity: 2,
+    optional: 0,
     func: |args, vm| {
         let car_val = args[0].clone();
         let cdr_val = args[1].clone();
@@ -224,11 +304,351 @@ const CONS: BuiltIn = BuiltIn {
 
         let cons_ptr = unsafe { vm.allocate_value(ObjectValue::ConsCell(ConsCell(car, cdr))) };
 
-        SmallVal::ObjectPtr(cons_ptr)
+        Ok(SmallVal::ObjectPtr(cons_ptr))
+    },
+};
+
+/// (error kind message [payload])
+/// raises a new error, `kind` should be a quoted symbol like `'not-found`
+const ERROR: BuiltIn = BuiltIn {
+    name: "error",
+    arity: 2,
+    optional: 1,
+    func: |args, _vm| {
+        let (kind, message, payload) = match &args[..] {
+            [kind, message] => (kind, message, SmallVal::Nil),
+            [kind, message, payload] => (kind, message, payload.clone()),
+            _ => {
+                return Err(type_error(
+                    "error expects a kind, a message and an optional payload".to_string(),
+                ))
+            }
+        };
+        let kind = match symbol_name(kind) {
+            Some(kind) => kind,
+            None => {
+                return Err(type_error(format!(
+                    "error kind must be a symbol, got {}",
+                    kind
+                )))
+            }
+        };
+        let message = match string_contents(message) {
+            Some(message) => message,
+            None => message.to_string(),
+        };
+        Err(ErrorValue::new(&kind, message, payload))
+    },
+};
+
+/// (raise value)
+/// re-raises an error caught by `catch`, any other value is raised as the payload of a `raise` error
+const RAISE: BuiltIn = BuiltIn {
+    name: "raise",
+    arity: 1,
+    optional: 0,
+    func: |args, _vm| match as_error(&args[0]) {
+        Ok(e) => Err(e.clone()),
+        Err(_) => Err(ErrorValue::new(
+            "raise",
+            format!("raised {}", args[0]),
+            args[0].clone(),
+        )),
+    },
+};
+
+const ERROR_KIND: BuiltIn = BuiltIn {
+    name: "error-kind",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| {
+        let kind = as_error(&args[0])?.kind.clone();
+        Ok(SmallVal::Quote(vm.intern(&kind)))
+    },
+};
+
+const ERROR_MESSAGE: BuiltIn = BuiltIn {
+    name: "error-message",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| {
+        let message = as_error(&args[0])?.message.clone();
+        let string = unsafe { vm.allocate_value(ObjectValue::String(message)) };
+        Ok(SmallVal::ObjectPtr(string))
+    },
+};
+
+const ERROR_PAYLOAD: BuiltIn = BuiltIn {
+    name: "error-payload",
+    arity: 1,
+    optional: 0,
+    func: |args, _vm| Ok(as_error(&args[0])?.payload.clone()),
+};
+
+/// (eq? a b)
+/// whether two values are the same object, or the same number, boolean or nil.
+/// Symbols are interned, so two symbols with the same name are always `eq?`.
+const EQ_P: BuiltIn = BuiltIn {
+    name: "eq?",
+    arity: 2,
+    optional: 0,
+    func: |args, _vm| {
+        let same = match (&args[0], &args[1]) {
+            (
+                SmallVal::ObjectPtr(a) | SmallVal::Quote(a),
+                SmallVal::ObjectPtr(b) | SmallVal::Quote(b),
+            ) => a == b,
+            (a, b) => a == b,
+        };
+        Ok(SmallVal::Bool(same))
+    },
+};
+
+/// (intern name)
+/// the symbol called `name`, a string
+const INTERN: BuiltIn = BuiltIn {
+    name: "intern",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| match string_contents(&args[0]) {
+        Some(name) => Ok(SmallVal::Quote(vm.intern(&name))),
+        None => Err(type_error(format!(
+            "intern expects a string, got {}",
+            args[0]
+        ))),
+    },
+};
+
+const SYMBOL_TO_STRING: BuiltIn = BuiltIn {
+    name: "symbol->string",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| match symbol_name(&args[0]) {
+        Some(name) => {
+            let string = unsafe { vm.allocate_value(ObjectValue::String(name)) };
+            Ok(SmallVal::ObjectPtr(string))
+        }
+        None => Err(type_error(format!(
+            "symbol->string expects a symbol, got {}",
+            args[0]
+        ))),
+    },
+};
+
+const COROUTINE: BuiltIn = BuiltIn {
+    name: "coroutine",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| make_coroutine(&args[0], false, vm),
+};
+
+const GENERATOR: BuiltIn = BuiltIn {
+    name: "generator",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| make_coroutine(&args[0], true, vm),
+};
+
+const DONE: BuiltIn = BuiltIn {
+    name: "done?",
+    arity: 1,
+    optional: 0,
+    func: |args, _vm| match &args[0] {
+        SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+            ObjectValue::Coroutine(co) => Ok(SmallVal::Bool(co.status == CoroutineStatus::Dead)),
+            ObjectValue::Task(task) => Ok(SmallVal::Bool(task.status == TaskStatus::Done)),
+            got => Err(type_error(format!(
+                "expected a coroutine or task, got {}",
+                got
+            ))),
+        },
+        got => Err(type_error(format!(
+            "expected a coroutine or task, got {}",
+            got
+        ))),
+    },
+};
+
+/// coroutines can only wrap closures taking 0 or 1 arguments
+fn make_coroutine(
+    function: &SmallVal,
+    generator: bool,
+    vm: &mut VM,
+) -> Result<SmallVal, ErrorValue> {
+    match function {
+        SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+            ObjectValue::Closure(closure) if closure.f.arity <= 1 => {}
+            got => {
+                let message = format!("expected a function of 0 or 1 arguments, got {}", got);
+                return Err(type_error(message));
+            }
+        },
+        got => {
+            let message = format!("expected a function of 0 or 1 arguments, got {}", got);
+            return Err(type_error(message));
+        }
+    }
+    let co = Coroutine::new(function.clone(), generator);
+    let co_ptr = unsafe { vm.allocate_value(ObjectValue::Coroutine(co)) };
+    Ok(SmallVal::ObjectPtr(co_ptr))
+}
+
+const SPAWN: BuiltIn = BuiltIn {
+    name: "spawn",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| {
+        match &args[0] {
+            SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+                ObjectValue::Closure(closure) if closure.f.arity == 0 => {}
+                got => {
+                    let message = format!("expected a function of no arguments, got {}", got);
+                    return Err(type_error(message));
+                }
+            },
+            got => {
+                let message = format!("expected a function of no arguments, got {}", got);
+                return Err(type_error(message));
+            }
+        }
+        let task_ptr = vm.spawn_task(args[0].clone());
+        Ok(SmallVal::ObjectPtr(task_ptr))
+    },
+};
+
+const CHAN: BuiltIn = BuiltIn {
+    name: "chan",
+    arity: 0,
+    optional: 0,
+    func: |_args, vm| {
+        let chan_ptr = unsafe { vm.allocate_value(ObjectValue::Channel(Channel::default())) };
+        Ok(SmallVal::ObjectPtr(chan_ptr))
+    },
+};
+
+const SEND: BuiltIn = BuiltIn {
+    name: "send",
+    arity: 2,
+    optional: 0,
+    func: |args, _vm| {
+        as_channel(&args[0])?.buffer.push_back(args[1].clone());
+        Ok(SmallVal::Nil)
+    },
+};
+
+/// blocks the current task until there's a value on the channel
+const RECV: BuiltIn = BuiltIn {
+    name: "recv",
+    arity: 1,
+    optional: 0,
+    func: |args, vm| {
+        if let Some(value) = as_channel(&args[0])?.buffer.pop_front() {
+            return Ok(value);
+        }
+        let SmallVal::ObjectPtr(chan_ptr) = args[0] else {
+            unreachable!()
+        };
+        vm.block_on(chan_ptr)?;
+        Ok(SmallVal::Nil)
+    },
+};
+
+/// `((total objects bytes) (<kind> objects bytes) ...)`
+const HEAP_STATS: BuiltIn = BuiltIn {
+    name: "heap-stats",
+    arity: 0,
+    optional: 0,
+    func: |_args, vm| {
+        let stats = vm.heap_stats();
+        let rows = std::iter::once(("total", stats.live)).chain(stats.by_kind);
+        let mut row_vals = vec![];
+        for (kind, kind_stats) in rows {
+            let kind_ptr = vm.intern(kind);
+            let row = vec![
+                SmallVal::ObjectPtr(kind_ptr),
+                SmallVal::Integer(kind_stats.objects as i64),
+                SmallVal::Integer(kind_stats.bytes as i64),
+            ];
+            row_vals.push(vm.allocate_list_of(row));
+        }
+        Ok(vm.allocate_list_of(row_vals))
     },
 };
 
-pub const BUILT_INS: [&BuiltIn; 18] = [
-    &ADD, &SUB, &MUL, &DIV, &MOD, &INC, &PRINT, &EQ, &GT, &LT, &GTE, &LTE, &AND, &OR, &NOT, &CAR,
-    &CDR, &CONS,
+fn as_channel<'a>(val: &SmallVal) -> Result<&'a mut Channel, ErrorValue> {
+    match val {
+        SmallVal::ObjectPtr(ptr) => match &mut unsafe { &mut **ptr }.value {
+            ObjectValue::Channel(channel) => Ok(channel),
+            got => Err(type_error(format!("expected a channel, got {}", got))),
+        },
+        got => Err(type_error(format!("expected a channel, got {}", got))),
+    }
+}
+
+fn as_error(val: &SmallVal) -> Result<&ErrorValue, ErrorValue> {
+    match val {
+        SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+            ObjectValue::Error(e) => Ok(e),
+            got => Err(type_error(format!("expected an error, got {}", got))),
+        },
+        got => Err(type_error(format!("expected an error, got {}", got))),
+    }
+}
+
+/// the name of a symbol, whether it's quoted (`'foo`) or not
+fn symbol_name(val: &SmallVal) -> Option<String> {
+    match val {
+        SmallVal::ObjectPtr(ptr) | SmallVal::Quote(ptr) => match &unsafe { &**ptr }.value {
+            ObjectValue::Symbol(s) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn string_contents(val: &SmallVal) -> Option<String> {
+    match val {
+        SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+            ObjectValue::String(s) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub const BUILT_INS: [&BuiltIn; 34] = [
+    &ADD,
+    &SUB,
+    &MUL,
+    &DIV,
+    &MOD,
+    &INC,
+    &PRINT,
+    &EQ,
+    &GT,
+    &LT,
+    &GTE,
+    &LTE,
+    &AND,
+    &OR,
+    &NOT,
+    &CAR,
+    &CDR,
+    &CONS,
+    &ERROR,
+    &RAISE,
+    &ERROR_KIND,
+    &ERROR_MESSAGE,
+    &ERROR_PAYLOAD,
+    &EQ_P,
+    &INTERN,
+    &SYMBOL_TO_STRING,
+    &COROUTINE,
+    &GENERATOR,
+    &DONE,
+    &SPAWN,
+    &CHAN,
+    &SEND,
+    &RECV,
+    &HEAP_STATS,
 ];
@@ -0,0 +1,240 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    disassembler::{decode, format_instruction, Instruction, Operands},
+    vm::{BytecodeChunk, ConstantObject, ConstantValue, Op},
+};
+
+/// Why control can go from one block to another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    /// on to the next instruction, which starts another block
+    FallThrough,
+    Jump,
+    /// a `CondJump`'s condition was truthy
+    Then,
+    /// a `CondJump`'s condition was falsy
+    Else,
+    /// an error was raised while a `PushHandler`'s handler was pushed
+    Handler,
+}
+
+/// A run of instructions that's only entered at the top and only left at the bottom
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    /// the offset of the first instruction
+    pub start: usize,
+    pub instructions: Vec<Instruction>,
+    /// the start of each block control can go to next
+    pub successors: Vec<(usize, Edge)>,
+}
+
+/// The basic blocks of one function, split at `Jump`, `CondJump` and `Return`
+/// (and anything else that jumps or stops, like `PushHandler`'s landing and `Raise`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph {
+    pub function: String,
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl ControlFlowGraph {
+    pub fn new(function: &str, chunk: &BytecodeChunk) -> Result<Self, String> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let instruction = decode(chunk, offset).map_err(|e| format!("in {function}, {e}"))?;
+            offset = instruction.next;
+            instructions.push(instruction);
+        }
+
+        // the instructions that start a block
+        let mut leaders = BTreeSet::from([0]);
+        for instruction in instructions.iter() {
+            if let Operands::Jump(target) = instruction.operands {
+                leaders.insert(target);
+            }
+            if ends_block(instruction.op) {
+                leaders.insert(instruction.next);
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = vec![];
+        for instruction in instructions {
+            if leaders.contains(&instruction.offset) {
+                blocks.push(BasicBlock {
+                    start: instruction.offset,
+                    instructions: vec![],
+                    successors: vec![],
+                });
+            }
+            blocks.last_mut().unwrap().instructions.push(instruction);
+        }
+
+        let end = chunk.code.len();
+        for block in blocks.iter_mut() {
+            let last = block.instructions.last().unwrap();
+            let next = last.next;
+            block.successors = match (last.op, &last.operands) {
+                (Op::Jump, Operands::Jump(target)) => vec![(*target, Edge::Jump)],
+                (Op::CondJump, Operands::Jump(target)) => {
+                    vec![(*target, Edge::Then), (next, Edge::Else)]
+                }
+                (Op::PushHandler, Operands::Jump(landing)) => {
+                    vec![(next, Edge::FallThrough), (*landing, Edge::Handler)]
+                }
+                (Op::Return | Op::Raise | Op::DebugEnd, _) => vec![],
+                _ => vec![(next, Edge::FallThrough)],
+            };
+            // malformed code can run or jump off the end, which isn't a block
+            block.successors.retain(|(start, _)| *start < end);
+        }
+
+        Ok(ControlFlowGraph {
+            function: function.to_string(),
+            blocks,
+        })
+    }
+
+    /// The graph in Graphviz's DOT language, with each block labelled with its instructions
+    pub fn to_dot(&self, chunk: &BytecodeChunk) -> String {
+        let mut out = format!("digraph {} {{\n", quote(&self.function));
+        out.push_str("  node [shape=box, fontname=monospace];\n");
+        for block in self.blocks.iter() {
+            let mut label = String::new();
+            for instruction in block.instructions.iter() {
+                let text = format_instruction(chunk, instruction);
+                label.push_str(&format!("{:04} {}\\l", instruction.offset, escape(&text)));
+            }
+            out.push_str(&format!("  b{} [label=\"{label}\"];\n", block.start));
+        }
+        for block in self.blocks.iter() {
+            for (to, edge) in block.successors.iter() {
+                let attributes = match edge {
+                    Edge::FallThrough | Edge::Jump => "",
+                    Edge::Then => " [label=\"then\"]",
+                    Edge::Else => " [label=\"else\"]",
+                    Edge::Handler => " [label=\"error\", style=dashed]",
+                };
+                out.push_str(&format!("  b{} -> b{to}{attributes};\n", block.start));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// whether control can't carry on to the next instruction as usual after `op`
+fn ends_block(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Jump | Op::CondJump | Op::PushHandler | Op::Return | Op::Raise | Op::DebugEnd
+    )
+}
+
+/// A DOT graph for the top level of `chunk`, followed by one for every function inside it
+pub fn dot(chunk: &BytecodeChunk) -> Result<String, String> {
+    let mut out = String::new();
+    add_function("<top level>", chunk, &mut out)?;
+    Ok(out)
+}
+
+fn add_function(name: &str, chunk: &BytecodeChunk, out: &mut String) -> Result<(), String> {
+    out.push_str(&ControlFlowGraph::new(name, chunk)?.to_dot(chunk));
+    for constant in chunk.constants.iter() {
+        if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+            add_function(&closure.f.name, closure.f.bytecode(), out)?;
+        }
+    }
+    Ok(())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    fn graph_of(src: &str) -> ControlFlowGraph {
+        let chunk = compile(&src.to_string());
+        ControlFlowGraph::new("<top level>", &chunk).unwrap()
+    }
+
+    fn edges(graph: &ControlFlowGraph) -> Vec<(usize, usize, Edge)> {
+        graph
+            .blocks
+            .iter()
+            .flat_map(|block| {
+                block
+                    .successors
+                    .iter()
+                    .map(|(to, edge)| (block.start, *to, *edge))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn straight_line_code_is_one_block() {
+        let graph = graph_of("(define x (+ 1 2))");
+        assert_eq!(graph.blocks.len(), 1);
+        assert_eq!(edges(&graph), vec![]);
+    }
+
+    #[test]
+    fn if_splits_into_diamond() {
+        // Constant, Constant, LT, CondJump | Constant 'no, Jump | Constant 'yes | Pop, DebugEnd
+        let graph = graph_of("(if (< 1 2) 'yes 'no)");
+        let starts = graph.blocks.iter().map(|b| b.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 7, 11, 13]);
+        assert_eq!(
+            edges(&graph),
+            vec![
+                (0, 11, Edge::Then),
+                (0, 7, Edge::Else),
+                (7, 13, Edge::Jump),
+                (11, 13, Edge::FallThrough),
+            ]
+        );
+    }
+
+    #[test]
+    fn handlers_have_error_edges() {
+        let graph = graph_of("(try (print 1) (finally (print 2)))");
+        assert!(edges(&graph)
+            .iter()
+            .any(|(_, _, edge)| *edge == Edge::Handler));
+        // re-raising after the `finally` ends its block without any successors
+        let raising = graph
+            .blocks
+            .iter()
+            .find(|b| b.instructions.last().unwrap().op == Op::Raise)
+            .unwrap();
+        assert_eq!(raising.successors, vec![]);
+    }
+
+    #[test]
+    fn dot_output() {
+        let chunk = compile(&"(defun (sign n) (if (< n 0) 'negative 'positive))".to_string());
+        let dot = dot(&chunk).unwrap();
+        let expected = r#"digraph "sign" {
+  node [shape=box, fontname=monospace];
+  b0 [label="0000 ReferenceLocal 1\l0002 Constant k0 ; 0\l0004 LT\l0005 CondJump L11\l"];
+  b7 [label="0007 Constant k1 ; 'positive\l0009 Jump L13\l"];
+  b11 [label="0011 Constant k2 ; 'negative\l"];
+  b13 [label="0013 Return\l"];
+  b0 -> b11 [label="then"];
+  b0 -> b7 [label="else"];
+  b7 -> b13;
+  b11 -> b13;
+}
+"#;
+        assert!(dot.starts_with("digraph \"<top level>\" {\n"), "{dot}");
+        assert!(dot.ends_with(expected), "{dot}");
+    }
+}
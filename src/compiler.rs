@@ -1,9 +1,10 @@
+use crate::ir::{self, FunctionNode, Node};
 use crate::vm::{CaptureType, Closure, ConstantObject};
 use crate::{
     lexer, parser,
     sexpr::SrcSexpr,
     structural_parser::structure_ast,
-    vm::{BytecodeChunk, ConstantValue, Function, Op},
+    vm::{BytecodeChunk, ConstantValue, DebugInfo, Function, Op},
 };
 
 // The goal is to get this to be `SrcSexpr`
@@ -46,6 +47,32 @@ pub enum Expression {
     // /// the value of `nil`
     // NilLit,
     Discard(Box<Expression>),
+
+    /// (try body (catch e ..handler) (finally ..cleanup))
+    /// the handler is compiled as a function of the caught error
+    Try {
+        body: Box<Expression>,
+        catch: Option<FunctionExpression>,
+        finally: Vec<Expression>,
+    },
+
+    /// (call/cc f)
+    CallCC(Box<Expression>),
+
+    /// (resume co [value])
+    Resume {
+        coroutine: Box<Expression>,
+        value: Option<Box<Expression>>,
+    },
+
+    /// (yield [value])
+    Yield(Option<Box<Expression>>),
+
+    /// an expression and the source line it starts on
+    Located {
+        line: usize,
+        expr: Box<Expression>,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -75,6 +102,7 @@ impl Into<ConstantValue> for SrcSexpr {
             SrcSexpr::Symbol(x) => ConstantValue::Object(ConstantObject::Symbol(x)),
             SrcSexpr::List(l) => ConstantValue::List(l.into_iter().map(Into::into).collect()),
             SrcSexpr::Quote(x) => ConstantValue::Quote(Box::new((*x).into())),
+            SrcSexpr::Located(_, x) => (*x).into(),
         }
     }
 }
@@ -120,6 +148,10 @@ pub struct ChunkCompiler {
     args: Vec<Local>,
     locals: Vec<Local>,
     captured_upvalues: Vec<UpvalueCapture>,
+    /// the names of `captured_upvalues`, for debug info
+    upvalue_names: Vec<String>,
+    /// (code offset, source line) for the start of each located expression
+    lines: Vec<(usize, usize)>,
 }
 
 impl ChunkCompiler {
@@ -130,6 +162,21 @@ impl ChunkCompiler {
             locals: vec![],
             code: vec![],
             captured_upvalues: vec![],
+            upvalue_names: vec![],
+            lines: vec![],
+        }
+    }
+
+    fn debug_info(&self) -> DebugInfo {
+        DebugInfo {
+            lines: self.lines.clone(),
+            locals: self
+                .args
+                .iter()
+                .chain(self.locals.iter())
+                .map(|local| local.name.clone())
+                .collect(),
+            upvalues: self.upvalue_names.clone(),
         }
     }
 }
@@ -147,54 +194,160 @@ impl Compiler {
         self.current_mut().code.push(op);
     }
 
-    fn compile_expression(&mut self, expression: Expression) {
-        match expression {
-            Expression::SrcSexpr(sexpr) => match sexpr {
-                SrcSexpr::Symbol(sym) => {
-                    self.compile_symbol_as_reference(sym);
-                }
-                SrcSexpr::Bool(_) | SrcSexpr::Int(_) | SrcSexpr::Float(_) | SrcSexpr::String(_) => {
-                    self.compile_self_evaluation(sexpr)
-                }
-                SrcSexpr::Quote(_) => self.compile_self_evaluation(sexpr),
-                SrcSexpr::List(_) => {
-                    unreachable!("this should have been handled by the structural parser")
-                }
-            },
-            Expression::If {
+    fn compile_node(&mut self, node: Node) {
+        match node {
+            Node::Constant(c) => self.compile_constant(c),
+            Node::Local(name) => self.compile_local_reference(name),
+            Node::Global(name) => {
+                self.code_push(Op::ReferenceGlobal.into());
+                // this is one of those wierd/cool cases where a language concept becomes a runtime concept: the symbol in the code is a runtime value
+                // can abstract this?
+                self.add_constant_and_push_idx(ConstantValue::Object(ConstantObject::String(name)));
+            }
+            Node::If {
                 condition,
                 then,
                 else_,
             } => self.compile_if_statement(condition, else_, then),
-            Expression::RegularForm(exprs) => self.compile_regular_form(exprs),
-            Expression::FunctionLiteral(function_expr) => {
-                self.compile_function(function_expr);
-            }
-            Expression::DeclareGlobal { name, value } => {
-                self.compile_global_declaration(name, value)
+            Node::Call { callee, args } => self.compile_call(*callee, args),
+            Node::Function(function) => {
+                self.compile_function(function);
             }
-            Expression::LocalDefine { name, value } => self.compile_local_definition(name, value),
-            Expression::Discard(expr) => {
-                match *expr {
-                    Expression::LocalDefine { name: _, value: _ }
-                    | Expression::DeclareGlobal { name: _, value: _ }
-                    | Expression::Discard(_) => {
-                        panic!("should not be discarding this expr: {:?}", expr)
+            Node::DeclareGlobal { name, value } => self.compile_global_declaration(name, value),
+            Node::DefineLocal { name, value } => self.compile_local_definition(name, value),
+            Node::Discard(node) => {
+                match *node {
+                    Node::DefineLocal { name: _, value: _ }
+                    | Node::DeclareGlobal { name: _, value: _ }
+                    | Node::Discard(_) => {
+                        panic!("should not be discarding this node: {:?}", node)
                     }
                     _ => {}
                 };
 
-                self.compile_expression(*expr);
+                self.compile_node(*node);
                 self.code_push(Op::Pop.into());
             }
-            Expression::LocalSet { name, value } => {
+            Node::SetLocal { name, value } => {
                 self.compile_local_set(name, value);
             }
+            Node::Try {
+                body,
+                catch,
+                finally,
+            } => self.compile_try(*body, catch, finally),
+            Node::CallCC(function) => {
+                self.compile_node(*function);
+                self.code_push(Op::CallCC.into());
+            }
+            Node::Resume { coroutine, value } => {
+                self.compile_node(*coroutine);
+                self.compile_optional_value(value);
+                self.code_push(Op::Resume.into());
+            }
+            Node::Yield(value) => {
+                self.compile_optional_value(value);
+                self.code_push(Op::Yield.into());
+            }
+            Node::Block(nodes) => {
+                for node in nodes {
+                    self.compile_node(node);
+                }
+            }
+            Node::Located { line, node } => {
+                self.mark_line(line);
+                self.compile_node(*node);
+            }
+        }
+    }
+
+    /// record that the next instruction starts an expression on `line`
+    fn mark_line(&mut self, line: usize) {
+        let offset = self.current().code.len();
+        let lines = &mut self.current_mut().lines;
+        // nested expressions can start at the same offset, keep the innermost
+        if let Some(last) = lines.last_mut() {
+            if last.0 == offset {
+                *last = (offset, line);
+                return;
+            }
         }
+        lines.push((offset, line));
     }
 
-    fn compile_local_set(&mut self, sym: String, value: Box<Expression>) {
-        self.compile_expression(*value);
+    /// a missing value compiles to `nil`
+    fn compile_optional_value(&mut self, value: Option<Box<Node>>) {
+        match value {
+            Some(value) => self.compile_node(*value),
+            None => self.compile_constant(ConstantValue::Nil),
+        }
+    }
+
+    fn compile_try(&mut self, body: Node, catch: Option<FunctionNode>, finally: Vec<Node>) {
+        // the `finally` guard is registered first so that it also covers errors raised by the handler
+        let finally_landing_idx = if finally.is_empty() {
+            None
+        } else {
+            self.compile_constant(ConstantValue::Nil);
+            Some(self.compile_push_handler())
+        };
+
+        match catch {
+            Some(handler) => {
+                self.compile_function(handler);
+                let catch_landing_idx = self.compile_push_handler();
+                self.compile_node(body);
+                self.code_push(Op::PopHandler.into());
+                // skip over the handler call
+                self.code_push(Op::Jump.into());
+                self.code_push(3);
+                // CATCH
+                // the VM unwinds to here with the handler and the error on the stack
+                self.patch_jump(catch_landing_idx);
+                self.code_push(Op::FuncCall.into());
+                self.code_push(1);
+            }
+            None => self.compile_node(body),
+        }
+
+        if let Some(finally_landing_idx) = finally_landing_idx {
+            self.code_push(Op::PopHandler.into());
+            for node in finally.clone() {
+                self.compile_node(node);
+            }
+            self.code_push(Op::Jump.into());
+            self.code_push(0x00);
+            let finish_jump_idx = self.current().code.len() - 1;
+            // FINALLY (raised)
+            // the VM unwinds to here with the error on the stack, run the cleanup and re-raise
+            self.patch_jump(finally_landing_idx);
+            for node in finally {
+                self.compile_node(node);
+            }
+            self.code_push(Op::Raise.into());
+            // FINISH
+            self.patch_jump(finish_jump_idx);
+        }
+    }
+
+    /// emits a `PushHandler` with a placeholder landing offset, returning the index to patch
+    fn compile_push_handler(&mut self) -> usize {
+        self.code_push(Op::PushHandler.into());
+        self.code_push(0x00);
+        self.current().code.len() - 1
+    }
+
+    /// point the jump operand at `operand_idx` to the next instruction to be emitted
+    fn patch_jump(&mut self, operand_idx: usize) {
+        let offset = self.current().code.len() - operand_idx;
+        if offset > u8::MAX as usize {
+            panic!("jump too large: {}", offset)
+        }
+        self.current_mut().code[operand_idx] = offset as u8;
+    }
+
+    fn compile_local_set(&mut self, sym: String, value: Box<Node>) {
+        self.compile_node(*value);
 
         if let Some(idx) = self.resolve_local_pos(&sym, self.chunks.len() - 1) {
             self.code_push(Op::SetLocal.into());
@@ -208,38 +361,38 @@ impl Compiler {
         }
     }
 
-    fn compile_function(&mut self, function_expr: FunctionExpression) {
-        let ChunkCompiler {
-            code,
-            constants,
-            captured_upvalues,
-            args,
-            locals,
-        } = {
+    fn compile_function(&mut self, function_expr: FunctionNode) {
+        let chunk = {
             self.chunks.push(ChunkCompiler {
-                code: vec![],
-                constants: vec![],
                 args: function_expr
                     .parameters
                     .iter()
                     .map(|name| Local::new(name.clone()))
                     .collect(),
-                locals: vec![],
-                captured_upvalues: vec![],
+                ..ChunkCompiler::new()
             });
-            for expr in function_expr.body {
-                self.compile_expression(expr);
+            for node in function_expr.body {
+                self.compile_node(node);
             }
             self.code_push(Op::Return.into());
             self.chunks.pop().unwrap()
         };
+        let debug = chunk.debug_info();
+        let ChunkCompiler {
+            code,
+            constants,
+            captured_upvalues,
+            args,
+            locals,
+            ..
+        } = chunk;
 
         let closure = Closure::new(
             Function::new(
                 function_expr.name.unwrap_or("anonymous".to_string()),
                 args.len(),
                 locals.len(),
-                BytecodeChunk::new(code, constants),
+                BytecodeChunk::new(code, constants).with_debug_info(debug),
             ),
             captured_upvalues.len(),
         );
@@ -259,7 +412,7 @@ impl Compiler {
         }
     }
 
-    fn compile_local_definition(&mut self, name: String, value: Box<Expression>) {
+    fn compile_local_definition(&mut self, name: String, value: Box<Node>) {
         let redefining_local = self
             .current()
             .args
@@ -270,15 +423,15 @@ impl Compiler {
             panic!("redefining local variable")
         };
         self.current_mut().locals.push(Local::new(name.clone()));
-        self.compile_expression(*value);
+        self.compile_node(*value);
         self.code_push(Op::Define.into());
         let idx = self.current().args.len() + self.current().locals.len();
         self.code_push(idx as u8);
     }
 
-    fn compile_symbol_as_reference(&mut self, sym: String) {
-        // evaulate as reference as opposed to value
-        // local / function argument
+    /// `ir::build` only makes a `Local` for a name that's a local here or in a function this
+    /// one is nested in
+    fn compile_local_reference(&mut self, sym: String) {
         let chunk_idx = self.chunks.len() - 1;
         let local_idx = self.resolve_local_pos(&sym, chunk_idx);
 
@@ -290,57 +443,57 @@ impl Compiler {
             self.code_push(Op::ReferenceUpvalue.into());
             self.code_push(upvalue_idx as u8);
         } else {
-            // fall back to global
-            self.code_push(Op::ReferenceGlobal.into());
-            // this can be optimized by reusing the same constant for the same symbol
-            // also - this is one of those wierd/cool cases where a language concept becomes a runtime concept: the symbol in the code is a runtime value
-            // can abstract this?
-            self.add_constant_and_push_idx(ConstantValue::Object(ConstantObject::String(sym)));
+            unreachable!("{sym} isn't a local")
         }
     }
 
-    fn compile_regular_form(&mut self, exprs: Vec<Expression>) {
+    /// Calls to the arithmetic and comparison builtins with two arguments get their own opcode,
+    /// unless a local or upvalue shadows them, which `ir::build` has resolved. The VM notices
+    /// globals that rebind them.
+    fn compile_call(&mut self, callee: Node, args: Vec<Node>) {
+        if let Node::Global(name) = &callee {
+            if let (Some(op), 2) = (Op::for_operator(name), args.len()) {
+                for arg in args {
+                    self.compile_node(arg);
+                }
+                self.code_push(op.into());
+                return;
+            }
+        }
+
         // We don't know the arity of the function at compile-time so we
         // defensively put the number of arguments to check at runtime
         let arity = {
-            let arity = exprs.len() - 1;
+            let arity = args.len();
             if arity > 255 {
                 panic!()
             }
             arity as u8
         };
-        for expr in exprs {
-            self.compile_expression(expr);
+        self.compile_node(callee);
+        for arg in args {
+            self.compile_node(arg);
         }
         self.code_push(Op::FuncCall.into());
         self.code_push(arity);
     }
 
-    fn compile_global_declaration(&mut self, name: String, value: Box<Expression>) {
-        self.compile_expression(*value);
+    fn compile_global_declaration(&mut self, name: String, value: Box<Node>) {
+        self.compile_node(*value);
         self.code_push(Op::DeclareGlobal.into());
-        self.current_mut()
-            .constants
-            .push(ConstantValue::Object(ConstantObject::String(name)));
-        let idx = self.current().constants.len() as u8 - 1;
-        self.code_push(idx);
+        self.add_constant_and_push_idx(ConstantValue::Object(ConstantObject::String(name)));
     }
 
-    fn compile_if_statement(
-        &mut self,
-        condition: Box<Expression>,
-        else_: Box<Expression>,
-        then: Box<Expression>,
-    ) {
+    fn compile_if_statement(&mut self, condition: Box<Node>, else_: Box<Node>, then: Box<Node>) {
         // IF
-        self.compile_expression(*condition);
+        self.compile_node(*condition);
         // skip to "then"
         self.code_push(Op::CondJump.into());
         self.code_push(0x00);
         // will mutate this later
         let then_jump_idx = self.current().code.len() - 1;
         // ELSE
-        self.compile_expression(*else_);
+        self.compile_node(*else_);
         // skip to end
         self.code_push(Op::Jump.into());
         // self.current().code[to_then_jump_address as usize] = self.current().code.len() as u8;
@@ -350,7 +503,7 @@ impl Compiler {
         // THEN
         let then_jump = (self.current().code.len() - then_jump_idx) as u8;
         self.current_mut().code[then_jump_idx] = then_jump;
-        self.compile_expression(*then);
+        self.compile_node(*then);
         // FINISH
         let finish_jump = (self.current().code.len() - finish_jump_idx) as u8;
         self.current_mut().code[finish_jump_idx] = finish_jump
@@ -361,39 +514,20 @@ impl Compiler {
         self.add_constant_and_push_idx(c);
     }
 
+    /// Reuses an identical constant in the chunk if there is one
     fn add_constant_and_push_idx(&mut self, c: ConstantValue) {
-        self.current_mut().constants.push(c);
-        let idx = self.current().constants.len() as u8 - 1;
-        self.code_push(idx);
-    }
-
-    fn compile_self_evaluation(&mut self, sexpr: SrcSexpr) {
-        match sexpr {
-            SrcSexpr::Bool(x) => {
-                self.compile_constant(ConstantValue::Boolean(x));
-            }
-            SrcSexpr::Int(x) => {
-                self.compile_constant(ConstantValue::Integer(x));
-            }
-            SrcSexpr::Float(x) => {
-                self.compile_constant(ConstantValue::Float(x));
-            }
-            SrcSexpr::String(x) => {
-                self.compile_constant(ConstantValue::Object(ConstantObject::String(x)));
-            }
-            SrcSexpr::Symbol(x) => {
-                self.compile_constant(ConstantValue::Object(ConstantObject::Symbol(x)));
-            }
-            // NOTE this is a literal sexpr list: `'()`, not a list constructor: `(list 1 2 3)`. The latter is a regular form
-            SrcSexpr::List(sexprs) => {
-                let const_sexprs = sexprs.into_iter().map(Into::into).collect();
-                self.compile_constant(ConstantValue::List(const_sexprs))
-            }
-            SrcSexpr::Quote(quoted_sexpr) => {
-                let const_sexpr = (*quoted_sexpr).into();
-                self.compile_constant(ConstantValue::Quote(Box::new(const_sexpr)));
+        let constants = &mut self.current_mut().constants;
+        let idx = match constants
+            .iter()
+            .position(|existing| identical(existing, &c))
+        {
+            Some(idx) => idx,
+            None => {
+                constants.push(c);
+                constants.len() - 1
             }
-        }
+        };
+        self.code_push(idx as u8);
     }
 
     fn resolve_upvalue(&mut self, sym: &str) -> Option<usize> {
@@ -412,13 +546,14 @@ impl Compiler {
         if let Some(local_index) = self.resolve_local_pos(sym, chunk_idx - 1) {
             // mark the local as captured
             // self.chunks.get_mut(chunk_idx - 1).unwrap().locals[local_index].captured = true;
-            let upvalue_idx = self.add_upvalue(UpvalueCapture::Local { i: local_index }, chunk_idx);
+            let upvalue_idx =
+                self.add_upvalue(UpvalueCapture::Local { i: local_index }, sym, chunk_idx);
             return Some(upvalue_idx);
         };
 
         if let Some(upvalue_index) = self.resolve_upvalue_rec(sym, chunk_idx - 1) {
             let upvalue_idx =
-                self.add_upvalue(UpvalueCapture::Upvalue { i: upvalue_index }, chunk_idx);
+                self.add_upvalue(UpvalueCapture::Upvalue { i: upvalue_index }, sym, chunk_idx);
             return Some(upvalue_idx);
         };
 
@@ -434,39 +569,67 @@ impl Compiler {
             .position(|x| x.name == sym);
     }
 
-    fn add_upvalue(&mut self, uv: UpvalueCapture, chunk_index: usize) -> usize {
+    fn add_upvalue(&mut self, uv: UpvalueCapture, name: &str, chunk_index: usize) -> usize {
         let compiler = self.chunks.get_mut(chunk_index).unwrap();
         if let Some(upvalue_idx) = compiler.captured_upvalues.iter().position(|x| x == &uv) {
             return upvalue_idx;
         }
 
         compiler.captured_upvalues.push(uv);
+        compiler.upvalue_names.push(name.to_string());
         compiler.captured_upvalues.len() - 1
     }
 }
 
+/// Whether two constants can share a slot in the constant pool. Floats are compared bit for bit,
+/// so `0.0` and `-0.0` stay apart, and functions are never shared.
+fn identical(a: &ConstantValue, b: &ConstantValue) -> bool {
+    match (a, b) {
+        (ConstantValue::Float(a), ConstantValue::Float(b)) => a.to_bits() == b.to_bits(),
+        (ConstantValue::List(a), ConstantValue::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| identical(a, b))
+        }
+        (ConstantValue::Quote(a), ConstantValue::Quote(b)) => identical(a, b),
+        (ConstantValue::Object(ConstantObject::Closure(_)), _) => false,
+        _ => a == b,
+    }
+}
+
 pub fn compile(src: &String) -> BytecodeChunk {
-    let tokens = lexer::lex(src).unwrap_or_else(|e| {
+    compile_expressions(structure(src))
+}
+
+/// Like `compile`, but optimises the program on the way, see `ir::optimise`.
+/// It has to be the whole program.
+pub fn compile_optimised(src: &String) -> BytecodeChunk {
+    let mut program = ir::build(structure(src));
+    ir::optimise(&mut program);
+    lower(program)
+}
+
+pub(crate) fn structure(src: &String) -> Vec<Expression> {
+    let (tokens, lines) = lexer::lex_with_lines(src).unwrap_or_else(|e| {
         panic!("Lexing error: {}", e);
     });
     // println!("{:#?}", tokens);
 
-    let ast = parser::parse(tokens).unwrap_or_else(|e| {
+    let ast = parser::parse_with_lines(tokens, lines).unwrap_or_else(|e| {
         panic!("Parsing error: {}", e);
     });
     // println!("{:#?}", ast);
 
-    let expressions = structure_ast(ast);
-    // println!("{:#?}", expressions);
-
-    compile_expressions(expressions)
+    structure_ast(ast)
 }
 
 fn compile_expressions(expressions: Vec<Expression>) -> BytecodeChunk {
+    lower(ir::build(expressions))
+}
+
+fn lower(program: Vec<Node>) -> BytecodeChunk {
     let mut compiler = Compiler::new();
 
-    for expression in expressions {
-        compiler.compile_expression(expression);
+    for node in program {
+        compiler.compile_node(node);
     }
     compiler.current_mut().code.push(Op::DebugEnd.into());
 
@@ -475,8 +638,9 @@ fn compile_expressions(expressions: Vec<Expression>) -> BytecodeChunk {
     }
 
     let comp = compiler.chunks.pop().unwrap();
+    let debug = comp.debug_info();
 
-    BytecodeChunk::new(comp.code, comp.constants)
+    BytecodeChunk::new(comp.code, comp.constants).with_debug_info(debug)
 }
 
 #[cfg(test)]
@@ -524,7 +688,44 @@ mod tests {
         let mut vm = VM::default();
         vm.run(bc);
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(12));
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(12));
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let expression = Expression::Try {
+            body: Box::new(Expression::SrcSexpr(SrcSexpr::Int(1))),
+            catch: Some(FunctionExpression::new(
+                vec!["e".to_string()],
+                vec![Expression::SrcSexpr(SrcSexpr::Int(2))],
+                Some("catch".to_string()),
+            )),
+            finally: vec![],
+        };
+        let bc = compile_expressions(vec![expression]);
+        assert_eq!(
+            bc.code,
+            vec![
+                Op::Closure.into(),
+                0, // the handler
+                Op::PushHandler.into(),
+                6, // to the handler call
+                Op::Constant.into(),
+                1,
+                Op::PopHandler.into(),
+                Op::Jump.into(),
+                3, // over the handler call
+                Op::FuncCall.into(),
+                1, // call the handler with the error
+                Op::DebugEnd.into(),
+            ]
+        );
+
+        // NOTE: This test shouldn't be here but good for easy testing
+        let mut vm = VM::default();
+        vm.run(bc);
+        assert_eq!(vm.stack.len(), 1);
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(1));
     }
 
     #[test]
@@ -577,29 +778,24 @@ mod tests {
         assert_eq!(
             bc.constants,
             vec![
-                ConstantValue::Object(ConstantObject::String("+".to_string())),
                 ConstantValue::Integer(11),
                 ConstantValue::Integer(12),
                 ConstantValue::Object(ConstantObject::String("foo".to_string())),
-                ConstantValue::Object(ConstantObject::String("foo".to_string())),
             ]
         );
 
         assert_eq!(
             bc.code,
             vec![
-                Op::ReferenceGlobal.into(),
-                0, // "+"
                 Op::Constant.into(),
-                1, // "11"
+                0, // "11"
                 Op::Constant.into(),
-                2, // "12"
-                Op::FuncCall.into(),
-                2, // arity
+                1, // "12"
+                Op::Add.into(),
                 Op::DeclareGlobal.into(),
-                3, // "foo" constant index
+                2, // "foo" constant index
                 Op::ReferenceGlobal.into(),
-                4, // "foo" constant index
+                2, // the same "foo" constant
                 Op::DebugEnd.into(),
             ]
         );
@@ -609,13 +805,13 @@ mod tests {
         vm.run(bc);
         assert_eq!(vm.globals.get("foo"), Some(&SmallVal::Integer(23)));
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(23));
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(23));
     }
 
     #[test]
     fn test_call_function() {
         let bc = compile_expressions(vec![Expression::RegularForm(vec![
-            Expression::SrcSexpr(SrcSexpr::Symbol("*".to_string())),
+            Expression::SrcSexpr(SrcSexpr::Symbol("%".to_string())),
             Expression::SrcSexpr(SrcSexpr::Int(11)),
             Expression::SrcSexpr(SrcSexpr::Int(12)),
         ])]);
@@ -623,7 +819,7 @@ mod tests {
         assert_eq!(
             bc.constants,
             vec![
-                ConstantValue::Object(ConstantObject::String("*".to_string())),
+                ConstantValue::Object(ConstantObject::String("%".to_string())),
                 ConstantValue::Integer(11),
                 ConstantValue::Integer(12),
             ],
@@ -664,11 +860,8 @@ mod tests {
         assert_eq!(
             bc.constants,
             vec![
-                ConstantValue::Object(ConstantObject::String("+".to_string())),
-                ConstantValue::Object(ConstantObject::String("+".to_string())),
                 ConstantValue::Integer(11),
                 ConstantValue::Integer(12),
-                ConstantValue::Object(ConstantObject::String("*".to_string())),
                 ConstantValue::Integer(13),
                 ConstantValue::Integer(14),
             ]
@@ -677,26 +870,17 @@ mod tests {
         assert_eq!(
             bc.code,
             vec![
-                Op::ReferenceGlobal.into(),
-                0, // reference function symbol (outer)
-                Op::ReferenceGlobal.into(),
-                1, // reference function symbol (inner)
                 Op::Constant.into(),
-                2, // load arg 1: "11"
+                0, // load arg 1: "11"
                 Op::Constant.into(),
-                3, // load arg 2: "12"
-                Op::FuncCall.into(),
-                2, // call inner "+" with arity 2
-                Op::ReferenceGlobal.into(),
-                4, // load "*"
+                1,              // load arg 2: "12"
+                Op::Add.into(), // inner "+"
                 Op::Constant.into(),
-                5, // load arg 1: "13"
+                2, // load arg 1: "13"
                 Op::Constant.into(),
-                6, // load arg 2: "14"
-                Op::FuncCall.into(),
-                2, // call function "*" (outer) with arity 2
-                Op::FuncCall.into(),
-                2, // call function "+" (outer) with arity 2
+                3,              // load arg 2: "14"
+                Op::Mul.into(), // "*"
+                Op::Add.into(), // outer "+"
                 Op::DebugEnd.into(),
             ]
         );
@@ -705,6 +889,62 @@ mod tests {
         let mut vm = VM::default();
         vm.run(bc);
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(205));
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(205));
+    }
+
+    #[test]
+    fn test_shadowed_operators_are_called() {
+        let src = "(defun (apply + a b) (+ a b))\n(define x (apply - 5 3))".to_string();
+        let bc = compile(&src);
+        let ConstantValue::Object(ConstantObject::Closure(apply)) = &bc.constants[0] else {
+            panic!("expected a closure");
+        };
+        assert_eq!(
+            apply.f.bytecode().code,
+            vec![
+                Op::ReferenceLocal.into(),
+                1, // the `+` argument
+                Op::ReferenceLocal.into(),
+                2,
+                Op::ReferenceLocal.into(),
+                3,
+                Op::FuncCall.into(),
+                2,
+                Op::Return.into(),
+            ]
+        );
+
+        let mut vm = VM::default();
+        vm.run(bc);
+        assert_eq!(vm.globals.get("x"), Some(&SmallVal::Integer(2)));
+    }
+
+    #[test]
+    fn test_identical_constants_are_shared() {
+        let program = vec![
+            Expression::SrcSexpr(SrcSexpr::Float(0.0)),
+            Expression::SrcSexpr(SrcSexpr::Float(-0.0)),
+            Expression::SrcSexpr(SrcSexpr::Float(0.0)),
+            Expression::SrcSexpr(SrcSexpr::String("x".to_string())),
+            Expression::SrcSexpr(SrcSexpr::Symbol("x".to_string())),
+        ];
+        let bc = compile_expressions(program);
+        assert_eq!(bc.constants.len(), 3);
+        assert_eq!(
+            bc.code,
+            vec![
+                Op::Constant.into(),
+                0,
+                Op::Constant.into(),
+                1, // -0.0 isn't 0.0
+                Op::Constant.into(),
+                0,
+                Op::Constant.into(),
+                2,
+                Op::ReferenceGlobal.into(),
+                2, // the same "x"
+                Op::DebugEnd.into(),
+            ]
+        );
     }
 }
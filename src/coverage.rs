@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    disassembler::decode,
+    trace::{TraceContext, TraceHook},
+    vm::{BytecodeChunk, ConstantObject, ConstantValue, Op},
+};
+
+/// A `CondJump`, by the line it's on and where it is in its function
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct BranchId {
+    line: usize,
+    function: String,
+    offset: usize,
+}
+
+/// A `TraceHook` that records which lines and `if` branches of a program run.
+pub struct Coverage {
+    /// the number of times an expression on each line started
+    lines: BTreeMap<usize, u64>,
+    /// how many times each `CondJump` went to `[then, else]`
+    branches: BTreeMap<BranchId, [u64; 2]>,
+}
+
+impl Coverage {
+    /// Starts with every line and branch in `chunk` and the functions inside it,
+    /// so that code which never runs is reported too. Fails if the bytecode is malformed.
+    pub fn new(chunk: &BytecodeChunk) -> Result<Self, String> {
+        let mut coverage = Coverage {
+            lines: BTreeMap::new(),
+            branches: BTreeMap::new(),
+        };
+        coverage.add_chunk("<top level>", chunk)?;
+        Ok(coverage)
+    }
+
+    fn add_chunk(&mut self, function: &str, chunk: &BytecodeChunk) -> Result<(), String> {
+        for (_, line) in chunk.debug.lines.iter() {
+            self.lines.entry(*line).or_default();
+        }
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let instruction = decode(chunk, offset)?;
+            if instruction.op == Op::CondJump {
+                if let Some(line) = chunk.line_at(offset) {
+                    let id = BranchId {
+                        line,
+                        function: function.to_string(),
+                        offset,
+                    };
+                    self.branches.entry(id).or_default();
+                }
+            }
+            offset = instruction.next;
+        }
+        for constant in chunk.constants.iter() {
+            if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+                self.add_chunk(&closure.f.name, closure.f.bytecode())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many times an expression on `line` started, if there are any on it
+    pub fn line_hits(&self, line: usize) -> Option<u64> {
+        self.lines.get(&line).copied()
+    }
+
+    /// `[then, else]` counts for each branch on `line`, in code order
+    pub fn branch_hits(&self, line: usize) -> Vec<[u64; 2]> {
+        self.branches
+            .iter()
+            .filter(|(id, _)| id.line == line)
+            .map(|(_, hits)| *hits)
+            .collect()
+    }
+
+    fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|hits| **hits > 0).count()
+    }
+
+    fn branches_hit(&self) -> usize {
+        self.branches
+            .values()
+            .flat_map(|arms| arms.iter())
+            .filter(|hits| **hits > 0)
+            .count()
+    }
+
+    /// The coverage in the lcov tracefile format, for `source_file`
+    pub fn lcov(&self, source_file: &str) -> String {
+        let mut out = format!("TN:\nSF:{source_file}\n");
+        for (line, hits) in self.lines.iter() {
+            out.push_str(&format!("DA:{line},{hits}\n"));
+        }
+        out.push_str(&format!(
+            "LF:{}\nLH:{}\n",
+            self.lines.len(),
+            self.lines_hit()
+        ));
+
+        let mut block = 0;
+        let mut previous_line = None;
+        for (id, arms) in self.branches.iter() {
+            // blocks number the branches on each line
+            block = if previous_line == Some(id.line) {
+                block + 1
+            } else {
+                0
+            };
+            previous_line = Some(id.line);
+            let ran = arms.iter().any(|hits| *hits > 0);
+            for (branch, hits) in arms.iter().enumerate() {
+                let taken = if ran {
+                    hits.to_string()
+                } else {
+                    "-".to_string()
+                };
+                out.push_str(&format!("BRDA:{},{block},{branch},{taken}\n", id.line));
+            }
+        }
+        out.push_str(&format!(
+            "BRF:{}\nBRH:{}\n",
+            self.branches.len() * 2,
+            self.branches_hit()
+        ));
+        out.push_str("end_of_record\n");
+        out
+    }
+
+    /// A summary for printing, listing what didn't run
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "lines:    {}\nbranches: {}\n",
+            percentage(self.lines_hit(), self.lines.len()),
+            percentage(self.branches_hit(), self.branches.len() * 2)
+        );
+        let missed_lines = self
+            .lines
+            .iter()
+            .filter(|(_, hits)| **hits == 0)
+            .map(|(line, _)| line.to_string())
+            .collect::<Vec<_>>();
+        if !missed_lines.is_empty() {
+            out.push_str(&format!("lines not run: {}\n", missed_lines.join(", ")));
+        }
+        let missed_branches = self
+            .branches
+            .iter()
+            .flat_map(|(id, [then, else_])| {
+                let then = (*then == 0).then(|| format!("line {} then", id.line));
+                let else_ = (*else_ == 0).then(|| format!("line {} else", id.line));
+                then.into_iter().chain(else_)
+            })
+            .collect::<Vec<_>>();
+        if !missed_branches.is_empty() {
+            out.push_str(&format!(
+                "branches not taken: {}\n",
+                missed_branches.join(", ")
+            ));
+        }
+        out
+    }
+}
+
+fn percentage(hit: usize, total: usize) -> String {
+    if total == 0 {
+        return "0/0".to_string();
+    }
+    format!("{hit}/{total} ({:.1}%)", hit as f64 * 100.0 / total as f64)
+}
+
+impl TraceHook for Coverage {
+    fn on_dispatch(&mut self, context: &TraceContext) {
+        let lines = &context.chunk.debug.lines;
+        if let Ok(i) = lines.binary_search_by_key(&context.offset, |(offset, _)| *offset) {
+            *self.lines.entry(lines[i].1).or_default() += 1;
+        }
+    }
+
+    fn on_branch(&mut self, context: &TraceContext, taken: bool) {
+        let Some(line) = context.chunk.line_at(context.offset) else {
+            return;
+        };
+        let id = BranchId {
+            line,
+            function: context.function.to_string(),
+            offset: context.offset,
+        };
+        let arms = self.branches.entry(id).or_default();
+        arms[if taken { 0 } else { 1 }] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{compiler::compile, vm::VM};
+
+    fn cover(src: &str) -> Coverage {
+        let chunk = compile(&src.to_string());
+        let coverage = Rc::new(RefCell::new(Coverage::new(&chunk).unwrap()));
+        let mut vm = VM::default();
+        vm.set_trace_hook(Some(Box::new(coverage.clone())));
+        vm.run(chunk);
+        drop(vm);
+        Rc::try_unwrap(coverage).ok().unwrap().into_inner()
+    }
+
+    const SIGN: &str = "\
+(defun (sign n)
+  (if (< n 0)
+    'negative
+    'positive))
+(defun (unused) (print 1))
+(sign 5)
+(sign 3)";
+
+    #[test]
+    fn line_coverage() {
+        let coverage = cover(SIGN);
+        assert_eq!(coverage.line_hits(1), Some(1));
+        assert_eq!(coverage.line_hits(2), Some(2));
+        // atoms don't start a line table entry
+        assert_eq!(coverage.line_hits(3), None);
+        assert_eq!(coverage.line_hits(5), Some(1));
+        assert_eq!(coverage.line_hits(6), Some(1));
+    }
+
+    #[test]
+    fn never_called_functions_are_reported() {
+        let coverage = cover("(defun (unused)\n  (print 1))\n(print 2)");
+        assert_eq!(coverage.line_hits(2), Some(0));
+        assert!(coverage.summary().contains("lines not run: 2\n"));
+    }
+
+    #[test]
+    fn both_arms_of_an_if_are_counted() {
+        let coverage = cover(SIGN);
+        assert_eq!(coverage.branch_hits(2), vec![[0, 2]]);
+
+        // negative literals lex as symbols
+        let coverage = cover(&format!("{SIGN}\n(sign (- 0 1))"));
+        assert_eq!(coverage.branch_hits(2), vec![[1, 2]]);
+    }
+
+    #[test]
+    fn lcov_output() {
+        let lcov = cover(SIGN).lcov("sign.risp");
+        let expected = "\
+TN:
+SF:sign.risp
+DA:1,1
+DA:2,2
+DA:5,1
+DA:6,1
+DA:7,1
+LF:5
+LH:5
+BRDA:2,0,0,0
+BRDA:2,0,1,2
+BRF:2
+BRH:1
+end_of_record
+";
+        assert_eq!(lcov, expected);
+    }
+
+    #[test]
+    fn unrun_branches_are_marked() {
+        let lcov = cover("(defun (f x)\n  (if x 1 2))\n(print 0)").lcov("f.risp");
+        assert!(lcov.contains("BRDA:2,0,0,-\nBRDA:2,0,1,-\n"), "{lcov}");
+    }
+
+    #[test]
+    fn summary() {
+        let summary = cover(SIGN).summary();
+        assert_eq!(
+            summary,
+            "lines:    5/5 (100.0%)\nbranches: 1/2 (50.0%)\nbranches not taken: line 2 then\n"
+        );
+    }
+}
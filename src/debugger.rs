@@ -0,0 +1,457 @@
+use crate::{
+    disassembler::disassemble_instruction,
+    vm::{BytecodeChunk, FrameInfo, LimitExceeded, VM},
+};
+
+/// Where the debugger should stop when continuing
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    /// the start of every call to the function with this name
+    Function(String),
+    /// the start of every expression on this (1-based) source line
+    Line(usize),
+}
+
+impl Breakpoint {
+    /// a line number, or otherwise a function name
+    pub fn parse(s: &str) -> Breakpoint {
+        match s.parse() {
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) => Breakpoint::Function(s.to_string()),
+        }
+    }
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Step,
+    Breakpoint(Breakpoint),
+    Finished,
+    /// nothing caught an error, which is still on the instruction that raised it
+    Error(String),
+}
+
+/// Runs a chunk one instruction at a time, using the VM's fuel to pause it.
+pub struct Debugger {
+    vm: VM,
+    breakpoints: Vec<Breakpoint>,
+    finished: bool,
+}
+
+impl Debugger {
+    /// Loads `chunk`, paused before its first instruction
+    pub fn new(chunk: BytecodeChunk) -> Self {
+        let mut vm = VM::default();
+        vm.set_fuel(Some(0));
+        vm.set_stop_on_uncaught_errors(true);
+        let finished = vm.execute(chunk).is_ok();
+        Debugger {
+            vm,
+            breakpoints: vec![],
+            finished,
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// returns whether there was a breakpoint to remove
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    /// Runs a single instruction
+    pub fn step(&mut self) -> Stop {
+        self.run_instruction();
+        if self.finished {
+            return self.end();
+        }
+        Stop::Step
+    }
+
+    /// Runs until the start of the next expression, in any function
+    pub fn step_expression(&mut self) -> Stop {
+        loop {
+            self.run_instruction();
+            if self.finished {
+                return self.end();
+            }
+            if self.current_line_entry().is_some() {
+                return Stop::Step;
+            }
+        }
+    }
+
+    /// Runs until a breakpoint is hit or the program ends
+    pub fn continue_(&mut self) -> Stop {
+        loop {
+            self.run_instruction();
+            if self.finished {
+                return self.end();
+            }
+            if let Some(breakpoint) = self.hit_breakpoint() {
+                return Stop::Breakpoint(breakpoint);
+            }
+        }
+    }
+
+    pub fn backtrace(&self) -> Vec<FrameInfo> {
+        self.vm.backtrace()
+    }
+
+    /// The disassembly of the next instruction, or why it can't be decoded
+    pub fn current_instruction(&self) -> Result<String, String> {
+        let chunk = self.vm.current_chunk();
+        Ok(disassemble_instruction(chunk, self.vm.ip_offset())?.0)
+    }
+
+    fn run_instruction(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.vm.add_fuel(1);
+        match self.vm.resume() {
+            Ok(()) => self.finished = true,
+            Err(LimitExceeded::OutOfFuel) => {}
+            Err(LimitExceeded::UncaughtError) => self.finished = true,
+            Err(LimitExceeded::DeadlineExceeded) => {
+                unreachable!("the debugger doesn't set a deadline")
+            }
+        }
+    }
+
+    /// why the program can't go any further
+    fn end(&self) -> Stop {
+        match self.vm.uncaught_error() {
+            Some(error) => Stop::Error(format!("uncaught {error}")),
+            None => Stop::Finished,
+        }
+    }
+
+    /// the source line, if the next instruction starts an expression
+    fn current_line_entry(&self) -> Option<usize> {
+        let offset = self.vm.ip_offset();
+        self.vm
+            .current_chunk()
+            .debug
+            .lines
+            .iter()
+            .find(|(start, _)| *start == offset)
+            .map(|(_, line)| *line)
+    }
+
+    fn hit_breakpoint(&self) -> Option<Breakpoint> {
+        let line = self.current_line_entry();
+        let function_entry = self.vm.ip_offset() == 0;
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| match breakpoint {
+                Breakpoint::Function(name) => function_entry && self.vm.current_function() == name,
+                Breakpoint::Line(l) => line == Some(*l),
+            })
+            .cloned()
+    }
+
+    /// Runs a command from the `ruspc debug` prompt, returning what to print
+    pub fn command(&mut self, input: &str) -> String {
+        let words = input.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["break" | "b", target] => {
+                self.add_breakpoint(Breakpoint::parse(target));
+                format!(
+                    "breakpoint set at {}",
+                    describe_breakpoint(&Breakpoint::parse(target))
+                )
+            }
+            ["delete" | "d", target] => {
+                if self.remove_breakpoint(&Breakpoint::parse(target)) {
+                    "breakpoint deleted".to_string()
+                } else {
+                    format!(
+                        "no breakpoint at {}",
+                        describe_breakpoint(&Breakpoint::parse(target))
+                    )
+                }
+            }
+            ["step" | "s"] => {
+                let stop = self.step();
+                self.describe_stop(stop)
+            }
+            ["next" | "n"] => {
+                let stop = self.step_expression();
+                self.describe_stop(stop)
+            }
+            ["continue" | "c"] => {
+                let stop = self.continue_();
+                self.describe_stop(stop)
+            }
+            ["stack"] => (0..self.vm.stack.len())
+                .map(|i| format!("{i}: {}\n", self.vm.stack.at(i).unwrap()))
+                .collect(),
+            ["locals"] => match self.backtrace().first() {
+                Some(frame) if !frame.locals.is_empty() => frame
+                    .locals
+                    .iter()
+                    .map(|(name, value)| format!("{name} = {value}\n"))
+                    .collect(),
+                _ => "no locals\n".to_string(),
+            },
+            ["upvalues"] => match self.backtrace().first() {
+                Some(frame) if !frame.upvalues.is_empty() => frame
+                    .upvalues
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| format!("#{i} = {value}\n"))
+                    .collect(),
+                _ => "no upvalues\n".to_string(),
+            },
+            ["backtrace" | "bt"] => self
+                .backtrace()
+                .iter()
+                .enumerate()
+                .map(|(i, frame)| format!("#{i} {}\n", describe_frame(frame)))
+                .collect(),
+            ["list" | "l"] => self.list(),
+            ["help" | "h"] => HELP.to_string(),
+            _ => format!("unknown command: {input}, try `help`"),
+        }
+    }
+
+    fn describe_stop(&self, stop: Stop) -> String {
+        let reason = match stop {
+            Stop::Finished => return "program finished".to_string(),
+            Stop::Error(error) => format!("{error}\n"),
+            Stop::Step => "".to_string(),
+            Stop::Breakpoint(breakpoint) => {
+                format!("hit breakpoint at {}\n", describe_breakpoint(&breakpoint))
+            }
+        };
+        let location = match self.backtrace().first() {
+            Some(frame) => describe_frame(frame),
+            None => "<unknown>".to_string(),
+        };
+        let instruction = self
+            .current_instruction()
+            .unwrap_or_else(|e| format!("malformed bytecode: {e}"));
+        format!("{reason}{location}\n{instruction}")
+    }
+
+    /// the disassembly of the current chunk, with an arrow at the next instruction
+    fn list(&self) -> String {
+        let chunk = self.vm.current_chunk();
+        let ip_offset = self.vm.ip_offset();
+        let mut listing = String::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (instruction, next) = match disassemble_instruction(chunk, offset) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    listing.push_str(&format!("malformed bytecode: {e}\n"));
+                    break;
+                }
+            };
+            let marker = if offset == ip_offset { "->" } else { "  " };
+            listing.push_str(&format!("{marker} {offset:>4} {instruction}\n"));
+            offset = next;
+        }
+        listing
+    }
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    match breakpoint {
+        Breakpoint::Function(name) => format!("function {name}"),
+        Breakpoint::Line(line) => format!("line {line}"),
+    }
+}
+
+fn describe_frame(frame: &FrameInfo) -> String {
+    match frame.line {
+        Some(line) => format!(
+            "{} at offset {} (line {line})",
+            frame.function, frame.offset
+        ),
+        None => format!("{} at offset {}", frame.function, frame.offset),
+    }
+}
+
+const HELP: &str = "\
+break, b <function|line>  stop at the start of a function or a line
+delete, d <function|line> remove a breakpoint
+step, s                   run one instruction
+next, n                   run to the start of the next expression
+continue, c               run to the next breakpoint
+stack                     print the value stack
+locals                    print the current frame's arguments and locals
+upvalues                  print the current closure's upvalues
+backtrace, bt             print the call frames, innermost first
+list, l                   disassemble the current function
+quit, q                   stop debugging
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::SmallVal};
+
+    fn debugger(src: &str) -> Debugger {
+        Debugger::new(compile(&src.to_string()))
+    }
+
+    const ADD: &str = "\
+(defun (add a b)
+  (+ a b))
+(define x (add 1 2))
+(print x)";
+
+    #[test]
+    fn function_breakpoint_stops_on_entry() {
+        let mut debugger = debugger(ADD);
+        debugger.add_breakpoint(Breakpoint::Function("add".to_string()));
+
+        let stop = debugger.continue_();
+        assert_eq!(
+            stop,
+            Stop::Breakpoint(Breakpoint::Function("add".to_string()))
+        );
+
+        let backtrace = debugger.backtrace();
+        assert_eq!(backtrace.len(), 2);
+        assert_eq!(backtrace[0].function, "add");
+        assert_eq!(backtrace[0].offset, 0);
+        assert_eq!(backtrace[0].line, Some(2));
+        assert_eq!(
+            backtrace[0].locals,
+            vec![
+                ("a".to_string(), SmallVal::Integer(1)),
+                ("b".to_string(), SmallVal::Integer(2))
+            ]
+        );
+        assert_eq!(backtrace[1].function, "<top level>");
+        assert_eq!(backtrace[1].line, Some(3));
+
+        assert_eq!(debugger.continue_(), Stop::Finished);
+    }
+
+    #[test]
+    fn line_breakpoint() {
+        let mut debugger = debugger(ADD);
+        debugger.add_breakpoint(Breakpoint::Line(4));
+
+        assert_eq!(debugger.continue_(), Stop::Breakpoint(Breakpoint::Line(4)));
+        assert_eq!(debugger.vm().globals.get("x"), Some(&SmallVal::Integer(3)));
+        assert_eq!(debugger.backtrace()[0].line, Some(4));
+    }
+
+    #[test]
+    fn step_runs_one_instruction() {
+        let mut debugger = debugger("(define x (+ 1 2))");
+        assert_eq!(
+            debugger.current_instruction(),
+            Ok("Constant k0 ; 1".to_string())
+        );
+
+        assert_eq!(debugger.step(), Stop::Step);
+        assert_eq!(debugger.vm().ip_offset(), 2);
+        assert_eq!(debugger.vm().stack.len(), 1);
+
+        while debugger.step() != Stop::Finished {}
+        assert!(debugger.is_finished());
+        assert_eq!(debugger.vm().globals.get("x"), Some(&SmallVal::Integer(3)));
+    }
+
+    #[test]
+    fn step_expression_stops_at_each_expression() {
+        let mut debugger = debugger(ADD);
+        let mut lines = vec![];
+        while debugger.step_expression() == Stop::Step {
+            lines.push(debugger.backtrace()[0].line.unwrap());
+        }
+        // into the call to `add` on line 3, and back out to `print` on line 4
+        assert_eq!(lines, vec![3, 2, 4]);
+    }
+
+    #[test]
+    fn upvalues_are_visible() {
+        let mut debugger = debugger(
+            "\
+(defun (make-adder n)
+  (fn (x) (+ x n)))
+(define add-two (make-adder 2))
+(add-two 5)",
+        );
+        debugger.add_breakpoint(Breakpoint::Function("anonymous".to_string()));
+        debugger.continue_();
+
+        let frame = &debugger.backtrace()[0];
+        assert_eq!(frame.upvalues, vec![SmallVal::Integer(2)]);
+        assert_eq!(debugger.command("upvalues"), "#0 = 2\n");
+        assert_eq!(debugger.command("locals"), "x = 5\n");
+    }
+
+    #[test]
+    fn uncaught_errors_stop_with_the_frames_intact() {
+        let mut debugger = debugger(
+            "\
+(defun (check n)
+  (raise n))
+(print 1)
+(check 5)
+(print 2)",
+        );
+        while debugger.step() == Stop::Step {}
+        assert_eq!(
+            debugger.step(),
+            Stop::Error("uncaught error <raise: raised 5>".to_string())
+        );
+        assert!(debugger.is_finished());
+
+        let backtrace = debugger.backtrace();
+        assert_eq!(backtrace.len(), 2);
+        assert_eq!(backtrace[0].function, "check");
+        assert_eq!(backtrace[0].line, Some(2));
+        assert_eq!(
+            backtrace[0].locals,
+            vec![("n".to_string(), SmallVal::Integer(5))]
+        );
+        assert_eq!(backtrace[1].line, Some(4));
+        assert_eq!(debugger.command("locals"), "n = 5\n");
+    }
+
+    #[test]
+    fn commands() {
+        let mut debugger = debugger(ADD);
+        assert_eq!(debugger.command("b add"), "breakpoint set at function add");
+        assert_eq!(
+            debugger.command("c"),
+            "hit breakpoint at function add\nadd at offset 0 (line 2)\nReferenceLocal 1"
+        );
+        assert_eq!(
+            debugger.command("bt"),
+            "#0 add at offset 0 (line 2)\n#1 <top level> at offset 10 (line 3)\n"
+        );
+        assert!(debugger
+            .command("list")
+            .starts_with("->    0 ReferenceLocal"));
+        assert_eq!(debugger.command("d add"), "breakpoint deleted");
+        assert_eq!(debugger.command("c"), "program finished");
+    }
+}
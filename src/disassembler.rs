@@ -1,113 +1,447 @@
-use crate::vm::{BytecodeChunk, ConstantObject, ConstantValue, Op};
+use std::collections::BTreeSet;
 
-pub fn disassemble(bc: &BytecodeChunk) -> String {
-    let mut pc = 0;
+use crate::vm::{BytecodeChunk, CaptureType, ConstantObject, ConstantValue, Op};
+
+/// Disassembles a chunk into the text format read by `assembler::assemble`.
+/// The constant pool is listed first, with functions as nested `.func` blocks.
+pub fn disassemble(bc: &BytecodeChunk) -> Result<String, String> {
     let mut lines = "".to_string();
-    while pc < bc.code.len() {
-        let op: Op = bc.code[pc].try_into().expect("invalid opcode");
-        let line: String = match op {
-            Op::Add => "Add".to_string(),
-            Op::Sub => "Sub".to_string(),
-            Op::Mul => "Mul".to_string(),
-            Op::Div => "Div".to_string(),
-            Op::Return => "Return".to_string(),
-            Op::DebugEnd => "DebugEnd".to_string(),
-            Op::Pop => "pop".to_string(),
-            Op::Constant => {
-                pc += 1;
-                let idx = bc.code[pc];
-                format!("Constant\n  val: {:?}", bc.constants[idx as usize])
-            }
-            Op::Jump => {
-                pc += 1;
-                let offset = bc.code[pc];
-                format!("Jump\n  offset: {offset}")
-            }
-            Op::CondJump => {
-                pc += 1;
-                let offset = bc.code[pc];
-                format!("CondJump\n  offset: {offset}")
-            }
-            Op::FuncCall => {
-                pc += 1;
-                let arity = bc.code[pc];
-                format!("FuncCall\n  arity: {arity}")
-            }
-            Op::DeclareGlobal => {
-                pc += 1;
-                let name_idx = bc.code[pc];
-                let name = match &bc.constants[name_idx as usize] {
-                    ConstantValue::Object(o) => match o {
-                        ConstantObject::String(s) => s,
-                        got => panic!("expected string for global name, got {:?}", got),
-                    },
-                    got => panic!("expected object for global name, got {:?}", got),
-                };
-
-                // value is on the stack
-                format!("DeclareGlobal\n  name: {name} (value on stack)")
-            }
-            Op::ReferenceGlobal => {
-                pc += 1;
-                let name_idx = bc.code[pc];
-                let name = match &bc.constants[name_idx as usize] {
-                    ConstantValue::Object(o) => match o {
-                        ConstantObject::String(s) => s,
-                        got => panic!("expected string for global name, got {:?}", got),
-                    },
-                    got => panic!("expected object for global name, got {:?}", got),
-                };
-
-                format!("ReferenceGlobal\n  name: {name}")
-            }
-            Op::ReferenceLocal => {
-                pc += 1;
-                let idx = bc.code[pc];
-                format!("ReferenceLocal\n  idx: {idx}")
+    if !bc.debug.locals.is_empty() {
+        lines.push_str(&format!(".locals {}\n", bc.debug.locals.join(" ")));
+    }
+    if !bc.debug.upvalues.is_empty() {
+        lines.push_str(&format!(".upvalues {}\n", bc.debug.upvalues.join(" ")));
+    }
+    for (idx, constant) in bc.constants.iter().enumerate() {
+        match constant {
+            ConstantValue::Object(ConstantObject::Closure(closure)) => {
+                lines.push_str(&format!(
+                    ".func k{idx} {} arity={} locals={} upvalues={}\n",
+                    format_string(&closure.f.name),
+                    closure.f.arity,
+                    closure.f.num_locals(),
+                    closure.num_upvalues
+                ));
+                for line in disassemble(closure.f.bytecode())?.lines() {
+                    lines.push_str(&format!("  {line}\n"));
+                }
+                lines.push_str(".end\n");
             }
-            Op::Define => {
-                pc += 1;
-                let idx = bc.code[pc];
-                format!("Define\n  idx: {idx}")
+            constant => lines.push_str(&format!(".const k{idx} {}\n", format_constant(constant))),
+        }
+    }
+
+    let targets = jump_targets(bc)?;
+    let mut pc = 0;
+    while pc < bc.code.len() {
+        if targets.contains(&pc) {
+            lines.push_str(&format!("L{pc}:\n"));
+        }
+        if let Ok(i) = bc
+            .debug
+            .lines
+            .binary_search_by_key(&pc, |(offset, _)| *offset)
+        {
+            lines.push_str(&format!(".line {}\n", bc.debug.lines[i].1));
+        }
+        let (line, next) = disassemble_instruction(bc, pc)?;
+        lines.push_str("  ");
+        lines.push_str(line.as_str());
+        lines.push('\n');
+        pc = next;
+    }
+    Ok(lines)
+}
+
+/// the offsets that jumps and handlers land on
+fn jump_targets(bc: &BytecodeChunk) -> Result<BTreeSet<usize>, String> {
+    let mut targets = BTreeSet::new();
+    let mut pc = 0;
+    while pc < bc.code.len() {
+        let instruction = decode(bc, pc)?;
+        if let Operands::Jump(target) = instruction.operands {
+            targets.insert(target);
+        }
+        pc = instruction.next;
+    }
+    Ok(targets)
+}
+
+/// Disassembles the instruction at `offset` to a line of assembly, also returning the offset
+/// of the next instruction, or the error from `decode` if the bytecode is malformed
+pub fn disassemble_instruction(
+    bc: &BytecodeChunk,
+    offset: usize,
+) -> Result<(String, usize), String> {
+    let instruction = decode(bc, offset)?;
+    Ok((format_instruction(bc, &instruction), instruction.next))
+}
+
+/// A decoded instruction as a line of assembly.
+/// Constants are named `k<index>` and jump targets `L<offset>`.
+pub fn format_instruction(bc: &BytecodeChunk, instruction: &Instruction) -> String {
+    let op = instruction.op;
+    match &instruction.operands {
+        Operands::None => format!("{op:?}"),
+        Operands::Constant(idx) => {
+            let constant = format_constant(&bc.constants[*idx]);
+            format!("{op:?} k{idx} ; {constant}")
+        }
+        Operands::Jump(target) => format!("{op:?} L{target}"),
+        Operands::Byte(byte) => format!("{op:?} {byte}"),
+        Operands::Closure { constant, captures } => {
+            let mut s = format!("Closure k{constant}");
+            for (capture, idx) in captures {
+                s.push_str(&format!(" {} {idx}", capture_name(*capture)));
             }
-            Op::GT => "GT".to_string(),
-            Op::LT => "LT".to_string(),
-            Op::GTE => "GTE".to_string(),
-            Op::LTE => "LTE".to_string(),
-            Op::Print => "PRINT".to_string(),
-            // Op::Quote => "QUOTE".to_string(),
-            Op::ReferenceUpvalue => unimplemented!(),
-            Op::SetUpvalue => unimplemented!(),
-            Op::Closure => {
-                let mut s = "CLOSURE\n".to_string();
-                pc += 1;
-                let idx = bc.code[pc];
-                let closure = match &bc.constants[idx as usize] {
-                    ConstantValue::Object(o) => match o {
-                        ConstantObject::Closure(c) => c,
-                        got => panic!("expected function for closure, got {:?}", got),
-                    },
-                    got => panic!("expected object for closure, got {:?}", got),
-                };
-                let num_uv = closure.num_upvalues;
-                for i in 0..num_uv {
-                    pc += 1;
-                    let is_local = bc.code[pc];
-                    pc += 1;
-                    let idx = bc.code[pc];
-                    s.push_str(
-                        format!("  upvalue: {i} is_local: {is_local} idx: {idx}\n").as_str(),
-                    );
-                }
+            s
+        }
+    }
+}
+
+/// An instruction read from bytecode by `decode`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    pub offset: usize,
+    pub operands: Operands,
+    /// the offset of the instruction after this one
+    pub next: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operands {
+    None,
+    /// an index into the constant pool
+    Constant(usize),
+    /// the offset jumped to, already resolved from the relative operand
+    Jump(usize),
+    /// an argument count, or a local or upvalue slot
+    Byte(u8),
+    /// the function's constant, and where each of its upvalues is captured from
+    Closure {
+        constant: usize,
+        captures: Vec<(CaptureType, u8)>,
+    },
+}
 
-                s
+/// Reads the instruction at `offset`, checking that its operands are there
+/// and that its constants exist. Jump targets aren't checked.
+pub fn decode(bc: &BytecodeChunk, offset: usize) -> Result<Instruction, String> {
+    let byte_at = |pc: usize| {
+        bc.code.get(pc).copied().ok_or(format!(
+            "offset {offset}: instruction runs past the end of the code"
+        ))
+    };
+    let constant_at = |pc: usize| {
+        let idx = byte_at(pc)? as usize;
+        match bc.constants.get(idx) {
+            Some(constant) => Ok((idx, constant)),
+            None => Err(format!(
+                "offset {offset}: constant k{idx} doesn't exist, there are {}",
+                bc.constants.len()
+            )),
+        }
+    };
+
+    let op = Op::try_from(byte_at(offset)?)
+        .map_err(|e| format!("offset {offset}: invalid opcode {}", e.number))?;
+    let mut next = offset + 1;
+    let operands = match op {
+        Op::Constant | Op::DeclareGlobal | Op::ReferenceGlobal => {
+            let (idx, _) = constant_at(next)?;
+            next += 1;
+            Operands::Constant(idx)
+        }
+        Op::Jump | Op::CondJump | Op::PushHandler => {
+            // jumps are relative to their operand
+            let target = next + byte_at(next)? as usize;
+            next += 1;
+            Operands::Jump(target)
+        }
+        Op::FuncCall
+        | Op::ReferenceLocal
+        | Op::Define
+        | Op::SetLocal
+        | Op::ReferenceUpvalue
+        | Op::SetUpvalue => {
+            let byte = byte_at(next)?;
+            next += 1;
+            Operands::Byte(byte)
+        }
+        Op::Closure => {
+            let (constant, closure) = match constant_at(next)? {
+                (idx, ConstantValue::Object(ConstantObject::Closure(closure))) => (idx, closure),
+                (idx, _) => {
+                    return Err(format!("offset {offset}: constant k{idx} isn't a function"))
+                }
+            };
+            next += 1;
+            let mut captures = vec![];
+            for _ in 0..closure.num_upvalues {
+                let capture = CaptureType::try_from(byte_at(next)?)
+                    .map_err(|e| format!("offset {offset}: invalid capture type {}", e.number))?;
+                captures.push((capture, byte_at(next + 1)?));
+                next += 2;
             }
-            Op::CloseUpvalue => "CLOSE_UPVALUE".to_string(),
-            Op::SetLocal => todo!(),
+            Operands::Closure { constant, captures }
+        }
+        Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::Equal
+        | Op::GT
+        | Op::LT
+        | Op::GTE
+        | Op::LTE
+        | Op::Return
+        | Op::Print
+        | Op::Pop
+        | Op::CloseUpvalue
+        | Op::PopHandler
+        | Op::Raise
+        | Op::CallCC
+        | Op::Resume
+        | Op::Yield
+        | Op::DebugEnd => Operands::None,
+    };
+    Ok(Instruction {
+        op,
+        offset,
+        operands,
+        next,
+    })
+}
+
+fn capture_name(capture: CaptureType) -> &'static str {
+    match capture {
+        CaptureType::SurroundingLocal => "local",
+        CaptureType::SurroundingUpvalue => "upvalue",
+    }
+}
+
+/// A listing of a chunk for reading rather than reassembling: instructions with their byte
+/// offsets and source lines, jumps with their targets, and variables with their names where
+/// there's debug info. Every function inside the chunk is listed after it.
+pub fn listing(bc: &BytecodeChunk) -> Result<String, String> {
+    let mut out = "<top level>:\n".to_string();
+    list_function("<top level>", bc, &mut out)?;
+    Ok(out)
+}
+
+fn list_function(name: &str, bc: &BytecodeChunk, out: &mut String) -> Result<(), String> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < bc.code.len() {
+        let instruction = decode(bc, offset).map_err(|e| format!("in {name}, {e}"))?;
+        offset = instruction.next;
+        instructions.push(instruction);
+    }
+    // the widest offset in the listing, which may be a jump target past the end
+    let width = instructions
+        .iter()
+        .map(|instruction| match instruction.operands {
+            Operands::Jump(target) => target,
+            _ => instruction.offset,
+        })
+        .max()
+        .unwrap_or(0)
+        .to_string()
+        .len()
+        .max(4);
+
+    for instruction in instructions.iter() {
+        let line = match bc
+            .debug
+            .lines
+            .binary_search_by_key(&instruction.offset, |(offset, _)| *offset)
+        {
+            Ok(i) => bc.debug.lines[i].1.to_string(),
+            Err(_) => "".to_string(),
         };
-        lines.push_str(line.as_str());
-        lines.push('\n');
-        pc += 1;
+        let operands = list_operands(bc, instruction, width);
+        let op = format!("{:?}", instruction.op);
+        let text = format!(
+            "{:0width$} {line:>4}  {op:<16} {operands}",
+            instruction.offset
+        );
+        out.push_str(text.trim_end());
+        out.push('\n');
+    }
+
+    for constant in bc.constants.iter() {
+        if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+            let f = &closure.f;
+            out.push_str(&format!(
+                "\n{} (arity {}, {} locals, {} upvalues):\n",
+                f.name,
+                f.arity,
+                f.num_locals(),
+                closure.num_upvalues
+            ));
+            list_function(&f.name, f.bytecode(), out)?;
+        }
+    }
+    Ok(())
+}
+
+fn list_operands(bc: &BytecodeChunk, instruction: &Instruction, width: usize) -> String {
+    let local = |slot: u8| match (slot as usize).checked_sub(1) {
+        Some(i) => bc.debug.locals.get(i).map(|name| format!(" ({name})")),
+        // slot 0 is the function itself
+        None => None,
+    };
+    let upvalue = |idx: u8| {
+        bc.debug
+            .upvalues
+            .get(idx as usize)
+            .map(|name| format!(" ({name})"))
+    };
+    match (&instruction.operands, instruction.op) {
+        (Operands::None, _) => "".to_string(),
+        (Operands::Constant(idx), Op::Constant) => {
+            format!("k{idx} {}", format_constant(&bc.constants[*idx]))
+        }
+        (Operands::Constant(idx), _) => match &bc.constants[*idx] {
+            ConstantValue::Object(ConstantObject::String(name)) => format!("k{idx} global {name}"),
+            constant => format!("k{idx} {}", format_constant(constant)),
+        },
+        (Operands::Jump(target), _) => format!("-> {target:0width$}"),
+        (Operands::Byte(slot), Op::ReferenceLocal | Op::SetLocal | Op::Define) => {
+            format!("{slot}{}", local(*slot).unwrap_or_default())
+        }
+        (Operands::Byte(idx), Op::ReferenceUpvalue | Op::SetUpvalue) => {
+            format!("{idx}{}", upvalue(*idx).unwrap_or_default())
+        }
+        (Operands::Byte(byte), _) => byte.to_string(),
+        (Operands::Closure { constant, captures }, _) => {
+            let mut s = format!("k{constant} {}", format_constant(&bc.constants[*constant]));
+            let captures = captures
+                .iter()
+                .map(|(capture, idx)| {
+                    let name = match capture {
+                        // the local in slot `idx + 1`, skipping the function
+                        CaptureType::SurroundingLocal => local(idx + 1),
+                        CaptureType::SurroundingUpvalue => upvalue(*idx),
+                    };
+                    format!(
+                        "{} {idx}{}",
+                        capture_name(*capture),
+                        name.unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>();
+            if !captures.is_empty() {
+                s.push_str(&format!(" capturing {}", captures.join(", ")));
+            }
+            s
+        }
+    }
+}
+
+/// A constant in the syntax `assembler::assemble` reads. Functions can't be written inline.
+pub fn format_constant(constant: &ConstantValue) -> String {
+    match constant {
+        ConstantValue::Integer(i) => i.to_string(),
+        ConstantValue::Float(f) => format!("{f:?}"),
+        ConstantValue::Boolean(b) => b.to_string(),
+        ConstantValue::Nil => "nil".to_string(),
+        ConstantValue::Object(ConstantObject::String(s)) => format_string(s),
+        ConstantValue::Object(ConstantObject::Symbol(s)) => s.clone(),
+        ConstantValue::Object(ConstantObject::Closure(c)) => format!("<function {}>", c.f.name),
+        ConstantValue::List(items) => {
+            let items = items.iter().map(format_constant).collect::<Vec<_>>();
+            format!("({})", items.join(" "))
+        }
+        ConstantValue::Quote(quoted) => format!("'{}", format_constant(quoted)),
+    }
+}
+
+fn format_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn listing_resolves_jumps_and_names() {
+        let chunk = compile(
+            &"\
+(defun (make-counter start)
+  (define n start)
+  (fn () (set n (+ n 1)) (if (> n 3) 'big n)))"
+                .to_string(),
+        );
+        let expected = "\
+<top level>:
+0000    1  Closure          k0 <function make-counter>
+0002       DeclareGlobal    k1 global make-counter
+0004       DebugEnd
+
+make-counter (arity 1, 1 locals, 0 upvalues):
+0000    2  ReferenceLocal   1 (start)
+0002       Define           2 (n)
+0004    3  Closure          k0 <function anonymous> capturing local 1 (n)
+0008       Return
+
+anonymous (arity 0, 0 locals, 1 upvalues):
+0000    3  ReferenceUpvalue 0 (n)
+0002       Constant         k0 1
+0004       Add
+0005       SetUpvalue       0 (n)
+0007    3  ReferenceUpvalue 0 (n)
+0009       Constant         k1 3
+0011       GT
+0012       CondJump         -> 0018
+0014       ReferenceUpvalue 0 (n)
+0016       Jump             -> 0020
+0018       Constant         k2 'big
+0020       Return
+";
+        assert_eq!(listing(&chunk), Ok(expected.to_string()));
+    }
+
+    #[test]
+    fn disassembling_truncated_bytecode_is_an_error() {
+        let chunk = BytecodeChunk::new(vec![Op::Pop.into(), Op::Constant.into()], vec![]);
+        let error = "offset 1: instruction runs past the end of the code".to_string();
+        assert_eq!(disassemble_instruction(&chunk, 1), Err(error.clone()));
+        assert_eq!(disassemble(&chunk), Err(error));
+    }
+
+    #[test]
+    fn malformed_bytecode_is_an_error() {
+        let chunk = |code: Vec<u8>, constants| BytecodeChunk::new(code, constants);
+        assert_eq!(
+            listing(&chunk(vec![200], vec![])),
+            Err("in <top level>, offset 0: invalid opcode 200".to_string())
+        );
+        assert_eq!(
+            listing(&chunk(vec![Op::Pop.into(), Op::Constant.into()], vec![])),
+            Err("in <top level>, offset 1: instruction runs past the end of the code".to_string())
+        );
+        assert_eq!(
+            listing(&chunk(
+                vec![Op::Constant.into(), 1],
+                vec![ConstantValue::Nil]
+            )),
+            Err("in <top level>, offset 0: constant k1 doesn't exist, there are 1".to_string())
+        );
+        assert_eq!(
+            listing(&chunk(
+                vec![Op::Closure.into(), 0],
+                vec![ConstantValue::Nil]
+            )),
+            Err("in <top level>, offset 0: constant k0 isn't a function".to_string())
+        );
     }
-    lines
 }
@@ -0,0 +1,369 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    disassembler::{decode, Instruction, Operands},
+    vm::{BytecodeChunk, CaptureType, ConstantObject, ConstantValue, Op},
+};
+
+/// What the emitted code is linked against, see the comment at the top
+const RUNTIME: &str = include_str!("emit_c/runtime.c");
+
+/// The ops that go through a global when their operands aren't two integers. Their globals
+/// come first, in this order, which is what the runtime's `binary_op` expects.
+const OPERATORS: [(Op, &str); 9] = [
+    (Op::Add, "OP_ADD"),
+    (Op::Sub, "OP_SUB"),
+    (Op::Mul, "OP_MUL"),
+    (Op::Div, "OP_DIV"),
+    (Op::Equal, "OP_EQUAL"),
+    (Op::GT, "OP_GT"),
+    (Op::LT, "OP_LT"),
+    (Op::GTE, "OP_GTE"),
+    (Op::LTE, "OP_LTE"),
+];
+
+/// builtins that need the VM's scheduler or heap, which the runtime doesn't have
+const UNSUPPORTED_BUILTINS: [&str; 8] = [
+    "coroutine",
+    "generator",
+    "done?",
+    "spawn",
+    "chan",
+    "send",
+    "recv",
+    "heap-stats",
+];
+
+/// A function in the program, which becomes a C function and a `Proto` describing it
+struct Proto<'a> {
+    name: &'a str,
+    chunk: &'a BytecodeChunk,
+    arity: usize,
+    num_locals: usize,
+    num_upvalues: usize,
+    /// the proto for each of the chunk's closure constants
+    closures: HashMap<usize, usize>,
+}
+
+/// Compiles a program to a C program that does the same thing, with a small runtime that
+/// shares the VM's value model. Each function becomes a C function that works on the same
+/// stack the VM would use, jumps become `goto`s, and handlers `setjmp`s.
+/// Coroutines, continuations and tasks aren't supported.
+pub fn emit_c(chunk: &BytecodeChunk) -> Result<String, String> {
+    let mut protos = vec![];
+    collect("<top level>", chunk, 0, 0, 0, &mut protos);
+
+    let mut emitter = Emitter {
+        globals: OPERATORS
+            .iter()
+            .map(|(op, _)| op.operator_name().unwrap().to_string())
+            .collect(),
+        literals: 0,
+    };
+    let mut bodies = String::new();
+    for (id, proto) in protos.iter().enumerate() {
+        bodies.push_str(&emitter.function(id, proto)?);
+        bodies.push('\n');
+    }
+
+    let mut out = String::from("/* generated by `ruspc emit-c`, build with `cc -std=c99` */\n\n");
+    out.push_str(&format!("#define NUM_GLOBALS {}\n", emitter.globals.len()));
+    // C doesn't allow empty arrays
+    out.push_str(&format!(
+        "#define NUM_LITERALS {}\n\n",
+        emitter.literals.max(1)
+    ));
+    out.push_str("static const char *const global_names[NUM_GLOBALS] = {\n");
+    for name in emitter.globals.iter() {
+        out.push_str(&format!("    {},\n", c_string(name)));
+    }
+    out.push_str("};\n\n");
+    out.push_str(RUNTIME);
+    out.push('\n');
+    for id in 0..protos.len() {
+        out.push_str(&format!(
+            "static void chunk_{id}(Object *closure, int base);\n"
+        ));
+    }
+    out.push('\n');
+    for (id, proto) in protos.iter().enumerate() {
+        out.push_str(&format!(
+            "static const Proto proto_{id} = {{{}, chunk_{id}, {}, {}, {}}};\n",
+            c_string(proto.name),
+            proto.arity,
+            proto.num_locals,
+            proto.num_upvalues
+        ));
+    }
+    out.push('\n');
+    out.push_str(&bodies);
+    out.push_str(
+        "int main(void) {\n    init_runtime();\n    chunk_0(NULL, 0);\n    return 0;\n}\n",
+    );
+    Ok(out)
+}
+
+/// adds `chunk` and every function inside it to `protos`, returning `chunk`'s proto
+fn collect<'a>(
+    name: &'a str,
+    chunk: &'a BytecodeChunk,
+    arity: usize,
+    num_locals: usize,
+    num_upvalues: usize,
+    protos: &mut Vec<Proto<'a>>,
+) -> usize {
+    let id = protos.len();
+    protos.push(Proto {
+        name,
+        chunk,
+        arity,
+        num_locals,
+        num_upvalues,
+        closures: HashMap::new(),
+    });
+    for (idx, constant) in chunk.constants.iter().enumerate() {
+        if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+            let f = &closure.f;
+            let child = collect(
+                &f.name,
+                f.bytecode(),
+                f.arity,
+                f.num_locals(),
+                closure.num_upvalues,
+                protos,
+            );
+            protos[id].closures.insert(idx, child);
+        }
+    }
+    id
+}
+
+struct Emitter {
+    /// the name of each global slot
+    globals: Vec<String>,
+    /// the number of heap constants, which each get a slot in the runtime's `literals`
+    literals: usize,
+}
+
+impl Emitter {
+    fn function(&mut self, id: usize, proto: &Proto) -> Result<String, String> {
+        let chunk = proto.chunk;
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let instruction =
+                decode(chunk, offset).map_err(|e| format!("in {}, {e}", proto.name))?;
+            offset = instruction.next;
+            instructions.push(instruction);
+        }
+        let targets = instructions
+            .iter()
+            .filter_map(|instruction| match instruction.operands {
+                Operands::Jump(target) => Some(target),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+
+        // the literal slot of each heap constant, shared by every instruction that uses it
+        let mut literal_slots = HashMap::new();
+        let mut out = format!("static void chunk_{id}(Object *closure, int base) {{\n");
+        for instruction in instructions.iter() {
+            if targets.contains(&instruction.offset) {
+                out.push_str(&format!("L{}:;\n", instruction.offset));
+            }
+            let statement = self
+                .statement(proto, instruction, &mut literal_slots)
+                .map_err(|e| format!("in {}, {e}", proto.name))?;
+            out.push_str(&format!("    {statement}\n"));
+        }
+        if targets.contains(&chunk.code.len()) {
+            out.push_str(&format!("L{}:;\n", chunk.code.len()));
+        }
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    /// the C that does what `instruction` does
+    fn statement(
+        &mut self,
+        proto: &Proto,
+        instruction: &Instruction,
+        literal_slots: &mut HashMap<usize, usize>,
+    ) -> Result<String, String> {
+        let chunk = proto.chunk;
+        let statement = match (instruction.op, &instruction.operands) {
+            (Op::Constant, &Operands::Constant(idx)) => {
+                let value = match (&chunk.constants[idx], proto.closures.get(&idx)) {
+                    (_, Some(child)) => format!("object(new_closure(&proto_{child}))"),
+                    (
+                        constant @ (ConstantValue::Object(_)
+                        | ConstantValue::List(_)
+                        | ConstantValue::Quote(_)),
+                        None,
+                    ) => {
+                        let slot = *literal_slots.entry(idx).or_insert_with(|| {
+                            self.literals += 1;
+                            self.literals - 1
+                        });
+                        format!("LITERAL({slot}, {})", value(constant)?)
+                    }
+                    (constant, None) => value(constant)?,
+                };
+                format!("push({value});")
+            }
+            (Op::Jump, Operands::Jump(target)) => format!("goto L{target};"),
+            (Op::CondJump, Operands::Jump(target)) => format!("if (truthy(pop())) goto L{target};"),
+            (Op::FuncCall, Operands::Byte(arity)) => format!("call_value({arity});"),
+            (Op::Return, _) => "return_from(base);\n    return;".to_string(),
+            (Op::DeclareGlobal, &Operands::Constant(idx)) => {
+                format!("declare_global({});", self.global_slot(chunk, idx)?)
+            }
+            (Op::ReferenceGlobal, &Operands::Constant(idx)) => {
+                format!("push(global({}));", self.global_slot(chunk, idx)?)
+            }
+            (Op::ReferenceLocal, Operands::Byte(n)) => format!("push(stack[base + {n}]);"),
+            (Op::Print, _) => "print_line(pop());".to_string(),
+            (Op::Define | Op::SetLocal, Operands::Byte(n)) => format!("stack[base + {n}] = pop();"),
+            (Op::ReferenceUpvalue, Operands::Byte(n)) => {
+                format!("push(upvalue_value(closure, {n}));")
+            }
+            (Op::SetUpvalue, Operands::Byte(n)) => format!("set_upvalue(closure, {n}, pop());"),
+            (Op::Closure, Operands::Closure { constant, captures }) => {
+                let Some(child) = proto.closures.get(constant) else {
+                    return Err(format!("constant k{constant} isn't a function"));
+                };
+                let mut out = format!("{{\n        Object *c = new_closure(&proto_{child});\n");
+                for (i, (capture_type, idx)) in captures.iter().enumerate() {
+                    // see `VM::handle_closure`
+                    let upvalue = match capture_type {
+                        CaptureType::SurroundingLocal => {
+                            format!("capture_upvalue(&stack[base + 1 + {idx}])")
+                        }
+                        CaptureType::SurroundingUpvalue => {
+                            format!("closure->as.closure.upvalues[{idx}]")
+                        }
+                    };
+                    out.push_str(&format!(
+                        "        c->as.closure.upvalues[{i}] = {upvalue};\n"
+                    ));
+                }
+                out.push_str("        push(object(c));\n    }");
+                out
+            }
+            (Op::Pop, _) => "sp--;".to_string(),
+            (Op::PushHandler, Operands::Jump(landing)) => {
+                format!("if (setjmp(push_handler()->landing)) goto L{landing};")
+            }
+            (Op::PopHandler, _) => "handler_count--;".to_string(),
+            (Op::Raise, _) => "throw_value(pop());".to_string(),
+            (Op::DebugEnd, _) => "return;".to_string(),
+            (op, _) if op.operator_name().is_some() => {
+                let (_, name) = OPERATORS.iter().find(|(o, _)| *o == op).unwrap();
+                format!("binary_op({name});")
+            }
+            (op, _) => return Err(format!("emit-c doesn't support {op:?} yet")),
+        };
+        Ok(statement)
+    }
+
+    /// the slot of the global named by constant `idx`
+    fn global_slot(&mut self, chunk: &BytecodeChunk, idx: usize) -> Result<usize, String> {
+        let ConstantValue::Object(ConstantObject::String(name)) = &chunk.constants[idx] else {
+            return Err(format!("global k{idx} isn't named by a string"));
+        };
+        if UNSUPPORTED_BUILTINS.contains(&name.as_str()) {
+            return Err(format!("emit-c doesn't support {name} yet"));
+        }
+        if let Some(slot) = self.globals.iter().position(|global| global == name) {
+            return Ok(slot);
+        }
+        self.globals.push(name.clone());
+        Ok(self.globals.len() - 1)
+    }
+}
+
+/// A C expression making the value the VM makes from `constant`, see `VM::constant_to_value`
+fn value(constant: &ConstantValue) -> Result<String, String> {
+    Ok(match constant {
+        ConstantValue::Integer(i64::MIN) => "integer(INT64_MIN)".to_string(),
+        ConstantValue::Integer(i) => format!("integer(INT64_C({i}))"),
+        // Rust writes the shortest exponent form that reads back exactly
+        ConstantValue::Float(f) if f.is_finite() => format!("floating({f:e})"),
+        ConstantValue::Float(f) => return Err(format!("emit-c doesn't support the float {f}")),
+        ConstantValue::Boolean(b) => format!("boolean({})", *b as u8),
+        ConstantValue::Nil => "nil()".to_string(),
+        ConstantValue::Object(ConstantObject::String(s)) => {
+            format!("object(new_string({}))", c_string(s))
+        }
+        ConstantValue::Object(ConstantObject::Symbol(s)) => {
+            format!("object(intern({}))", c_string(s))
+        }
+        ConstantValue::Object(ConstantObject::Closure(_)) => {
+            return Err("emit-c doesn't support functions inside quoted data".to_string())
+        }
+        ConstantValue::List(items) if items.is_empty() => {
+            return Err("empty list constant".to_string())
+        }
+        ConstantValue::List(items) => {
+            let items = items.iter().map(value).collect::<Result<Vec<_>, _>>()?;
+            format!(
+                "list_literal({}, (Value[]){{{}}})",
+                items.len(),
+                items.join(", ")
+            )
+        }
+        ConstantValue::Quote(inner) => format!("quote(to_object({}))", value(inner)?),
+    })
+}
+
+/// a C string literal, escaping anything that isn't printable ASCII (and `?`, for trigraphs)
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' | b'?' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\{byte:03o}")),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    fn emit(src: &str) -> Result<String, String> {
+        emit_c(&compile(&src.to_string()))
+    }
+
+    #[test]
+    fn emits_each_function() {
+        let c = emit("(defun (double n) (* n 2)) (print (double 21))").unwrap();
+        assert!(c.contains("static const Proto proto_1 = {\"double\", chunk_1, 1, 0, 0};"));
+        assert!(c.contains("binary_op(OP_MUL);"), "{c}");
+        assert!(c.contains("    \"double\",\n"), "{c}");
+        assert!(c.contains("call_value(1);"), "{c}");
+        assert!(c.ends_with("chunk_0(NULL, 0);\n    return 0;\n}\n"));
+    }
+
+    #[test]
+    fn rejects_what_it_cant_run() {
+        let err = emit("(print (call/cc (fn (k) (k 1))))").unwrap_err();
+        assert!(err.contains("doesn't support CallCC"), "{err}");
+        let err = emit("(print (chan))").unwrap_err();
+        assert!(err.contains("doesn't support chan"), "{err}");
+    }
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(c_string("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(c_string("??=\n"), r#""\?\?=\012""#);
+        assert_eq!(c_string("é"), r#""\303\251""#);
+    }
+}
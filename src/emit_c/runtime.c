@@ -0,0 +1,945 @@
+/*
+ * The runtime for C emitted by `ruspc emit-c`, with the same value model as the VM: small
+ * values are held directly, everything else is a pointer to an object. Values live on one
+ * stack, and each function's frame is a window onto it, so upvalues can point into the stack
+ * until the frame returns. Errors unwind with `longjmp` to the innermost handler, as they do
+ * in the VM. Nothing is ever freed.
+ *
+ * The emitted code defines `global_names`, `NUM_GLOBALS` and `NUM_LITERALS` before this.
+ */
+
+#include <setjmp.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define STACK_SIZE 4096
+#define MAX_CALL_DEPTH 4096
+#define MAX_HANDLERS 4096
+/* the free slots a call needs besides its locals, as in the VM */
+#define STACK_HEADROOM 8
+
+/* a heap constant, which is made the first time it's used and then shared */
+#define LITERAL(slot, make) \
+    (literals_made[slot] ? literals[slot] : (literals_made[slot] = 1, literals[slot] = (make)))
+
+typedef struct Object Object;
+
+typedef enum { NIL, BOOL, INTEGER, FLOAT, OBJECT, QUOTE } Tag;
+
+typedef struct {
+    Tag tag;
+    union {
+        int boolean;
+        int64_t integer;
+        double floating;
+        Object *object;
+    } as;
+} Value;
+
+typedef void (*Code)(Object *closure, int base);
+typedef Value (*BuiltinFn)(Value *args, int count);
+
+/* a compiled function, which closures are made from */
+typedef struct {
+    const char *name;
+    Code code;
+    int arity;
+    int num_locals;
+    int num_upvalues;
+} Proto;
+
+typedef enum { SMALL_VALUE, STRING, SYMBOL, CONS, BUILTIN, CLOSURE, UPVALUE, ERROR } Kind;
+
+struct Object {
+    Kind kind;
+    union {
+        Value small;
+        char *string;
+        struct {
+            Object *car;
+            Object *cdr;
+        } cons;
+        struct {
+            const char *name;
+            BuiltinFn fn;
+        } builtin;
+        struct {
+            const Proto *proto;
+            Object **upvalues;
+        } closure;
+        struct {
+            Value *location;
+            Value closed;
+            Object *next;
+        } upvalue;
+        struct {
+            char *kind;
+            char *message;
+            Value payload;
+        } error;
+    } as;
+};
+
+/* a `try` block that's running, see `push_handler` */
+typedef struct {
+    jmp_buf landing;
+    /* the stack pointer and call depth to unwind to */
+    int sp;
+    int call_depth;
+    /* the `catch` closure, or nil if the block only has a `finally` */
+    Value catcher;
+} Handler;
+
+static Value stack[STACK_SIZE];
+/* the index of the top of the stack */
+static int sp = -1;
+static int call_depth = 0;
+static Value globals[NUM_GLOBALS];
+static int defined[NUM_GLOBALS];
+/* heap constants, made the first time they're used */
+static Value literals[NUM_LITERALS];
+static int literals_made[NUM_LITERALS];
+/* upvalues that still point into the stack, highest first */
+static Object *open_upvalues = NULL;
+static Handler handlers[MAX_HANDLERS];
+static int handler_count = 0;
+static Object **symbols = NULL;
+static int symbol_count = 0;
+static int symbol_capacity = 0;
+/* set once any of the operators' globals is declared, see `binary_op` */
+static int operators_rebound = 0;
+
+/* the operators' globals come first, in this order */
+enum { OP_ADD, OP_SUB, OP_MUL, OP_DIV, OP_EQUAL, OP_GT, OP_LT, OP_GTE, OP_LTE, NUM_OPERATORS };
+
+/* ---- values ---- */
+
+static Value nil(void) {
+    Value v;
+    v.tag = NIL;
+    v.as.integer = 0;
+    return v;
+}
+
+static Value boolean(int b) {
+    Value v;
+    v.tag = BOOL;
+    v.as.boolean = b != 0;
+    return v;
+}
+
+static Value integer(int64_t i) {
+    Value v;
+    v.tag = INTEGER;
+    v.as.integer = i;
+    return v;
+}
+
+static Value floating(double f) {
+    Value v;
+    v.tag = FLOAT;
+    v.as.floating = f;
+    return v;
+}
+
+static Value object(Object *o) {
+    Value v;
+    v.tag = OBJECT;
+    v.as.object = o;
+    return v;
+}
+
+static Value quote(Object *o) {
+    Value v;
+    v.tag = QUOTE;
+    v.as.object = o;
+    return v;
+}
+
+static Object *allocate(Kind kind) {
+    Object *o = calloc(1, sizeof(Object));
+    if (o == NULL) {
+        fprintf(stderr, "out of memory\n");
+        exit(101);
+    }
+    o->kind = kind;
+    return o;
+}
+
+static char *copy_string(const char *s) {
+    char *copy = malloc(strlen(s) + 1);
+    strcpy(copy, s);
+    return copy;
+}
+
+static Object *new_string(const char *s) {
+    Object *o = allocate(STRING);
+    o->as.string = copy_string(s);
+    return o;
+}
+
+static Object *intern(const char *name) {
+    int i;
+    Object *o;
+    for (i = 0; i < symbol_count; i++) {
+        if (strcmp(symbols[i]->as.string, name) == 0) {
+            return symbols[i];
+        }
+    }
+    o = allocate(SYMBOL);
+    o->as.string = copy_string(name);
+    if (symbol_count == symbol_capacity) {
+        symbol_capacity = symbol_capacity == 0 ? 16 : symbol_capacity * 2;
+        symbols = realloc(symbols, symbol_capacity * sizeof(Object *));
+    }
+    symbols[symbol_count++] = o;
+    return o;
+}
+
+/* small values are boxed to go in a cons cell or be quoted */
+static Object *to_object(Value v) {
+    Object *o;
+    if (v.tag == OBJECT || v.tag == QUOTE) {
+        return v.as.object;
+    }
+    o = allocate(SMALL_VALUE);
+    o->as.small = v;
+    return o;
+}
+
+static Object *small_object(Value v) {
+    Object *o = allocate(SMALL_VALUE);
+    o->as.small = v;
+    return o;
+}
+
+static Object *new_cons(Object *car, Object *cdr) {
+    Object *o = allocate(CONS);
+    o->as.cons.car = car;
+    o->as.cons.cdr = cdr;
+    return o;
+}
+
+/* a quoted list, built like the VM builds one from a constant */
+static Value list_literal(int count, Value *items) {
+    int i;
+    Object *list = new_cons(to_object(items[count - 1]), NULL);
+    for (i = count - 2; i >= 0; i--) {
+        list = new_cons(small_object(items[i]), list);
+    }
+    return object(list);
+}
+
+static int truthy(Value v) {
+    switch (v.tag) {
+    case NIL:
+        return 0;
+    case BOOL:
+        return v.as.boolean;
+    case OBJECT:
+        if (v.as.object != NULL && v.as.object->kind == SMALL_VALUE) {
+            return truthy(v.as.object->as.small);
+        }
+        return 1;
+    default:
+        return 1;
+    }
+}
+
+/* ---- printing, as `Display` does in the VM ---- */
+
+typedef struct {
+    char *data;
+    size_t length;
+    size_t capacity;
+} Buffer;
+
+static void append(Buffer *b, const char *s) {
+    size_t n = strlen(s);
+    if (b->length + n + 1 > b->capacity) {
+        b->capacity = (b->length + n + 1) * 2;
+        b->data = realloc(b->data, b->capacity);
+    }
+    memcpy(b->data + b->length, s, n + 1);
+    b->length += n;
+}
+
+/* the shortest digits that read back as the same double, written out in full like Rust does */
+static void append_float(Buffer *b, double f) {
+    char digits[32];
+    char out[400];
+    char text[40];
+    int precision, exponent, count = 0, i, o = 0;
+    const char *p;
+
+    if (f != f) {
+        append(b, "NaN");
+        return;
+    }
+    if (f > 1.7976931348623157e308 || f < -1.7976931348623157e308) {
+        append(b, f > 0 ? "inf" : "-inf");
+        return;
+    }
+    if (f == 0) {
+        append(b, 1 / f < 0 ? "-0" : "0");
+        return;
+    }
+    for (precision = 1; precision <= 17; precision++) {
+        sprintf(text, "%.*e", precision - 1, f);
+        if (strtod(text, NULL) == f) {
+            break;
+        }
+    }
+    p = text;
+    if (*p == '-') {
+        out[o++] = '-';
+        p++;
+    }
+    for (; *p != 'e'; p++) {
+        if (*p != '.') {
+            digits[count++] = *p;
+        }
+    }
+    exponent = atoi(p + 1);
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+    if (exponent < 0) {
+        out[o++] = '0';
+        out[o++] = '.';
+        for (i = 0; i < -exponent - 1; i++) {
+            out[o++] = '0';
+        }
+        for (i = 0; i < count; i++) {
+            out[o++] = digits[i];
+        }
+    } else {
+        for (i = 0; i <= exponent || i < count; i++) {
+            if (i == exponent + 1) {
+                out[o++] = '.';
+            }
+            out[o++] = i < count ? digits[i] : '0';
+        }
+    }
+    out[o] = '\0';
+    append(b, out);
+}
+
+static void append_object(Buffer *b, Object *o);
+
+static void append_value(Buffer *b, Value v) {
+    char text[32];
+    switch (v.tag) {
+    case NIL:
+        append(b, "nil");
+        break;
+    case BOOL:
+        append(b, v.as.boolean ? "true" : "false");
+        break;
+    case INTEGER:
+        sprintf(text, "%lld", (long long)v.as.integer);
+        append(b, text);
+        break;
+    case FLOAT:
+        append_float(b, v.as.floating);
+        break;
+    case QUOTE:
+        append(b, "'");
+        append_object(b, v.as.object);
+        break;
+    case OBJECT:
+        append_object(b, v.as.object);
+        break;
+    }
+}
+
+static void append_object(Buffer *b, Object *o) {
+    if (o == NULL) {
+        append(b, "nil");
+        return;
+    }
+    switch (o->kind) {
+    case SMALL_VALUE:
+        append_value(b, o->as.small);
+        break;
+    case STRING:
+        append(b, "\"");
+        append(b, o->as.string);
+        append(b, "\"");
+        break;
+    case SYMBOL:
+        append(b, o->as.string);
+        break;
+    case CONS:
+        append(b, "(");
+        append_object(b, o->as.cons.car);
+        append(b, " . ");
+        append_object(b, o->as.cons.cdr);
+        append(b, ")");
+        break;
+    case BUILTIN:
+        append(b, "builtin <");
+        append(b, o->as.builtin.name);
+        append(b, ">");
+        break;
+    case CLOSURE:
+        append(b, "closure <");
+        append(b, o->as.closure.proto->name);
+        append(b, ">");
+        break;
+    case UPVALUE:
+        append_value(b, *o->as.upvalue.location);
+        break;
+    case ERROR:
+        append(b, "error <");
+        append(b, o->as.error.kind);
+        append(b, ": ");
+        append(b, o->as.error.message);
+        append(b, ">");
+        break;
+    }
+}
+
+static char *format_value(Value v) {
+    Buffer b = {NULL, 0, 0};
+    append(&b, "");
+    append_value(&b, v);
+    return b.data;
+}
+
+static void print_line(Value v) {
+    char *text = format_value(v);
+    printf("%s\n", text);
+    free(text);
+}
+
+static void raise_error(const char *kind, const char *message);
+
+/* ---- the stack ---- */
+
+static void push(Value v) {
+    if (sp + 1 >= STACK_SIZE) {
+        raise_error("stack-overflow", "stack overflow");
+    }
+    stack[++sp] = v;
+}
+
+static Value pop(void) {
+    return stack[sp--];
+}
+
+static Object *capture_upvalue(Value *location) {
+    Object *previous = NULL;
+    Object *current = open_upvalues;
+    Object *created;
+    while (current != NULL && current->as.upvalue.location > location) {
+        previous = current;
+        current = current->as.upvalue.next;
+    }
+    if (current != NULL && current->as.upvalue.location == location) {
+        return current;
+    }
+    created = allocate(UPVALUE);
+    created->as.upvalue.location = location;
+    created->as.upvalue.next = current;
+    if (previous == NULL) {
+        open_upvalues = created;
+    } else {
+        previous->as.upvalue.next = created;
+    }
+    return created;
+}
+
+static void close_upvalues(Value *last) {
+    while (open_upvalues != NULL && open_upvalues->as.upvalue.location >= last) {
+        Object *upvalue = open_upvalues;
+        upvalue->as.upvalue.closed = *upvalue->as.upvalue.location;
+        upvalue->as.upvalue.location = &upvalue->as.upvalue.closed;
+        open_upvalues = upvalue->as.upvalue.next;
+    }
+}
+
+static Object *new_closure(const Proto *proto) {
+    Object *o = allocate(CLOSURE);
+    o->as.closure.proto = proto;
+    o->as.closure.upvalues = calloc(proto->num_upvalues + 1, sizeof(Object *));
+    return o;
+}
+
+static Value upvalue_value(Object *closure, int idx) {
+    return *closure->as.closure.upvalues[idx]->as.upvalue.location;
+}
+
+static void set_upvalue(Object *closure, int idx, Value v) {
+    *closure->as.closure.upvalues[idx]->as.upvalue.location = v;
+}
+
+/* ---- errors ---- */
+
+/* what `PushHandler` does, before the emitted code calls `setjmp` to land in the function */
+static Handler *push_handler(void) {
+    Handler *handler;
+    if (handler_count == MAX_HANDLERS) {
+        fprintf(stderr, "too many nested handlers\n");
+        exit(101);
+    }
+    handler = &handlers[handler_count++];
+    handler->catcher = pop();
+    handler->sp = sp;
+    handler->call_depth = call_depth;
+    return handler;
+}
+
+/* unwinds to the innermost handler and lands there with `value` on the stack, see
+ * `VM::throw` */
+static void throw_value(Value value) {
+    Handler *handler;
+    if (handler_count == 0) {
+        fflush(stdout);
+        fprintf(stderr, "Runtime error: uncaught %s\n", format_value(value));
+        exit(101);
+    }
+    handler = &handlers[--handler_count];
+    close_upvalues(&stack[handler->sp + 1]);
+    sp = handler->sp;
+    call_depth = handler->call_depth;
+    if (handler->catcher.tag != NIL) {
+        push(handler->catcher);
+    }
+    push(value);
+    longjmp(handler->landing, 1);
+}
+
+static Object *new_error(const char *kind, const char *message, Value payload) {
+    Object *o = allocate(ERROR);
+    o->as.error.kind = copy_string(kind);
+    o->as.error.message = copy_string(message);
+    o->as.error.payload = payload;
+    return o;
+}
+
+static void raise_error(const char *kind, const char *message) {
+    throw_value(object(new_error(kind, message, nil())));
+}
+
+static void type_error(const char *message) {
+    raise_error("type-error", message);
+}
+
+/* a type error with a value's description after `message` */
+static void type_error_with(const char *message, Value got) {
+    Buffer b = {NULL, 0, 0};
+    append(&b, message);
+    append_value(&b, got);
+    type_error(b.data);
+}
+
+/* ---- globals ---- */
+
+static void declare_global(int slot) {
+    if (slot < NUM_OPERATORS) {
+        operators_rebound = 1;
+    }
+    globals[slot] = pop();
+    defined[slot] = 1;
+}
+
+static Value global(int slot) {
+    if (!defined[slot]) {
+        Buffer b = {NULL, 0, 0};
+        append(&b, "undefined global variable: ");
+        append(&b, global_names[slot]);
+        raise_error("undefined-global", b.data);
+    }
+    return globals[slot];
+}
+
+/* ---- calls ---- */
+
+/* calls the function below the top `arity` values, leaving its result in their place */
+static void call_value(int arity) {
+    Value callee = stack[sp - arity];
+    char message[128];
+    if (callee.tag == OBJECT && callee.as.object != NULL) {
+        Object *o = callee.as.object;
+        if (o->kind == CLOSURE) {
+            const Proto *proto = o->as.closure.proto;
+            int base = sp - arity;
+            int i;
+            if (proto->arity != arity) {
+                sprintf(message, "arity mismatch: Expected %d arguments, got %d", proto->arity,
+                        arity);
+                raise_error("arity-error", message);
+            }
+            if (call_depth >= MAX_CALL_DEPTH
+                || sp + 1 + proto->num_locals + STACK_HEADROOM > STACK_SIZE) {
+                Buffer b = {NULL, 0, 0};
+                append(&b, "stack overflow in ");
+                append(&b, proto->name);
+                raise_error("stack-overflow", b.data);
+            }
+            for (i = 0; i < proto->num_locals; i++) {
+                push(nil());
+            }
+            call_depth++;
+            proto->code(o, base);
+            call_depth--;
+            return;
+        }
+        if (o->kind == BUILTIN) {
+            Value result = o->as.builtin.fn(&stack[sp - arity + 1], arity);
+            sp -= arity + 1;
+            push(result);
+            return;
+        }
+    }
+    {
+        Buffer b = {NULL, 0, 0};
+        append(&b, "");
+        append_value(&b, callee);
+        append(&b, " is not callable");
+        type_error(b.data);
+    }
+}
+
+/* what the `Return` instruction does, with the result on top of the stack */
+static void return_from(int base) {
+    Value result = pop();
+    close_upvalues(&stack[base]);
+    sp = base - 1;
+    push(result);
+}
+
+/* the arithmetic and comparison instructions, see `VM::handle_operator` */
+static void binary_op(int op) {
+    Value b = pop();
+    Value a = pop();
+    if (!operators_rebound && a.tag == INTEGER && b.tag == INTEGER) {
+        /* wrapping, rather than undefined */
+        uint64_t x = (uint64_t)a.as.integer, y = (uint64_t)b.as.integer;
+        switch (op) {
+        case OP_ADD:
+            push(integer((int64_t)(x + y)));
+            return;
+        case OP_SUB:
+            push(integer((int64_t)(x - y)));
+            return;
+        case OP_MUL:
+            push(integer((int64_t)(x * y)));
+            return;
+        case OP_DIV:
+            if (b.as.integer != 0 && !(a.as.integer == INT64_MIN && b.as.integer == -1)) {
+                push(integer(a.as.integer / b.as.integer));
+                return;
+            }
+            break;
+        case OP_EQUAL:
+            push(boolean(a.as.integer == b.as.integer));
+            return;
+        case OP_GT:
+            push(boolean(a.as.integer > b.as.integer));
+            return;
+        case OP_LT:
+            push(boolean(a.as.integer < b.as.integer));
+            return;
+        case OP_GTE:
+            push(boolean(a.as.integer >= b.as.integer));
+            return;
+        case OP_LTE:
+            push(boolean(a.as.integer <= b.as.integer));
+            return;
+        }
+    }
+    push(global(op));
+    push(a);
+    push(b);
+    call_value(2);
+}
+
+/* ---- builtins ---- */
+
+static int both_integers(Value *args, int count) {
+    return count == 2 && args[0].tag == INTEGER && args[1].tag == INTEGER;
+}
+
+static Value builtin_add(Value *args, int count) {
+    if (!both_integers(args, count)) {
+        type_error("add must be called with two integers");
+    }
+    return integer((int64_t)((uint64_t)args[0].as.integer + (uint64_t)args[1].as.integer));
+}
+
+static Value builtin_sub(Value *args, int count) {
+    if (!both_integers(args, count)) {
+        type_error("sub must be called with two integers");
+    }
+    return integer((int64_t)((uint64_t)args[0].as.integer - (uint64_t)args[1].as.integer));
+}
+
+static Value builtin_mul(Value *args, int count) {
+    if (!both_integers(args, count)) {
+        type_error("mul must be called with two integers");
+    }
+    return integer((int64_t)((uint64_t)args[0].as.integer * (uint64_t)args[1].as.integer));
+}
+
+static Value builtin_div(Value *args, int count) {
+    if (!both_integers(args, count)) {
+        type_error("div must be called with two integers");
+    }
+    if (args[1].as.integer == 0) {
+        raise_error("division-by-zero", "attempted to divide by zero");
+    }
+    return integer(args[0].as.integer / args[1].as.integer);
+}
+
+static Value builtin_mod(Value *args, int count) {
+    if (!both_integers(args, count)) {
+        type_error("mod must be called with two integers");
+    }
+    if (args[1].as.integer == 0) {
+        raise_error("division-by-zero", "attempted to take a remainder by zero");
+    }
+    return integer(args[0].as.integer % args[1].as.integer);
+}
+
+static Value builtin_inc(Value *args, int count) {
+    if (count < 1 || args[0].tag != INTEGER) {
+        type_error_with("inc must be called with an integer, got ", args[0]);
+    }
+    return integer(args[0].as.integer + 1);
+}
+
+static Value builtin_print(Value *args, int count) {
+    (void)count;
+    print_line(args[0]);
+    return nil();
+}
+
+static Value builtin_equal(Value *args, int count) {
+    if (count == 2 && args[0].tag == args[1].tag) {
+        switch (args[0].tag) {
+        case INTEGER:
+            return boolean(args[0].as.integer == args[1].as.integer);
+        case FLOAT:
+            return boolean(args[0].as.floating == args[1].as.floating);
+        case OBJECT:
+            return boolean(args[0].as.object == args[1].as.object);
+        default:
+            break;
+        }
+    }
+    type_error("= must be called with two values of the same type");
+    return nil();
+}
+
+#define COMPARISON(fn_name, operator, name)                                                   \
+    static Value fn_name(Value *args, int count) {                                          \
+        if (!both_integers(args, count)) {                                                  \
+            type_error(name " must be called with two integers");                           \
+        }                                                                                   \
+        return boolean(args[0].as.integer operator args[1].as.integer);                     \
+    }
+
+COMPARISON(builtin_gt, >, ">")
+COMPARISON(builtin_lt, <, "<")
+COMPARISON(builtin_gte, >=, ">=")
+COMPARISON(builtin_lte, <=, "<=")
+
+static Value builtin_and(Value *args, int count) {
+    if (count != 2 || args[0].tag != BOOL || args[1].tag != BOOL) {
+        type_error("and must be called with two booleans");
+    }
+    return boolean(args[0].as.boolean && args[1].as.boolean);
+}
+
+static Value builtin_or(Value *args, int count) {
+    if (count != 2 || args[0].tag != BOOL || args[1].tag != BOOL) {
+        type_error("or must be called with two booleans");
+    }
+    return boolean(args[0].as.boolean || args[1].as.boolean);
+}
+
+static Value builtin_not(Value *args, int count) {
+    if (count < 1 || args[0].tag != BOOL) {
+        type_error("not must be called with a boolean");
+    }
+    return boolean(!args[0].as.boolean);
+}
+
+static Object *as_cons(Value v, const char *message) {
+    if (v.tag != OBJECT || v.as.object == NULL || v.as.object->kind != CONS) {
+        type_error_with(message, v);
+    }
+    return v.as.object;
+}
+
+static Value builtin_car(Value *args, int count) {
+    (void)count;
+    return object(as_cons(args[0], "car must be called with a cons cell, got ")->as.cons.car);
+}
+
+static Value builtin_cdr(Value *args, int count) {
+    (void)count;
+    return object(as_cons(args[0], "cdr must be called with a cons cell, got ")->as.cons.cdr);
+}
+
+static Value builtin_cons(Value *args, int count) {
+    (void)count;
+    return object(new_cons(to_object(args[0]), to_object(args[1])));
+}
+
+/* the name of a symbol, quoted or not */
+static const char *symbol_name(Value v) {
+    if ((v.tag == OBJECT || v.tag == QUOTE) && v.as.object != NULL
+        && v.as.object->kind == SYMBOL) {
+        return v.as.object->as.string;
+    }
+    return NULL;
+}
+
+static Value builtin_error(Value *args, int count) {
+    const char *kind;
+    Value payload = count == 3 ? args[2] : nil();
+    if (count < 2 || count > 3) {
+        type_error("error expects a kind, a message and an optional payload");
+    }
+    kind = symbol_name(args[0]);
+    if (kind == NULL) {
+        type_error_with("error kind must be a symbol, got ", args[0]);
+    }
+    if (args[1].tag == OBJECT && args[1].as.object != NULL
+        && args[1].as.object->kind == STRING) {
+        throw_value(object(new_error(kind, args[1].as.object->as.string, payload)));
+    }
+    throw_value(object(new_error(kind, format_value(args[1]), payload)));
+    return nil();
+}
+
+static Object *as_error(Value v) {
+    if (v.tag != OBJECT || v.as.object == NULL || v.as.object->kind != ERROR) {
+        type_error_with("expected an error, got ", v);
+    }
+    return v.as.object;
+}
+
+/* re-raises an error, any other value is raised as the payload of a `raise` error */
+static Value builtin_raise(Value *args, int count) {
+    Buffer b = {NULL, 0, 0};
+    (void)count;
+    if (args[0].tag == OBJECT && args[0].as.object != NULL
+        && args[0].as.object->kind == ERROR) {
+        throw_value(args[0]);
+    }
+    append(&b, "raised ");
+    append_value(&b, args[0]);
+    throw_value(object(new_error("raise", b.data, args[0])));
+    return nil();
+}
+
+static Value builtin_error_kind(Value *args, int count) {
+    (void)count;
+    return quote(intern(as_error(args[0])->as.error.kind));
+}
+
+static Value builtin_error_message(Value *args, int count) {
+    (void)count;
+    return object(new_string(as_error(args[0])->as.error.message));
+}
+
+static Value builtin_error_payload(Value *args, int count) {
+    (void)count;
+    return as_error(args[0])->as.error.payload;
+}
+
+static Value builtin_eq_p(Value *args, int count) {
+    Value a = args[0], b = args[1];
+    (void)count;
+    if ((a.tag == OBJECT || a.tag == QUOTE) && (b.tag == OBJECT || b.tag == QUOTE)) {
+        return boolean(a.as.object == b.as.object);
+    }
+    if (a.tag != b.tag) {
+        return boolean(0);
+    }
+    switch (a.tag) {
+    case NIL:
+        return boolean(1);
+    case BOOL:
+        return boolean(a.as.boolean == b.as.boolean);
+    case INTEGER:
+        return boolean(a.as.integer == b.as.integer);
+    case FLOAT:
+        return boolean(a.as.floating == b.as.floating);
+    default:
+        return boolean(0);
+    }
+}
+
+static Value builtin_intern(Value *args, int count) {
+    (void)count;
+    if (args[0].tag != OBJECT || args[0].as.object == NULL
+        || args[0].as.object->kind != STRING) {
+        type_error_with("intern expects a string, got ", args[0]);
+    }
+    return quote(intern(args[0].as.object->as.string));
+}
+
+static Value builtin_symbol_to_string(Value *args, int count) {
+    const char *name = symbol_name(args[0]);
+    (void)count;
+    if (name == NULL) {
+        type_error_with("symbol->string expects a symbol, got ", args[0]);
+    }
+    return object(new_string(name));
+}
+
+static const struct {
+    const char *name;
+    BuiltinFn fn;
+} BUILTINS[] = {
+    {"+", builtin_add},
+    {"-", builtin_sub},
+    {"*", builtin_mul},
+    {"/", builtin_div},
+    {"%", builtin_mod},
+    {"inc", builtin_inc},
+    {"print", builtin_print},
+    {"=", builtin_equal},
+    {">", builtin_gt},
+    {"<", builtin_lt},
+    {">=", builtin_gte},
+    {"<=", builtin_lte},
+    {"and", builtin_and},
+    {"or", builtin_or},
+    {"not", builtin_not},
+    {"car", builtin_car},
+    {"cdr", builtin_cdr},
+    {"cons", builtin_cons},
+    {"error", builtin_error},
+    {"raise", builtin_raise},
+    {"error-kind", builtin_error_kind},
+    {"error-message", builtin_error_message},
+    {"error-payload", builtin_error_payload},
+    {"eq?", builtin_eq_p},
+    {"intern", builtin_intern},
+    {"symbol->string", builtin_symbol_to_string},
+};
+
+/* defines the builtins that the program mentions */
+static void init_runtime(void) {
+    size_t i;
+    int slot;
+    for (i = 0; i < sizeof(BUILTINS) / sizeof(BUILTINS[0]); i++) {
+        for (slot = 0; slot < NUM_GLOBALS; slot++) {
+            if (strcmp(global_names[slot], BUILTINS[i].name) == 0) {
+                Object *o = allocate(BUILTIN);
+                o->as.builtin.name = BUILTINS[i].name;
+                o->as.builtin.fn = BUILTINS[i].fn;
+                globals[slot] = object(o);
+                defined[slot] = 1;
+            }
+        }
+    }
+}
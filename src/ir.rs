@@ -0,0 +1,1034 @@
+//! An intermediate representation between `Expression` and bytecode, where programs are
+//! optimised before `compiler` lowers them to a `BytecodeChunk`.
+//!
+//! It's a tree like `Expression`, but every name is resolved to a local or a global when it's
+//! built, the way the compiler has always resolved them, so the passes can move code between
+//! functions without changing what it refers to. Inlining adds blocks, which bind a call's
+//! arguments to fresh locals before running the inlined body.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::compiler::{Expression, FunctionExpression};
+use crate::sexpr::SrcSexpr;
+use crate::vm::{ConstantValue, Op};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Node {
+    Constant(ConstantValue),
+    /// a parameter or local of the function the node is in, or of one it's nested in
+    Local(String),
+    Global(String),
+    Call {
+        callee: Box<Node>,
+        args: Vec<Node>,
+    },
+    If {
+        condition: Box<Node>,
+        then: Box<Node>,
+        else_: Box<Node>,
+    },
+    DefineLocal {
+        name: String,
+        value: Box<Node>,
+    },
+    SetLocal {
+        name: String,
+        value: Box<Node>,
+    },
+    DeclareGlobal {
+        name: String,
+        value: Box<Node>,
+    },
+    Function(FunctionNode),
+    Discard(Box<Node>),
+    Try {
+        body: Box<Node>,
+        catch: Option<FunctionNode>,
+        finally: Vec<Node>,
+    },
+    CallCC(Box<Node>),
+    Resume {
+        coroutine: Box<Node>,
+        value: Option<Box<Node>>,
+    },
+    Yield(Option<Box<Node>>),
+    /// runs each node in turn, and evaluates to the last one. The others are `DefineLocal`s
+    /// and `Discard`s, which leave nothing on the stack.
+    Block(Vec<Node>),
+    /// a node and the source line it starts on
+    Located {
+        line: usize,
+        node: Box<Node>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionNode {
+    pub name: Option<String>,
+    pub parameters: Vec<String>,
+    pub body: Vec<Node>,
+}
+
+impl Node {
+    /// see through any source locations wrapping the node
+    pub fn unlocated(&self) -> &Node {
+        match self {
+            Node::Located { node, .. } => node.unlocated(),
+            node => node,
+        }
+    }
+
+    fn unlocated_mut(&mut self) -> &mut Node {
+        match self {
+            Node::Located { node, .. } => node.unlocated_mut(),
+            node => node,
+        }
+    }
+
+    /// Every node directly inside this one, including the bodies of functions
+    fn children(&self) -> Vec<&Node> {
+        let mut children = vec![];
+        match self {
+            Node::Constant(_) | Node::Local(_) | Node::Global(_) | Node::Yield(None) => {}
+            Node::Call { callee, args } => {
+                children.push(callee.as_ref());
+                children.extend(args);
+            }
+            Node::If {
+                condition,
+                then,
+                else_,
+            } => children.extend([condition.as_ref(), then.as_ref(), else_.as_ref()]),
+            Node::DefineLocal { value, .. }
+            | Node::SetLocal { value, .. }
+            | Node::DeclareGlobal { value, .. }
+            | Node::Discard(value)
+            | Node::CallCC(value)
+            | Node::Yield(Some(value))
+            | Node::Located { node: value, .. } => children.push(value.as_ref()),
+            Node::Function(function) => children.extend(&function.body),
+            Node::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                children.extend(catch.iter().flat_map(|catch| &catch.body));
+                children.push(body.as_ref());
+                children.extend(finally);
+            }
+            Node::Resume { coroutine, value } => {
+                children.push(coroutine.as_ref());
+                children.extend(value.as_deref());
+            }
+            Node::Block(nodes) => children.extend(nodes),
+        }
+        children
+    }
+
+    /// The nodes directly inside this one that run in the same function, which leaves out the
+    /// bodies of functions, see `functions_mut`
+    fn children_mut(&mut self) -> Vec<&mut Node> {
+        let mut children = vec![];
+        match self {
+            Node::Constant(_)
+            | Node::Local(_)
+            | Node::Global(_)
+            | Node::Yield(None)
+            | Node::Function(_) => {}
+            Node::Call { callee, args } => {
+                children.push(callee.as_mut());
+                children.extend(args);
+            }
+            Node::If {
+                condition,
+                then,
+                else_,
+            } => children.extend([condition.as_mut(), then.as_mut(), else_.as_mut()]),
+            Node::DefineLocal { value, .. }
+            | Node::SetLocal { value, .. }
+            | Node::DeclareGlobal { value, .. }
+            | Node::Discard(value)
+            | Node::CallCC(value)
+            | Node::Yield(Some(value))
+            | Node::Located { node: value, .. } => children.push(value.as_mut()),
+            Node::Try { body, finally, .. } => {
+                children.push(body.as_mut());
+                children.extend(finally);
+            }
+            Node::Resume { coroutine, value } => {
+                children.push(coroutine.as_mut());
+                children.extend(value.as_deref_mut());
+            }
+            Node::Block(nodes) => children.extend(nodes),
+        }
+        children
+    }
+
+    /// the functions directly inside this one, a function literal or a `catch` handler
+    fn functions_mut(&mut self) -> Vec<&mut FunctionNode> {
+        match self {
+            Node::Function(function) => vec![function],
+            Node::Try {
+                catch: Some(catch), ..
+            } => vec![catch],
+            _ => vec![],
+        }
+    }
+}
+
+/// Builds the IR for a program, resolving each name to the parameters and locals defined so
+/// far in the function it's in and the ones that function is nested in, or else to a global
+pub fn build(expressions: Vec<Expression>) -> Vec<Node> {
+    // the top level is compiled like a function, which is never given any locals
+    let mut builder = Builder {
+        scopes: vec![vec![]],
+    };
+    expressions
+        .into_iter()
+        .map(|expression| builder.node(expression))
+        .collect()
+}
+
+struct Builder {
+    /// the names bound in each function being built, innermost last
+    scopes: Vec<Vec<String>>,
+}
+
+impl Builder {
+    /// built in the order the compiler has always compiled them, which decides where names
+    /// are visible
+    fn node(&mut self, expression: Expression) -> Node {
+        match expression {
+            Expression::SrcSexpr(SrcSexpr::Symbol(name)) => {
+                if self.scopes.iter().flatten().any(|local| *local == name) {
+                    Node::Local(name)
+                } else {
+                    Node::Global(name)
+                }
+            }
+            Expression::SrcSexpr(SrcSexpr::List(_) | SrcSexpr::Located(..)) => {
+                unreachable!("this should have been handled by the structural parser")
+            }
+            Expression::SrcSexpr(sexpr) => Node::Constant(sexpr.into()),
+            Expression::RegularForm(exprs) => {
+                let mut nodes = exprs.into_iter().map(|expr| self.node(expr));
+                let callee = nodes.next().expect("expected a function to call");
+                Node::Call {
+                    callee: Box::new(callee),
+                    args: nodes.collect(),
+                }
+            }
+            Expression::If {
+                condition,
+                then,
+                else_,
+            } => {
+                let condition = self.boxed(*condition);
+                let else_ = self.boxed(*else_);
+                let then = self.boxed(*then);
+                Node::If {
+                    condition,
+                    then,
+                    else_,
+                }
+            }
+            Expression::LocalDefine { name, value } => {
+                self.scopes.last_mut().unwrap().push(name.clone());
+                Node::DefineLocal {
+                    name,
+                    value: self.boxed(*value),
+                }
+            }
+            Expression::LocalSet { name, value } => Node::SetLocal {
+                name,
+                value: self.boxed(*value),
+            },
+            Expression::DeclareGlobal { name, value } => Node::DeclareGlobal {
+                name,
+                value: self.boxed(*value),
+            },
+            Expression::FunctionLiteral(function) => Node::Function(self.function(function)),
+            Expression::Discard(expr) => Node::Discard(self.boxed(*expr)),
+            Expression::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                let catch = catch.map(|catch| self.function(catch));
+                Node::Try {
+                    catch,
+                    body: self.boxed(*body),
+                    finally: finally.into_iter().map(|expr| self.node(expr)).collect(),
+                }
+            }
+            Expression::CallCC(function) => Node::CallCC(self.boxed(*function)),
+            Expression::Resume { coroutine, value } => Node::Resume {
+                coroutine: self.boxed(*coroutine),
+                value: value.map(|value| self.boxed(*value)),
+            },
+            Expression::Yield(value) => Node::Yield(value.map(|value| self.boxed(*value))),
+            Expression::Located { line, expr } => Node::Located {
+                line,
+                node: self.boxed(*expr),
+            },
+        }
+    }
+
+    fn boxed(&mut self, expression: Expression) -> Box<Node> {
+        Box::new(self.node(expression))
+    }
+
+    fn function(&mut self, function: FunctionExpression) -> FunctionNode {
+        self.scopes.push(function.parameters.clone());
+        let body = function
+            .body
+            .into_iter()
+            .map(|expr| self.node(expr))
+            .collect();
+        self.scopes.pop();
+        FunctionNode {
+            name: function.name,
+            parameters: function.parameters,
+            body,
+        }
+    }
+}
+
+/// Optimises a program, which has to be all the code the VM will run, because the passes
+/// assume that a global the program never declares keeps the value it starts with, and that
+/// a function declared once at the top level is what its name refers to from then on.
+/// That's not the case for a REPL, which compiles each line on its own.
+pub fn optimise(program: &mut [Node]) {
+    fold_constants(program);
+    eliminate_dead_branches(program);
+    inline_functions(program);
+    // inlining leaves constant arguments to fold, and parameters it didn't need
+    loop {
+        let changed = fold_constants(program)
+            | eliminate_dead_branches(program)
+            | remove_unused_bindings(program);
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// calls `f` on every node in `node`, innermost first
+fn post_order(node: &mut Node, f: &mut impl FnMut(&mut Node)) {
+    for function in node.functions_mut() {
+        for node in &mut function.body {
+            post_order(node, f);
+        }
+    }
+    for child in node.children_mut() {
+        post_order(child, f);
+    }
+    f(node);
+}
+
+/// calls `f` on every node in `node`, outermost first
+fn pre_order<'a>(node: &'a Node, f: &mut impl FnMut(&'a Node)) {
+    f(node);
+    for child in node.children() {
+        pre_order(child, f);
+    }
+}
+
+/// How many times each global is declared anywhere in the program
+fn declared_globals(program: &[Node]) -> HashMap<String, usize> {
+    let mut declared = HashMap::new();
+    for node in program {
+        pre_order(node, &mut |node| {
+            if let Node::DeclareGlobal { name, .. } = node {
+                *declared.entry(name.clone()).or_insert(0) += 1;
+            }
+        });
+    }
+    declared
+}
+
+/// Replaces calls to the arithmetic and comparison operators on integer constants with their
+/// results, unless the program rebinds the operator. Returns whether anything changed.
+fn fold_constants(program: &mut [Node]) -> bool {
+    let declared = declared_globals(program);
+    let mut changed = false;
+    for node in program {
+        post_order(node, &mut |node| {
+            if let Some(value) = fold(node, &declared) {
+                *node = Node::Constant(value);
+                changed = true;
+            }
+        });
+    }
+    changed
+}
+
+/// What `VM::integer_operator` would do with the call, when the operands are constants.
+/// Anything that overflows or divides by zero is left to fail when it's run.
+fn fold(node: &Node, declared: &HashMap<String, usize>) -> Option<ConstantValue> {
+    let Node::Call { callee, args } = node else {
+        return None;
+    };
+    let Node::Global(name) = callee.as_ref() else {
+        return None;
+    };
+    if declared.contains_key(name) {
+        return None;
+    }
+    let op = Op::for_operator(name)?;
+    let [a, b] = args.as_slice() else {
+        return None;
+    };
+    let (Node::Constant(ConstantValue::Integer(a)), Node::Constant(ConstantValue::Integer(b))) =
+        (a.unlocated(), b.unlocated())
+    else {
+        return None;
+    };
+    Some(match op {
+        Op::Add => ConstantValue::Integer(a.checked_add(*b)?),
+        Op::Sub => ConstantValue::Integer(a.checked_sub(*b)?),
+        Op::Mul => ConstantValue::Integer(a.checked_mul(*b)?),
+        Op::Div => ConstantValue::Integer(a.checked_div(*b)?),
+        Op::Equal => ConstantValue::Boolean(a == b),
+        Op::GT => ConstantValue::Boolean(a > b),
+        Op::LT => ConstantValue::Boolean(a < b),
+        Op::GTE => ConstantValue::Boolean(a >= b),
+        Op::LTE => ConstantValue::Boolean(a <= b),
+        _ => return None,
+    })
+}
+
+/// Replaces `if`s with constant conditions with the branch they'd take.
+/// Returns whether anything changed.
+fn eliminate_dead_branches(program: &mut [Node]) -> bool {
+    let mut changed = false;
+    for node in program {
+        post_order(node, &mut |node| {
+            let Node::If {
+                condition,
+                then,
+                else_,
+            } = node
+            else {
+                return;
+            };
+            let Node::Constant(condition) = condition.unlocated() else {
+                return;
+            };
+            let Some(taken) = truthy(condition) else {
+                return;
+            };
+            let branch = if taken { then } else { else_ };
+            // a definition is only allowed where the `if` would have discarded its value
+            if matches!(
+                branch.unlocated(),
+                Node::DefineLocal { .. } | Node::DeclareGlobal { .. }
+            ) {
+                return;
+            }
+            *node = std::mem::replace(branch.as_mut(), Node::Constant(ConstantValue::Nil));
+            changed = true;
+        });
+    }
+    changed
+}
+
+/// whether a constant is truthy, for the ones that are simple values on the stack
+fn truthy(constant: &ConstantValue) -> Option<bool> {
+    match constant {
+        ConstantValue::Nil => Some(false),
+        ConstantValue::Boolean(b) => Some(*b),
+        ConstantValue::Integer(_) | ConstantValue::Float(_) => Some(true),
+        _ => None,
+    }
+}
+
+/// Removes `define`s of locals that are never referred to or set, keeping their values'
+/// effects when they have any. Returns whether anything changed.
+fn remove_unused_bindings(program: &mut [Node]) -> bool {
+    let mut changed = false;
+    for node in program {
+        for_each_function(node, &mut |function| {
+            let mut used = HashSet::new();
+            for node in &function.body {
+                pre_order(node, &mut |node| match node {
+                    Node::Local(name) | Node::SetLocal { name, .. } => {
+                        used.insert(name.clone());
+                    }
+                    _ => {}
+                });
+            }
+            changed |= remove_unused_from(&mut function.body, &used);
+        });
+    }
+    changed
+}
+
+/// calls `f` on every function in `node`, outermost first
+fn for_each_function(node: &mut Node, f: &mut impl FnMut(&mut FunctionNode)) {
+    for function in node.functions_mut() {
+        f(function);
+        for node in &mut function.body {
+            for_each_function(node, f);
+        }
+    }
+    for child in node.children_mut() {
+        for_each_function(child, f);
+    }
+}
+
+/// removes the unused `define`s in a function body or block, and the blocks inside them
+fn remove_unused_from(nodes: &mut Vec<Node>, used: &HashSet<String>) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < nodes.len() {
+        // the last node is the value
+        let last = idx + 1 == nodes.len();
+        if let Node::DefineLocal { name, value } = nodes[idx].unlocated_mut() {
+            if !last && !used.contains(name) {
+                changed = true;
+                if pure(value) {
+                    nodes.remove(idx);
+                    continue;
+                }
+                let value = std::mem::replace(value.as_mut(), Node::Constant(ConstantValue::Nil));
+                *nodes[idx].unlocated_mut() = Node::Discard(Box::new(value));
+            }
+        }
+        changed |= remove_unused_in(&mut nodes[idx], used);
+        idx += 1;
+    }
+    changed
+}
+
+fn remove_unused_in(node: &mut Node, used: &HashSet<String>) -> bool {
+    if let Node::Block(nodes) = node {
+        return remove_unused_from(nodes, used);
+    }
+    let mut changed = false;
+    for child in node.children_mut() {
+        changed |= remove_unused_in(child, used);
+    }
+    changed
+}
+
+/// whether evaluating a node can't do anything but make its value
+fn pure(node: &Node) -> bool {
+    matches!(
+        node.unlocated(),
+        Node::Constant(_) | Node::Local(_) | Node::Function(_)
+    )
+}
+
+/// the most nodes a function's body can have to be inlined, not counting locations
+const MAX_INLINE_SIZE: usize = 16;
+/// Locals are numbered with a byte, leave room for the ones that aren't counted, like the
+/// locals of functions nested in the one being inlined into
+const MAX_LOCALS: usize = 128;
+
+/// Replaces calls to small functions with their bodies. Returns whether anything changed.
+///
+/// Only functions that are declared once, at the top level, whose bodies are one expression
+/// using nothing but their parameters and globals the program never declares are inlined.
+/// They can't be recursive, and nothing they refer to changes, so the inlined body does
+/// what the call would have. They're only inlined after the declaration, where the global
+/// is known to hold the function. Arguments that aren't constants are bound to fresh locals,
+/// so they're still evaluated once and in order, which can't be done at the top level.
+fn inline_functions(program: &mut [Node]) -> bool {
+    let declared = declared_globals(program);
+    let mut inliner = Inliner {
+        inlinable: HashMap::new(),
+        inlined: 0,
+        declared,
+    };
+    for statement in program {
+        inliner.inline_in(statement, None);
+        if let Some((name, function)) = inlinable(statement, &inliner.declared) {
+            inliner.inlinable.insert(name, function);
+        }
+    }
+    inliner.inlined > 0
+}
+
+/// the function a top level statement declares, if it can be inlined
+fn inlinable(
+    statement: &Node,
+    declared: &HashMap<String, usize>,
+) -> Option<(String, FunctionNode)> {
+    let Node::DeclareGlobal { name, value } = statement.unlocated() else {
+        return None;
+    };
+    let Node::Function(function) = value.unlocated() else {
+        return None;
+    };
+    let [body] = function.body.as_slice() else {
+        return None;
+    };
+    let parameters: HashSet<_> = function.parameters.iter().collect();
+    let mut size = 0;
+    let mut simple = true;
+    pre_order(body, &mut |node| {
+        size += !matches!(node, Node::Located { .. }) as usize;
+        simple &= match node {
+            Node::Constant(_) | Node::If { .. } | Node::Located { .. } => true,
+            Node::Local(name) => parameters.contains(name),
+            Node::Global(name) => !declared.contains_key(name),
+            // functions passed in as arguments aren't called, they might set a local the
+            // caller passed in too
+            Node::Call { callee, .. } => matches!(callee.as_ref(), Node::Global(_)),
+            _ => false,
+        };
+    });
+    let once = declared[name] == 1 && parameters.len() == function.parameters.len();
+    (once && simple && size <= MAX_INLINE_SIZE).then(|| (name.clone(), function.clone()))
+}
+
+struct Inliner {
+    /// the functions that can be inlined so far, by name
+    inlinable: HashMap<String, FunctionNode>,
+    /// the number of calls inlined, which numbers the locals their arguments are bound to
+    inlined: usize,
+    declared: HashMap<String, usize>,
+}
+
+impl Inliner {
+    /// `locals` is the number of locals in the function `node` is in, or `None` at the top level
+    fn inline_in(&mut self, node: &mut Node, mut locals: Option<&mut usize>) {
+        for function in node.functions_mut() {
+            let mut count = function.parameters.len();
+            for node in &function.body {
+                pre_order(node, &mut |node| {
+                    count += matches!(node, Node::DefineLocal { .. }) as usize;
+                });
+            }
+            for node in &mut function.body {
+                self.inline_in(node, Some(&mut count));
+            }
+        }
+        if let Node::Try { body, finally, .. } = node {
+            self.inline_in(body, locals.as_deref_mut());
+            // `finally` is compiled twice, which would define its locals twice
+            for node in finally {
+                self.inline_in(node, None);
+            }
+        } else {
+            for child in node.children_mut() {
+                self.inline_in(child, locals.as_deref_mut());
+            }
+        }
+        if let Some(inlined) = self.inline(node, locals) {
+            *node = inlined;
+        }
+    }
+
+    fn inline(&mut self, node: &Node, locals: Option<&mut usize>) -> Option<Node> {
+        let Node::Call { callee, args } = node else {
+            return None;
+        };
+        let Node::Global(name) = callee.as_ref() else {
+            return None;
+        };
+        let function = self.inlinable.get(name)?;
+        if args.len() != function.parameters.len() {
+            // left to raise the arity error
+            return None;
+        }
+
+        let constant = |arg: &Node| matches!(arg.unlocated(), Node::Constant(_));
+        let only_operators = {
+            let mut only_operators = true;
+            pre_order(&function.body[0], &mut |node| {
+                if let Node::Call { callee, args } = node {
+                    only_operators &= matches!(callee.as_ref(), Node::Global(name)
+                        if Op::for_operator(name).is_some() && args.len() == 2);
+                }
+            });
+            only_operators
+        };
+        // a local can be read where the parameter's used if nothing can set it in between
+        let simple = |arg: &Node| {
+            constant(arg)
+                || only_operators
+                    && matches!(arg.unlocated(), Node::Local(_))
+                    && args
+                        .iter()
+                        .all(|arg| matches!(arg.unlocated(), Node::Constant(_) | Node::Local(_)))
+        };
+        let bound = args.iter().filter(|arg| !simple(arg)).count();
+        if bound > 0 {
+            let locals = locals?;
+            if *locals + bound > MAX_LOCALS {
+                return None;
+            }
+            *locals += bound;
+        }
+
+        let mut block = vec![];
+        let mut substitutions = HashMap::new();
+        for (parameter, arg) in function.parameters.iter().zip(args) {
+            if simple(arg) {
+                substitutions.insert(parameter.as_str(), arg.unlocated().clone());
+                continue;
+            }
+            let local = format!("{name}.{parameter}#{}", self.inlined);
+            block.push(Node::DefineLocal {
+                name: local.clone(),
+                value: Box::new(arg.clone()),
+            });
+            substitutions.insert(parameter.as_str(), Node::Local(local));
+        }
+        let mut body = function.body[0].clone();
+        post_order(&mut body, &mut |node| {
+            if let Node::Local(name) = node {
+                *node = substitutions[name.as_str()].clone();
+            }
+        });
+        // so a call it's an argument to can be inlined too, if it's left a constant
+        post_order(&mut body, &mut |node| {
+            if let Some(value) = fold(node, &self.declared) {
+                *node = Node::Constant(value);
+            }
+        });
+        self.inlined += 1;
+
+        if block.is_empty() {
+            return Some(body);
+        }
+        block.push(body);
+        Some(Node::Block(block))
+    }
+}
+
+/// Written like the source, without locations, with blocks as `(block ..)`
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn list(f: &mut std::fmt::Formatter<'_>, nodes: &[Node]) -> std::fmt::Result {
+            nodes.iter().try_for_each(|node| write!(f, " {node}"))
+        }
+        match self {
+            Node::Constant(constant) => write_constant(f, constant),
+            Node::Local(name) | Node::Global(name) => write!(f, "{name}"),
+            Node::Call { callee, args } => {
+                write!(f, "({callee}")?;
+                list(f, args)?;
+                write!(f, ")")
+            }
+            Node::If {
+                condition,
+                then,
+                else_,
+            } => write!(f, "(if {condition} {then} {else_})"),
+            Node::DefineLocal { name, value } | Node::DeclareGlobal { name, value } => {
+                write!(f, "(define {name} {value})")
+            }
+            Node::SetLocal { name, value } => write!(f, "(set {name} {value})"),
+            Node::Function(function) => {
+                write!(f, "(fn ({})", function.parameters.join(" "))?;
+                list(f, &function.body)?;
+                write!(f, ")")
+            }
+            Node::Discard(node) | Node::Located { node, .. } => write!(f, "{node}"),
+            Node::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                write!(f, "(try {body}")?;
+                if let Some(catch) = catch {
+                    write!(f, " (catch {}", catch.parameters.join(" "))?;
+                    list(f, &catch.body)?;
+                    write!(f, ")")?;
+                }
+                if !finally.is_empty() {
+                    write!(f, " (finally")?;
+                    list(f, finally)?;
+                    write!(f, ")")?;
+                }
+                write!(f, ")")
+            }
+            Node::CallCC(function) => write!(f, "(call/cc {function})"),
+            Node::Resume { coroutine, value } => match value {
+                Some(value) => write!(f, "(resume {coroutine} {value})"),
+                None => write!(f, "(resume {coroutine})"),
+            },
+            Node::Yield(value) => match value {
+                Some(value) => write!(f, "(yield {value})"),
+                None => write!(f, "(yield)"),
+            },
+            Node::Block(nodes) => {
+                write!(f, "(block")?;
+                list(f, nodes)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+fn write_constant(f: &mut std::fmt::Formatter<'_>, constant: &ConstantValue) -> std::fmt::Result {
+    match constant {
+        ConstantValue::Integer(i) => write!(f, "{i}"),
+        ConstantValue::Float(x) => write!(f, "{x:?}"),
+        ConstantValue::Boolean(b) => write!(f, "{b}"),
+        ConstantValue::Nil => write!(f, "nil"),
+        ConstantValue::Object(object) => write!(f, "{object}"),
+        ConstantValue::List(items) => {
+            write!(f, "(")?;
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, " ")?;
+                }
+                write_constant(f, item)?;
+            }
+            write!(f, ")")
+        }
+        ConstantValue::Quote(quoted) => {
+            write!(f, "'")?;
+            write_constant(f, quoted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, compile_optimised, structure};
+    use crate::vm::VM;
+
+    fn program(src: &str) -> Vec<Node> {
+        build(structure(&src.to_string()))
+    }
+
+    /// runs a pass over `src`, returning the program it's left with
+    fn after(pass: fn(&mut [Node]) -> bool, src: &str) -> String {
+        let mut program = program(src);
+        pass(&mut program);
+        print(&program)
+    }
+
+    fn print(program: &[Node]) -> String {
+        program
+            .iter()
+            .map(Node::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn global(vm: &VM, name: &str) -> String {
+        vm.globals.get(name).unwrap().to_string()
+    }
+
+    #[test]
+    fn names_resolve_to_what_they_refer_to() {
+        let program = program("(defun (f car) (define x car) (fn () (cons x y)))");
+        let Node::DeclareGlobal { value, .. } = program[0].unlocated() else {
+            panic!("expected a declaration");
+        };
+        let Node::Function(f) = value.unlocated() else {
+            panic!("expected a function");
+        };
+        let Node::Function(inner) = f.body[1].unlocated() else {
+            panic!("expected a function");
+        };
+        let Node::Call { callee, args } = inner.body[0].unlocated() else {
+            panic!("expected a call");
+        };
+        assert_eq!(callee.as_ref(), &Node::Global("cons".to_string()));
+        assert_eq!(
+            args,
+            &[Node::Local("x".to_string()), Node::Global("y".to_string())]
+        );
+        assert_eq!(
+            f.body[0].unlocated(),
+            &Node::DefineLocal {
+                name: "x".to_string(),
+                value: Box::new(Node::Local("car".to_string()))
+            }
+        );
+    }
+
+    #[test]
+    fn constant_operators_are_folded() {
+        assert_eq!(
+            after(fold_constants, "(define x (+ 1 (* 2 3)))"),
+            "(define x 7)"
+        );
+        assert_eq!(
+            after(fold_constants, "(define x (< (- 1 2) 0))"),
+            "(define x true)"
+        );
+        // not operators, or not integers
+        assert_eq!(
+            after(fold_constants, "(define x (% 7 2))\n(define y (+ 1.5 2))"),
+            "(define x (% 7 2))\n(define y (+ 1.5 2))"
+        );
+    }
+
+    #[test]
+    fn folding_leaves_what_would_fail_or_was_rebound() {
+        assert_eq!(
+            after(fold_constants, "(define x (/ 1 0))"),
+            "(define x (/ 1 0))"
+        );
+        assert_eq!(
+            after(fold_constants, "(define x (+ 9223372036854775807 1))"),
+            "(define x (+ 9223372036854775807 1))"
+        );
+        assert_eq!(
+            after(fold_constants, "(define + -)\n(define x (+ 1 2))"),
+            "(define + -)\n(define x (+ 1 2))"
+        );
+        // a parameter called `+` isn't the operator
+        assert_eq!(
+            after(fold_constants, "(defun (f +) (+ 1 2))"),
+            "(define f (fn (+) (+ 1 2)))"
+        );
+    }
+
+    #[test]
+    fn constant_conditions_take_their_branch() {
+        assert_eq!(
+            after(eliminate_dead_branches, "(print (if true 1 2))"),
+            "(print 1)"
+        );
+        assert_eq!(
+            after(eliminate_dead_branches, "(print (if false 1 (if 0 2 3)))"),
+            "(print 2)"
+        );
+        assert_eq!(
+            after(eliminate_dead_branches, "(print (if x 1 2))"),
+            "(print (if x 1 2))"
+        );
+        // a quoted list might be empty, which is nil
+        assert_eq!(
+            after(eliminate_dead_branches, "(print (if '() 1 2))"),
+            "(print (if '() 1 2))"
+        );
+    }
+
+    #[test]
+    fn unused_bindings_are_removed() {
+        assert_eq!(
+            after(
+                remove_unused_bindings,
+                "(defun (f x) (define a 1) (define b (print x)) (define c x) (define d c) d)"
+            ),
+            "(define f (fn (x) (print x) (define c x) (define d c) d))"
+        );
+        // set, captured, or the value of the function
+        assert_eq!(
+            after(
+                remove_unused_bindings,
+                "(defun (f) (define a 1) (set a 2) (define b 3) (fn () b) (define c 4))"
+            ),
+            "(define f (fn () (define a 1) (set a 2) (define b 3) (fn () b) (define c 4)))"
+        );
+    }
+
+    #[test]
+    fn unused_bindings_are_removed_until_there_are_none() {
+        let mut program = program("(defun (f x) (define a x) (define b a) (define c (fn () b)) x)");
+        while remove_unused_bindings(&mut program) {}
+        assert_eq!(print(&program), "(define f (fn (x) x))");
+    }
+
+    #[test]
+    fn small_functions_are_inlined() {
+        let src = "(defun (dec n) (- n 1))
+                   (define x (dec 5))
+                   (defun (f a) (dec (car a)))
+                   (defun (g a b) (dec a))";
+        assert_eq!(
+            after(inline_functions, src),
+            "(define dec (fn (n) (- n 1)))
+(define x 4)
+(define f (fn (a) (block (define dec.n#1 (car a)) (- dec.n#1 1))))
+(define g (fn (a b) (- a 1)))"
+        );
+    }
+
+    #[test]
+    fn only_what_can_be_inlined_is() {
+        let src = "(f 1)
+                   (defun (f n) (+ n 1))
+                   (defun (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+                   (defun (g n) (f n))
+                   (defun (twice h) (h (h 1)))
+                   (define x (fib (f 1)))
+                   (define y (f x))
+                   (define z (f 1 2))";
+        assert_eq!(
+            after(inline_functions, src),
+            "(f 1)
+(define f (fn (n) (+ n 1)))
+(define fib (fn (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))
+(define g (fn (n) (+ n 1)))
+(define twice (fn (h) (h (h 1))))
+(define x (fib 2))
+(define y (f x))
+(define z (f 1 2))"
+        );
+        // declared twice
+        let src = "(defun (f n) n)\n(define x (f 1))\n(defun (f n) 2)";
+        assert_eq!(after(inline_functions, src), print(&program(src)));
+    }
+
+    #[test]
+    fn inlined_arguments_are_evaluated_once_in_order() {
+        let src = "(defun (sub a b) (- b a))
+                   (defun (f) (sub (print 1) (print 2)))";
+        assert_eq!(
+            after(inline_functions, src).lines().nth(1),
+            Some(
+                "(define f (fn () (block (define sub.a#0 (print 1)) (define sub.b#0 (print 2)) \
+                 (- sub.b#0 sub.a#0))))"
+            )
+        );
+    }
+
+    #[test]
+    fn passes_run_until_theres_nothing_left() {
+        let src = "(defun (dec n) (- n 1))
+                   (defun (sign n) (if (< n 0) -1 (if (= n 0) 0 1)))
+                   (define x (sign (dec 1)))
+                   (defun (f a) (define unused (dec a)) (ignore a))
+                   (defun (ignore a) 1)
+                   (defun (g a) (ignore (print a)))";
+        let mut program = program(src);
+        optimise(&mut program);
+        assert_eq!(
+            print(&program),
+            "(define dec (fn (n) (- n 1)))
+(define sign (fn (n) (if (< n 0) -1 (if (= n 0) 0 1))))
+(define x 0)
+(define f (fn (a) (- a 1) (ignore a)))
+(define ignore (fn (a) 1))
+(define g (fn (a) (block (print a) 1)))"
+        );
+    }
+
+    #[test]
+    fn optimised_programs_do_the_same() {
+        let src = r#"
+(defun (add a b) (+ a b))
+(defun (dec n) (- n 1))
+(defun (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+(defun (count-down n) (define next (dec n)) (if (< n 1) 'done (count-down next)))
+(defun (cleanup n) (try (add n 1) (finally (print (add (add n 1) 2)))))
+(define results (cons (add 1 2) (cons (dec 5) (cons (fib 15)
+    (cons (try (add 1.5 2) (catch e (error-kind e)))
+          (try (dec "a") (catch e (error-kind e))))))))
+(define down (count-down 10))
+(define cleaned (cleanup 5))
+(define - +)
+(define rebound (dec 1))
+"#;
+        let mut vm = VM::default();
+        vm.run(compile(&src.to_string()));
+        let mut optimised = VM::default();
+        optimised.run(compile_optimised(&src.to_string()));
+        for name in ["results", "down", "cleaned", "rebound"] {
+            assert_eq!(global(&vm, name), global(&optimised, name));
+        }
+    }
+}
@@ -53,13 +53,30 @@ enum LexerState {
 }
 
 pub fn lex(s: &String) -> Result<Vec<Token>, String> {
+    lex_with_lines(s).map(|(tokens, _)| tokens)
+}
+
+/// Also returns the (1-based) line each token is on
+pub fn lex_with_lines(s: &String) -> Result<(Vec<Token>, Vec<usize>), String> {
     let chars = s.to_string().trim().chars().collect::<Vec<_>>();
+    let trimmed_start = s.len() - s.trim_start().len();
 
     let mut state: LexerState = LexerState::None;
     let mut tokens: Vec<Token> = vec![];
+    let mut lines: Vec<usize> = vec![];
+    let mut line = 1 + s[..trimmed_start].matches('\n').count();
+    let mut line_scanned = 0;
     let mut i = 0;
     while i < chars.len() {
         let c = chars[i];
+        while line_scanned < i {
+            if chars[line_scanned] == '\n' {
+                line += 1;
+            }
+            line_scanned += 1;
+        }
+        // tokens are pushed on the line of the character that ends them
+        lines.resize(tokens.len(), line);
 
         match state {
             LexerState::Symbol(ref mut s) => {
@@ -144,8 +161,9 @@ pub fn lex(s: &String) -> Result<Vec<Token>, String> {
         LexerState::StringLiteral(_) => return Err("Unexpected end of input".to_string()),
         LexerState::None => (),
     }
+    lines.resize(tokens.len(), line);
 
-    Ok(tokens)
+    Ok((tokens, lines))
 }
 
 #[cfg(test)]
@@ -1,13 +1,24 @@
 mod builtins;
 mod builtins_comp;
+pub mod assembler;
+pub mod cfg;
 pub mod compiler;
+pub mod coverage;
+pub mod debugger;
 pub mod disassembler;
+pub mod emit_c;
 mod evaluator;
 pub mod interpreter;
+pub mod ir;
 mod lexer;
 mod memory;
+pub mod nanbox;
 mod parser;
+pub mod predecode;
+pub mod profiler;
 mod sexpr;
 mod static_stack;
 mod structural_parser;
+pub mod trace;
+pub mod verifier;
 pub mod vm;
@@ -0,0 +1,226 @@
+//! An 8 byte encoding of `SmallVal`, which the VM's stack holds instead with the `nan_boxing`
+//! feature.
+//!
+//! Floats are stored as themselves. Everything else lives in the negative quiet NaNs, which no
+//! float uses once NaNs have been canonicalised: the top 13 bits are set, the next 3 are a tag
+//! and the bottom 48 are the payload. Pointers fit in 48 bits on the platforms we run on, and so
+//! do almost all integers. Integers that don't are boxed, and owned by the value.
+
+use std::fmt::{Debug, Display};
+
+use crate::vm::{HeapObject, SmallVal};
+
+/// set in every value that isn't a float
+pub(crate) const BOXED: u64 = 0xFFF8_0000_0000_0000;
+pub(crate) const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0b111;
+const PAYLOAD: u64 = (1 << TAG_SHIFT) - 1;
+/// the only NaN that's stored, so that the other NaNs are free for the tags
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+const NIL: u64 = 0;
+pub(crate) const BOOL: u64 = 1;
+pub(crate) const INTEGER: u64 = 2;
+const OBJECT_PTR: u64 = 3;
+const QUOTE: u64 = 4;
+/// an integer outside the 48 bit range, the payload points to a `Box<i64>`
+pub(crate) const BIG_INTEGER: u64 = 5;
+
+const MIN_INTEGER: i64 = -(1 << (TAG_SHIFT - 1));
+const MAX_INTEGER: i64 = (1 << (TAG_SHIFT - 1)) - 1;
+
+/// A `SmallVal` packed into 8 bytes. Converting back gives an equal value, except that every
+/// NaN comes back as the same NaN.
+#[repr(transparent)]
+pub struct PackedVal(u64);
+
+impl PackedVal {
+    #[inline]
+    pub fn pack(value: &SmallVal) -> Self {
+        match value {
+            SmallVal::Float(f) if f.is_nan() => PackedVal(CANONICAL_NAN),
+            SmallVal::Float(f) => PackedVal(f.to_bits()),
+            SmallVal::Nil => Self::tagged(NIL, 0),
+            SmallVal::Bool(b) => Self::tagged(BOOL, *b as u64),
+            SmallVal::Integer(i) if (MIN_INTEGER..=MAX_INTEGER).contains(i) => {
+                Self::tagged(INTEGER, *i as u64 & PAYLOAD)
+            }
+            SmallVal::Integer(i) => Self::tagged(BIG_INTEGER, pointer(Box::into_raw(Box::new(*i)))),
+            SmallVal::ObjectPtr(ptr) => Self::tagged(OBJECT_PTR, pointer(*ptr)),
+            SmallVal::Quote(ptr) => Self::tagged(QUOTE, pointer(*ptr)),
+        }
+    }
+
+    #[inline]
+    pub fn unpack(&self) -> SmallVal {
+        if self.0 & BOXED != BOXED {
+            return SmallVal::Float(f64::from_bits(self.0));
+        }
+        let payload = self.0 & PAYLOAD;
+        match (self.0 >> TAG_SHIFT) & TAG_MASK {
+            NIL => SmallVal::Nil,
+            BOOL => SmallVal::Bool(payload != 0),
+            // shifting back down extends the sign
+            INTEGER => SmallVal::Integer(((payload << 16) as i64) >> 16),
+            BIG_INTEGER => SmallVal::Integer(unsafe { *(payload as *const i64) }),
+            OBJECT_PTR => SmallVal::ObjectPtr(payload as *mut HeapObject),
+            QUOTE => SmallVal::Quote(payload as *mut HeapObject),
+            tag => unreachable!("invalid tag {tag}"),
+        }
+    }
+
+    /// the bits, for machine code that reads and writes them itself
+    #[cfg(all(feature = "jit", feature = "nan_boxing"))]
+    pub(crate) fn to_bits(&self) -> u64 {
+        self.0
+    }
+
+    fn tagged(tag: u64, payload: u64) -> Self {
+        PackedVal(BOXED | tag << TAG_SHIFT | payload)
+    }
+
+    fn big_integer(&self) -> Option<*mut i64> {
+        // a single comparison, as this is checked whenever a value is dropped
+        let top = (BOXED | BIG_INTEGER << TAG_SHIFT) >> TAG_SHIFT;
+        (self.0 >> TAG_SHIFT == top).then_some((self.0 & PAYLOAD) as *mut i64)
+    }
+}
+
+fn pointer<T>(ptr: *mut T) -> u64 {
+    let address = ptr as u64;
+    assert_eq!(
+        address & !PAYLOAD,
+        0,
+        "pointer {ptr:?} doesn't fit in 48 bits"
+    );
+    address
+}
+
+impl From<SmallVal> for PackedVal {
+    fn from(value: SmallVal) -> Self {
+        PackedVal::pack(&value)
+    }
+}
+
+impl From<&PackedVal> for SmallVal {
+    fn from(value: &PackedVal) -> Self {
+        value.unpack()
+    }
+}
+
+impl Default for PackedVal {
+    fn default() -> Self {
+        Self::tagged(NIL, 0)
+    }
+}
+
+impl Clone for PackedVal {
+    fn clone(&self) -> Self {
+        match self.big_integer() {
+            // each value owns its box
+            Some(_) => PackedVal::pack(&self.unpack()),
+            None => PackedVal(self.0),
+        }
+    }
+}
+
+impl Drop for PackedVal {
+    fn drop(&mut self) {
+        if let Some(boxed) = self.big_integer() {
+            free(boxed);
+        }
+    }
+}
+
+#[cold]
+fn free(boxed: *mut i64) {
+    drop(unsafe { Box::from_raw(boxed) });
+}
+
+impl PartialEq for PackedVal {
+    fn eq(&self, other: &Self) -> bool {
+        self.unpack() == other.unpack()
+    }
+}
+
+impl Debug for PackedVal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.unpack())
+    }
+}
+
+impl Display for PackedVal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.unpack())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::ObjectValue;
+
+    fn round_trip(value: SmallVal) -> SmallVal {
+        PackedVal::pack(&value).unpack()
+    }
+
+    #[test]
+    fn is_8_bytes() {
+        assert_eq!(std::mem::size_of::<PackedVal>(), 8);
+        assert_eq!(std::mem::size_of::<SmallVal>(), 16);
+    }
+
+    #[test]
+    fn matches_the_enum() {
+        let mut vm = crate::vm::VM::default();
+        let string = unsafe { vm.allocate_value(ObjectValue::String("s".to_string())) };
+        let values = [
+            SmallVal::Nil,
+            SmallVal::Bool(true),
+            SmallVal::Bool(false),
+            SmallVal::Integer(0),
+            SmallVal::Integer(1),
+            SmallVal::Integer(-1),
+            SmallVal::Integer(MAX_INTEGER),
+            SmallVal::Integer(MIN_INTEGER),
+            SmallVal::Integer(MAX_INTEGER + 1),
+            SmallVal::Integer(MIN_INTEGER - 1),
+            SmallVal::Integer(i64::MAX),
+            SmallVal::Integer(i64::MIN),
+            SmallVal::Float(0.0),
+            SmallVal::Float(1.5),
+            SmallVal::Float(-1.5),
+            SmallVal::Float(f64::INFINITY),
+            SmallVal::Float(f64::NEG_INFINITY),
+            SmallVal::Float(f64::MAX),
+            SmallVal::Float(f64::MIN_POSITIVE),
+            SmallVal::Float(5e-324),
+            SmallVal::ObjectPtr(string),
+            SmallVal::Quote(string),
+            SmallVal::ObjectPtr(std::ptr::null_mut()),
+        ];
+        for value in values {
+            assert_eq!(round_trip(value.clone()), value);
+        }
+
+        let SmallVal::Float(zero) = round_trip(SmallVal::Float(-0.0)) else {
+            panic!("expected a float");
+        };
+        assert_eq!(zero.to_bits(), (-0.0f64).to_bits());
+        for nan in [f64::NAN, -f64::NAN, f64::from_bits(0xFFFF_0000_0000_0001)] {
+            let SmallVal::Float(unpacked) = round_trip(SmallVal::Float(nan)) else {
+                panic!("expected a float");
+            };
+            assert!(unpacked.is_nan());
+        }
+    }
+
+    #[test]
+    fn big_integers_are_owned() {
+        let packed = PackedVal::pack(&SmallVal::Integer(i64::MAX));
+        let copy = packed.clone();
+        drop(packed);
+        assert_eq!(copy.unpack(), SmallVal::Integer(i64::MAX));
+        assert_eq!(copy, PackedVal::pack(&SmallVal::Integer(i64::MAX)));
+    }
+}
@@ -7,7 +7,11 @@ pub struct Ast {
     pub expressions: Vec<SrcSexpr>,
 }
 
-fn parse_list(rest_tokens: &[Token]) -> Result<(Vec<SrcSexpr>, usize), String> {
+// `lines` runs parallel to the tokens, when source locations are being kept
+fn parse_list(
+    rest_tokens: &[Token],
+    lines: Option<&[usize]>,
+) -> Result<(Vec<SrcSexpr>, usize), String> {
     let mut list = vec![];
 
     let mut i = 0;
@@ -22,7 +26,7 @@ fn parse_list(rest_tokens: &[Token]) -> Result<(Vec<SrcSexpr>, usize), String> {
             // list.push(SrcSexpr::CommaUnquote(Box::new(s_expr)));
             // i += i_diff + 1;
         } else {
-            let (s_expr, i_diff) = parse_sexpr(&rest_tokens[i..])?;
+            let (s_expr, i_diff) = parse_located_sexpr(&rest_tokens[i..], lines.map(|l| &l[i..]))?;
             list.push(s_expr);
             i += i_diff;
         }
@@ -32,12 +36,23 @@ fn parse_list(rest_tokens: &[Token]) -> Result<(Vec<SrcSexpr>, usize), String> {
 }
 
 pub fn parse_sexpr(rest_tokens: &[Token]) -> Result<(SrcSexpr, usize), String> {
+    parse_located_sexpr(rest_tokens, None)
+}
+
+fn parse_located_sexpr(
+    rest_tokens: &[Token],
+    lines: Option<&[usize]>,
+) -> Result<(SrcSexpr, usize), String> {
     let first = &rest_tokens[0];
 
     match first {
         Token::Parenthesis(LR::Left) => {
-            let (sexprs, i_diff) = parse_list(&rest_tokens[1..])?;
+            let (sexprs, i_diff) = parse_list(&rest_tokens[1..], lines.map(|l| &l[1..]))?;
             let list = SrcSexpr::List(sexprs);
+            let list = match lines {
+                Some(lines) => SrcSexpr::Located(lines[0], Box::new(list)),
+                None => list,
+            };
             Ok((list, i_diff + 1))
         }
         Token::Literal(lit) => {
@@ -62,6 +77,7 @@ pub fn parse_sexpr(rest_tokens: &[Token]) -> Result<(SrcSexpr, usize), String> {
         Token::Backtick => {
             panic!()
         }
+        // quoted data is never evaluated, so it doesn't need locations
         Token::Apostrophe => {
             parse_sexpr(&rest_tokens[1..]).map(|op| {
                 (
@@ -74,11 +90,20 @@ pub fn parse_sexpr(rest_tokens: &[Token]) -> Result<(SrcSexpr, usize), String> {
 }
 
 pub fn parse(tokens: Vec<Token>) -> Result<Ast, String> {
+    parse_tokens(tokens, None)
+}
+
+/// Like `parse`, but wraps every list in a `SrcSexpr::Located` with the line it starts on
+pub fn parse_with_lines(tokens: Vec<Token>, lines: Vec<usize>) -> Result<Ast, String> {
+    parse_tokens(tokens, Some(&lines))
+}
+
+fn parse_tokens(tokens: Vec<Token>, lines: Option<&[usize]>) -> Result<Ast, String> {
     let mut expressions = vec![];
     let mut i = 0;
     let mut rest = &tokens[i..];
     loop {
-        let (s_expr, i_diff) = parse_sexpr(rest)?;
+        let (s_expr, i_diff) = parse_located_sexpr(rest, lines.map(|l| &l[i..]))?;
         expressions.push(s_expr);
         i += i_diff;
         rest = &tokens[i..];
@@ -0,0 +1,424 @@
+//! The form the VM runs bytecode in. When a chunk is linked, its bytes are decoded once into
+//! an array of `Instr`s with their operands resolved, so the dispatch loop doesn't decode
+//! opcodes or read operands byte by byte, and jumps, globals and captures are already worked
+//! out. Common runs of instructions are fused into superinstructions along the way.
+
+use crate::disassembler::{decode, Operands};
+use crate::vm::{BytecodeChunk, CallCaches, CaptureType, ConstantValue, Op};
+
+/// An instruction with its operands resolved. Jumps are relative to the jumping instruction,
+/// counted in instructions rather than bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    Constant(u8),
+    /// one of the arithmetic or comparison ops
+    Operator(Op),
+    Jump(i32),
+    CondJump(i32),
+    /// `cache` is the call site's index in `Decoded::call_caches`
+    FuncCall {
+        arity: u8,
+        cache: u32,
+    },
+    Return,
+    /// a global slot, see `Globals`
+    DeclareGlobal(u32),
+    ReferenceGlobal(u32),
+    ReferenceLocal(u8),
+    Print,
+    Define(u8),
+    ReferenceUpvalue(u8),
+    SetUpvalue(u8),
+    /// the function's constant, and the index of its first capture in `Decoded::captures`
+    Closure {
+        constant: u8,
+        captures: u32,
+    },
+    Pop,
+    CloseUpvalue,
+    SetLocal(u8),
+    /// relative to the handler instruction, like a jump
+    PushHandler(i32),
+    PopHandler,
+    Raise,
+    CallCC,
+    Resume,
+    Yield,
+    DebugEnd,
+    /// `ReferenceLocal a`, `ReferenceLocal b`, then an operator
+    LocalLocalOperator {
+        a: u8,
+        b: u8,
+        op: Op,
+    },
+    /// `ReferenceLocal local`, `Constant` of an integer, then an operator
+    LocalIntegerOperator {
+        local: u8,
+        op: Op,
+        integer: i32,
+    },
+    /// `Constant constant` then `FuncCall`
+    ConstantCall {
+        constant: u8,
+        arity: u8,
+        cache: u32,
+    },
+    /// where the bytecode stops making sense, see `Decoded::error`
+    Invalid,
+}
+
+impl Instr {
+    /// The opcode of the instruction, or of the first instruction of a superinstruction
+    pub fn op(self) -> Op {
+        match self {
+            Instr::Constant(_) | Instr::ConstantCall { .. } => Op::Constant,
+            Instr::Operator(op) => op,
+            Instr::Jump(_) => Op::Jump,
+            Instr::CondJump(_) => Op::CondJump,
+            Instr::FuncCall { .. } => Op::FuncCall,
+            Instr::Return => Op::Return,
+            Instr::DeclareGlobal(_) => Op::DeclareGlobal,
+            Instr::ReferenceGlobal(_) => Op::ReferenceGlobal,
+            Instr::ReferenceLocal(_)
+            | Instr::LocalLocalOperator { .. }
+            | Instr::LocalIntegerOperator { .. } => Op::ReferenceLocal,
+            Instr::Print => Op::Print,
+            Instr::Define(_) => Op::Define,
+            Instr::ReferenceUpvalue(_) => Op::ReferenceUpvalue,
+            Instr::SetUpvalue(_) => Op::SetUpvalue,
+            Instr::Closure { .. } => Op::Closure,
+            Instr::Pop => Op::Pop,
+            Instr::CloseUpvalue => Op::CloseUpvalue,
+            Instr::SetLocal(_) => Op::SetLocal,
+            Instr::PushHandler(_) => Op::PushHandler,
+            Instr::PopHandler => Op::PopHandler,
+            Instr::Raise => Op::Raise,
+            Instr::CallCC => Op::CallCC,
+            Instr::Resume => Op::Resume,
+            Instr::Yield => Op::Yield,
+            Instr::DebugEnd | Instr::Invalid => Op::DebugEnd,
+        }
+    }
+
+    /// The first instruction of a superinstruction, which the instructions after it in the
+    /// array carry on from. Anything else is returned as it is.
+    pub fn unfused(self) -> Instr {
+        match self {
+            Instr::LocalLocalOperator { a: local, .. }
+            | Instr::LocalIntegerOperator { local, .. } => Instr::ReferenceLocal(local),
+            Instr::ConstantCall { constant, .. } => Instr::Constant(constant),
+            instr => instr,
+        }
+    }
+}
+
+/// A chunk's code as `Instr`s, made by `predecode`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Decoded {
+    /// One per instruction in the bytecode, followed by an `Instr::Invalid`.
+    /// The first instruction of a fused run is replaced by the superinstruction, and the
+    /// rest are kept, so jumping into the middle of one still works.
+    pub instructions: Vec<Instr>,
+    /// the bytecode offset of each instruction
+    pub offsets: Vec<usize>,
+    /// what each `Instr::Closure` captures, where from, in order
+    pub captures: Vec<(CaptureType, u8)>,
+    /// the number of `Instr::FuncCall`s, which are given consecutive call caches
+    pub call_sites: usize,
+    /// what each call site called last, see `VM::call_target`
+    pub(crate) call_caches: CallCaches,
+    /// why decoding stopped early, if it did, which is reported when the VM reaches
+    /// the `Instr::Invalid`
+    pub error: Option<String>,
+    /// how often the function's been called, and its machine code once it's hot
+    #[cfg(feature = "jit")]
+    pub(crate) jit: crate::vm::jit::JitState,
+}
+
+impl Decoded {
+    /// the bytecode offset of the instruction that `ip` points to
+    pub fn offset_of(&self, ip: *const Instr) -> usize {
+        let idx =
+            (ip as usize - self.instructions.as_ptr() as usize) / std::mem::size_of::<Instr>();
+        self.offsets[idx]
+    }
+}
+
+/// Decodes `chunk`, which must have been linked, so that its globals have slots.
+/// Decoding stops at the first instruction that's malformed, as checked by `verifier::verify`,
+/// which becomes the `Instr::Invalid`. Jumps that don't land on an instruction go there too.
+pub fn predecode(chunk: &BytecodeChunk) -> Decoded {
+    let mut decoded = Decoded::default();
+
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        match decode(chunk, offset) {
+            Ok(instruction) => {
+                offset = instruction.next;
+                instructions.push(instruction);
+            }
+            Err(e) => {
+                decoded.error = Some(e);
+                break;
+            }
+        }
+    }
+    // where the `Instr::Invalid` is, unless an instruction turns out to be malformed below
+    let mut end_offset = offset;
+    decoded.offsets = instructions.iter().map(|i| i.offset).collect();
+
+    for (idx, instruction) in instructions.iter().enumerate() {
+        let jump = |target: usize| match decoded.offsets.binary_search(&target) {
+            Ok(target_idx) => Ok(target_idx as i32 - idx as i32),
+            // to code that didn't decode
+            Err(_) if decoded.error.is_some() && target >= offset => {
+                Ok((instructions.len() - idx) as i32)
+            }
+            Err(_) => Err(format!(
+                "offset {}: jumps to {target}, which isn't an instruction",
+                instruction.offset
+            )),
+        };
+        let global_slot = |constant: usize| match chunk.global_slots.get(constant) {
+            Some(slot) if *slot != usize::MAX => Ok(*slot as u32),
+            _ => Err(format!(
+                "offset {}: expected constant k{constant} to be the name of a global",
+                instruction.offset
+            )),
+        };
+        let instr = match (instruction.op, &instruction.operands) {
+            (Op::Constant, Operands::Constant(idx)) => Ok(Instr::Constant(*idx as u8)),
+            (Op::DeclareGlobal, Operands::Constant(idx)) => {
+                global_slot(*idx).map(Instr::DeclareGlobal)
+            }
+            (Op::ReferenceGlobal, Operands::Constant(idx)) => {
+                global_slot(*idx).map(Instr::ReferenceGlobal)
+            }
+            (Op::Jump, Operands::Jump(target)) => jump(*target).map(Instr::Jump),
+            (Op::CondJump, Operands::Jump(target)) => jump(*target).map(Instr::CondJump),
+            (Op::PushHandler, Operands::Jump(target)) => jump(*target).map(Instr::PushHandler),
+            (Op::FuncCall, Operands::Byte(arity)) => {
+                decoded.call_sites += 1;
+                Ok(Instr::FuncCall {
+                    arity: *arity,
+                    cache: (decoded.call_sites - 1) as u32,
+                })
+            }
+            (Op::ReferenceLocal, Operands::Byte(slot)) => Ok(Instr::ReferenceLocal(*slot)),
+            (Op::Define, Operands::Byte(slot)) => Ok(Instr::Define(*slot)),
+            (Op::SetLocal, Operands::Byte(slot)) => Ok(Instr::SetLocal(*slot)),
+            (Op::ReferenceUpvalue, Operands::Byte(idx)) => Ok(Instr::ReferenceUpvalue(*idx)),
+            (Op::SetUpvalue, Operands::Byte(idx)) => Ok(Instr::SetUpvalue(*idx)),
+            (Op::Closure, Operands::Closure { constant, captures }) => {
+                let instr = Instr::Closure {
+                    constant: *constant as u8,
+                    captures: decoded.captures.len() as u32,
+                };
+                decoded.captures.extend(captures);
+                Ok(instr)
+            }
+            (Op::Return, _) => Ok(Instr::Return),
+            (Op::Print, _) => Ok(Instr::Print),
+            (Op::Pop, _) => Ok(Instr::Pop),
+            (Op::CloseUpvalue, _) => Ok(Instr::CloseUpvalue),
+            (Op::PopHandler, _) => Ok(Instr::PopHandler),
+            (Op::Raise, _) => Ok(Instr::Raise),
+            (Op::CallCC, _) => Ok(Instr::CallCC),
+            (Op::Resume, _) => Ok(Instr::Resume),
+            (Op::Yield, _) => Ok(Instr::Yield),
+            (Op::DebugEnd, _) => Ok(Instr::DebugEnd),
+            (op, _) if op.operator_name().is_some() => Ok(Instr::Operator(op)),
+            (op, operands) => unreachable!("{op:?} decoded with {operands:?}"),
+        };
+        match instr {
+            Ok(instr) => decoded.instructions.push(instr),
+            Err(e) => {
+                decoded.error = Some(e);
+                end_offset = instruction.offset;
+                break;
+            }
+        }
+    }
+
+    // anything after a malformed instruction is dropped, including its jump targets
+    let end = decoded.instructions.len();
+    decoded.offsets.truncate(end);
+    for (idx, instr) in decoded.instructions.iter_mut().enumerate() {
+        if let Instr::Jump(delta) | Instr::CondJump(delta) | Instr::PushHandler(delta) = instr {
+            *delta = (*delta).min((end - idx) as i32);
+        }
+    }
+    decoded.instructions.push(Instr::Invalid);
+    decoded.offsets.push(end_offset);
+
+    fuse(&mut decoded.instructions, &chunk.constants);
+    decoded.call_caches = CallCaches::new(decoded.call_sites);
+    decoded
+}
+
+/// Replaces the first instruction of each run that has a superinstruction
+fn fuse(instructions: &mut [Instr], constants: &[ConstantValue]) {
+    for idx in 0..instructions.len() {
+        let next = |n: usize| instructions.get(idx + n).copied();
+        let fused = match (instructions[idx], next(1), next(2)) {
+            (
+                Instr::ReferenceLocal(a),
+                Some(Instr::ReferenceLocal(b)),
+                Some(Instr::Operator(op)),
+            ) => Instr::LocalLocalOperator { a, b, op },
+            (
+                Instr::ReferenceLocal(local),
+                Some(Instr::Constant(constant)),
+                Some(Instr::Operator(op)),
+            ) => match constants[constant as usize] {
+                ConstantValue::Integer(integer) if i32::try_from(integer).is_ok() => {
+                    Instr::LocalIntegerOperator {
+                        local,
+                        op,
+                        integer: integer as i32,
+                    }
+                }
+                _ => continue,
+            },
+            (Instr::Constant(constant), Some(Instr::FuncCall { arity, cache }), _) => {
+                Instr::ConstantCall {
+                    constant,
+                    arity,
+                    cache,
+                }
+            }
+            _ => continue,
+        };
+        instructions[idx] = fused;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::compiler::compile;
+
+    fn linked(src: &str) -> BytecodeChunk {
+        let mut chunk = compile(&src.to_string());
+        chunk.global_slots = (0..chunk.constants.len()).collect();
+        chunk
+    }
+
+    #[test]
+    fn resolves_operands() {
+        let chunk = assemble(
+            "
+.const k0 1
+  Constant k0
+  CondJump L6
+  Constant k0
+  Jump L8
+L6:
+  Constant k0
+L8:
+  Pop
+  DebugEnd
+",
+        )
+        .unwrap();
+        let decoded = predecode(&chunk);
+        assert_eq!(
+            decoded.instructions,
+            vec![
+                Instr::Constant(0),
+                Instr::CondJump(3),
+                Instr::Constant(0),
+                Instr::Jump(2),
+                Instr::Constant(0),
+                Instr::Pop,
+                Instr::DebugEnd,
+                Instr::Invalid,
+            ]
+        );
+        assert_eq!(decoded.offsets, vec![0, 2, 4, 6, 8, 10, 11, 12]);
+        assert_eq!(decoded.error, None);
+    }
+
+    #[test]
+    fn fuses_common_runs() {
+        let chunk = linked("(defun (f a b) (+ a b)) (defun (g n) (- n 1)) (g 2)");
+        let decoded = predecode(&chunk);
+        assert!(decoded.instructions.contains(&Instr::ConstantCall {
+            constant: 4,
+            arity: 1,
+            cache: 0
+        }));
+        assert_eq!(decoded.call_sites, 1);
+
+        let f = predecode(functions(&chunk)[0]);
+        assert_eq!(
+            f.instructions[..4],
+            [
+                Instr::LocalLocalOperator {
+                    a: 1,
+                    b: 2,
+                    op: Op::Add
+                },
+                // kept for anything that jumps past the start
+                Instr::ReferenceLocal(2),
+                Instr::Operator(Op::Add),
+                Instr::Return,
+            ]
+        );
+        let g = predecode(functions(&chunk)[1]);
+        assert_eq!(
+            g.instructions[0],
+            Instr::LocalIntegerOperator {
+                local: 1,
+                op: Op::Sub,
+                integer: 1
+            }
+        );
+        for decoded in [decoded, f, g] {
+            for instr in decoded.instructions {
+                assert_eq!(instr.unfused().unfused(), instr.unfused());
+            }
+        }
+    }
+
+    fn functions(chunk: &BytecodeChunk) -> Vec<&BytecodeChunk> {
+        chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                crate::vm::ConstantValue::Object(crate::vm::ConstantObject::Closure(c)) => {
+                    Some(c.f.bytecode())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stops_at_malformed_code() {
+        let mut chunk = BytecodeChunk::new(
+            vec![Op::Jump.into(), 3, Op::Pop.into(), 200, Op::DebugEnd.into()],
+            vec![],
+        );
+        let decoded = predecode(&chunk);
+        assert_eq!(
+            decoded.instructions,
+            vec![Instr::Jump(2), Instr::Pop, Instr::Invalid]
+        );
+        assert_eq!(decoded.offsets, vec![0, 2, 3]);
+        assert_eq!(
+            decoded.error,
+            Some("offset 3: invalid opcode 200".to_string())
+        );
+
+        chunk.code = vec![Op::Jump.into(), 2, Op::DebugEnd.into()];
+        let decoded = predecode(&chunk);
+        assert_eq!(decoded.instructions, vec![Instr::Invalid]);
+        assert_eq!(
+            decoded.error,
+            Some("offset 0: jumps to 3, which isn't an instruction".to_string())
+        );
+    }
+}
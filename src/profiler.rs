@@ -0,0 +1,329 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::trace::{TraceContext, TraceHook};
+
+/// What a script spent in one function, keyed by `Function::name`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    /// instructions run while the function was on the call stack
+    pub inclusive: u64,
+    /// instructions run in the function itself
+    pub exclusive: u64,
+    pub allocations: u64,
+}
+
+struct Activation {
+    name: String,
+    /// the instruction count when it was entered
+    entered_at: u64,
+    /// the length of `Profiler::stack_key` before it was entered
+    key_len: usize,
+}
+
+/// A `TraceHook` that counts calls, instructions and allocations per function.
+/// Call stacks are sampled every `sample_interval` instructions for the folded stacks.
+pub struct Profiler {
+    functions: HashMap<String, FunctionProfile>,
+    stack: Vec<Activation>,
+    /// the shadow stack joined with `;`, the key for folded stacks
+    stack_key: String,
+    /// the number of activations of each function on the stack, for recursion
+    active: HashMap<String, usize>,
+    folded: HashMap<String, u64>,
+    instructions: u64,
+    sample_interval: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    /// Samples every instruction, so the folded stacks are exact
+    pub fn new() -> Self {
+        Profiler::with_sample_interval(1)
+    }
+
+    pub fn with_sample_interval(sample_interval: u64) -> Self {
+        assert!(sample_interval > 0, "the sample interval must be positive");
+        Profiler {
+            functions: HashMap::new(),
+            stack: vec![],
+            stack_key: String::new(),
+            active: HashMap::new(),
+            folded: HashMap::new(),
+            instructions: 0,
+            sample_interval,
+        }
+    }
+
+    /// The total number of instructions run
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Every function seen, the most expensive (inclusively) first
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions = self.functions.clone();
+        // the functions still running haven't had their inclusive counts added yet
+        for activation in self.outermost_activations() {
+            functions.get_mut(&activation.name).unwrap().inclusive +=
+                self.instructions - activation.entered_at;
+        }
+        let mut functions = functions.into_values().collect::<Vec<_>>();
+        functions.sort_by(|a, b| {
+            b.inclusive
+                .cmp(&a.inclusive)
+                .then(b.exclusive.cmp(&a.exclusive))
+                .then(a.name.cmp(&b.name))
+        });
+        functions
+    }
+
+    /// A table of `functions`, for printing
+    pub fn table(&self) -> String {
+        let functions = self.functions();
+        let width = functions
+            .iter()
+            .map(|f| f.name.len())
+            .chain(["function".len()])
+            .max()
+            .unwrap();
+        let mut table = format!(
+            "{:<width$} {:>10} {:>12} {:>12} {:>12}\n",
+            "function", "calls", "inclusive", "exclusive", "allocations"
+        );
+        for f in functions {
+            table.push_str(&format!(
+                "{:<width$} {:>10} {:>12} {:>12} {:>12}\n",
+                f.name, f.calls, f.inclusive, f.exclusive, f.allocations
+            ));
+        }
+        table
+    }
+
+    /// The sampled call stacks in the folded format used by flamegraph tools,
+    /// one `outer;inner count` line per stack
+    pub fn folded(&self) -> String {
+        let mut stacks = self.folded.iter().collect::<Vec<_>>();
+        stacks.sort();
+        stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{stack} {count}\n"))
+            .collect()
+    }
+
+    fn outermost_activations(&self) -> impl Iterator<Item = &Activation> {
+        let mut seen = HashSet::new();
+        self.stack
+            .iter()
+            .filter(move |activation| seen.insert(activation.name.as_str()))
+    }
+
+    fn function(&mut self, name: &str) -> &mut FunctionProfile {
+        if !self.functions.contains_key(name) {
+            let profile = FunctionProfile {
+                name: name.to_string(),
+                ..FunctionProfile::default()
+            };
+            self.functions.insert(name.to_string(), profile);
+        }
+        self.functions.get_mut(name).unwrap()
+    }
+
+    fn push(&mut self, name: &str) {
+        let key_len = self.stack_key.len();
+        if !self.stack.is_empty() {
+            self.stack_key.push(';');
+        }
+        self.stack_key.push_str(name);
+        self.stack.push(Activation {
+            name: name.to_string(),
+            entered_at: self.instructions,
+            key_len,
+        });
+        *self.active.entry(name.to_string()).or_default() += 1;
+    }
+
+    fn pop(&mut self) {
+        let Some(activation) = self.stack.pop() else {
+            return;
+        };
+        self.stack_key.truncate(activation.key_len);
+        let active = self.active.get_mut(&activation.name).unwrap();
+        *active -= 1;
+        // recursive calls are already counted by the outermost one
+        if *active == 0 {
+            let elapsed = self.instructions - activation.entered_at;
+            self.function(&activation.name).inclusive += elapsed;
+        }
+    }
+
+    /// Non-local exits (errors, continuations, switching coroutines) don't return normally,
+    /// so the shadow stack is brought back in line with the VM's before each instruction
+    fn sync(&mut self, context: &TraceContext) {
+        if self.stack.is_empty() {
+            self.function(context.function).calls += 1;
+        }
+        while self.stack.len() > context.call_depth + 1 {
+            self.pop();
+        }
+        while self.stack.len() < context.call_depth + 1 {
+            self.push(context.function);
+        }
+        if self.stack.last().unwrap().name != context.function {
+            self.pop();
+            self.push(context.function);
+        }
+    }
+}
+
+impl TraceHook for Profiler {
+    fn on_dispatch(&mut self, context: &TraceContext) {
+        self.sync(context);
+        self.instructions += 1;
+        self.function(context.function).exclusive += 1;
+        if self.instructions.is_multiple_of(self.sample_interval) {
+            match self.folded.get_mut(self.stack_key.as_str()) {
+                Some(count) => *count += 1,
+                None => {
+                    self.folded.insert(self.stack_key.clone(), 1);
+                }
+            }
+        }
+    }
+
+    fn on_call(&mut self, _context: &TraceContext, callee: &str) {
+        self.function(callee).calls += 1;
+        self.push(callee);
+    }
+
+    fn on_return(&mut self, _context: &TraceContext) {
+        self.pop();
+    }
+
+    fn on_alloc(&mut self, context: &TraceContext, _kind: &'static str, _bytes: usize) {
+        self.function(context.function).allocations += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{compiler::compile, vm::VM};
+
+    fn profile(src: &str) -> Profiler {
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let mut vm = VM::default();
+        vm.set_trace_hook(Some(Box::new(profiler.clone())));
+        vm.run(compile(&src.to_string()));
+        drop(vm);
+        Rc::try_unwrap(profiler).ok().unwrap().into_inner()
+    }
+
+    fn by_name(profiler: &Profiler, name: &str) -> FunctionProfile {
+        profiler
+            .functions()
+            .into_iter()
+            .find(|f| f.name == name)
+            .unwrap()
+    }
+
+    const NESTED: &str = "\
+(defun (inner x) (+ x 1))
+(defun (outer x) (inner (inner x)))
+(outer 1)
+(outer 2)";
+
+    #[test]
+    fn counts_calls_and_instructions() {
+        let profiler = profile(NESTED);
+
+        let inner = by_name(&profiler, "inner");
+        assert_eq!(inner.calls, 4);
+        // ReferenceLocal, Constant, Add, Return
+        assert_eq!(inner.exclusive, 4 * 4);
+        assert_eq!(inner.inclusive, inner.exclusive);
+
+        let outer = by_name(&profiler, "outer");
+        assert_eq!(outer.calls, 2);
+        assert_eq!(outer.inclusive, outer.exclusive + inner.inclusive);
+
+        let top = by_name(&profiler, "<top level>");
+        assert_eq!(top.inclusive, profiler.instructions());
+        let exclusive: u64 = profiler.functions().iter().map(|f| f.exclusive).sum();
+        assert_eq!(exclusive, profiler.instructions());
+
+        assert_eq!(profiler.functions()[0].name, "<top level>");
+    }
+
+    #[test]
+    fn recursion_is_counted_once_inclusively() {
+        let profiler = profile(
+            "\
+(defun (count n) (if (= n 0) 0 (count (- n 1))))
+(count 10)",
+        );
+        let count = by_name(&profiler, "count");
+        assert_eq!(count.calls, 11);
+        assert_eq!(count.inclusive, count.exclusive);
+    }
+
+    #[test]
+    fn counts_allocations() {
+        let pair = "(defun (pair) (cons 1 2))\n";
+        let once = by_name(&profile(&format!("{pair}(pair)")), "pair").allocations;
+        let twice = by_name(&profile(&format!("{pair}(pair)\n(pair)")), "pair").allocations;
+        assert!(once > 0);
+        assert_eq!(twice, 2 * once);
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let profiler = profile(NESTED);
+        let folded = profiler.folded();
+        let lines = folded.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("<top level> "));
+        assert!(lines[1].starts_with("<top level>;outer "));
+        assert_eq!(lines[2], "<top level>;outer;inner 16");
+
+        let total: u64 = lines
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, profiler.instructions());
+    }
+
+    #[test]
+    fn errors_unwind_the_shadow_stack() {
+        let profiler = profile(
+            "\
+(defun (fail) (raise 'oops))
+(try (fail) (catch e 0))
+(defun (after) 1)
+(after)",
+        );
+        assert!(profiler
+            .folded()
+            .lines()
+            .any(|line| line.starts_with("<top level>;after ")));
+        assert!(!profiler.folded().contains("fail;"));
+    }
+
+    #[test]
+    fn table_is_sorted() {
+        let table = profile(NESTED).table();
+        let names = table
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["function", "<top", "outer", "inner"]);
+    }
+}
@@ -33,6 +33,7 @@ pub enum SrcSexpr {
     Symbol(String), // +, -, *, /, foo
     List(Vec<SrcSexpr>), // (+ 2 3)
     Quote(Box<SrcSexpr>), // '(+ 2 3), 'foo
+    Located(usize, Box<SrcSexpr>), // a list and the line it starts on
 }
 
 impl SrcSexpr {
@@ -45,6 +46,25 @@ impl SrcSexpr {
             SrcSexpr::Int(i) => LispValue::Int(*i),
             SrcSexpr::Float(f) => LispValue::Float(*f),
             SrcSexpr::Quote(sexpr) => LispValue::Quote(Box::new(sexpr.to_sexpr())),
+            SrcSexpr::Located(_, sexpr) => sexpr.to_sexpr(),
+        }
+    }
+
+    pub fn unlocated(&self) -> &SrcSexpr {
+        match self {
+            SrcSexpr::Located(_, sexpr) => sexpr.unlocated(),
+            sexpr => sexpr,
+        }
+    }
+
+    pub fn without_locations(&self) -> SrcSexpr {
+        match self {
+            SrcSexpr::List(sexprs) => {
+                SrcSexpr::List(sexprs.iter().map(|s| s.without_locations()).collect())
+            }
+            SrcSexpr::Quote(sexpr) => SrcSexpr::Quote(Box::new(sexpr.without_locations())),
+            SrcSexpr::Located(_, sexpr) => sexpr.without_locations(),
+            sexpr => sexpr.clone(),
         }
     }
 }
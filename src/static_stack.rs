@@ -1,8 +1,10 @@
-use std::{array::from_fn, fmt::Display};
+use std::fmt::Display;
 
-#[derive(Debug)]
+/// A stack that never reallocates on its own, so pointers into it stay valid.
+/// It holds `MAX` values unless it's explicitly grown.
+#[derive(Debug, Clone)]
 pub struct StaticStack<T, const MAX: usize> {
-    stack: [T; MAX],
+    stack: Vec<T>,
     pub ptr: i32, // needs to be i to allow -1
 }
 
@@ -49,13 +51,38 @@ impl<T: Default /* + Copy */ + Clone, const MAX: usize> StaticStack<T, MAX> {
     }
 
     pub fn new() -> Self {
+        Self::with_capacity(MAX)
+    }
+
+    /// A stack that holds `capacity` values rather than `MAX`, until it's grown
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
-            stack: from_fn(|_i| Default::default()), // [Default::default(); MAX],
+            stack: vec![Default::default(); capacity],
             ptr: -1,
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Moves the values to a bigger allocation, invalidating pointers into the stack.
+    /// Returns the old base pointer, so that callers can fix up their pointers.
+    pub fn grow(&mut self, capacity: usize) -> *const T {
+        let old_base = self.stack.as_ptr();
+        if capacity > self.stack.len() {
+            let mut stack = Vec::with_capacity(capacity);
+            stack.append(&mut self.stack);
+            stack.resize(capacity, Default::default());
+            self.stack = stack;
+        }
+        old_base
+    }
+
     pub fn push(&mut self, value: T) {
+        if self.len() == self.capacity() {
+            panic!("stack overflow: the stack holds {} values", self.capacity());
+        }
         self.ptr += 1;
         self.stack[self.ptr as usize] = value;
     }
@@ -64,8 +91,7 @@ impl<T: Default /* + Copy */ + Clone, const MAX: usize> StaticStack<T, MAX> {
         if self.ptr == -1 {
             return None;
         }
-        let value = self.stack[self.ptr as usize].clone();
-        self.stack[self.ptr as usize] = Default::default();
+        let value = std::mem::take(&mut self.stack[self.ptr as usize]);
         self.ptr -= 1;
         Some(value)
     }
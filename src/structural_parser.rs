@@ -35,6 +35,10 @@ fn structure_sexpr(sexpr: &SrcSexpr, in_function: bool, discarding: bool) -> Exp
             }
             return regular_form;
         }
+        SrcSexpr::Located(line, sexpr) => Expression::Located {
+            line: *line,
+            expr: Box::new(structure_sexpr(sexpr, in_function, discarding)),
+        },
         // self-eval
         v => {
             let x = Expression::SrcSexpr(v.clone());
@@ -70,7 +74,8 @@ fn map_to_special_form(
                 if rest.len() != 1 {
                     panic!("quote expects 1 argument")
                 }
-                let expr = Expression::SrcSexpr(SrcSexpr::Quote(Box::new(rest[0].clone())));
+                let expr =
+                    Expression::SrcSexpr(SrcSexpr::Quote(Box::new(rest[0].without_locations())));
                 return Some(optionally_wrap_discard(expr, discarding));
             }
             "define" => {
@@ -78,12 +83,19 @@ fn map_to_special_form(
                     panic!("define expects 2 arguments, got {:?}", rest)
                 }
 
-                let name = match &rest[0] {
+                let name = match rest[0].unlocated() {
                     SrcSexpr::Symbol(s) => s.clone(),
                     _ => panic!("define expects symbol as first argument"),
                 };
 
-                let value = Box::new(structure_sexpr(&rest[1], in_function, false));
+                let mut value = Box::new(structure_sexpr(&rest[1], in_function, false));
+
+                // name functions after what they're defined as, for error messages
+                if let Expression::FunctionLiteral(function) = unlocated_mut(value.as_mut()) {
+                    if function.name.is_none() {
+                        function.name = Some(name.clone());
+                    }
+                }
 
                 // ignore discarding as define doesn't evaluate to a stackval
                 return Some(if in_function {
@@ -113,7 +125,7 @@ fn map_to_special_form(
             "defun" => {
                 let (signature, body_sexprs) = rest.split_first().unwrap();
 
-                let (name, parameters) = match signature {
+                let (name, parameters) = match signature.unlocated() {
                     SrcSexpr::List(arg_sexprs) => {
                         let name = match &arg_sexprs[0] {
                             SrcSexpr::Symbol(s) => s.clone(),
@@ -153,7 +165,7 @@ fn map_to_special_form(
             "fn" => {
                 let (parameters, body_sexprs) = rest.split_first().unwrap();
 
-                let parameters = match parameters {
+                let parameters = match parameters.unlocated() {
                     SrcSexpr::List(arg_sexprs) => arg_sexprs
                         .iter()
                         .map(|sexpr| match sexpr {
@@ -173,6 +185,79 @@ fn map_to_special_form(
                 ));
                 return Some(optionally_wrap_discard(function_literal, discarding));
             }
+            "call/cc" => {
+                if rest.len() != 1 {
+                    panic!("call/cc expects 1 argument, got {:?}", rest)
+                }
+                let function = Box::new(structure_sexpr(&rest[0], in_function, false));
+                let expr = Expression::CallCC(function);
+                return Some(optionally_wrap_discard(expr, discarding));
+            }
+            "resume" => {
+                let (coroutine, value) = match rest {
+                    [coroutine] => (coroutine, None),
+                    [coroutine, value] => (coroutine, Some(value)),
+                    _ => panic!("resume expects 1 or 2 arguments, got {:?}", rest),
+                };
+                let expr = Expression::Resume {
+                    coroutine: Box::new(structure_sexpr(coroutine, in_function, false)),
+                    value: value.map(|v| Box::new(structure_sexpr(v, in_function, false))),
+                };
+                return Some(optionally_wrap_discard(expr, discarding));
+            }
+            "yield" => {
+                let value = match rest {
+                    [] => None,
+                    [value] => Some(Box::new(structure_sexpr(value, in_function, false))),
+                    _ => panic!("yield expects 0 or 1 arguments, got {:?}", rest),
+                };
+                let expr = Expression::Yield(value);
+                return Some(optionally_wrap_discard(expr, discarding));
+            }
+            "try" => {
+                let (body, clauses) = match rest.split_first() {
+                    Some(split) => split,
+                    None => panic!("try expects a body"),
+                };
+                let body = Box::new(structure_sexpr(body, in_function, false));
+
+                let mut catch = None;
+                let mut finally = vec![];
+                for clause in clauses {
+                    let clause = match clause.unlocated() {
+                        SrcSexpr::List(clause) if !clause.is_empty() => clause,
+                        got => panic!("expected catch or finally clause, got {:?}", got),
+                    };
+                    match &clause[0] {
+                        SrcSexpr::Symbol(s) if s == "catch" => {
+                            let name = match clause.get(1) {
+                                Some(SrcSexpr::Symbol(name)) => name.clone(),
+                                got => panic!("expected symbol for caught error, got {:?}", got),
+                            };
+                            catch = Some(FunctionExpression::new(
+                                vec![name],
+                                compile_sequential_expressions(&clause[2..]),
+                                Some("catch".to_string()),
+                            ));
+                        }
+                        SrcSexpr::Symbol(s) if s == "finally" => {
+                            // the cleanup is only run for its effects
+                            finally = clause[1..]
+                                .iter()
+                                .map(|s| structure_sexpr(s, in_function, true))
+                                .collect();
+                        }
+                        got => panic!("expected catch or finally clause, got {:?}", got),
+                    }
+                }
+
+                let expr = Expression::Try {
+                    body,
+                    catch,
+                    finally,
+                };
+                return Some(optionally_wrap_discard(expr, discarding));
+            }
             _ => {}
         }
     }
@@ -187,6 +272,14 @@ fn compile_sequential_expressions(sexprs: &[SrcSexpr]) -> Vec<Expression> {
         .collect()
 }
 
+/// see through any source locations wrapping `expr`
+fn unlocated_mut(expr: &mut Expression) -> &mut Expression {
+    match expr {
+        Expression::Located { expr, .. } => unlocated_mut(expr),
+        expr => expr,
+    }
+}
+
 fn optionally_wrap_discard(expr: Expression, discarding: bool) -> Expression {
     if discarding {
         return Expression::Discard(Box::new(expr));
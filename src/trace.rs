@@ -0,0 +1,250 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use crate::vm::{BytecodeChunk, Op};
+
+/// Where the VM was when an event was traced.
+/// For events other than dispatch, this is the instruction that was dispatched last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext<'a> {
+    pub op: Op,
+    /// the offset of the instruction in its function's bytecode
+    pub offset: usize,
+    /// the number of values on the stack
+    pub stack_depth: usize,
+    /// the number of call frames
+    pub call_depth: usize,
+    /// the name of the running function, or `<top level>`
+    pub function: &'a str,
+    /// the running function's bytecode, for looking up source lines
+    pub chunk: &'a BytecodeChunk,
+}
+
+/// Callbacks for watching a VM run, see `VM::set_trace_hook`.
+/// Every callback does nothing by default.
+pub trait TraceHook {
+    /// before each instruction is run
+    fn on_dispatch(&mut self, _context: &TraceContext) {}
+
+    /// a closure or builtin named `callee` is called, from the function in the context
+    fn on_call(&mut self, _context: &TraceContext, _callee: &str) {}
+
+    /// the function in the context returns.
+    /// Builtins return from the caller's context, named after the builtin and one call deeper.
+    fn on_return(&mut self, _context: &TraceContext) {}
+
+    /// a `CondJump` jumps to the `then` branch of an `if` (`taken`), or falls through to `else`
+    fn on_branch(&mut self, _context: &TraceContext, _taken: bool) {}
+
+    /// an object of `kind` (see `ObjectValue::kind`) is allocated
+    fn on_alloc(&mut self, _context: &TraceContext, _kind: &'static str, _bytes: usize) {}
+
+    /// a garbage collection freed `freed` objects, leaving `live`
+    fn on_gc(&mut self, _context: &TraceContext, _freed: usize, _live: usize) {}
+}
+
+/// Lets the embedder keep a handle on a hook that's been given to the VM
+impl<T: TraceHook> TraceHook for Rc<RefCell<T>> {
+    fn on_dispatch(&mut self, context: &TraceContext) {
+        self.borrow_mut().on_dispatch(context)
+    }
+
+    fn on_call(&mut self, context: &TraceContext, callee: &str) {
+        self.borrow_mut().on_call(context, callee)
+    }
+
+    fn on_return(&mut self, context: &TraceContext) {
+        self.borrow_mut().on_return(context)
+    }
+
+    fn on_branch(&mut self, context: &TraceContext, taken: bool) {
+        self.borrow_mut().on_branch(context, taken)
+    }
+
+    fn on_alloc(&mut self, context: &TraceContext, kind: &'static str, bytes: usize) {
+        self.borrow_mut().on_alloc(context, kind, bytes)
+    }
+
+    fn on_gc(&mut self, context: &TraceContext, freed: usize, live: usize) {
+        self.borrow_mut().on_gc(context, freed, live)
+    }
+}
+
+/// Writes a line for every event, indented by call depth
+pub struct Tracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W) -> Self {
+        Tracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn line(&mut self, context: &TraceContext, message: String) {
+        let indent = "  ".repeat(context.call_depth);
+        writeln!(self.out, "{indent}{message}").expect("failed to write trace");
+    }
+}
+
+impl Tracer<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        Tracer::new(std::io::stdout())
+    }
+}
+
+impl<W: Write> TraceHook for Tracer<W> {
+    fn on_dispatch(&mut self, context: &TraceContext) {
+        let message = format!(
+            "{} {:04} {:?} (stack {})",
+            context.function, context.offset, context.op, context.stack_depth
+        );
+        self.line(context, message);
+    }
+
+    fn on_call(&mut self, context: &TraceContext, callee: &str) {
+        self.line(context, format!("call {callee}"));
+    }
+
+    fn on_return(&mut self, context: &TraceContext) {
+        self.line(context, format!("return from {}", context.function));
+    }
+
+    fn on_branch(&mut self, context: &TraceContext, taken: bool) {
+        let branch = if taken { "then" } else { "else" };
+        self.line(context, format!("branch to {branch}"));
+    }
+
+    fn on_alloc(&mut self, context: &TraceContext, kind: &'static str, bytes: usize) {
+        self.line(context, format!("alloc {kind} ({bytes} bytes)"));
+    }
+
+    fn on_gc(&mut self, context: &TraceContext, freed: usize, live: usize) {
+        self.line(context, format!("gc freed {freed} objects, {live} live"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::VM};
+
+    #[derive(Default)]
+    struct Recorder {
+        dispatched: usize,
+        events: Vec<String>,
+        allocations: usize,
+        collections: usize,
+    }
+
+    impl TraceHook for Recorder {
+        fn on_dispatch(&mut self, _context: &TraceContext) {
+            self.dispatched += 1;
+        }
+
+        fn on_call(&mut self, context: &TraceContext, callee: &str) {
+            self.events.push(format!(
+                "{} calls {callee} with {:?}",
+                context.function, context.op
+            ));
+        }
+
+        fn on_return(&mut self, context: &TraceContext) {
+            self.events.push(format!("{} returns", context.function));
+        }
+
+        fn on_alloc(&mut self, _context: &TraceContext, _kind: &'static str, _bytes: usize) {
+            self.allocations += 1;
+        }
+
+        fn on_gc(&mut self, _context: &TraceContext, _freed: usize, _live: usize) {
+            self.collections += 1;
+        }
+    }
+
+    fn trace(src: &str) -> Recorder {
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let mut vm = VM::default();
+        vm.set_trace_hook(Some(Box::new(recorder.clone())));
+        vm.run(compile(&src.to_string()));
+        drop(vm);
+        Rc::try_unwrap(recorder).ok().unwrap().into_inner()
+    }
+
+    #[test]
+    fn traces_calls_and_returns() {
+        let recorder = trace(
+            "\
+(defun (inner x) (+ x 1))
+(defun (outer x) (inner x))
+(outer 1)",
+        );
+        assert_eq!(
+            recorder.events,
+            vec![
+                "<top level> calls outer with FuncCall",
+                "outer calls inner with FuncCall",
+                "inner returns",
+                "outer returns",
+            ]
+        );
+    }
+
+    #[test]
+    fn traces_builtin_calls() {
+        let recorder = trace("(defun (pair x) (cons x x))\n(car (pair 1))");
+        assert_eq!(
+            recorder.events,
+            vec![
+                "<top level> calls pair with FuncCall",
+                "pair calls cons with FuncCall",
+                "cons returns",
+                "pair returns",
+                "<top level> calls car with FuncCall",
+                "car returns",
+            ]
+        );
+    }
+
+    #[test]
+    fn traces_every_instruction() {
+        // Constant, Constant, Add, Pop, DebugEnd
+        assert_eq!(trace("(+ 1 2)").dispatched, 5);
+    }
+
+    #[test]
+    fn traces_allocations_and_collections() {
+        let recorder = trace(
+            r#"
+(define garbage (fn (n)
+    (cons 1 2)
+    (if (= n 0) 0 (garbage (- n 1)))))
+(garbage 2000)
+"#,
+        );
+        assert!(recorder.allocations >= 2000);
+        assert!(recorder.collections > 0);
+    }
+
+    #[test]
+    fn tracer_writes_a_readable_log() {
+        let mut vm = VM::default();
+        let tracer = Rc::new(RefCell::new(Tracer::new(vec![])));
+        vm.set_trace_hook(Some(Box::new(tracer.clone())));
+        vm.run(compile(&"(defun (f) (cons 1 2))\n(f)".to_string()));
+
+        let log = String::from_utf8(tracer.borrow().out.clone()).unwrap();
+        let expected = "\
+<top level> 0000 Closure (stack 0)
+alloc closure";
+        assert!(log.starts_with(expected), "{log}");
+        assert!(log.contains("\ncall f\n"), "{log}");
+        assert!(
+            log.contains("\n  f 0000 ReferenceGlobal (stack 1)\n"),
+            "{log}"
+        );
+        assert!(log.contains("\n  return from f\n"), "{log}");
+    }
+}
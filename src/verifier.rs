@@ -0,0 +1,462 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    disassembler::{decode, Instruction, Operands},
+    vm::{BytecodeChunk, CaptureType, ConstantObject, ConstantValue, Op},
+};
+
+/// What's known about the function being verified
+struct Frame<'a> {
+    name: &'a str,
+    /// `None` for the top level, which has no call frame for locals
+    num_slots: Option<usize>,
+    num_upvalues: usize,
+}
+
+/// The stack at an instruction, which has to be the same on every path to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    /// values on the stack, above the function's locals
+    depth: usize,
+    /// handlers pushed by the function and not popped yet
+    handlers: usize,
+}
+
+/// Checks that bytecode is safe for the VM to run, which trusts it completely:
+/// - every instruction decodes, with its constants in the pool and of the right kind
+/// - jumps land on the start of an instruction, and no path runs off the end of the code
+/// - the stack has the same depth on every path to an instruction, never underflows,
+///   and holds exactly the return value at a `Return`
+/// - handlers are popped by the function that pushed them
+/// - local and upvalue indices are in range for the function
+///
+/// Functions in the constant pool are verified too. Run this before `VM::run` on bytecode
+/// that didn't come from the compiler, or use `VM::run_untrusted`.
+pub fn verify(chunk: &BytecodeChunk) -> Result<(), String> {
+    let top_level = Frame {
+        name: "<top level>",
+        num_slots: None,
+        num_upvalues: 0,
+    };
+    verify_function(&top_level, chunk)
+}
+
+fn verify_function(frame: &Frame, chunk: &BytecodeChunk) -> Result<(), String> {
+    let error =
+        |offset: usize, message: String| format!("in {}, offset {offset}: {message}", frame.name);
+
+    let mut instructions = HashMap::new();
+    // the instruction before each one in the code, for finding what pushed a handler's catcher
+    let mut previous = HashMap::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let instruction = decode(chunk, offset).map_err(|e| format!("in {}, {e}", frame.name))?;
+        check_operands(frame, chunk, &instruction).map_err(|e| error(offset, e))?;
+        if instruction.next < chunk.code.len() {
+            previous.insert(instruction.next, offset);
+        }
+        offset = instruction.next;
+        instructions.insert(instruction.offset, instruction);
+    }
+
+    let mut states: HashMap<usize, State> = HashMap::new();
+    let mut worklist = BTreeSet::new();
+    let reach = |states: &mut HashMap<usize, State>,
+                 worklist: &mut BTreeSet<usize>,
+                 from: usize,
+                 to: usize,
+                 state: State| {
+        if !instructions.contains_key(&to) {
+            let message = if to >= chunk.code.len() {
+                format!("jumps to {to}, past the end of the code")
+            } else {
+                format!("jumps to {to}, which isn't the start of an instruction")
+            };
+            return Err(error(from, message));
+        }
+        match states.get(&to) {
+            None => {
+                states.insert(to, state);
+                worklist.insert(to);
+                Ok(())
+            }
+            Some(existing) if *existing == state => Ok(()),
+            Some(existing) => Err(error(
+                to,
+                format!(
+                    "reached with {} values and {} handlers, but also with {} values and {} handlers",
+                    existing.depth, existing.handlers, state.depth, state.handlers
+                ),
+            )),
+        }
+    };
+
+    if chunk.code.is_empty() {
+        return Err(error(0, "the code is empty".to_string()));
+    }
+    reach(
+        &mut states,
+        &mut worklist,
+        0,
+        0,
+        State {
+            depth: 0,
+            handlers: 0,
+        },
+    )?;
+    while let Some(offset) = worklist.pop_first() {
+        let instruction = &instructions[&offset];
+        let state = states[&offset];
+        let (pops, pushes) = stack_effect(instruction);
+        if state.depth < pops {
+            return Err(error(
+                offset,
+                format!(
+                    "{:?} pops {pops} values, but the stack only has {}",
+                    instruction.op, state.depth
+                ),
+            ));
+        }
+        let after = State {
+            depth: state.depth - pops + pushes,
+            ..state
+        };
+
+        let mut successors = vec![];
+        match (instruction.op, &instruction.operands) {
+            (Op::Jump, Operands::Jump(target)) => successors.push((*target, after)),
+            (Op::CondJump, Operands::Jump(target)) => {
+                successors.push((*target, after));
+                successors.push((instruction.next, after));
+            }
+            (Op::PushHandler, Operands::Jump(landing)) => {
+                // the VM unwinds to the depth after the catcher was popped, then pushes the
+                // catcher (unless it's nil) and the error
+                let catcher = match catcher_is_nil(chunk, &instructions, &previous, offset) {
+                    Some(true) => 0,
+                    Some(false) => 1,
+                    None => {
+                        let message = "the handler's catcher must be pushed just before it by \
+                                       a Constant or a Closure"
+                            .to_string();
+                        return Err(error(offset, message));
+                    }
+                };
+                let landing_state = State {
+                    depth: after.depth + catcher + 1,
+                    handlers: state.handlers,
+                };
+                successors.push((*landing, landing_state));
+                let handlers = state.handlers + 1;
+                successors.push((instruction.next, State { handlers, ..after }));
+            }
+            (Op::PopHandler, _) => {
+                let Some(handlers) = state.handlers.checked_sub(1) else {
+                    return Err(error(offset, "PopHandler without a handler".to_string()));
+                };
+                successors.push((instruction.next, State { handlers, ..after }));
+            }
+            (Op::Return, _) => {
+                if frame.num_slots.is_none() {
+                    return Err(error(offset, "Return at the top level".to_string()));
+                }
+                if state.depth != 1 {
+                    let message = format!(
+                        "Return with {} values on the stack, expected just the return value",
+                        state.depth
+                    );
+                    return Err(error(offset, message));
+                }
+                if state.handlers != 0 {
+                    let message = format!("Return with {} handlers still pushed", state.handlers);
+                    return Err(error(offset, message));
+                }
+            }
+            (Op::DebugEnd, _) => {
+                if frame.num_slots.is_some() {
+                    return Err(error(offset, "DebugEnd inside a function".to_string()));
+                }
+            }
+            (Op::Raise, _) => {}
+            _ => successors.push((instruction.next, after)),
+        }
+
+        for (to, state) in successors {
+            if to >= chunk.code.len() && instruction.next == to {
+                return Err(error(offset, "runs off the end of the code".to_string()));
+            }
+            reach(&mut states, &mut worklist, offset, to, state)?;
+        }
+    }
+
+    for constant in chunk.constants.iter() {
+        if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+            let f = &closure.f;
+            let frame = Frame {
+                name: &f.name,
+                num_slots: Some(f.arity + f.num_locals()),
+                num_upvalues: closure.num_upvalues,
+            };
+            verify_function(&frame, f.bytecode())?;
+        }
+    }
+    Ok(())
+}
+
+/// the number of values an instruction pops, and then pushes
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    match (instruction.op, &instruction.operands) {
+        (Op::FuncCall, Operands::Byte(arity)) => (*arity as usize + 1, 1),
+        (Op::Constant | Op::ReferenceGlobal | Op::ReferenceLocal | Op::ReferenceUpvalue, _) => {
+            (0, 1)
+        }
+        (Op::Closure, _) => (0, 1),
+        (
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Equal | Op::GT | Op::LT | Op::GTE | Op::LTE,
+            _,
+        ) => (2, 1),
+        (Op::Resume, _) => (2, 1),
+        (Op::CallCC | Op::Yield, _) => (1, 1),
+        (
+            Op::Pop
+            | Op::Print
+            | Op::DeclareGlobal
+            | Op::Define
+            | Op::SetLocal
+            | Op::SetUpvalue
+            | Op::CondJump
+            | Op::PushHandler
+            | Op::Raise,
+            _,
+        ) => (1, 0),
+        (Op::Return, _) => (1, 0),
+        _ => (0, 0),
+    }
+}
+
+/// Checks what the stack-depth analysis doesn't: the kinds of constants, and indices into the
+/// function's locals and upvalues
+fn check_operands(
+    frame: &Frame,
+    chunk: &BytecodeChunk,
+    instruction: &Instruction,
+) -> Result<(), String> {
+    let op = instruction.op;
+    let check_slot = |slot: usize| match frame.num_slots {
+        None => Err(format!("{op:?} at the top level, which has no locals")),
+        Some(num_slots) if slot > num_slots => Err(format!(
+            "local slot {slot} is out of range, the function has {num_slots}"
+        )),
+        Some(_) => Ok(()),
+    };
+    let check_upvalue = |idx: usize| {
+        if idx >= frame.num_upvalues {
+            return Err(format!(
+                "upvalue {idx} is out of range, the function has {}",
+                frame.num_upvalues
+            ));
+        }
+        Ok(())
+    };
+
+    match (op, &instruction.operands) {
+        (Op::CloseUpvalue, _) => Err("CloseUpvalue isn't supported by the VM".to_string()),
+        (Op::DeclareGlobal | Op::ReferenceGlobal, Operands::Constant(idx)) => {
+            match chunk.constants[*idx] {
+                ConstantValue::Object(ConstantObject::String(_)) => Ok(()),
+                _ => Err(format!("constant k{idx} isn't a global's name")),
+            }
+        }
+        (Op::ReferenceLocal | Op::SetLocal | Op::Define, Operands::Byte(slot)) => {
+            check_slot(*slot as usize)
+        }
+        (Op::ReferenceUpvalue | Op::SetUpvalue, Operands::Byte(idx)) => {
+            check_upvalue(*idx as usize)
+        }
+        (Op::Closure, Operands::Closure { captures, .. }) => {
+            for (capture, idx) in captures {
+                match capture {
+                    // captured locals are numbered from the slot after the function
+                    CaptureType::SurroundingLocal => check_slot(*idx as usize + 1)?,
+                    CaptureType::SurroundingUpvalue => check_upvalue(*idx as usize)?,
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether the catcher popped by the `PushHandler` at `offset` is nil, if that can be told from
+/// the instruction before it
+fn catcher_is_nil(
+    chunk: &BytecodeChunk,
+    instructions: &HashMap<usize, Instruction>,
+    previous: &HashMap<usize, usize>,
+    offset: usize,
+) -> Option<bool> {
+    let previous = &instructions[previous.get(&offset)?];
+    match (previous.op, &previous.operands) {
+        (Op::Constant, Operands::Constant(idx)) => {
+            Some(chunk.constants[*idx] == ConstantValue::Nil)
+        }
+        (Op::Closure, _) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, compiler::compile};
+
+    fn verify_asm(src: &str) -> Result<(), String> {
+        verify(&assemble(src).unwrap())
+    }
+
+    #[test]
+    fn compiled_programs_verify() {
+        let programs = [
+            "(define x (+ 1 2))\n(print x)",
+            "(defun (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))\n(print (fib 10))",
+            "(defun (make-adder n) (fn (x) (+ x n)))\n(print ((make-adder 1) 2))",
+            "(print (try (raise 'oops) (catch e e) (finally (print 1))))",
+            "(print (try 1 (finally (print 2))))",
+            "(defun (f x) (define y (* x 2)) (if (> y 2) y 'small))\n(print (f 3))",
+            "(print (call/cc (fn (k) (k 1))))",
+        ];
+        for src in programs {
+            assert_eq!(verify(&compile(&src.to_string())), Ok(()), "{src}");
+        }
+    }
+
+    #[test]
+    fn bad_opcodes_and_constants() {
+        assert_eq!(
+            verify(&BytecodeChunk::new(vec![250], vec![])),
+            Err("in <top level>, offset 0: invalid opcode 250".to_string())
+        );
+        assert_eq!(
+            verify(&BytecodeChunk::new(
+                vec![Op::ReferenceGlobal.into(), 0, Op::DebugEnd.into()],
+                vec![ConstantValue::Integer(1)]
+            )),
+            Err("in <top level>, offset 0: constant k0 isn't a global's name".to_string())
+        );
+        assert_eq!(
+            verify(&BytecodeChunk::new(
+                vec![Op::Constant.into(), 3, Op::DebugEnd.into()],
+                vec![]
+            )),
+            Err("in <top level>, offset 0: constant k3 doesn't exist, there are 0".to_string())
+        );
+    }
+
+    #[test]
+    fn bad_jumps() {
+        assert_eq!(
+            verify_asm("  Jump 9\n  DebugEnd"),
+            Err("in <top level>, offset 0: jumps to 10, past the end of the code".to_string())
+        );
+        assert_eq!(
+            verify_asm("  Constant 1\n  Jump 2\n  Constant 2\n  Pop\n  DebugEnd"),
+            Err(
+                "in <top level>, offset 2: jumps to 5, which isn't the start of an instruction"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            verify_asm("  Constant 1\n  Pop"),
+            Err("in <top level>, offset 2: runs off the end of the code".to_string())
+        );
+    }
+
+    #[test]
+    fn unbalanced_stacks() {
+        assert_eq!(
+            verify_asm("  Pop\n  DebugEnd"),
+            Err(
+                "in <top level>, offset 0: Pop pops 1 values, but the stack only has 0".to_string()
+            )
+        );
+        // the `then` branch pushes two values, the `else` branch one
+        let branches = "
+  Constant true
+  CondJump then
+  Constant 1
+  Jump end
+then:
+  Constant 2
+  Constant 3
+end:
+  DebugEnd";
+        assert_eq!(
+            verify_asm(branches),
+            Err(
+                "in <top level>, offset 12: reached with 1 values and 0 handlers, but also with \
+                 2 values and 0 handlers"
+                    .to_string()
+            )
+        );
+        let returns_two = r#"
+.func f "f"
+  Constant 1
+  Constant 2
+  Return
+.end
+  Closure f
+  DebugEnd"#;
+        assert_eq!(
+            verify_asm(returns_two),
+            Err(
+                "in f, offset 4: Return with 2 values on the stack, expected just the return value"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn locals_and_upvalues() {
+        let local = |slot| {
+            format!(
+                ".func f \"f\" arity=1 locals=1\n  ReferenceLocal {slot}\n  Return\n.end\n  \
+                 Closure f\n  DebugEnd"
+            )
+        };
+        assert_eq!(verify_asm(&local(2)), Ok(()));
+        assert_eq!(
+            verify_asm(&local(3)),
+            Err("in f, offset 0: local slot 3 is out of range, the function has 2".to_string())
+        );
+        assert_eq!(
+            verify_asm("  ReferenceLocal 1\n  DebugEnd"),
+            Err(
+                "in <top level>, offset 0: ReferenceLocal at the top level, which has no locals"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            verify_asm(".func f \"f\" upvalues=1\n  ReferenceUpvalue 0\n  Return\n.end\n  Closure f upvalue 0\n  DebugEnd"),
+            Err("in <top level>, offset 0: upvalue 0 is out of range, the function has 0".to_string())
+        );
+    }
+
+    #[test]
+    fn handlers_must_be_popped() {
+        let src = r#"
+.func f "f"
+  Constant nil
+  PushHandler landing
+  Constant 1
+  Return
+landing:
+  Return
+.end
+  Closure f
+  DebugEnd"#;
+        assert_eq!(
+            verify_asm(src),
+            Err("in f, offset 6: Return with 1 handlers still pushed".to_string())
+        );
+    }
+}
@@ -1,13 +1,22 @@
 use crate::builtins_comp::{self, BuiltIn};
-use crate::disassembler::disassemble;
+use crate::disassembler::{decode, disassemble, Operands};
+use crate::predecode::{predecode, Decoded, Instr};
 use crate::static_stack::StaticStack;
+use crate::trace::{TraceContext, TraceHook};
+use crate::verifier::verify;
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use std::alloc::{alloc, Layout};
-use std::collections::HashMap;
+use std::alloc::{alloc, dealloc, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::default;
 use std::fmt::{Debug, Display};
+use std::rc::Rc;
+use std::time::Instant;
+
+#[cfg(feature = "jit")]
+pub(crate) mod jit;
 
 #[repr(u8)]
 #[derive(Debug, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
@@ -23,26 +32,305 @@ use CaptureType::*;
 // }
 
 const STACK_SIZE: usize = 4096; // will need to dial this in
+/// The slots a coroutine's or task's stack starts with. There can be lots of them, so they
+/// start small and grow like the main stack does, up to `VM::set_max_stack_slots`.
+const SMALL_STACK_SIZE: usize = 64;
+/// the number of live objects before the first collection
+const INITIAL_GC_THRESHOLD: usize = 1024;
+/// the number of instructions a task runs for before the next one gets a turn
+const DEFAULT_TIME_SLICE: usize = 100;
+/// reading the clock on every instruction would be slow, so the deadline is checked this often
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+const DEFAULT_MAX_CALL_DEPTH: usize = 4096;
+/// Free slots that must be available before each instruction. No instruction pushes more than
+/// this, apart from calls which check for the space they need.
+const STACK_HEADROOM: usize = 8;
+
+/// What the stack holds, the values themselves or, with the `nan_boxing` feature, the same
+/// values packed into 8 bytes
+#[cfg(not(feature = "nan_boxing"))]
+type Slot = SmallVal;
+#[cfg(feature = "nan_boxing")]
+type Slot = crate::nanbox::PackedVal;
+
+trait StackSlot {
+    fn from_value(value: SmallVal) -> Self;
+    fn to_value(&self) -> SmallVal;
+}
+
+impl StackSlot for SmallVal {
+    fn from_value(value: SmallVal) -> Self {
+        value
+    }
+
+    fn to_value(&self) -> SmallVal {
+        self.clone()
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+impl StackSlot for crate::nanbox::PackedVal {
+    fn from_value(value: SmallVal) -> Self {
+        Self::pack(&value)
+    }
+
+    fn to_value(&self) -> SmallVal {
+        self.unpack()
+    }
+}
+
+/// A stack of values, stored as `Slot`s.
+/// Switching coroutines swaps these, but the values stay where they are, so upvalues pointing
+/// into a stack stay valid. The values only move when the stack grows.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stack(StaticStack<Slot, STACK_SIZE>);
+
+impl Stack {
+    /// a stack that starts with room for `slots` values, see `VM::ensure_stack_space`
+    fn with_capacity(slots: usize) -> Self {
+        Stack(StaticStack::with_capacity(slots))
+    }
+
+    pub fn push(&mut self, value: SmallVal) {
+        self.0.push(Slot::from_value(value));
+    }
+
+    pub fn pop(&mut self) -> Option<SmallVal> {
+        self.0.pop().map(|slot| slot.to_value())
+    }
+
+    pub fn pop_n(&mut self, n: usize) -> Option<Vec<SmallVal>> {
+        let slots = self.0.pop_n(n)?;
+        Some(slots.iter().map(|slot| slot.to_value()).collect())
+    }
+
+    pub fn at(&self, idx: usize) -> Option<SmallVal> {
+        self.0.at(idx).map(|slot| slot.to_value())
+    }
+
+    pub fn peek_top(&self) -> Option<SmallVal> {
+        self.0.peek_top().map(|slot| slot.to_value())
+    }
+
+    pub fn peek_back(&self, back: usize) -> Option<SmallVal> {
+        self.0.peek_back(back).map(|slot| slot.to_value())
+    }
+}
+
+impl std::ops::Deref for Stack {
+    type Target = StaticStack<Slot, STACK_SIZE>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Stack {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 pub struct VM {
-    pub stack: StaticStack<SmallVal, STACK_SIZE>, // pub for testing, ugh
-    pub globals: HashMap<String, SmallVal>,       // same, need to make interface nicer
-    ip: *const u8,
+    pub stack: Stack, // pub for testing, ugh
+    pub globals: Globals,
+    ip: *const Instr,
     callframes: Vec<CallFrame>,
+    handlers: Vec<Handler>,
     heap: *mut HeapObject,
-    chunk_constants: Vec<ConstantValue>,
+    // kept so that an interrupted run can be resumed
+    chunk: BytecodeChunk,
+    /// incremented on every call to `run`, continuations are only valid within the run they were captured in
+    run_count: usize,
     // open_upvalues: *mut UpValue,
     // open_upvalues: *mut ObjectValue,
     open_upvalues: *mut HeapObject,
+    /// the chain of coroutines currently being resumed, innermost last
+    coroutines: Vec<*mut HeapObject>,
+    num_objects: usize,
+    next_gc: usize,
+    /// the running task, or null if nothing has been spawned
+    current_task: *mut HeapObject,
+    /// the task running the top level of the program, created by the first `spawn`
+    main_task: *mut HeapObject,
+    /// tasks waiting for their turn, in the order they'll get it
+    run_queue: VecDeque<*mut HeapObject>,
+    time_slice: usize,
+    slice_remaining: usize,
+    /// xorshift state for jittering time slices, if a seed was set
+    scheduler_rng: Option<u64>,
+    /// set by `recv` when the current task needs to wait for a value
+    blocked_on: Option<*mut HeapObject>,
+    /// the number of instructions left to run, if limited
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    /// whether an uncaught error stops the run rather than panicking
+    stop_on_uncaught: bool,
+    /// the error that stopped the run, with `stop_on_uncaught`
+    uncaught: Option<SmallVal>,
+    /// the number of instructions run so far
+    steps: u64,
+    heap_bytes: usize,
+    heap_by_kind: BTreeMap<&'static str, AllocationStats>,
+    /// the number of bytes the heap can grow to before allocations fail
+    heap_limit: Option<usize>,
+    collections: usize,
+    max_call_depth: usize,
+    /// the stack grows up to this many slots when it's full
+    max_stack_slots: usize,
+    trace_hook: Option<Box<dyn TraceHook>>,
+    /// the last instruction dispatched and its offset, only kept while tracing
+    trace_position: Option<(Op, usize)>,
+    /// every symbol on the heap, by name, so that there's only ever one of each and symbols can
+    /// be compared by pointer. Weak, entries are removed when their symbol is collected.
+    symbols: HashMap<String, *mut HeapObject>,
+    /// whether a script has declared a global with the name of a builtin that has its own
+    /// opcode, after which those opcodes always look the global up
+    operators_rebound: bool,
+    /// Bumped whenever a global is declared and after every collection, which invalidates
+    /// every call cache. Until then a cached callee can't have been freed and its address
+    /// reused, so a call site that sees the same pointer is calling the same thing.
+    call_cache_epoch: u64,
+    /// the number of calls before a function is compiled to machine code, `None` to never
+    #[cfg(feature = "jit")]
+    jit_threshold: Option<u32>,
+    /// set when `ip` moves to a function that might have machine code to run
+    #[cfg(feature = "jit")]
+    jit_check: bool,
+    /// a panic caught in machine code, which can't unwind through it
+    #[cfg(feature = "jit")]
+    jit_panic: Option<Box<dyn std::any::Any + Send>>,
+    /// where machine code finds the stack while it's running
+    #[cfg(feature = "jit")]
+    jit_frame: jit::NativeFrame,
+}
+
+/// The global variables, by slot. Chunks are linked to their slots when they're run, so
+/// compiled code reaches a global with an index rather than by hashing its name. A name gets
+/// a slot the first time any code mentions it, which is undefined until the name is declared.
+#[derive(Debug, Default)]
+pub struct Globals {
+    /// `None` for globals that have been referred to but not declared yet
+    slots: Vec<Option<SmallVal>>,
+    names: Vec<String>,
+    by_name: HashMap<String, usize>,
 }
 
+impl Globals {
+    /// The slot for `name`, which is added (undefined) if it doesn't have one yet
+    pub fn slot(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.by_name.get(name) {
+            return *slot;
+        }
+        let slot = self.slots.len();
+        self.slots.push(None);
+        self.names.push(name.to_string());
+        self.by_name.insert(name.to_string(), slot);
+        slot
+    }
+
+    /// The slot for `name`, if any code has referred to it
+    pub fn slot_of(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SmallVal> {
+        self.at(self.slot_of(name)?)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn insert(&mut self, name: &str, value: SmallVal) {
+        let slot = self.slot(name);
+        self.set(slot, value);
+    }
+
+    /// The value in `slot`, or `None` if it hasn't been declared
+    pub fn at(&self, slot: usize) -> Option<&SmallVal> {
+        self.slots[slot].as_ref()
+    }
+
+    pub fn set(&mut self, slot: usize, value: SmallVal) {
+        self.slots[slot] = Some(value);
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    /// The declared globals and their values, in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SmallVal)> {
+        self.names
+            .iter()
+            .zip(self.slots.iter())
+            .filter_map(|(name, value)| Some((name.as_str(), value.as_ref()?)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AllocationStats {
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+/// A snapshot of what's live on the heap, from `VM::heap_stats`.
+/// Sizes are estimates, measured when each object is allocated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapStats {
+    pub live: AllocationStats,
+    /// live objects by `ObjectValue::kind`
+    pub by_kind: BTreeMap<&'static str, AllocationStats>,
+    pub limit: Option<usize>,
+    /// the number of garbage collections so far
+    pub collections: usize,
+}
+
+/// A call frame, as seen by a debugger. See `VM::backtrace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    pub function: String,
+    /// the offset of the frame's next instruction in its function's bytecode.
+    /// Outer frames are waiting on a call, and are at the end of the call instruction.
+    pub offset: usize,
+    pub line: Option<usize>,
+    /// the arguments and then the locals, with their names where they're known
+    pub locals: Vec<(String, SmallVal)>,
+    pub upvalues: Vec<SmallVal>,
+}
+
+/// Why `VM::execute` stopped before the end of the program.
+/// The VM is left as it was, so the run can be continued with `VM::resume` after raising the limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitExceeded {
+    OutOfFuel,
+    DeadlineExceeded,
+    /// Only with `VM::set_stop_on_uncaught_errors`. The error is `VM::uncaught_error`, and the
+    /// VM is left as it was when it was raised, but the run can't be resumed.
+    UncaughtError,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::OutOfFuel => write!(f, "ran out of fuel"),
+            LimitExceeded::DeadlineExceeded => write!(f, "deadline exceeded"),
+            LimitExceeded::UncaughtError => write!(f, "uncaught error"),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct UpValue {
-    location: *mut SmallVal,
-    closed_val: Option<SmallVal>, // I think option is wrong here
+    location: *mut Slot,
+    closed_val: Option<Slot>, // I think option is wrong here
     next: *mut HeapObject,
 }
 impl UpValue {
-    fn new(stack_location: *mut SmallVal) -> Self {
+    fn new(stack_location: *mut Slot) -> Self {
         UpValue {
             location: stack_location,
             next: std::ptr::null_mut(),
@@ -78,6 +366,11 @@ pub enum ObjectValue {
     ConsCell(ConsCell),
     BuiltIn(BuiltIn),
     UpValue(UpValue),
+    Error(ErrorValue),
+    Continuation(Continuation),
+    Coroutine(Coroutine),
+    Task(Task),
+    Channel(Channel),
 }
 
 impl ObjectValue {
@@ -91,6 +384,53 @@ impl ObjectValue {
             ObjectValue::BuiltIn(_) => true,
             ObjectValue::UpValue(_) => unreachable!(),
             ObjectValue::Closure(_) => true,
+            ObjectValue::Error(_) => true,
+            ObjectValue::Continuation(_) => true,
+            ObjectValue::Coroutine(_) => true,
+            ObjectValue::Task(_) => true,
+            ObjectValue::Channel(_) => true,
+        }
+    }
+
+    /// the name used for this kind of object in heap statistics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ObjectValue::SmallValue(_) => "small-value",
+            ObjectValue::String(_) => "string",
+            ObjectValue::Closure(_) => "closure",
+            ObjectValue::Symbol(_) => "symbol",
+            ObjectValue::ConsCell(_) => "cons",
+            ObjectValue::BuiltIn(_) => "builtin",
+            ObjectValue::UpValue(_) => "upvalue",
+            ObjectValue::Error(_) => "error",
+            ObjectValue::Continuation(_) => "continuation",
+            ObjectValue::Coroutine(_) => "coroutine",
+            ObjectValue::Task(_) => "task",
+            ObjectValue::Channel(_) => "channel",
+        }
+    }
+
+    /// An estimate of the memory owned by the value, outside of its `HeapObject`.
+    /// Doesn't include other heap objects it points to.
+    fn owned_size(&self) -> usize {
+        use std::mem::size_of;
+        match self {
+            ObjectValue::String(s) | ObjectValue::Symbol(s) => s.capacity(),
+            // the bytecode is shared with the function's constant, so isn't counted
+            ObjectValue::Closure(closure) => {
+                closure.upvalues.capacity() * size_of::<*mut HeapObject>()
+            }
+            ObjectValue::Error(error) => error.kind.capacity() + error.message.capacity(),
+            ObjectValue::Continuation(continuation) => {
+                continuation.stack.capacity() * size_of::<SmallVal>()
+                    + continuation.callframes.capacity() * size_of::<CallFrame>()
+                    + continuation.handlers.capacity() * size_of::<Handler>()
+            }
+            ObjectValue::Coroutine(co) => co.state.owned_size(),
+            ObjectValue::Task(task) => task.state.owned_size(),
+            ObjectValue::Channel(channel) => channel.buffer.capacity() * size_of::<SmallVal>(),
+            ObjectValue::SmallValue(_) | ObjectValue::ConsCell(_) | ObjectValue::BuiltIn(_) => 0,
+            ObjectValue::UpValue(_) => 0,
         }
     }
 }
@@ -127,17 +467,49 @@ impl Display for ObjectValue {
             ObjectValue::ConsCell(cell) => write!(f, "{}", cell),
             ObjectValue::SmallValue(v) => write!(f, "{}", v),
             ObjectValue::BuiltIn(b) => write!(f, "builtin <{}>", b.name),
-            ObjectValue::UpValue(u) => write!(f, "{}", &unsafe { &*u.location }),
+            ObjectValue::UpValue(u) => write!(f, "{}", unsafe { &*u.location }.to_value()),
             ObjectValue::Closure(c) => write!(f, "closure <{}>", c.f.name),
+            ObjectValue::Error(e) => write!(f, "{}", e),
+            ObjectValue::Continuation(_) => write!(f, "continuation"),
+            ObjectValue::Coroutine(co) if co.generator => write!(f, "generator <{}>", co.function),
+            ObjectValue::Coroutine(co) => write!(f, "coroutine <{}>", co.function),
+            ObjectValue::Task(task) => write!(f, "task <{}>", task.function),
+            ObjectValue::Channel(_) => write!(f, "channel"),
+        }
+    }
+}
+
+/// A catchable error, raised by `error`/`raise`, by a builtin, or by the VM itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorValue {
+    /// the name of a symbol, like `type-error`
+    pub kind: String,
+    pub message: String,
+    pub payload: SmallVal,
+}
+
+impl ErrorValue {
+    pub fn new(kind: &str, message: String, payload: SmallVal) -> Self {
+        ErrorValue {
+            kind: kind.to_string(),
+            message,
+            payload,
         }
     }
 }
 
+impl Display for ErrorValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error <{}: {}>", self.kind, self.message)
+    }
+}
+
 #[derive(Clone, PartialEq)]
 pub struct Function {
     pub name: String,
     pub arity: usize,
-    bytecode: Box<BytecodeChunk>,
+    /// shared, so that making a closure doesn't copy its code
+    bytecode: Rc<BytecodeChunk>,
     num_locals: usize,
 }
 
@@ -148,7 +520,10 @@ impl Debug for Function {
             "Function(name={}, arity={} bc={})",
             self.name,
             self.arity,
-            indent(disassemble(&self.bytecode), 2)
+            indent(
+                disassemble(&self.bytecode).unwrap_or_else(|e| format!("<{e}>")),
+                2
+            )
         )
     }
 }
@@ -159,9 +534,17 @@ impl Function {
             name,
             arity,
             num_locals,
-            bytecode: Box::new(bytecode),
+            bytecode: Rc::new(bytecode),
         }
     }
+
+    pub fn bytecode(&self) -> &BytecodeChunk {
+        &self.bytecode
+    }
+
+    pub fn num_locals(&self) -> usize {
+        self.num_locals
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -169,7 +552,9 @@ pub struct HeapObject {
     next: *mut HeapObject,
     // pub value: *mut ObjectValue,
     pub value: ObjectValue,
-    // marked: bool,
+    marked: bool,
+    /// the number of bytes it was counted as when allocated
+    size: usize,
 }
 
 impl Display for HeapObject {
@@ -178,7 +563,9 @@ impl Display for HeapObject {
     }
 }
 
+/// `repr(u8)` so that compiled code can read and write them, see `jit`
 #[derive(Debug, Clone, PartialEq)]
+#[repr(u8)]
 pub enum SmallVal {
     Integer(i64),
     Float(f64),
@@ -226,11 +613,202 @@ impl default::Default for SmallVal {
 #[derive(Debug, Clone, PartialEq)]
 struct CallFrame {
     closure: Closure,
-    return_address: *const u8,
+    return_address: *const Instr,
     /// the stack index of the function being called
     start_idx: i32,
 }
 
+/// A snapshot of the VM's control state, captured by `Op::CallCC`.
+/// Locals are restored to their values at the time of capture when it's invoked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Continuation {
+    stack: Vec<SmallVal>,
+    callframes: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    /// the `CallCC` instruction to continue after
+    resume_address: *const Instr,
+    run_count: usize,
+    /// the coroutine it was captured in, or null for the main program
+    context: *mut HeapObject,
+    /// the task it was captured in, or null if nothing has been spawned
+    task: *mut HeapObject,
+}
+
+/// The registers of a line of execution that isn't running.
+/// While it is running, this holds the state of whatever it was swapped with.
+#[derive(Debug, Clone, PartialEq)]
+struct ExecutionState {
+    stack: Stack,
+    callframes: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    open_upvalues: *mut HeapObject,
+    ip: *const Instr,
+}
+
+impl ExecutionState {
+    fn owned_size(&self) -> usize {
+        self.stack.capacity() * std::mem::size_of::<Slot>()
+            + self.callframes.capacity() * std::mem::size_of::<CallFrame>()
+            + self.handlers.capacity() * std::mem::size_of::<Handler>()
+    }
+
+    fn new() -> Self {
+        ExecutionState {
+            stack: Stack::with_capacity(SMALL_STACK_SIZE),
+            callframes: vec![],
+            handlers: vec![],
+            open_upvalues: std::ptr::null_mut(),
+            ip: std::ptr::null(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineStatus {
+    /// hasn't been resumed yet
+    Fresh,
+    Suspended,
+    Running,
+    Dead,
+}
+
+/// A function with its own stack and callframes, which can be suspended by `yield` and
+/// continued by `resume`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coroutine {
+    function: SmallVal,
+    pub status: CoroutineStatus,
+    /// generators evaluate to `nil` when resumed after they're done, rather than raising
+    generator: bool,
+    state: ExecutionState,
+}
+
+impl Coroutine {
+    pub fn new(function: SmallVal, generator: bool) -> Self {
+        Coroutine {
+            function,
+            status: CoroutineStatus::Fresh,
+            generator,
+            state: ExecutionState::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskStatus {
+    Ready,
+    Running,
+    /// waiting for a value on the channel
+    Blocked(*mut HeapObject),
+    Done,
+}
+
+/// A green thread, created by `spawn`. Tasks take turns running, switching every time slice,
+/// or when the running one blocks on an empty channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Task {
+    /// `nil` for the main task
+    function: SmallVal,
+    pub status: TaskStatus,
+    started: bool,
+    state: ExecutionState,
+    /// the coroutines the task is inside of, while it's not running
+    coroutines: Vec<*mut HeapObject>,
+}
+
+impl Task {
+    pub fn new(function: SmallVal) -> Self {
+        Task {
+            function,
+            status: TaskStatus::Ready,
+            started: false,
+            state: ExecutionState::new(),
+            coroutines: vec![],
+        }
+    }
+}
+
+/// An unbounded queue of values for passing messages between tasks
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Channel {
+    pub buffer: VecDeque<SmallVal>,
+}
+
+/// A call site's inline cache, see `VM::call_target`
+#[derive(Debug, Clone, Copy)]
+struct CallCache {
+    callee: *mut HeapObject,
+    /// `VM::call_cache_epoch` when it was filled
+    epoch: u64,
+    target: CallTarget,
+}
+
+impl Default for CallCache {
+    fn default() -> Self {
+        CallCache {
+            callee: std::ptr::null_mut(),
+            epoch: 0,
+            target: CallTarget::Uncached,
+        }
+    }
+}
+
+/// A chunk's call caches, by call site, kept with its instructions so they go when it does
+#[derive(Default)]
+pub(crate) struct CallCaches(Box<[Cell<CallCache>]>);
+
+impl CallCaches {
+    pub(crate) fn new(call_sites: usize) -> Self {
+        CallCaches((0..call_sites).map(|_| Cell::default()).collect())
+    }
+}
+
+/// copies start empty
+impl Clone for CallCaches {
+    fn clone(&self) -> Self {
+        CallCaches::new(self.0.len())
+    }
+}
+
+/// doesn't change what the code does
+impl PartialEq for CallCaches {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Debug for CallCaches {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallCaches")
+            .field("call_sites", &self.0.len())
+            .finish()
+    }
+}
+
+/// What calling a cached callee does, already checked against the call site's arity
+#[derive(Debug, Clone, Copy)]
+enum CallTarget {
+    /// anything that doesn't have a fast path, or would fail, goes through `VM::call_value`
+    Uncached,
+    /// the closure inside the callee object
+    Closure(*const Closure),
+    /// the builtin inside the callee object
+    BuiltIn(*const BuiltIn),
+}
+
+/// An active `try` block, registered by `Op::PushHandler`
+#[derive(Debug, Clone, PartialEq)]
+struct Handler {
+    /// where to continue when an error is caught
+    landing: *const Instr,
+    /// the number of callframes to keep when unwinding
+    frame_depth: usize,
+    /// the stack pointer to restore when unwinding
+    stack_ptr: i32,
+    /// the `catch` closure, or `Nil` if the block only has a `finally`
+    catcher: SmallVal,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ConstantValue {
     Integer(i64),
@@ -294,22 +872,103 @@ impl SmallVal {
 pub struct BytecodeChunk {
     pub code: Vec<u8>,
     pub constants: Vec<ConstantValue>,
+    pub debug: DebugInfo,
+    /// the global slot for each constant that names a global, filled in by `VM::link`
+    pub global_slots: Vec<usize>,
+    /// the heap values of its strings, symbols and quoted data, made when they're first used
+    pub literals: Literals,
+    /// the code as the VM runs it, filled in by `VM::link`
+    pub decoded: Decoded,
 }
 
 impl BytecodeChunk {
     pub fn new(code: Vec<u8>, constants: Vec<ConstantValue>) -> Self {
-        BytecodeChunk { code, constants }
+        BytecodeChunk {
+            code,
+            constants,
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
+        }
+    }
+
+    pub fn with_debug_info(mut self, debug: DebugInfo) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// the source line of the expression that the instruction at `offset` belongs to
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        self.debug
+            .lines
+            .iter()
+            .take_while(|(start, _)| *start <= offset)
+            .last()
+            .map(|(_, line)| *line)
+    }
+}
+
+/// The heap values of a chunk's literal constants, by constant, so each is made once and then
+/// shared. They're kept alive by the chunk, so they go when it does, see `mark_chunk`.
+#[derive(Default)]
+pub struct Literals(RefCell<Vec<Option<SmallVal>>>);
+
+impl Literals {
+    fn get(&self, idx: usize) -> Option<SmallVal> {
+        self.0.borrow().get(idx).cloned().flatten()
+    }
+
+    fn set(&self, idx: usize, value: SmallVal) {
+        let mut literals = self.0.borrow_mut();
+        if literals.len() <= idx {
+            literals.resize(idx + 1, None);
+        }
+        literals[idx] = Some(value);
+    }
+}
+
+/// copies start without any, they're made again when they're used
+impl Clone for Literals {
+    fn clone(&self) -> Self {
+        Literals::default()
+    }
+}
+
+/// doesn't change what the code does
+impl PartialEq for Literals {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Debug for Literals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let made = self.0.borrow().iter().flatten().count();
+        f.debug_struct("Literals").field("made", &made).finish()
     }
 }
 
+/// Source information kept alongside a chunk for the debugger
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    /// (code offset, source line) for the start of each expression, in code order
+    pub lines: Vec<(usize, usize)>,
+    /// the names of the function's arguments and then its locals, by slot (starting from slot 1)
+    pub locals: Vec<String>,
+    /// the names of the variables the function captures, by upvalue index
+    pub upvalues: Vec<String>,
+}
+
 #[repr(u8)]
-#[derive(Debug, PartialEq, Clone, IntoPrimitive, TryFromPrimitive)]
+#[derive(Debug, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
 pub enum Op {
     Constant = 0,
     Add = 1,
     Sub = 2,
     Mul = 3,
     Div = 4,
+    Equal = 5,
     GT = 6,
     LT = 7,
     GTE = 8,
@@ -330,9 +989,47 @@ pub enum Op {
     Pop = 23,
     CloseUpvalue = 24,
     SetLocal = 25,
+    PushHandler = 26,
+    PopHandler = 27,
+    Raise = 28,
+    CallCC = 29,
+    Resume = 30,
+    Yield = 31,
     DebugEnd = 254,
 }
 
+/// The builtins that have their own opcodes, see `Op::for_operator`
+const OPERATORS: [(&str, Op); 9] = [
+    ("+", Op::Add),
+    ("-", Op::Sub),
+    ("*", Op::Mul),
+    ("/", Op::Div),
+    ("=", Op::Equal),
+    (">", Op::GT),
+    ("<", Op::LT),
+    (">=", Op::GTE),
+    ("<=", Op::LTE),
+];
+
+impl Op {
+    /// The opcode the compiler emits for a two-argument call to the builtin `name`, which
+    /// does exactly what calling the builtin would
+    pub fn for_operator(name: &str) -> Option<Op> {
+        OPERATORS
+            .iter()
+            .find(|(operator, _)| *operator == name)
+            .map(|(_, op)| *op)
+    }
+
+    /// The builtin an arithmetic or comparison opcode stands for
+    pub fn operator_name(self) -> Option<&'static str> {
+        OPERATORS
+            .iter()
+            .find(|(_, op)| *op == self)
+            .map(|(name, _)| *name)
+    }
+}
+
 impl Default for VM {
     fn default() -> Self {
         Self::new()
@@ -343,68 +1040,850 @@ impl VM {
     fn new() -> VM {
         let mut vm = VM {
             ip: std::ptr::null_mut(),
-            stack: StaticStack::new(),
+            stack: Stack::default(),
             heap: std::ptr::null_mut(),
-            globals: HashMap::default(),
+            globals: Globals::default(),
             callframes: Vec::default(),
-            chunk_constants: Vec::default(),
+            handlers: Vec::default(),
+            chunk: BytecodeChunk::new(vec![], vec![]),
+            run_count: 0,
             open_upvalues: std::ptr::null_mut(),
+            coroutines: Vec::default(),
+            num_objects: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            current_task: std::ptr::null_mut(),
+            main_task: std::ptr::null_mut(),
+            run_queue: VecDeque::default(),
+            time_slice: DEFAULT_TIME_SLICE,
+            slice_remaining: DEFAULT_TIME_SLICE,
+            scheduler_rng: None,
+            blocked_on: None,
+            fuel: None,
+            deadline: None,
+            stop_on_uncaught: false,
+            uncaught: None,
+            steps: 0,
+            heap_bytes: 0,
+            heap_by_kind: BTreeMap::default(),
+            heap_limit: None,
+            collections: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_stack_slots: STACK_SIZE,
+            trace_hook: None,
+            trace_position: None,
+            symbols: HashMap::default(),
+            operators_rebound: false,
+            call_cache_epoch: 0,
+            #[cfg(feature = "jit")]
+            jit_threshold: jit::threshold_from_env(),
+            #[cfg(feature = "jit")]
+            jit_check: false,
+            #[cfg(feature = "jit")]
+            jit_panic: None,
+            #[cfg(feature = "jit")]
+            jit_frame: jit::NativeFrame::default(),
+        };
+
+        for builtin in builtins_comp::BUILT_INS.into_iter() {
+            let obj_ptr = unsafe { vm.allocate_value(ObjectValue::BuiltIn(builtin.clone())) };
+            vm.globals
+                .insert(builtin.name, SmallVal::ObjectPtr(obj_ptr));
+        }
+
+        vm
+    }
+
+    /// Mark and sweep. Only safe to run between instructions, when every live object is
+    /// reachable from the VM's roots.
+    fn gc(&mut self) {
+        #[cfg(feature = "gc_debug")]
+        println!("gc running, {} objects", self.num_objects);
+        let objects_before = self.num_objects;
+
+        let mut gray = vec![];
+
+        for i in 0..self.stack.len() {
+            mark_value(&self.stack.at(i).unwrap(), &mut gray);
+        }
+        for (_, val) in self.globals.iter() {
+            mark_value(val, &mut gray);
+        }
+        mark_chunk(&self.chunk, &mut gray);
+        if let Some(error) = &self.uncaught {
+            mark_value(error, &mut gray);
+        }
+        mark_callframes(&self.callframes, &mut gray);
+        mark_handlers(&self.handlers, &mut gray);
+        mark_open_upvalues(self.open_upvalues, &mut gray);
+        for co in self.coroutines.iter() {
+            mark_object(*co, &mut gray);
+        }
+        mark_object(self.current_task, &mut gray);
+        mark_object(self.main_task, &mut gray);
+        for task in self.run_queue.iter() {
+            mark_object(*task, &mut gray);
+        }
+
+        while let Some(obj) = gray.pop() {
+            trace_object(obj, &mut gray);
+        }
+
+        self.sweep();
+        self.call_cache_epoch += 1;
+        self.next_gc = (self.num_objects * 2).max(INITIAL_GC_THRESHOLD);
+        self.collections += 1;
+
+        #[cfg(feature = "gc_debug")]
+        println!("gc done, {} objects", self.num_objects);
+
+        let live = self.num_objects;
+        self.trace(|hook, context| hook.on_gc(context, objects_before - live, live));
+    }
+
+    fn sweep(&mut self) {
+        let mut previous: *mut HeapObject = std::ptr::null_mut();
+        let mut current = self.heap;
+        while !current.is_null() {
+            let obj = unsafe { &mut *current };
+            let next = obj.next;
+            if obj.marked {
+                obj.marked = false;
+                previous = current;
+            } else {
+                if previous.is_null() {
+                    self.heap = next;
+                } else {
+                    unsafe { (*previous).next = next };
+                }
+                self.untrack_allocation(obj);
+                if let ObjectValue::Symbol(name) = &obj.value {
+                    self.symbols.remove(name);
+                }
+                unsafe { free_object(current) };
+            }
+            current = next;
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.callframes.last().expect("expected a call frame")
+    }
+
+    pub fn run(&mut self, chunk: BytecodeChunk) {
+        if let Err(limit) = self.execute(chunk) {
+            self.runtime_error(limit.to_string().as_str());
+        }
+    }
+
+    /// Like `run`, but checks the bytecode with `verifier::verify` first,
+    /// for bytecode that didn't come from the compiler
+    pub fn run_untrusted(&mut self, chunk: BytecodeChunk) -> Result<(), String> {
+        verify(&chunk)?;
+        self.run(chunk);
+        Ok(())
+    }
+
+    /// Like `run`, but stops with an error if the fuel or deadline runs out
+    pub fn execute(&mut self, mut chunk: BytecodeChunk) -> Result<(), LimitExceeded> {
+        self.link(&mut chunk);
+        self.chunk = chunk;
+        self.ip = self.chunk.decoded.instructions.as_ptr();
+        self.run_count += 1;
+        self.uncaught = None;
+        if !self.main_task.is_null() {
+            as_task(self.main_task).status = TaskStatus::Running;
+        }
+        self.resume()
+    }
+
+    /// Gives every global that `chunk` and the functions inside it refer to a slot, so the
+    /// global ops can index `globals` instead of looking names up while they run, and forgets
+    /// any literals a previous run made, which nothing has kept alive since. Then decodes the
+    /// code into the instructions the VM runs, see `predecode`, with a call cache for each
+    /// call site.
+    /// Malformed code is left for the verifier (or the ops themselves) to complain about.
+    fn link(&mut self, chunk: &mut BytecodeChunk) {
+        chunk.global_slots = vec![usize::MAX; chunk.constants.len()];
+        chunk.literals = Literals::default();
+        let mut offset = 0;
+        while let Ok(instruction) = decode(chunk, offset) {
+            offset = instruction.next;
+            let (Op::ReferenceGlobal | Op::DeclareGlobal, Operands::Constant(idx)) =
+                (instruction.op, instruction.operands)
+            else {
+                continue;
+            };
+            if let ConstantValue::Object(ConstantObject::String(name)) = &chunk.constants[idx] {
+                chunk.global_slots[idx] = self.globals.slot(name);
+            }
+        }
+        for constant in chunk.constants.iter_mut() {
+            if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+                self.link(Rc::make_mut(&mut closure.f.bytecode));
+            }
+        }
+        chunk.decoded = predecode(chunk);
+    }
+
+    /// The maximum number of instructions to run, or `None` for no limit.
+    /// Limits aren't visible to scripts, so they can't be caught and ignored.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0) + fuel);
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Stop with `LimitExceeded::UncaughtError` when nothing catches an error, with the
+    /// frames and stack as they were when it was raised, rather than panicking
+    pub fn set_stop_on_uncaught_errors(&mut self, stop: bool) {
+        self.stop_on_uncaught = stop;
+    }
+
+    /// the error that stopped the run, see `set_stop_on_uncaught_errors`
+    pub fn uncaught_error(&self) -> Option<&SmallVal> {
+        self.uncaught.as_ref()
+    }
+
+    /// continues a run that was stopped by a limit
+    pub fn resume(&mut self) -> Result<(), LimitExceeded> {
+        if self.ip.is_null() {
+            panic!("there's nothing to resume");
+        }
+
+        loop {
+            if self.uncaught.is_some() {
+                return Err(LimitExceeded::UncaughtError);
+            }
+            // checked before anything changes, so that resuming picks up at the same instruction
+            if self.fuel == Some(0) {
+                return Err(LimitExceeded::OutOfFuel);
+            }
+            if let Some(deadline) = self.deadline {
+                if self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline
+                {
+                    return Err(LimitExceeded::DeadlineExceeded);
+                }
+            }
+            if let Some(fuel) = self.fuel.as_mut() {
+                *fuel -= 1;
+            }
+            self.steps += 1;
+
+            if self.stack.len() + STACK_HEADROOM > self.stack.capacity()
+                && !self.ensure_stack_space(STACK_HEADROOM)
+            {
+                let function = match self.callframes.last() {
+                    Some(frame) => frame.closure.f.name.clone(),
+                    None => "<top level>".to_string(),
+                };
+                self.stack_overflow(&function);
+                continue;
+            }
+            if self.num_objects >= self.next_gc || self.over_heap_limit() {
+                self.gc();
+                if self.over_heap_limit() {
+                    let message =
+                        format!("heap limit of {} bytes exceeded", self.heap_limit.unwrap());
+                    self.raise(ErrorValue::new("out-of-memory", message, SmallVal::Nil));
+                    continue;
+                }
+            }
+            if !self.current_task.is_null() {
+                if self.slice_remaining == 0 {
+                    self.preempt();
+                } else {
+                    self.slice_remaining -= 1;
+                }
+            }
+            #[cfg(feature = "jit")]
+            if self.jit_check && self.run_native() {
+                continue;
+            }
+            let mut instr = unsafe { *self.ip };
+            if !self.can_fuse() {
+                instr = instr.unfused();
+            }
+            if self.trace_hook.is_some() {
+                self.trace_position = Some((instr.op(), self.ip_offset()));
+                self.trace(|hook, context| hook.on_dispatch(context));
+            }
+            match instr {
+                Instr::Constant(idx) => self.handle_constant(idx),
+                Instr::Operator(op) => self.handle_operator(op),
+                Instr::Jump(delta) => self.handle_jump(delta),
+                Instr::CondJump(delta) => self.handle_cond_jump(delta),
+                Instr::FuncCall { arity, cache } => self.handle_func_call(arity, cache),
+                Instr::DeclareGlobal(slot) => self.handle_declare_global(slot as usize),
+                Instr::ReferenceGlobal(slot) => self.handle_reference_global(slot as usize),
+                Instr::Print => self.handle_print(),
+                Instr::ReferenceLocal(idx) => self.handle_reference_local(idx),
+                Instr::Return => self.handle_return(),
+                // Op::Quote => self.handle_quote(),
+                Instr::Define(idx) => self.handle_local_define(idx),
+                Instr::DebugEnd => {
+                    if self.finish_main_task() {
+                        return Ok(());
+                    }
+                }
+                Instr::Closure { constant, captures } => self.handle_closure(constant, captures),
+                Instr::ReferenceUpvalue(idx) => self.handle_reference_upvalue(idx),
+                Instr::SetUpvalue(idx) => self.handle_set_upvalue(idx),
+                Instr::Pop => self.handle_pop(),
+                Instr::CloseUpvalue => unimplemented!(),
+                Instr::SetLocal(idx) => self.handle_set_local(idx),
+                Instr::PushHandler(delta) => self.handle_push_handler(delta),
+                Instr::PopHandler => self.handle_pop_handler(),
+                Instr::Raise => self.handle_raise(),
+                Instr::CallCC => self.handle_call_cc(),
+                Instr::Resume => self.handle_resume(),
+                Instr::Yield => self.handle_yield(),
+                Instr::LocalLocalOperator { a, b, op } => {
+                    self.handle_local_local_operator(a, b, op)
+                }
+                Instr::LocalIntegerOperator { local, op, integer } => {
+                    self.handle_local_integer_operator(local, op, integer)
+                }
+                Instr::ConstantCall {
+                    constant,
+                    arity,
+                    cache,
+                } => self.handle_constant_call(constant, arity, cache),
+                Instr::Invalid => {
+                    let error = self.current_chunk().decoded.error.clone();
+                    panic!(
+                        "{}",
+                        error.unwrap_or("ran past the end of the code".to_string())
+                    );
+                }
+            }
+        }
+    }
+
+    /// Whether superinstructions can run as one. Each instruction has to be dispatched on its
+    /// own if anything is counting or watching them.
+    fn can_fuse(&self) -> bool {
+        self.fuel.is_none()
+            && self.deadline.is_none()
+            && self.current_task.is_null()
+            && self.trace_hook.is_none()
+    }
+
+    /// expects the `catch` closure (or `Nil`) on top of the stack
+    fn handle_push_handler(&mut self, landing: i32) {
+        let catcher = self.stack.pop().expect("expected a catch closure or nil");
+        self.handlers.push(Handler {
+            landing: unsafe { self.ip.offset(landing as isize) },
+            frame_depth: self.callframes.len(),
+            stack_ptr: self.stack.ptr,
+            catcher,
+        });
+        self.advance();
+    }
+
+    fn handle_pop_handler(&mut self) {
+        self.handlers.pop().expect("expected a handler to pop");
+        self.advance();
+    }
+
+    fn handle_raise(&mut self) {
+        let value = self.stack.pop().expect("expected a value to raise");
+        self.throw(value);
+    }
+
+    /// Raise `error` as a catchable error value.
+    /// Callers must not touch `ip` afterwards, as it's moved to the landing site of the handler.
+    fn raise(&mut self, error: ErrorValue) {
+        let obj_ptr = unsafe { self.allocate_value(ObjectValue::Error(error)) };
+        self.throw(SmallVal::ObjectPtr(obj_ptr));
+    }
+
+    /// unwind to the innermost handler and jump to its landing site with `value` on the stack
+    fn throw(&mut self, value: SmallVal) {
+        let Some(handler) = self.handlers.pop() else {
+            if !self.coroutines.is_empty() {
+                // an uncaught error kills the coroutine and propagates to whoever resumed it
+                self.finish_coroutine();
+                return self.throw(value);
+            }
+            if self.stop_on_uncaught {
+                // `ip` stays on the instruction that raised it
+                self.uncaught = Some(value);
+                return;
+            }
+            self.runtime_error(format!("uncaught {}", value).as_str());
+        };
+
+        // anything above the handler's stack pointer is about to be discarded
+        let first_discarded = unsafe {
+            self.stack
+                .as_mut_ptr()
+                .add((handler.stack_ptr + 1) as usize)
+        };
+        self.close_upvalues(first_discarded);
+
+        self.callframes.truncate(handler.frame_depth);
+        self.stack.ptr = handler.stack_ptr;
+
+        if handler.catcher != SmallVal::Nil {
+            self.stack.push(handler.catcher);
+        }
+        self.stack.push(value);
+        self.ip = handler.landing;
+        self.entered_function();
+    }
+
+    /// expects the function to call with the current continuation on top of the stack
+    fn handle_call_cc(&mut self) {
+        let function = self.stack.pop().expect("expected a function to call");
+        let continuation = Continuation {
+            stack: (0..self.stack.len())
+                .map(|i| self.stack.at(i).unwrap())
+                .collect(),
+            callframes: self.callframes.clone(),
+            handlers: self.handlers.clone(),
+            resume_address: self.ip,
+            run_count: self.run_count,
+            context: self.current_coroutine(),
+            task: self.current_task,
+        };
+        let obj_ptr = unsafe { self.allocate_value(ObjectValue::Continuation(continuation)) };
+
+        self.stack.push(function);
+        self.stack.push(SmallVal::ObjectPtr(obj_ptr));
+        self.call_value(1);
+    }
+
+    fn resume_continuation(&mut self, continuation: &Continuation, value: SmallVal) {
+        if continuation.run_count != self.run_count {
+            let message = "continuation was captured in a previous run".to_string();
+            self.raise(ErrorValue::new(
+                "invalid-continuation",
+                message,
+                SmallVal::Nil,
+            ));
+            return;
+        }
+        if continuation.context != self.current_coroutine()
+            || continuation.task != self.current_task
+        {
+            let message = "continuation was captured in a different coroutine".to_string();
+            self.raise(ErrorValue::new(
+                "invalid-continuation",
+                message,
+                SmallVal::Nil,
+            ));
+            return;
+        }
+
+        // frames that aren't part of the continuation are discarded
+        let first_discarded = unsafe { self.stack.as_mut_ptr().add(continuation.stack.len()) };
+        self.close_upvalues(first_discarded);
+
+        self.stack.ptr = -1;
+        for val in continuation.stack.iter() {
+            self.stack.push(val.clone());
+        }
+        self.callframes = continuation.callframes.clone();
+        self.handlers = continuation.handlers.clone();
+
+        // as if `call/cc` returned `value`
+        self.ip = continuation.resume_address;
+        self.stack.push(value);
+        self.advance();
+        self.entered_function();
+    }
+
+    fn current_coroutine(&self) -> *mut HeapObject {
+        self.coroutines
+            .last()
+            .copied()
+            .unwrap_or(std::ptr::null_mut())
+    }
+
+    /// The maximum number of bytes of live objects, or `None` for no limit.
+    /// Going over it triggers a collection, and if that doesn't free enough,
+    /// an `out-of-memory` error is raised before the next instruction.
+    pub fn set_heap_limit(&mut self, bytes: Option<usize>) {
+        self.heap_limit = bytes;
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        HeapStats {
+            live: AllocationStats {
+                objects: self.num_objects,
+                bytes: self.heap_bytes,
+            },
+            by_kind: self.heap_by_kind.clone(),
+            limit: self.heap_limit,
+            collections: self.collections,
+        }
+    }
+
+    fn over_heap_limit(&self) -> bool {
+        self.heap_limit.is_some_and(|limit| self.heap_bytes > limit)
+    }
+
+    fn track_allocation(&mut self, obj: &HeapObject) {
+        self.num_objects += 1;
+        self.heap_bytes += obj.size;
+        let stats = self.heap_by_kind.entry(obj.value.kind()).or_default();
+        stats.objects += 1;
+        stats.bytes += obj.size;
+    }
+
+    fn untrack_allocation(&mut self, obj: &HeapObject) {
+        self.num_objects -= 1;
+        self.heap_bytes -= obj.size;
+        let kind = obj.value.kind();
+        let stats = self.heap_by_kind.get_mut(kind).unwrap();
+        stats.objects -= 1;
+        stats.bytes -= obj.size;
+        if stats.objects == 0 {
+            self.heap_by_kind.remove(kind);
+        }
+    }
+
+    /// The maximum number of nested calls, beyond which a `stack-overflow` error is raised
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Compile functions to machine code once they've been called `threshold` times, or
+    /// never if it's `None`. Defaults to `RUSP_JIT_THRESHOLD`, or 100 calls.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, threshold: Option<u32>) {
+        self.jit_threshold = threshold;
+    }
+
+    /// Let the stack grow up to `slots` values when it fills up, rather than raising a
+    /// `stack-overflow` error. By default it can't grow past its initial size.
+    pub fn set_max_stack_slots(&mut self, slots: usize) {
+        self.max_stack_slots = slots;
+    }
+
+    /// makes sure `needed` more values can be pushed, growing the stack if allowed
+    fn ensure_stack_space(&mut self, needed: usize) -> bool {
+        let required = self.stack.len() + needed;
+        let capacity = self.stack.capacity();
+        if required <= capacity {
+            return true;
+        }
+        if required > self.max_stack_slots {
+            return false;
+        }
+
+        let new_capacity = (capacity * 2).clamp(required, self.max_stack_slots);
+        let old_base = self.stack.grow(new_capacity);
+        let new_base = self.stack.as_mut_ptr();
+
+        // open upvalues are the only pointers into the running stack
+        let mut current = self.open_upvalues;
+        while !current.is_null() {
+            let upvalue = as_upvalue(current);
+            let offset = unsafe { upvalue.location.offset_from(old_base) };
+            upvalue.location = unsafe { new_base.offset(offset) };
+            current = upvalue.next;
+        }
+        true
+    }
+
+    fn stack_overflow(&mut self, function: &str) {
+        let message = format!("stack overflow in {}", function);
+        self.raise(ErrorValue::new("stack-overflow", message, SmallVal::Nil));
+    }
+
+    /// swaps the running execution state with the one saved in `state`
+    fn swap_state(&mut self, state: &mut ExecutionState) {
+        std::mem::swap(&mut self.stack, &mut state.stack);
+        std::mem::swap(&mut self.callframes, &mut state.callframes);
+        std::mem::swap(&mut self.handlers, &mut state.handlers);
+        std::mem::swap(&mut self.open_upvalues, &mut state.open_upvalues);
+        std::mem::swap(&mut self.ip, &mut state.ip);
+        self.entered_function();
+    }
+
+    /// Called when `ip` moves to code that might have been compiled, without a call
+    fn entered_function(&mut self) {
+        #[cfg(feature = "jit")]
+        {
+            self.jit_check = true;
+        }
+    }
+
+    fn swap_context(&mut self, co_ptr: *mut HeapObject) {
+        self.swap_state(&mut as_coroutine(co_ptr).state);
+    }
+
+    /// expects the stack to be [..., coroutine, value]
+    fn handle_resume(&mut self) {
+        let value = self.stack.pop().expect("expected a value to resume with");
+        let target = self.stack.pop().expect("expected a coroutine to resume");
+
+        let co_ptr = match target {
+            SmallVal::ObjectPtr(ptr)
+                if matches!(unsafe { &*ptr }.value, ObjectValue::Coroutine(_)) =>
+            {
+                ptr
+            }
+            got => {
+                let message = format!("cannot resume {}", got);
+                self.raise(ErrorValue::new("type-error", message, SmallVal::Nil));
+                return;
+            }
+        };
+
+        let co = as_coroutine(co_ptr);
+        let status = co.status;
+        match status {
+            CoroutineStatus::Dead if co.generator => {
+                self.stack.push(SmallVal::Nil);
+                self.advance();
+                return;
+            }
+            CoroutineStatus::Dead => {
+                let message = format!("cannot resume dead {}", unsafe { &*co_ptr });
+                self.raise(ErrorValue::new("dead-coroutine", message, SmallVal::Nil));
+                return;
+            }
+            CoroutineStatus::Running => {
+                let message = format!("{} is already running", unsafe { &*co_ptr });
+                self.raise(ErrorValue::new("coroutine-running", message, SmallVal::Nil));
+                return;
+            }
+            CoroutineStatus::Fresh | CoroutineStatus::Suspended => {}
+        }
+
+        co.status = CoroutineStatus::Running;
+        self.swap_context(co_ptr);
+        self.coroutines.push(co_ptr);
+
+        if status == CoroutineStatus::Fresh {
+            // the first value resumed with is the argument, if the function takes one
+            let function = as_coroutine(co_ptr).function.clone();
+            let arity = match &function {
+                SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+                    ObjectValue::Closure(c) => c.f.arity,
+                    _ => 0,
+                },
+                _ => 0,
+            };
+            self.stack.push(function);
+            if arity == 1 {
+                self.stack.push(value);
+            }
+            self.call_value(arity);
+        } else {
+            // as if `yield` evaluated to `value`
+            self.stack.push(value);
+            self.advance();
+        }
+    }
+
+    fn handle_yield(&mut self) {
+        let value = self.stack.pop().expect("expected a value to yield");
+        let Some(co_ptr) = self.coroutines.pop() else {
+            let message = "yield outside of a coroutine".to_string();
+            self.raise(ErrorValue::new(
+                "yield-outside-coroutine",
+                message,
+                SmallVal::Nil,
+            ));
+            return;
         };
+        as_coroutine(co_ptr).status = CoroutineStatus::Suspended;
+        self.swap_context(co_ptr);
+
+        // as if `resume` evaluated to `value`
+        self.stack.push(value);
+        self.advance();
+    }
+
+    /// marks the running coroutine as dead and switches back to whoever resumed it
+    fn finish_coroutine(&mut self) -> *mut HeapObject {
+        let co_ptr = self.coroutines.pop().expect("expected a running coroutine");
+        // nothing on the coroutine's stack outlives it
+        let stack_start = self.stack.as_mut_ptr();
+        self.close_upvalues(stack_start);
+        as_coroutine(co_ptr).status = CoroutineStatus::Dead;
+        self.swap_context(co_ptr);
+        co_ptr
+    }
+
+    /// the number of instructions each task runs for before being preempted
+    pub fn set_time_slice(&mut self, instructions: usize) {
+        self.time_slice = instructions.max(1);
+    }
+
+    /// Randomise the length of each time slice, so that scripts see a variety of interleavings.
+    /// The schedule is the same for every run with the same seed.
+    pub fn set_scheduler_seed(&mut self, seed: u64) {
+        // xorshift gets stuck on 0
+        self.scheduler_rng = Some(seed ^ 0x9E37_79B9_7F4A_7C15);
+    }
+
+    fn next_time_slice(&mut self) -> usize {
+        match &mut self.scheduler_rng {
+            None => self.time_slice,
+            Some(x) => {
+                *x ^= *x << 13;
+                *x ^= *x >> 7;
+                *x ^= *x << 17;
+                1 + (*x % (2 * self.time_slice as u64)) as usize
+            }
+        }
+    }
+
+    /// creates a task to call `function` when it gets its first turn
+    pub(crate) fn spawn_task(&mut self, function: SmallVal) -> *mut HeapObject {
+        if self.current_task.is_null() {
+            // the program so far becomes the main task
+            let mut main = Task::new(SmallVal::Nil);
+            main.status = TaskStatus::Running;
+            main.started = true;
+            let main_ptr = unsafe { self.allocate_value(ObjectValue::Task(main)) };
+            self.current_task = main_ptr;
+            self.main_task = main_ptr;
+            self.slice_remaining = self.next_time_slice();
+        }
+        let task_ptr = unsafe { self.allocate_value(ObjectValue::Task(Task::new(function))) };
+        self.run_queue.push_back(task_ptr);
+        task_ptr
+    }
+
+    /// Called by `recv` on an empty channel. The current task is suspended once the builtin returns.
+    pub(crate) fn block_on(&mut self, channel: *mut HeapObject) -> Result<(), ErrorValue> {
+        if self.current_task.is_null() {
+            let message = "recv on an empty channel, with no other tasks to send to it".to_string();
+            return Err(ErrorValue::new("deadlock", message, SmallVal::Nil));
+        }
+        self.blocked_on = Some(channel);
+        Ok(())
+    }
 
-        for builtin in builtins_comp::BUILT_INS.into_iter() {
-            let name = builtin.name.to_string();
-            let obj_ptr = unsafe { vm.allocate_value(ObjectValue::BuiltIn(builtin.clone())) };
-            vm.globals.insert(name, SmallVal::ObjectPtr(obj_ptr));
+    fn is_runnable(task_ptr: *mut HeapObject) -> bool {
+        match as_task(task_ptr).status {
+            TaskStatus::Ready => true,
+            TaskStatus::Blocked(channel) => !as_channel(channel).buffer.is_empty(),
+            TaskStatus::Running | TaskStatus::Done => false,
         }
+    }
 
-        vm
+    /// takes the first task in the run queue that can make progress
+    fn next_runnable_task(&mut self) -> Option<*mut HeapObject> {
+        let idx = self
+            .run_queue
+            .iter()
+            .position(|task| Self::is_runnable(*task))?;
+        self.run_queue.remove(idx)
+    }
+
+    /// Makes `task_ptr` the running task. `ip` must be on the next instruction to run, or the
+    /// last byte of the call to `recv` if the current task is blocked.
+    fn switch_to_task(&mut self, task_ptr: *mut HeapObject) {
+        let current = as_task(self.current_task);
+        self.swap_state(&mut current.state);
+        std::mem::swap(&mut self.coroutines, &mut current.coroutines);
+
+        let task = as_task(task_ptr);
+        self.swap_state(&mut task.state);
+        std::mem::swap(&mut self.coroutines, &mut task.coroutines);
+        self.current_task = task_ptr;
+        self.slice_remaining = self.next_time_slice();
+
+        let status = task.status;
+        if status == TaskStatus::Done {
+            // only the main task, waiting at the end of the program
+            return;
+        }
+        task.status = TaskStatus::Running;
+        match status {
+            TaskStatus::Ready if !task.started => {
+                task.started = true;
+                self.stack.push(task.function.clone());
+                self.call_value(0);
+            }
+            TaskStatus::Ready => {}
+            TaskStatus::Blocked(channel) => {
+                // finish the `recv` it was blocked on
+                let value = as_channel(channel)
+                    .buffer
+                    .pop_front()
+                    .expect("expected a value on the channel");
+                self.stack.push(value);
+                self.advance();
+            }
+            TaskStatus::Running | TaskStatus::Done => unreachable!(),
+        }
     }
 
-    fn gc(&mut self) {
-        #[cfg(feature = "gc_debug")]
-        println!("gc running");
+    fn preempt(&mut self) {
+        match self.next_runnable_task() {
+            Some(next) => {
+                as_task(self.current_task).status = TaskStatus::Ready;
+                self.run_queue.push_back(self.current_task);
+                self.switch_to_task(next);
+            }
+            None => self.slice_remaining = self.next_time_slice(),
+        }
     }
 
-    fn frame(&self) -> &CallFrame {
-        self.callframes.last().expect("expected a call frame")
+    fn block_current_task(&mut self, channel: *mut HeapObject) {
+        as_task(self.current_task).status = TaskStatus::Blocked(channel);
+        self.run_queue.push_back(self.current_task);
+        self.schedule_next();
     }
 
-    pub fn run(&mut self, chunk: BytecodeChunk) {
-        // these are kind of like a cache of `chunk`
-        // not sure I like this pattern though
-        self.ip = chunk.code.as_ptr();
-        self.chunk_constants = chunk.constants;
+    /// called at the end of a spawned task's function
+    fn finish_task(&mut self) {
+        // nothing on the task's stack outlives it
+        let stack_start = self.stack.as_mut_ptr();
+        self.close_upvalues(stack_start);
+        as_task(self.current_task).status = TaskStatus::Done;
+        self.schedule_next();
+    }
 
-        loop {
-            let byte: Op = unsafe { *self.ip }.try_into().unwrap();
-            match byte {
-                Op::Constant => self.handle_constant(),
-                Op::Add => self.handle_add(),
-                Op::Sub => self.handle_sub(),
-                Op::Mul => self.handle_mul(),
-                Op::Div => self.handle_div(),
-                Op::GT => self.handle_gt(),
-                Op::LT => self.handle_lt(),
-                Op::GTE => self.handle_gte(),
-                Op::LTE => self.handle_lte(),
-                Op::Jump => self.handle_jump(),
-                Op::CondJump => self.handle_cond_jump(),
-                Op::FuncCall => self.handle_func_call(),
-                Op::DeclareGlobal => self.handle_declare_global(),
-                Op::ReferenceGlobal => self.handle_reference_global(),
-                Op::Print => self.handle_print(),
-                Op::ReferenceLocal => self.handle_reference_local(),
-                Op::Return => self.handle_return(),
-                // Op::Quote => self.handle_quote(),
-                Op::Define => self.handle_local_define(),
-                Op::DebugEnd => return,
-                Op::Closure => self.handle_closure(),
-                Op::ReferenceUpvalue => self.handle_reference_upvalue(),
-                Op::SetUpvalue => self.handle_set_upvalue(),
-                Op::Pop => self.handle_pop(),
-                Op::CloseUpvalue => unimplemented!(),
-                Op::SetLocal => self.handle_set_local(),
+    /// switches away from a task that can't continue
+    fn schedule_next(&mut self) {
+        if let Some(next) = self.next_runnable_task() {
+            return self.switch_to_task(next);
+        }
+        let main_ptr = self.main_task;
+        if as_task(main_ptr).status == TaskStatus::Done {
+            // the program is over, and the tasks that are still blocked never will be
+            return self.switch_to_task(main_ptr);
+        }
+
+        // every task is waiting on another, so the main task gets an error instead
+        self.run_queue.retain(|task| *task != main_ptr);
+        as_task(main_ptr).status = TaskStatus::Ready;
+        self.switch_to_task(main_ptr);
+        let message = "all tasks are blocked on empty channels".to_string();
+        self.raise(ErrorValue::new("deadlock", message, SmallVal::Nil));
+    }
+
+    /// Called when the main task reaches the end of the program.
+    /// Spawned tasks get to finish first, so returns whether there's nothing left to run.
+    fn finish_main_task(&mut self) -> bool {
+        if self.current_task.is_null() {
+            return true;
+        }
+        match self.next_runnable_task() {
+            Some(next) => {
+                as_task(self.main_task).status = TaskStatus::Done;
+                self.switch_to_task(next);
+                false
             }
+            None => true,
         }
     }
 
@@ -413,13 +1892,13 @@ impl VM {
         self.advance();
     }
 
-    fn handle_reference_upvalue(&mut self) {
-        let upvalue_idx = self.consume_next_byte_as_byte() as usize;
+    fn handle_reference_upvalue(&mut self, upvalue_idx: u8) {
+        let upvalue_idx = upvalue_idx as usize;
         let ptr = self.frame().closure.upvalues[upvalue_idx];
         let val = match &unsafe { &*ptr }.value {
             ObjectValue::UpValue(uv) => {
                 println!("found upvalue {}", upvalue_idx);
-                unsafe { &*uv.location }.clone()
+                unsafe { &*uv.location }.to_value()
                 // ALERT ALERT ALERT           ^^V^^
                 // this is wrong ----------------'
                 // ALERT ALERT ALERT
@@ -431,32 +1910,27 @@ impl VM {
         self.advance();
     }
 
-    fn handle_set_upvalue(&mut self) {
+    fn handle_set_upvalue(&mut self, idx: u8) {
         let value = self.stack.pop().expect("expected value");
-        let idx = self.consume_next_byte_as_byte() as usize;
-        let ptr = self.frame().closure.upvalues[idx];
+        let ptr = self.frame().closure.upvalues[idx as usize];
         match &unsafe { &*ptr }.value {
             ObjectValue::UpValue(uv) => {
-                *unsafe { &mut *uv.location } = value;
+                *unsafe { &mut *uv.location } = Slot::from_value(value);
             }
             _ => panic!("expected upvalue"),
         };
         self.advance();
     }
 
-    fn handle_set_local(&mut self) {
+    fn handle_set_local(&mut self, local_idx: u8) {
         let val = self.stack.pop().expect("expected value");
-        let local_idx = self.consume_next_byte_as_byte();
-        *self.local_var_mut(local_idx) = val;
+        *self.local_var_mut(local_idx) = Slot::from_value(val);
+        self.advance();
     }
 
-    /// expects the next byte to be a constants array index to a closure object
-    fn handle_closure(&mut self) {
-        let constant_idx = unsafe {
-            self.ip = self.ip.add(1);
-            *self.ip
-        };
-
+    /// `constant_idx` is the closure's function, and `captures` where its captures start in
+    /// the chunk's `Decoded::captures`
+    fn handle_closure(&mut self, constant_idx: u8, captures: u32) {
         let mut closure = match self.get_constant(constant_idx as usize) {
             ConstantValue::Object(o) => match o {
                 ConstantObject::Closure(c) => c.clone(),
@@ -468,8 +1942,8 @@ impl VM {
         // upvalues is empty right now, fill it:
         for i in 0..closure.num_upvalues {
             // thought: might be nice to store "num upvalues" as an operand to `Op::Closure` instead of preallocating
-            let capture_type: CaptureType = self.consume_next_byte_as_byte().try_into().unwrap();
-            let upvalue_index = self.consume_next_byte_as_byte();
+            let (capture_type, upvalue_index) =
+                self.constants_chunk().decoded.captures[captures as usize + i];
             let uv_ptr = match capture_type {
                 SurroundingLocal => {
                     let ptr = {
@@ -500,7 +1974,7 @@ impl VM {
     }
 
     // fn capture_upvalue(&mut self, idx_in_stack: usize) -> *mut HeapObject {
-    fn capture_upvalue(&mut self, stack_local: *mut SmallVal) -> *mut HeapObject {
+    fn capture_upvalue(&mut self, stack_local: *mut Slot) -> *mut HeapObject {
         println!("capturing upvalue");
         let mut previous_upvalue: *mut HeapObject = std::ptr::null_mut();
         let mut current = self.open_upvalues;
@@ -545,6 +2019,8 @@ impl VM {
     // }
 
     fn handle_return(&mut self) {
+        self.trace(|hook, context| hook.on_return(context));
+        self.entered_function();
         let CallFrame {
             closure,
             return_address,
@@ -554,12 +2030,34 @@ impl VM {
             .pop()
             .expect("expected a call frame to return from");
 
+        if self.callframes.is_empty() && !self.coroutines.is_empty() {
+            // returning from the bottom of a coroutine
+            let return_val = self.stack.pop().expect("expected a return value");
+            let co_ptr = self.finish_coroutine();
+            let result = if as_coroutine(co_ptr).generator {
+                SmallVal::Nil
+            } else {
+                return_val
+            };
+            self.stack.push(result);
+            self.advance();
+            return;
+        }
+
+        if self.callframes.is_empty() && self.current_task != self.main_task {
+            // returning from the bottom of a spawned task, nothing uses the result
+            self.stack.pop().expect("expected a return value");
+            self.finish_task();
+            return;
+        }
+
         self.ip = return_address;
 
         // clean up the stack
         let return_val = self.stack.pop().expect("expected a return value");
 
-        self.close_upvalues(stack_frame_start as *mut SmallVal);
+        let frame_start = unsafe { self.stack.as_mut_ptr().add(stack_frame_start as usize) };
+        self.close_upvalues(frame_start);
 
         // pop the           arguments,       locals, and      function
         self.stack.pop_n(closure.f.arity + closure.f.num_locals + 1);
@@ -576,38 +2074,75 @@ impl VM {
         self.advance();
     }
 
-    fn close_upvalues(&mut self, last: *mut SmallVal) {
+    fn close_upvalues(&mut self, last: *mut Slot) {
         while !self.open_upvalues.is_null() && as_upvalue(self.open_upvalues).location >= last {
             unsafe {
                 // img:  https://craftinginterpreters.com/image/closures/closing.png
                 let upvalue = as_upvalue(self.open_upvalues);
-                upvalue.closed_val = Some(upvalue.location.read());
+                upvalue.closed_val = Some((*upvalue.location).clone());
                 upvalue.location = upvalue.closed_val.as_mut().unwrap();
                 self.open_upvalues = upvalue.next;
             }
         }
     }
 
-    fn handle_reference_local(&mut self) {
-        let offset = self.consume_next_byte_as_byte();
+    fn handle_reference_local(&mut self, offset: u8) {
+        // copied as it's stored, without unpacking it
         let value = self.local_var_mut(offset).clone();
-        self.stack.push(value);
+        self.stack.0.push(value);
         self.advance();
     }
 
     /// includes function and arguments
     /// [function, arg1, arg2, ... argN, local1, ...]
-    fn local_var_mut(&mut self, n: u8) -> &mut SmallVal {
+    fn local_var_mut(&mut self, n: u8) -> &mut Slot {
         let global_offset = (self.frame().start_idx + n as i32) as usize;
 
         self.stack.at_mut(global_offset).unwrap()
     }
 
-    fn handle_func_call(&mut self) {
+    fn handle_func_call(&mut self, given_arity: u8, cache: u32) {
         // expects the stack to be:
         // [..., function, arg1, arg2, ... argN]
         // and the operand to be the arity of the function, so we can lookup the function and args
-        let given_arity = self.consume_next_byte_as_byte() as usize;
+        let given_arity = given_arity as usize;
+        match self.call_target(given_arity, cache as usize) {
+            CallTarget::Closure(closure) => self.enter_closure(unsafe { &*closure }),
+            CallTarget::BuiltIn(builtin) => self.call_builtin(unsafe { &*builtin }, given_arity),
+            CallTarget::Uncached => self.call_value(given_arity),
+        }
+    }
+
+    /// What the call site with the call cache `cache` is calling. The callee is only looked
+    /// at when it isn't the one the site called last time, or the cache has been invalidated.
+    fn call_target(&mut self, given_arity: usize, cache: usize) -> CallTarget {
+        let Some(SmallVal::ObjectPtr(callee)) = self.stack.peek_back(given_arity) else {
+            return CallTarget::Uncached;
+        };
+        let entry = &self.constants_chunk().decoded.call_caches.0[cache];
+        let mut cached = entry.get();
+        if cached.callee != callee || cached.epoch != self.call_cache_epoch {
+            cached = CallCache {
+                callee,
+                epoch: self.call_cache_epoch,
+                target: match &unsafe { &*callee }.value {
+                    ObjectValue::Closure(closure) if closure.f.arity == given_arity => {
+                        CallTarget::Closure(closure)
+                    }
+                    ObjectValue::BuiltIn(builtin) if builtin.accepts(given_arity) => {
+                        CallTarget::BuiltIn(builtin)
+                    }
+                    _ => CallTarget::Uncached,
+                },
+            };
+            entry.set(cached);
+        }
+        cached.target
+    }
+
+    /// calls the function below the top `given_arity` values on the stack.
+    /// `ip` should be on the calling instruction, which is where the call returns to
+    fn call_value(&mut self, given_arity: usize) {
         // let callframe = self.frame();
 
         match self
@@ -619,37 +2154,109 @@ impl VM {
                 ObjectValue::Closure(func_obj) => {
                     // ObjectValue::Function(func_obj) => {
                     if func_obj.f.arity != given_arity {
-                        self.runtime_error(
-                            format!(
-                                "arity mismatch: Expected {} arguments, got {}",
-                                func_obj.f.arity, given_arity
-                            )
-                            .as_str(),
-                        )
+                        let message = format!(
+                            "arity mismatch: Expected {} arguments, got {}",
+                            func_obj.f.arity, given_arity
+                        );
+                        self.raise(ErrorValue::new("arity-error", message, SmallVal::Nil));
+                        return;
                     }
-
-                    self.callframes.push(self.make_callframe(func_obj.clone()));
-
-                    // set to the start of the function
-                    self.ip = func_obj.f.bytecode.code.as_ptr();
-
-                    // allocate space for the locals so they don't get overwritten
-                    // args are already at the top of the stack
-                    self.stack.ptr += func_obj.f.num_locals as i32;
+                    self.enter_closure(func_obj);
                 }
                 ObjectValue::BuiltIn(b) => {
-                    let args = self.stack.pop_n(given_arity).unwrap();
-                    let result = (b.func)(args, self);
-                    self.stack.pop(); // pop off function too
-                    self.stack.push(result);
-                    self.advance();
+                    if !b.accepts(given_arity) {
+                        let expected = match b.optional {
+                            0 => b.arity.to_string(),
+                            optional => format!("{} to {}", b.arity, b.arity + optional),
+                        };
+                        let message = format!(
+                            "{} expects {} arguments, got {}",
+                            b.name, expected, given_arity
+                        );
+                        self.raise(ErrorValue::new("arity-error", message, SmallVal::Nil));
+                        return;
+                    }
+                    self.call_builtin(b, given_arity);
+                }
+                ObjectValue::Continuation(continuation) => {
+                    if given_arity != 1 {
+                        let message = format!("continuations take 1 argument, got {}", given_arity);
+                        self.raise(ErrorValue::new("arity-error", message, SmallVal::Nil));
+                        return;
+                    }
+                    let value = self.stack.pop().unwrap();
+                    self.resume_continuation(continuation, value);
+                }
+                got => {
+                    let message = format!("{} is not callable", got);
+                    self.raise(ErrorValue::new("type-error", message, SmallVal::Nil));
                 }
-                _ => panic!("expected ObjectValue::Function"),
             },
-            got => panic!("expected StackValue::Object, got {:?}", got),
+            got => {
+                let message = format!("{} is not callable", got);
+                self.raise(ErrorValue::new("type-error", message, SmallVal::Nil));
+            }
         };
     }
 
+    /// calls the closure below its arguments on the stack, which has the right arity
+    fn enter_closure(&mut self, func_obj: &Closure) {
+        if self.callframes.len() >= self.max_call_depth
+            || !self.ensure_stack_space(func_obj.f.num_locals + STACK_HEADROOM)
+        {
+            self.stack_overflow(&func_obj.f.name);
+            return;
+        }
+
+        if self.trace_hook.is_some() {
+            let callee = func_obj.f.name.clone();
+            self.trace(|hook, context| hook.on_call(context, &callee));
+        }
+        self.callframes.push(self.make_callframe(func_obj.clone()));
+        #[cfg(feature = "jit")]
+        self.count_call(&func_obj.f.bytecode);
+
+        // set to the start of the function
+        self.ip = func_obj.f.bytecode.decoded.instructions.as_ptr();
+
+        // allocate space for the locals so they don't get overwritten
+        // args are already at the top of the stack
+        // (they're initialised so the GC never sees stale values)
+        for _ in 0..func_obj.f.num_locals {
+            self.stack.push(SmallVal::Nil);
+        }
+    }
+
+    fn call_builtin(&mut self, builtin: &BuiltIn, given_arity: usize) {
+        if self.trace_hook.is_some() {
+            self.trace(|hook, context| hook.on_call(context, builtin.name));
+        }
+        let args = self.stack.pop_n(given_arity).unwrap();
+        match (builtin.func)(args, self) {
+            Ok(result) => {
+                if self.trace_hook.is_some() {
+                    // traced as if the builtin had a frame of its own, so calls and returns pair up
+                    self.trace(|hook, context| {
+                        hook.on_return(&TraceContext {
+                            call_depth: context.call_depth + 1,
+                            function: builtin.name,
+                            ..context.clone()
+                        })
+                    });
+                }
+                self.stack.pop(); // pop off function too
+                if let Some(channel) = self.blocked_on.take() {
+                    // the result is pushed when the task is woken
+                    self.block_current_task(channel);
+                    return;
+                }
+                self.stack.push(result);
+                self.advance();
+            }
+            Err(error) => self.raise(error),
+        }
+    }
+
     fn make_callframe(&self, closure: Closure) -> CallFrame {
         let arity = closure.f.arity;
         let stack_frame_start = self.stack.ptr - arity as i32;
@@ -679,185 +2286,162 @@ impl VM {
         self.advance();
     }
 
-    fn handle_reference_global(&mut self) {
-        let name = match self.consume_next_byte_as_constant() {
-            SmallVal::ObjectPtr(ptr) => match &unsafe { &*ptr }.value {
-                ObjectValue::String(s) => s,
-                got => panic!(
-                    "expected ObjectPtr to be String for reference, got {:?}",
-                    got
-                ),
-            },
-            constant_val => panic!(
-                "expected constant to be ObjectPtr(String) for reference, got constant {:?}",
-                constant_val
-            ),
+    fn handle_reference_global(&mut self, slot: usize) {
+        let Some(global) = self.globals.at(slot) else {
+            let message = format!("undefined global variable: {}", self.globals.name(slot));
+            self.raise(ErrorValue::new("undefined-global", message, SmallVal::Nil));
+            return;
         };
-        let global = self.globals.get(name).unwrap_or_else(|| {
-            self.runtime_error(format!("undefined global variable: {}", name).as_str());
-        });
         self.stack.push(global.clone());
         self.advance();
     }
 
-    fn handle_declare_global(&mut self) {
+    fn handle_declare_global(&mut self, slot: usize) {
         let value = self.stack.pop().unwrap();
-        let name = self.consume_next_byte_as_constant();
-        match name {
-            SmallVal::ObjectPtr(ptr) => match &unsafe { &*ptr }.value {
-                ObjectValue::String(s) => {
-                    self.globals.insert(s.clone(), value);
-                }
-                _ => panic!("expected string"),
-            },
-            _ => panic!("expected string"),
+        if Op::for_operator(self.globals.name(slot)).is_some() {
+            self.operators_rebound = true;
         }
+        self.globals.set(slot, value);
+        self.call_cache_epoch += 1;
         self.advance();
     }
 
-    fn handle_local_define(&mut self) {
+    fn handle_local_define(&mut self, local_idx: u8) {
         let value = self.stack.pop().unwrap();
-        let local_idx = self.consume_next_byte_as_byte();
-        *self.local_var_mut(local_idx) = value;
-        self.advance();
-    }
-
-    fn handle_div(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Integer(a / b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Float(a / b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Float(a as f64 / b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Float(a / b as f64),
-            _ => panic!("expected integer or float"),
-        });
-        self.advance();
-    }
-
-    fn handle_mul(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Integer(a * b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Float(a * b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Float(a as f64 * b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Float(a * b as f64),
-            _ => panic!("expected integer or float"),
-        });
+        *self.local_var_mut(local_idx) = Slot::from_value(value);
         self.advance();
     }
 
-    fn handle_sub(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Integer(a - b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Float(a - b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Float(a as f64 - b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Float(a - b as f64),
-            _ => panic!("expected integer or float"),
-        });
-        self.advance();
-    }
+    /// The arithmetic and comparison ops. Two integers are handled here, anything else goes
+    /// through the global the op stands for, so errors (and rebound globals) behave exactly as
+    /// they would for a call.
+    fn handle_operator(&mut self, op: Op) {
+        let b = self.stack.pop().expect("expected two operands");
+        let a = self.stack.pop().expect("expected two operands");
+        if let Some(result) = self.integer_operator(op, &a, &b) {
+            self.stack.push(result);
+            self.advance();
+            return;
+        }
 
-    fn handle_gt(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Bool(a > b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Bool(a > b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Bool(a as f64 > b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Bool(a > b as f64),
-            _ => panic!("expected integer or float"),
-        });
-        self.advance();
+        let name = op.operator_name().expect("expected an operator");
+        let Some(function) = self.globals.get(name).cloned() else {
+            let message = format!("undefined global variable: {}", name);
+            self.raise(ErrorValue::new("undefined-global", message, SmallVal::Nil));
+            return;
+        };
+        self.stack.push(function);
+        self.stack.push(a);
+        self.stack.push(b);
+        self.call_value(2);
+    }
+
+    /// The result of `op` when both operands are integers and it can't fail, which is what
+    /// the builtin would return. `None` when the builtin has to be called.
+    fn integer_operator(&self, op: Op, a: &SmallVal, b: &SmallVal) -> Option<SmallVal> {
+        match (op, a, b) {
+            _ if self.operators_rebound => None,
+            (_, SmallVal::Integer(a), SmallVal::Integer(b)) => match op {
+                Op::Add => Some(SmallVal::Integer(a + b)),
+                Op::Sub => Some(SmallVal::Integer(a - b)),
+                Op::Mul => Some(SmallVal::Integer(a * b)),
+                Op::Div if *b != 0 => Some(SmallVal::Integer(a / b)),
+                Op::Equal => Some(SmallVal::Bool(a == b)),
+                Op::GT => Some(SmallVal::Bool(a > b)),
+                Op::LT => Some(SmallVal::Bool(a < b)),
+                Op::GTE => Some(SmallVal::Bool(a >= b)),
+                Op::LTE => Some(SmallVal::Bool(a <= b)),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
-    fn handle_lt(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Bool(a < b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Bool(a < b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Bool((a as f64) < b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Bool(a < b as f64),
-            _ => panic!("expected integer or float"),
-        });
-        self.advance();
+    /// Superinstructions skip the instructions they stand for when they can. When they can't,
+    /// they do what they've done so far, and carry on from the instruction that's left.
+    fn handle_local_local_operator(&mut self, a: u8, b: u8, op: Op) {
+        let a = self.local_var_mut(a).to_value();
+        let b = self.local_var_mut(b).to_value();
+        self.run_operator_after(a, b, op);
     }
 
-    fn handle_gte(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Bool(a >= b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Bool(a >= b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Bool(a as f64 >= b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Bool(a >= b as f64),
-            _ => panic!("expected integer or float"),
-        });
-        self.advance();
+    fn handle_local_integer_operator(&mut self, local: u8, op: Op, integer: i32) {
+        let a = self.local_var_mut(local).to_value();
+        self.run_operator_after(a, SmallVal::Integer(integer as i64), op);
     }
 
-    fn handle_lte(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Bool(a <= b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Bool(a <= b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Bool(a as f64 <= b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Bool(a <= b as f64),
-            _ => panic!("expected integer or float"),
-        });
-        self.advance();
+    /// the operator at the end of a superinstruction that's pushed `a` and `b`
+    fn run_operator_after(&mut self, a: SmallVal, b: SmallVal, op: Op) {
+        self.steps += 2;
+        if let Some(result) = self.integer_operator(op, &a, &b) {
+            self.stack.push(result);
+            self.ip = unsafe { self.ip.add(3) };
+            return;
+        }
+        self.stack.push(a);
+        self.stack.push(b);
+        self.ip = unsafe { self.ip.add(2) };
+        self.handle_operator(op);
     }
 
-    fn handle_add(&mut self) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        let result = match (a, b) {
-            (SmallVal::Integer(a), SmallVal::Integer(b)) => SmallVal::Integer(a + b),
-            (SmallVal::Float(a), SmallVal::Float(b)) => SmallVal::Float(a + b),
-            (SmallVal::Integer(a), SmallVal::Float(b)) => SmallVal::Float(a as f64 + b),
-            (SmallVal::Float(a), SmallVal::Integer(b)) => SmallVal::Float(a + b as f64),
-            _ => panic!("expected integer or float"),
-        };
-        self.stack.push(result);
+    fn handle_constant_call(&mut self, constant: u8, arity: u8, cache: u32) {
+        self.steps += 1;
+        let constant = self.constant(constant);
+        self.stack.push(constant);
         self.advance();
+        self.handle_func_call(arity, cache);
     }
 
-    fn handle_jump(&mut self) {
-        let offset = self.consume_next_byte_as_byte() as usize;
-        self.ip = unsafe { self.ip.add(offset) };
+    fn handle_jump(&mut self, delta: i32) {
+        self.ip = unsafe { self.ip.offset(delta as isize) };
     }
 
-    fn handle_cond_jump(&mut self) {
-        let mut offset = self.consume_next_byte_as_byte() as usize;
+    fn handle_cond_jump(&mut self, delta: i32) {
         let cond_val = self.stack.pop().unwrap();
-        if !cond_val.truthy() {
-            offset = 1;
-        };
-        self.ip = unsafe { self.ip.add(offset) };
+        let taken = cond_val.truthy();
+        self.trace(|hook, context| hook.on_branch(context, taken));
+        let delta = if taken { delta } else { 1 };
+        self.ip = unsafe { self.ip.offset(delta as isize) };
     }
 
-    fn handle_constant(&mut self) {
-        let constant = self.consume_next_byte_as_constant();
+    fn handle_constant(&mut self, idx: u8) {
+        let constant = self.constant(idx);
         self.stack.push(constant);
         self.advance();
     }
 
+    /// the chunk whose constants the running code refers to
+    fn constants_chunk(&self) -> &BytecodeChunk {
+        match self.callframes.last() {
+            Some(frame) => &frame.closure.f.bytecode,
+            None => &self.chunk,
+        }
+    }
+
     fn get_constant(&self, idx: usize) -> &ConstantValue {
-        if let Some(frame) = &self.callframes.last() {
-            return &frame.closure.f.bytecode.constants[idx];
-        };
-        &self.chunk_constants[idx]
+        &self.constants_chunk().constants[idx]
     }
 
-    fn consume_next_byte_as_constant(&mut self) -> SmallVal {
-        let constant_idx = self.consume_next_byte_as_byte();
-        let constant = self.get_constant(constant_idx as usize).clone();
-        self.constant_to_value(constant)
+    /// Heap constants are made the first time they're used, and then shared, see `Literals`
+    fn constant(&mut self, constant_idx: u8) -> SmallVal {
+        let constant_idx = constant_idx as usize;
+        if let Some(value) = self.constants_chunk().literals.get(constant_idx) {
+            return value;
+        }
+        let constant = self.get_constant(constant_idx).clone();
+        let literal = matches!(
+            constant,
+            ConstantValue::Object(ConstantObject::String(_) | ConstantObject::Symbol(_))
+                | ConstantValue::List(_)
+                | ConstantValue::Quote(_)
+        );
+        let value = self.constant_to_value(constant);
+        if literal {
+            self.constants_chunk()
+                .literals
+                .set(constant_idx, value.clone());
+        }
+        value
     }
 
     fn constant_to_value(&mut self, constant: ConstantValue) -> SmallVal {
@@ -866,11 +2450,14 @@ impl VM {
             ConstantValue::Float(f) => SmallVal::Float(f),
             ConstantValue::Boolean(b) => SmallVal::Bool(b),
             ConstantValue::Nil => SmallVal::Nil,
+            ConstantValue::Object(ConstantObject::Symbol(s)) => {
+                SmallVal::ObjectPtr(self.intern(&s))
+            }
             ConstantValue::Object(value) => {
                 let obj_ptr = unsafe {
                     self.allocate_value(match value {
                         ConstantObject::String(s) => ObjectValue::String(s),
-                        ConstantObject::Symbol(s) => ObjectValue::Symbol(s),
+                        ConstantObject::Symbol(_) => unreachable!("symbols are interned"),
                         ConstantObject::Closure(c) => ObjectValue::Closure(c),
                     })
                 };
@@ -913,6 +2500,16 @@ impl VM {
         SmallVal::ObjectPtr(cons_cell_ptr)
     }
 
+    /// builds a proper list, ending in null like quoted lists do
+    pub(crate) fn allocate_list_of(&mut self, items: Vec<SmallVal>) -> SmallVal {
+        let mut list: *mut HeapObject = std::ptr::null_mut();
+        for item in items.into_iter().rev() {
+            let item_ptr = self.val_to_obj(item);
+            list = unsafe { self.allocate_value(ObjectValue::ConsCell(ConsCell(item_ptr, list))) };
+        }
+        SmallVal::ObjectPtr(list)
+    }
+
     fn val_to_obj(&mut self, val: SmallVal) -> *mut HeapObject {
         match val {
             SmallVal::Integer(_) | SmallVal::Float(_) | SmallVal::Bool(_) | SmallVal::Nil => unsafe {
@@ -923,21 +2520,25 @@ impl VM {
         }
     }
 
-    fn consume_next_byte_as_byte(&mut self) -> u8 {
+    fn advance(&mut self) {
         unsafe {
-            self.advance();
-            *self.ip
+            self.ip = self.ip.add(1);
         }
     }
 
-    fn advance(&mut self) {
-        unsafe {
-            self.ip = self.ip.add(1);
+    /// The symbol called `name`, which is only allocated the first time it's asked for
+    pub fn intern(&mut self, name: &str) -> *mut HeapObject {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
         }
+        let symbol = unsafe { self.allocate_value(ObjectValue::Symbol(name.to_string())) };
+        self.symbols.insert(name.to_string(), symbol);
+        symbol
     }
 
     pub unsafe fn allocate_value(&mut self, obj_value: ObjectValue) -> *mut HeapObject {
-        self.gc();
+        // collection happens between instructions rather than here, as values allocated
+        // mid-instruction might not be reachable from any root yet
 
         // manually allocate the value on the heap
         // let obj_val_ptr = Box::into_raw(Box::new(obj_value.clone())); // todo remove
@@ -967,19 +2568,138 @@ impl VM {
 
         let obj_ptr = alloc(Layout::new::<HeapObject>()) as *mut HeapObject;
 
+        let size = std::mem::size_of::<HeapObject>() + obj_value.owned_size();
         let obj = HeapObject {
             next: self.heap,
             // value: obj_val_ptr,
             value: obj_value,
+            marked: false,
+            size,
         };
 
         obj_ptr.write(obj);
 
         self.heap = obj_ptr;
+        self.track_allocation(&*obj_ptr);
+        if self.trace_hook.is_some() {
+            let kind = (*obj_ptr).value.kind();
+            self.trace(|hook, context| hook.on_alloc(context, kind, size));
+        }
 
         obj_ptr
     }
 
+    /// Watch the VM run, or stop watching with `None`. See `TraceHook`.
+    pub fn set_trace_hook(&mut self, hook: Option<Box<dyn TraceHook>>) {
+        self.trace_hook = hook;
+        self.trace_position = None;
+    }
+
+    /// Calls the trace hook, if there is one, with the last instruction dispatched
+    fn trace(&mut self, event: impl FnOnce(&mut dyn TraceHook, &TraceContext)) {
+        let Some((op, offset)) = self.trace_position else {
+            return;
+        };
+        // taken for the duration of the call so the context can borrow the VM
+        if let Some(mut hook) = self.trace_hook.take() {
+            let context = TraceContext {
+                op,
+                offset,
+                stack_depth: self.stack.len(),
+                call_depth: self.callframes.len(),
+                function: self.current_function(),
+                chunk: self.current_chunk(),
+            };
+            event(hook.as_mut(), &context);
+            self.trace_hook = Some(hook);
+        }
+    }
+
+    /// The chunk that the next instruction belongs to
+    pub fn current_chunk(&self) -> &BytecodeChunk {
+        match self.callframes.last() {
+            Some(frame) => self.frame_chunk(frame),
+            None => &self.chunk,
+        }
+    }
+
+    /// The chunk that `ip` points into while `frame` is running.
+    /// Frames hold a copy of the closure, but calls run the code of the closure on the stack.
+    fn frame_chunk<'a>(&'a self, frame: &'a CallFrame) -> &'a BytecodeChunk {
+        if let Some(SmallVal::ObjectPtr(ptr)) = self.stack.at(frame.start_idx as usize) {
+            if let ObjectValue::Closure(closure) = &unsafe { &*ptr }.value {
+                return &closure.f.bytecode;
+            }
+        }
+        &frame.closure.f.bytecode
+    }
+
+    /// The name of the function that the next instruction belongs to
+    pub fn current_function(&self) -> &str {
+        match self.callframes.last() {
+            Some(frame) => &frame.closure.f.name,
+            None => "<top level>",
+        }
+    }
+
+    /// The offset of the next instruction in `current_chunk`
+    pub fn ip_offset(&self) -> usize {
+        self.current_chunk().decoded.offset_of(self.ip)
+    }
+
+    /// The call frames of whatever is running, innermost first.
+    /// The top level of the program is included unless a coroutine or spawned task is running.
+    pub fn backtrace(&self) -> Vec<FrameInfo> {
+        let mut frames = vec![];
+        // each frame is paused at the instruction that called the frame above it
+        let mut ip = self.ip;
+        for frame in self.callframes.iter().rev() {
+            let chunk = self.frame_chunk(frame);
+            let offset = chunk.decoded.offset_of(ip);
+            let start = frame.start_idx as usize;
+            let num_slots = frame.closure.f.arity + frame.closure.f.num_locals;
+            let locals = (1..=num_slots)
+                // locals that haven't been defined yet might not have a slot
+                .filter(|slot| start + slot < self.stack.len())
+                .map(|slot| {
+                    let name = match chunk.debug.locals.get(slot - 1) {
+                        Some(name) => name.clone(),
+                        None => format!("#{slot}"),
+                    };
+                    (name, self.stack.at(start + slot).unwrap())
+                })
+                .collect();
+            let upvalues = frame
+                .closure
+                .upvalues
+                .iter()
+                .map(|ptr| match &unsafe { &**ptr }.value {
+                    ObjectValue::UpValue(uv) => unsafe { &*uv.location }.to_value(),
+                    got => panic!("expected upvalue, got {got}"),
+                })
+                .collect();
+            frames.push(FrameInfo {
+                function: frame.closure.f.name.clone(),
+                offset,
+                line: chunk.line_at(offset),
+                locals,
+                upvalues,
+            });
+            ip = frame.return_address;
+        }
+        if self.coroutines.is_empty() && self.current_task == self.main_task {
+            let offset = self.chunk.decoded.offset_of(ip);
+            frames.push(FrameInfo {
+                function: "<top level>".to_string(),
+                offset,
+                line: self.chunk.line_at(offset),
+                locals: vec![],
+                upvalues: vec![],
+            });
+        }
+        frames
+    }
+
     fn runtime_error(&self, message: &str) -> ! {
         for frame in self.callframes.iter() {
             println!("in {:?}", frame.closure.f.name);
@@ -988,6 +2708,158 @@ impl VM {
     }
 }
 
+impl Drop for VM {
+    fn drop(&mut self) {
+        let mut current = self.heap;
+        while !current.is_null() {
+            let next = unsafe { &*current }.next;
+            unsafe { free_object(current) };
+            current = next;
+        }
+    }
+}
+
+unsafe fn free_object(obj_ptr: *mut HeapObject) {
+    std::ptr::drop_in_place(obj_ptr);
+    dealloc(obj_ptr as *mut u8, Layout::new::<HeapObject>());
+}
+
+fn mark_object(obj_ptr: *mut HeapObject, gray: &mut Vec<*mut HeapObject>) {
+    // cons cells use null for the end of a list
+    if obj_ptr.is_null() {
+        return;
+    }
+    let obj = unsafe { &mut *obj_ptr };
+    if !obj.marked {
+        obj.marked = true;
+        gray.push(obj_ptr);
+    }
+}
+
+fn mark_value(val: &SmallVal, gray: &mut Vec<*mut HeapObject>) {
+    match val {
+        SmallVal::ObjectPtr(ptr) | SmallVal::Quote(ptr) => mark_object(*ptr, gray),
+        SmallVal::Integer(_) | SmallVal::Float(_) | SmallVal::Bool(_) | SmallVal::Nil => {}
+    }
+}
+
+fn mark_closure(closure: &Closure, gray: &mut Vec<*mut HeapObject>) {
+    for upvalue in closure.upvalues.iter() {
+        mark_object(*upvalue, gray);
+    }
+    mark_chunk(&closure.f.bytecode, gray);
+}
+
+/// marks the literals of `chunk` and of the functions inside it, which can still be made into
+/// closures that use theirs
+fn mark_chunk(chunk: &BytecodeChunk, gray: &mut Vec<*mut HeapObject>) {
+    for val in chunk.literals.0.borrow().iter().flatten() {
+        mark_value(val, gray);
+    }
+    for constant in chunk.constants.iter() {
+        if let ConstantValue::Object(ConstantObject::Closure(closure)) = constant {
+            mark_chunk(&closure.f.bytecode, gray);
+        }
+    }
+}
+
+fn mark_callframes(callframes: &[CallFrame], gray: &mut Vec<*mut HeapObject>) {
+    for frame in callframes {
+        mark_closure(&frame.closure, gray);
+    }
+}
+
+fn mark_handlers(handlers: &[Handler], gray: &mut Vec<*mut HeapObject>) {
+    for handler in handlers {
+        mark_value(&handler.catcher, gray);
+    }
+}
+
+fn mark_open_upvalues(open_upvalues: *mut HeapObject, gray: &mut Vec<*mut HeapObject>) {
+    let mut current = open_upvalues;
+    while !current.is_null() {
+        mark_object(current, gray);
+        current = as_upvalue(current).next;
+    }
+}
+
+/// marks everything `obj_ptr` references
+fn trace_object(obj_ptr: *mut HeapObject, gray: &mut Vec<*mut HeapObject>) {
+    match &unsafe { &*obj_ptr }.value {
+        ObjectValue::SmallValue(val) => mark_value(val, gray),
+        ObjectValue::String(_) | ObjectValue::Symbol(_) | ObjectValue::BuiltIn(_) => {}
+        ObjectValue::Closure(closure) => mark_closure(closure, gray),
+        ObjectValue::ConsCell(ConsCell(car, cdr)) => {
+            mark_object(*car, gray);
+            mark_object(*cdr, gray);
+        }
+        ObjectValue::UpValue(upvalue) => {
+            // open upvalues point into a stack, which is a root anyway
+            if let Some(val) = &upvalue.closed_val {
+                mark_value(&val.to_value(), gray);
+            }
+        }
+        ObjectValue::Error(error) => mark_value(&error.payload, gray),
+        ObjectValue::Continuation(continuation) => {
+            for val in continuation.stack.iter() {
+                mark_value(val, gray);
+            }
+            mark_callframes(&continuation.callframes, gray);
+            mark_handlers(&continuation.handlers, gray);
+            mark_object(continuation.context, gray);
+        }
+        ObjectValue::Coroutine(co) => {
+            mark_value(&co.function, gray);
+            mark_state(&co.state, gray);
+        }
+        ObjectValue::Task(task) => {
+            mark_value(&task.function, gray);
+            mark_state(&task.state, gray);
+            for co in task.coroutines.iter() {
+                mark_object(*co, gray);
+            }
+            if let TaskStatus::Blocked(channel) = task.status {
+                mark_object(channel, gray);
+            }
+        }
+        ObjectValue::Channel(channel) => {
+            for val in channel.buffer.iter() {
+                mark_value(val, gray);
+            }
+        }
+    }
+}
+
+fn mark_state(state: &ExecutionState, gray: &mut Vec<*mut HeapObject>) {
+    for i in 0..state.stack.len() {
+        mark_value(&state.stack.at(i).unwrap(), gray);
+    }
+    mark_callframes(&state.callframes, gray);
+    mark_handlers(&state.handlers, gray);
+    mark_open_upvalues(state.open_upvalues, gray);
+}
+
+fn as_coroutine<'a>(obj_ptr: *mut HeapObject) -> &'a mut Coroutine {
+    match unsafe { &mut (*obj_ptr).value } {
+        ObjectValue::Coroutine(co) => co,
+        _ => panic!("expected coroutine"),
+    }
+}
+
+fn as_task<'a>(obj_ptr: *mut HeapObject) -> &'a mut Task {
+    match unsafe { &mut (*obj_ptr).value } {
+        ObjectValue::Task(task) => task,
+        _ => panic!("expected task"),
+    }
+}
+
+fn as_channel<'a>(obj_ptr: *mut HeapObject) -> &'a mut Channel {
+    match unsafe { &mut (*obj_ptr).value } {
+        ObjectValue::Channel(channel) => channel,
+        _ => panic!("expected channel"),
+    }
+}
+
 fn as_upvalue<'a>(current: *mut HeapObject) -> &'a mut UpValue {
     unsafe {
         let upvalue = &mut (*current).value;
@@ -1016,10 +2888,14 @@ mod tests {
         let chunk = BytecodeChunk {
             code: vec![Op::Constant.into(), 0x00, Op::DebugEnd.into()],
             constants: vec![ConstantValue::Integer(5)],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         };
         vm.run(chunk);
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(5));
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(5));
     }
 
     #[test]
@@ -1037,9 +2913,13 @@ mod tests {
                 Op::DebugEnd.into(),
             ],
             constants: vec![ConstantValue::Integer(5), ConstantValue::Integer(6)],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         };
         vm.run(chunk);
-        assert_eq!(vm.stack.peek_top().unwrap(), &SmallVal::Integer(11))
+        assert_eq!(vm.stack.peek_top().unwrap(), SmallVal::Integer(11))
     }
 
     #[test]
@@ -1057,8 +2937,6 @@ mod tests {
             2,
             Op::DebugEnd.into(),
         ];
-        let ptr = bytecode.as_ptr();
-
         let mut vm = VM::default();
         vm.run(BytecodeChunk {
             code: bytecode,
@@ -1067,10 +2945,14 @@ mod tests {
                 ConstantValue::Integer(3),
                 ConstantValue::Integer(2),
             ],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         });
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(2));
-        assert_eq!(vm.ip, unsafe { ptr.add(10) }); // idx after the last byte
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(2));
+        assert_eq!(vm.ip_offset(), 10); // the last byte
     }
 
     #[test]
@@ -1096,14 +2978,16 @@ mod tests {
                 ConstantValue::Integer(3),
                 ConstantValue::Integer(2),
             ],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         };
-        let ptr = chunk.code.as_ptr();
-
         let mut vm = VM::default();
         vm.run(chunk);
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(2));
-        assert_eq!(vm.ip, unsafe { ptr.add(10) });
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(2));
+        assert_eq!(vm.ip_offset(), 10);
     }
 
     #[test]
@@ -1113,15 +2997,17 @@ mod tests {
             constants: vec![ConstantValue::Object(ConstantObject::String(
                 "Hello, world!".to_string(),
             ))],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         };
-        let ptr = chunk.code.as_ptr();
-
         let mut vm = VM::default();
         vm.run(chunk);
         assert_eq!(vm.stack.len(), 1);
 
         let string = match vm.stack.peek_top().unwrap() {
-            SmallVal::ObjectPtr(ptr) => match &unsafe { &**ptr }.value {
+            SmallVal::ObjectPtr(ptr) => match &unsafe { &*ptr }.value {
                 ObjectValue::String(str) => str,
                 _ => panic!(),
             },
@@ -1129,7 +3015,7 @@ mod tests {
         };
 
         assert_eq!(string, "Hello, world!");
-        assert_eq!(vm.ip, unsafe { ptr.add(2) });
+        assert_eq!(vm.ip_offset(), 2);
     }
 
     #[test]
@@ -1152,7 +3038,7 @@ mod tests {
                         num_locals: 0,
                         name: "asdf".to_string(),
                         arity: 2,
-                        bytecode: Box::new(BytecodeChunk {
+                        bytecode: Rc::new(BytecodeChunk {
                             code: vec![
                                 Op::ReferenceLocal.into(),
                                 1,
@@ -1162,6 +3048,10 @@ mod tests {
                                 Op::Return.into(),
                             ],
                             constants: vec![],
+                            debug: DebugInfo::default(),
+                            global_slots: vec![],
+                            literals: Literals::default(),
+                            decoded: Decoded::default(),
                         }),
                     },
                     upvalues: vec![],
@@ -1170,13 +3060,17 @@ mod tests {
                 ConstantValue::Integer(20),
                 ConstantValue::Integer(30),
             ],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         };
 
         let mut vm = VM::default();
         vm.run(bc);
-        assert_eq!(vm.stack.peek_top().unwrap(), &SmallVal::Integer(50));
+        assert_eq!(vm.stack.peek_top().unwrap(), SmallVal::Integer(50));
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(50));
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(50));
     }
 
     #[test]
@@ -1199,7 +3093,7 @@ mod tests {
                         num_locals: 0,
                         name: "asdf".to_string(),
                         arity: 2,
-                        bytecode: Box::new(BytecodeChunk {
+                        bytecode: Rc::new(BytecodeChunk {
                             code: vec![
                                 Op::ReferenceLocal.into(),
                                 1,
@@ -1209,6 +3103,10 @@ mod tests {
                                 Op::Return.into(),
                             ],
                             constants: vec![],
+                            debug: DebugInfo::default(),
+                            global_slots: vec![],
+                            literals: Literals::default(),
+                            decoded: Decoded::default(),
                         }),
                     },
                     upvalues: vec![],
@@ -1217,12 +3115,291 @@ mod tests {
                 ConstantValue::Integer(20),
                 ConstantValue::Integer(30),
             ],
+            debug: DebugInfo::default(),
+            global_slots: vec![],
+            literals: Literals::default(),
+            decoded: Decoded::default(),
         };
 
         let mut vm = VM::default();
         vm.run(bc);
-        assert_eq!(vm.stack.peek_top().unwrap(), &SmallVal::Integer(50));
+        assert_eq!(vm.stack.peek_top().unwrap(), SmallVal::Integer(50));
         assert_eq!(vm.stack.len(), 1);
-        assert_eq!(vm.stack.at(0).unwrap(), &SmallVal::Integer(50));
+        assert_eq!(vm.stack.at(0).unwrap(), SmallVal::Integer(50));
+    }
+
+    fn run_source(src: &str) -> VM {
+        let mut vm = VM::default();
+        vm.run(crate::compiler::compile(&src.to_string()));
+        vm
+    }
+
+    fn count_heap(vm: &VM) -> usize {
+        let mut count = 0;
+        let mut current = vm.heap;
+        while !current.is_null() {
+            count += 1;
+            current = unsafe { &*current }.next;
+        }
+        count
+    }
+
+    #[test]
+    fn gc_frees_unreachable_objects() {
+        let mut vm = run_source(
+            r#"
+(define garbage (fn (n)
+    (cons 1 2)
+    (if (= n 0) 0 (garbage (- n 1)))))
+(define kept '(1 2))
+(garbage 100)
+"#,
+        );
+        let before = count_heap(&vm);
+        vm.gc();
+        let after = count_heap(&vm);
+
+        assert!(after < before);
+        assert_eq!(after, vm.num_objects);
+        assert_eq!(
+            format!("{}", vm.globals.get("kept").unwrap()),
+            "'(1 . (2 . nil))"
+        );
+    }
+
+    #[test]
+    fn gc_runs_during_execution() {
+        // allocates well past the first threshold
+        let vm = run_source(
+            r#"
+(define garbage (fn (n)
+    (cons 1 2)
+    (if (= n 0) 0 (garbage (- n 1)))))
+(define loop (fn (n)
+    (if (= n 0) 0 (+ (garbage 50) (loop (- n 1))))))
+(define result (loop 100))
+"#,
+        );
+        assert!(vm.num_objects < 3 * INITIAL_GC_THRESHOLD);
+        assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "0");
+    }
+
+    #[test]
+    fn gc_keeps_suspended_coroutine_state() {
+        let mut vm = run_source(
+            r#"
+(define gen (generator (fn ()
+    (define xs (cons 1 (cons 2 3)))
+    (yield 0)
+    (yield (car (cdr xs))))))
+(resume gen)
+"#,
+        );
+        vm.stack.pop();
+        vm.gc();
+
+        // the list only lives on the suspended coroutine's stack
+        vm.run(crate::compiler::compile(
+            &"(define result (resume gen))".to_string(),
+        ));
+        assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "2");
+    }
+
+    #[test]
+    fn heap_stats_track_live_objects_by_kind() {
+        let mut vm = run_source(
+            r#"
+(define garbage (fn (n)
+    (cons 1 2)
+    (if (= n 0) 0 (garbage (- n 1)))))
+(define name "rusp")
+(garbage 10)
+"#,
+        );
+        let stats = vm.heap_stats();
+        assert_eq!(stats.live.objects, count_heap(&vm));
+        assert_eq!(
+            stats.by_kind["builtin"].objects,
+            builtins_comp::BUILT_INS.len()
+        );
+        assert_eq!(stats.by_kind["cons"].objects, 11);
+
+        vm.gc();
+        let stats = vm.heap_stats();
+        assert_eq!(stats.collections, 1);
+        assert!(!stats.by_kind.contains_key("cons"));
+        assert_eq!(stats.live.objects, count_heap(&vm));
+        let total: AllocationStats =
+            stats
+                .by_kind
+                .values()
+                .fold(AllocationStats::default(), |total, kind| AllocationStats {
+                    objects: total.objects + kind.objects,
+                    bytes: total.bytes + kind.bytes,
+                });
+        assert_eq!(total, stats.live);
+    }
+
+    #[test]
+    fn globals_are_linked_to_slots() {
+        let vm = run_source("(define x 1)\n(define y x)");
+        let chunk = &vm.chunk;
+        for (idx, constant) in chunk.constants.iter().enumerate() {
+            if let ConstantValue::Object(ConstantObject::String(name)) = constant {
+                assert_eq!(chunk.global_slots[idx], vm.globals.slot_of(name).unwrap());
+            }
+        }
+        assert_eq!(vm.globals.get("y"), Some(&SmallVal::Integer(1)));
+    }
+
+    #[test]
+    fn referring_to_globals_doesnt_allocate() {
+        let vm = run_source(
+            r#"
+(define x 1)
+(define loop (fn (n) (if (= n 0) x (loop (- n 1)))))
+(loop 100)
+"#,
+        );
+        assert!(!vm.heap_stats().by_kind.contains_key("string"));
+    }
+
+    #[test]
+    fn forward_references_are_resolved_by_later_runs() {
+        let mut vm = run_source("(defun (f) later)");
+        assert!(!vm.globals.contains_key("later"));
+        vm.run(crate::compiler::compile(
+            &"(define later 5)\n(define result (f))".to_string(),
+        ));
+        assert_eq!(vm.globals.get("result"), Some(&SmallVal::Integer(5)));
+    }
+
+    #[test]
+    fn heap_constants_are_made_once() {
+        let vm = run_source(
+            r#"
+(define loop (fn (n)
+    (define s "hi")
+    (define q '(1 2))
+    (if (= n 0) (cons s q) (loop (- n 1)))))
+(define a (loop 50))
+(define b (loop 50))
+"#,
+        );
+        let stats = vm.heap_stats();
+        assert_eq!(stats.by_kind["string"].objects, 1);
+        // the quoted list's two cells, and one from each `cons`
+        assert_eq!(stats.by_kind["cons"].objects, 4);
+    }
+
+    #[test]
+    fn literals_go_with_their_chunk() {
+        let mut vm = run_source("(defun (greet) (fn () \"hello\"))\n(define hi (greet))\n(hi)");
+        let line =
+            || crate::compiler::compile(&"(define s \"a string\")\n(define q '(1 2))".to_string());
+        vm.run(line());
+        vm.gc();
+        let before = vm.heap_stats().live;
+        for _ in 0..1000 {
+            vm.run(line());
+        }
+        vm.gc();
+        assert_eq!(vm.heap_stats().live, before);
+        // "a string", and "hello", which `hi` keeps for the next time it's called
+        assert_eq!(vm.heap_stats().by_kind["string"].objects, 2);
+    }
+
+    #[test]
+    fn symbol_table_is_weak() {
+        let mut vm = run_source("(define kept (intern \"kept\"))\n(intern \"dropped\")");
+        assert!(vm.symbols.contains_key("dropped"));
+        vm.gc();
+        assert!(!vm.symbols.contains_key("dropped"));
+        let kept = vm.symbols["kept"];
+        assert_eq!(vm.globals.get("kept"), Some(&SmallVal::Quote(kept)));
+        assert_eq!(vm.intern("kept"), kept);
+    }
+
+    #[test]
+    fn values_survive_the_stack() {
+        // the extremes don't fit in a NaN-boxed slot, so are boxed with the `nan_boxing` feature
+        let vm = run_source(
+            r#"
+(defun (keep x) (fn () x))
+(define big (keep 9223372036854775807))
+(define small (keep (- 0 140737488355329)))
+(defun (id x) x)
+(define results (cons (big) (cons (small) (cons (id 2.5) (id true)))))
+"#,
+        );
+        assert_eq!(
+            format!("{}", vm.globals.get("results").unwrap()),
+            "(9223372036854775807 . (-140737488355329 . (2.5 . true)))"
+        );
+    }
+
+    #[test]
+    fn superinstructions_match_running_each_instruction() {
+        // anything but integers goes through the builtin, which raises here, and so does
+        // everything once `-` is rebound
+        let src = r#"
+(defun (add a b) (+ a b))
+(defun (dec n) (- n 1))
+(defun (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+(define results (cons (add 1 2) (cons (dec 5) (cons (fib 15)
+    (cons (try (add 1.5 2) (catch e (error-kind e)))
+          (try (dec "a") (catch e (error-kind e))))))))
+(define - +)
+(define rebound (dec 1))
+"#;
+        let fused = run_source(src);
+        // fuel makes every instruction run on its own
+        let mut vm = VM::default();
+        vm.set_fuel(Some(u64::MAX));
+        vm.run(crate::compiler::compile(&src.to_string()));
+        for name in ["results", "rebound"] {
+            assert_eq!(
+                format!("{}", fused.globals.get(name).unwrap()),
+                format!("{}", vm.globals.get(name).unwrap())
+            );
+        }
+        assert_eq!(
+            format!("{}", fused.globals.get("results").unwrap()),
+            "(3 . (4 . (610 . (type-error . type-error))))"
+        );
+        assert_eq!(fused.globals.get("rebound"), Some(&SmallVal::Integer(2)));
+        assert_eq!(fused.steps, vm.steps);
+    }
+
+    #[test]
+    fn call_caches_follow_redefined_globals() {
+        let vm = run_source(
+            r#"
+(defun (f x) x)
+(defun (call) (f 1))
+(define a (call))
+(define f car)
+(define b (try (call) (catch e (error-kind e))))
+(defun (f x y) x)
+(define c (try (call) (catch e (error-kind e))))
+(defun (f x) (+ x 1))
+(define d (call))
+"#,
+        );
+        let results: Vec<String> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| format!("{}", vm.globals.get(name).unwrap()))
+            .collect();
+        assert_eq!(results, ["1", "'type-error", "'arity-error", "2"]);
+        let Some(SmallVal::ObjectPtr(call)) = vm.globals.get("call") else {
+            panic!("expected call to be a function");
+        };
+        let ObjectValue::Closure(call) = &unsafe { &**call }.value else {
+            panic!("expected call to be a closure");
+        };
+        let caches = &call.f.bytecode.decoded.call_caches.0;
+        assert!(caches
+            .iter()
+            .any(|cache| matches!(cache.get().target, CallTarget::Closure(_))));
     }
 }
@@ -0,0 +1,1159 @@
+//! A baseline JIT for x86-64 Linux, behind the `jit` feature.
+//!
+//! Functions are compiled once they've been called `VM::set_jit_threshold` times, and jumps
+//! become native jumps, so there's no dispatch between instructions. Constants, locals, jumps
+//! and arithmetic and comparisons on integers are done inline. Each checks the tags of the
+//! values it uses first, and if they aren't what it handles, falls back to a template that calls
+//! a helper for its op, with the operands as immediates. The rest of the ops always do that.
+//! The helpers run the interpreter's handlers, and the inline code keeps `ip` and the step count
+//! up to date the same way, so native code can stop before any instruction and the interpreter
+//! carries on from there. It stops at calls, returns and errors, which move `ip` to another
+//! function, at backward jumps, so the interpreter's checks (like collecting garbage) still run
+//! between iterations of a loop, and at the ops it doesn't compile, like `CallCC` and `Yield`.
+//! The interpreter goes back into native code whenever it enters a function that has some.
+
+use std::cell::{Cell, OnceCell};
+use std::ffi::{c_int, c_void};
+use std::mem::{offset_of, size_of};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use super::{BytecodeChunk, ConstantValue, Op, Slot, STACK_HEADROOM, VM};
+use crate::predecode::Instr;
+#[cfg(feature = "nan_boxing")]
+use crate::{
+    nanbox::{self, PackedVal},
+    vm::SmallVal,
+};
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the `jit` feature only supports x86-64 Linux");
+
+/// the number of calls before a function is compiled, unless `RUSP_JIT_THRESHOLD` says otherwise
+const DEFAULT_THRESHOLD: u32 = 100;
+
+/// `RUSP_JIT_THRESHOLD` is a number of calls, or `off` to never compile anything
+pub(super) fn threshold_from_env() -> Option<u32> {
+    threshold(std::env::var("RUSP_JIT_THRESHOLD").ok().as_deref())
+}
+
+/// anything that isn't a number or `off` gets a warning and the default
+fn threshold(value: Option<&str>) -> Option<u32> {
+    match value {
+        Some("off") => None,
+        Some(value) => match value.parse() {
+            Ok(threshold) => Some(threshold),
+            Err(_) => {
+                eprintln!(
+                    "RUSP_JIT_THRESHOLD should be a number or off, not {value:?}, \
+                     using {DEFAULT_THRESHOLD}"
+                );
+                Some(DEFAULT_THRESHOLD)
+            }
+        },
+        None => Some(DEFAULT_THRESHOLD),
+    }
+}
+
+/// A function's call count and native code, kept with its instructions
+#[derive(Default)]
+pub(crate) struct JitState {
+    calls: Cell<u32>,
+    /// `None` inside if the code couldn't be mapped
+    code: OnceCell<Option<Rc<Code>>>,
+}
+
+impl JitState {
+    fn code(&self) -> Option<&Rc<Code>> {
+        self.code.get().and_then(Option::as_ref)
+    }
+}
+
+/// copies start cold, compiling is cheap enough to do again
+impl Clone for JitState {
+    fn clone(&self) -> Self {
+        JitState::default()
+    }
+}
+
+/// doesn't change what the code does
+impl PartialEq for JitState {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for JitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JitState")
+            .field("calls", &self.calls.get())
+            .field("compiled", &self.code().is_some())
+            .finish()
+    }
+}
+
+/// Where the values of the running function are, which native code reads from here rather
+/// than working out from the stack and call frames. Set before it runs, and after every
+/// helper it carries on from, as a helper can grow the stack and move them.
+pub(crate) struct NativeFrame {
+    slots: *mut Slot,
+    locals: *mut Slot,
+}
+
+impl Default for NativeFrame {
+    fn default() -> Self {
+        NativeFrame {
+            slots: std::ptr::null_mut(),
+            locals: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl NativeFrame {
+    fn of(vm: &mut VM) -> Self {
+        let start = vm.frame().start_idx as usize;
+        let slots = vm.stack.as_mut_ptr();
+        NativeFrame {
+            slots,
+            locals: unsafe { slots.add(start) },
+        }
+    }
+}
+
+impl VM {
+    /// Counts a call to the function `chunk` is the code of, and compiles it once it's hot
+    pub(super) fn count_call(&mut self, chunk: &BytecodeChunk) {
+        self.jit_check = true;
+        let decoded = &chunk.decoded;
+        let Some(threshold) = self.jit_threshold else {
+            return;
+        };
+        let calls = decoded.jit.calls.get();
+        if calls < threshold {
+            decoded.jit.calls.set(calls + 1);
+            return;
+        }
+        decoded.jit.code.get_or_init(|| compile(chunk).map(Rc::new));
+    }
+
+    /// Runs native code from `ip` if there's any for it, returning whether it ran
+    pub(super) fn run_native(&mut self) -> bool {
+        if self.jit_threshold.is_none() || self.callframes.is_empty() || !self.can_fuse() {
+            self.jit_check = false;
+            return false;
+        }
+        let decoded = &self.current_chunk().decoded;
+        let Some(code) = decoded.jit.code() else {
+            self.jit_check = false;
+            return false;
+        };
+        let idx = (self.ip as usize).wrapping_sub(decoded.instructions.as_ptr() as usize)
+            / std::mem::size_of::<Instr>();
+        let entry = match code.entries.get(idx) {
+            Some(Some(entry)) => *entry,
+            // the interpreter runs this one, and checks again at the next
+            Some(None) => return false,
+            None => {
+                self.jit_check = false;
+                return false;
+            }
+        };
+        let needed = code.stack_needed[idx] as usize + STACK_HEADROOM;
+        // kept alive in case running it frees the function
+        let code = code.clone();
+        if self.stack.len() + needed > self.stack.capacity() && !self.ensure_stack_space(needed) {
+            // the interpreter raises the stack overflow when it gets there
+            self.jit_check = false;
+            return false;
+        }
+
+        // counted again by native code
+        self.steps -= 1;
+        self.jit_frame = NativeFrame::of(self);
+        unsafe { code.run(self, entry) };
+        self.jit_check = true;
+        if let Some(payload) = self.jit_panic.take() {
+            panic::resume_unwind(payload);
+        }
+        true
+    }
+}
+
+/// A compiled function
+pub(crate) struct Code {
+    buffer: ExecutableBuffer,
+    /// where each instruction's template starts, `None` for the ones only the interpreter runs
+    entries: Vec<Option<u32>>,
+    /// the most the stack can grow by running native code from each instruction
+    stack_needed: Vec<u32>,
+}
+
+impl Code {
+    /// runs the code from `entry` until it has to stop
+    unsafe fn run(&self, vm: *mut VM, entry: u32) {
+        let function: extern "C" fn(*mut VM, *const u8) =
+            std::mem::transmute(self.buffer.ptr as *const u8);
+        function(vm, self.buffer.ptr.add(entry as usize));
+    }
+}
+
+/// How native code carries on after an instruction's helper
+enum Continuation {
+    /// to the instruction `span` after this one, unless the helper returned non-zero
+    Next { span: usize },
+    /// always stops
+    Stop,
+    /// to the instruction `delta` from this one
+    Jump { delta: i32 },
+    /// to the instruction `delta` from this one if the helper returned 1, to the next if 0
+    Branch { delta: i32 },
+}
+
+type Helper = extern "C" fn(*mut VM, u32, u32, u32) -> u32;
+
+/// The helper for an instruction, with its operands, and how its template carries on.
+/// `None` for instructions that are left to the interpreter.
+fn template(instr: Instr) -> Option<(Helper, [u32; 3], Continuation)> {
+    use Continuation::*;
+    let next = Next { span: 1 };
+    Some(match instr {
+        Instr::Constant(idx) => (constant, [idx as u32, 0, 0], next),
+        Instr::Operator(op) => (operator_helper(op), [0; 3], next),
+        Instr::Jump(delta) => (jump, [delta as u32, 0, 0], Jump { delta }),
+        Instr::CondJump(delta) => (cond_jump, [delta as u32, 0, 0], Branch { delta }),
+        Instr::FuncCall { arity, cache } => (func_call, [arity as u32, cache, 0], next),
+        Instr::Return => (return_helper, [0; 3], Stop),
+        Instr::DeclareGlobal(slot) => (declare_global, [slot, 0, 0], next),
+        Instr::ReferenceGlobal(slot) => (reference_global, [slot, 0, 0], next),
+        Instr::ReferenceLocal(idx) => (reference_local, [idx as u32, 0, 0], next),
+        Instr::Print => (print, [0; 3], next),
+        Instr::Define(idx) => (define, [idx as u32, 0, 0], next),
+        Instr::ReferenceUpvalue(idx) => (reference_upvalue, [idx as u32, 0, 0], next),
+        Instr::SetUpvalue(idx) => (set_upvalue, [idx as u32, 0, 0], next),
+        Instr::Closure { constant, captures } => (closure, [constant as u32, captures, 0], next),
+        Instr::Pop => (pop, [0; 3], next),
+        Instr::SetLocal(idx) => (set_local, [idx as u32, 0, 0], next),
+        Instr::PushHandler(delta) => (push_handler, [delta as u32, 0, 0], next),
+        Instr::PopHandler => (pop_handler, [0; 3], next),
+        Instr::Raise => (raise, [0; 3], Stop),
+        Instr::LocalLocalOperator { a, b, op } => (
+            local_local_operator,
+            [a as u32, b as u32, op as u32],
+            Next { span: 3 },
+        ),
+        Instr::LocalIntegerOperator { local, op, integer } => (
+            local_integer_operator,
+            [local as u32, op as u32, integer as u32],
+            Next { span: 3 },
+        ),
+        Instr::ConstantCall {
+            constant,
+            arity,
+            cache,
+        } => (
+            constant_call,
+            [constant as u32, arity as u32, cache],
+            Next { span: 2 },
+        ),
+        Instr::CloseUpvalue
+        | Instr::CallCC
+        | Instr::Resume
+        | Instr::Yield
+        | Instr::DebugEnd
+        | Instr::Invalid => return None,
+    })
+}
+
+/// How many values an instruction leaves on the stack, less how many it takes, when native
+/// code carries on after it
+fn stack_effect(instr: Instr) -> i64 {
+    match instr {
+        Instr::Constant(_)
+        | Instr::ReferenceGlobal(_)
+        | Instr::ReferenceLocal(_)
+        | Instr::ReferenceUpvalue(_)
+        | Instr::Closure { .. }
+        | Instr::LocalLocalOperator { .. }
+        | Instr::LocalIntegerOperator { .. } => 1,
+        // a builtin was called
+        Instr::FuncCall { arity, .. } => -(arity as i64),
+        Instr::ConstantCall { arity, .. } => 1 - arity as i64,
+        Instr::Operator(_)
+        | Instr::CondJump(_)
+        | Instr::DeclareGlobal(_)
+        | Instr::Print
+        | Instr::Define(_)
+        | Instr::SetUpvalue(_)
+        | Instr::Pop
+        | Instr::SetLocal(_)
+        | Instr::PushHandler(_) => -1,
+        _ => 0,
+    }
+}
+
+/// Compiles a function's instructions, or `None` if there's no memory to put them in
+fn compile(chunk: &BytecodeChunk) -> Option<Code> {
+    let instructions = &chunk.decoded.instructions;
+    let target = |idx: usize, delta: i32| idx.checked_add_signed(delta as isize);
+    let mut asm = Assembler::default();
+
+    // push rbx; mov rbx, rdi; jmp rsi
+    // rbx holds the VM for the helpers, and pushing it aligns the stack for calls
+    asm.bytes(&[0x53, 0x48, 0x89, 0xFB, 0xFF, 0xE6]);
+
+    let mut entries = vec![None; instructions.len()];
+    for (idx, instr) in instructions.iter().enumerate() {
+        asm.labels.push(asm.code.len());
+        let Some((helper, operands, continuation)) = template(*instr) else {
+            asm.jump(&[0xE9], Label::Exit);
+            continue;
+        };
+        entries[idx] = Some(asm.code.len() as u32);
+
+        // only forward jumps stay in native code
+        let forward = |delta: i32| match target(idx, delta) {
+            Some(to) if delta > 0 && to < instructions.len() => Label::Instruction(to),
+            _ => Label::Exit,
+        };
+        if asm.fast_path(chunk, idx, *instr, forward) && asm.bails.is_empty() {
+            continue;
+        }
+        asm.land_bails();
+
+        // mov rdi, rbx; mov esi, a; mov edx, b; mov ecx, c
+        asm.bytes(&[0x48, 0x89, 0xDF]);
+        for (opcode, operand) in [0xBE, 0xBA, 0xB9].into_iter().zip(operands) {
+            asm.bytes(&[opcode]);
+            asm.bytes(&operand.to_le_bytes());
+        }
+        // mov rax, helper; call rax
+        asm.bytes(&[0x48, 0xB8]);
+        asm.bytes(&(helper as usize as u64).to_le_bytes());
+        asm.bytes(&[0xFF, 0xD0]);
+
+        match continuation {
+            Continuation::Next { span } => {
+                // test eax, eax; jnz exit
+                asm.bytes(&[0x85, 0xC0]);
+                asm.jump(&[0x0F, 0x85], Label::Exit);
+                if span != 1 {
+                    asm.jump(&[0xE9], forward(span as i32));
+                }
+            }
+            Continuation::Stop => asm.jump(&[0xE9], Label::Exit),
+            Continuation::Jump { delta } => asm.jump(&[0xE9], forward(delta)),
+            Continuation::Branch { delta } => {
+                // test eax, eax; jz next; cmp eax, 1; jne exit; jmp target
+                asm.bytes(&[0x85, 0xC0]);
+                asm.jump(&[0x0F, 0x84], Label::Instruction(idx + 1));
+                asm.bytes(&[0x83, 0xF8, 0x01]);
+                asm.jump(&[0x0F, 0x85], Label::Exit);
+                asm.jump(&[0xE9], forward(delta));
+            }
+        }
+    }
+    // pop rbx; ret
+    asm.exit = asm.code.len();
+    asm.bytes(&[0x5B, 0xC3]);
+
+    Some(Code {
+        buffer: ExecutableBuffer::new(&asm.finish())?,
+        entries,
+        stack_needed: stack_needed(instructions),
+    })
+}
+
+/// The most the stack grows by, from the start of each instruction, on any path native code
+/// can take from it. The interpreter checks for room before every instruction, and native
+/// code doesn't, so it's checked for all of them before going in.
+fn stack_needed(instructions: &[Instr]) -> Vec<u32> {
+    let mut needed = vec![0i64; instructions.len()];
+    // native code only jumps forwards, so everything after an instruction is done before it
+    for idx in (0..instructions.len()).rev() {
+        let instr = instructions[idx];
+        let Some((_, _, continuation)) = template(instr) else {
+            continue;
+        };
+        let at = |to: Option<usize>| {
+            to.filter(|to| *to > idx && *to < instructions.len())
+                .map_or(0, |to| needed[to])
+        };
+        let after = match continuation {
+            Continuation::Next { span } => at(Some(idx + span)),
+            Continuation::Stop => 0,
+            Continuation::Jump { delta } => at(idx.checked_add_signed(delta as isize)),
+            Continuation::Branch { delta } => {
+                at(Some(idx + 1)).max(at(idx.checked_add_signed(delta as isize)))
+            }
+        };
+        needed[idx] = (stack_effect(instr) + after).max(0);
+    }
+    needed.into_iter().map(|n| n as u32).collect()
+}
+
+/// where a jump goes
+enum Label {
+    Instruction(usize),
+    Exit,
+}
+
+/// the registers the fast paths use, numbered as they are in ModRM
+#[derive(Clone, Copy)]
+enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsi = 6,
+    Rdi = 7,
+}
+
+/// Just enough of an assembler for the templates
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    /// where each instruction's template starts
+    labels: Vec<usize>,
+    exit: usize,
+    /// the rel32 operands to fill in once every label is known
+    fixups: Vec<(usize, Label)>,
+    /// the rel32 operands of the jumps from a fast path to its helper, see `fast_path`
+    bails: Vec<usize>,
+}
+
+impl Assembler {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// a jump with a rel32 operand, `opcode` being `jmp` or a `jcc`
+    fn jump(&mut self, opcode: &[u8], to: Label) {
+        self.bytes(opcode);
+        self.fixups.push((self.code.len(), to));
+        self.bytes(&[0; 4]);
+    }
+
+    /// a jump to somewhere later in the same template, which goes wherever `land` is called
+    fn forward_jump(&mut self, opcode: &[u8]) -> usize {
+        self.bytes(opcode);
+        let at = self.code.len();
+        self.bytes(&[0; 4]);
+        at
+    }
+
+    fn land(&mut self, at: usize) {
+        let rel = (self.code.len() - (at + 4)) as i32;
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    /// a jump to the helper, `opcode` being a `jcc`
+    fn bail(&mut self, opcode: &[u8]) {
+        let at = self.forward_jump(opcode);
+        self.bails.push(at);
+    }
+
+    fn land_bails(&mut self) {
+        for at in std::mem::take(&mut self.bails) {
+            self.land(at);
+        }
+    }
+
+    /// an instruction with a `[base + disp32]` operand, `reg` being its other register or the
+    /// extension of its opcode
+    fn memory(&mut self, opcode: &[u8], reg: u8, base: Reg, disp: i32) {
+        self.bytes(opcode);
+        self.bytes(&[0x80 | reg << 3 | base as u8]);
+        self.bytes(&disp.to_le_bytes());
+    }
+
+    /// mov dst, [base + disp]
+    fn load(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.memory(&[0x48, 0x8B], dst as u8, base, disp);
+    }
+
+    /// mov [base + disp], src
+    fn store(&mut self, base: Reg, disp: i32, src: Reg) {
+        self.memory(&[0x48, 0x89], src as u8, base, disp);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (at, to) in std::mem::take(&mut self.fixups) {
+            let to = match to {
+                Label::Instruction(idx) => self.labels[idx],
+                Label::Exit => self.exit,
+            };
+            // relative to the end of the operand
+            let rel = to as i64 - (at + 4) as i64;
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+}
+
+// the parts of the VM that fast paths read and write themselves
+const IP: i32 = offset_of!(VM, ip) as i32;
+const STEPS: i32 = offset_of!(VM, steps) as i32;
+const STACK_PTR: i32 = offset_of!(VM, stack.0.ptr) as i32;
+const SLOTS: i32 = offset_of!(VM, jit_frame.slots) as i32;
+const LOCALS: i32 = offset_of!(VM, jit_frame.locals) as i32;
+const OPERATORS_REBOUND: i32 = offset_of!(VM, operators_rebound) as i32;
+const SLOT_SIZE: i32 = size_of::<Slot>() as i32;
+const INSTR_SIZE: i32 = size_of::<Instr>() as i32;
+
+/// the operators with fast paths, `Div` is left to its helper as it has more ways to fail
+fn inline_operator(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Add | Op::Sub | Op::Mul | Op::Equal | Op::GT | Op::LT | Op::GTE | Op::LTE
+    )
+}
+
+impl Assembler {
+    /// Emits `instr` inline, if it has a fast path, returning whether it did. Fast paths bail
+    /// out to the helper, before they've changed anything, when the values they're given aren't
+    /// ones they handle, and otherwise do what the helper would.
+    fn fast_path(
+        &mut self,
+        chunk: &BytecodeChunk,
+        idx: usize,
+        instr: Instr,
+        forward: impl Fn(i32) -> Label,
+    ) -> bool {
+        match instr {
+            Instr::Constant(constant) => {
+                let Some(words) = immediate(&chunk.constants[constant as usize]) else {
+                    return false;
+                };
+                self.push_target();
+                for (i, word) in words.into_iter().enumerate() {
+                    // mov rax, word; mov [rdi + 8i], rax
+                    self.bytes(&[0x48, 0xB8]);
+                    self.bytes(&word.to_le_bytes());
+                    self.store(Reg::Rdi, 8 * i as i32, Reg::Rax);
+                }
+                self.move_top(1);
+                self.carry_on(1, 1, forward(1));
+            }
+            Instr::ReferenceLocal(local) => {
+                self.push_target();
+                self.load(Reg::Rsi, Reg::Rbx, LOCALS);
+                self.copy(Reg::Rsi, local as i32 * SLOT_SIZE, Reg::Rdi, 0);
+                self.move_top(1);
+                self.carry_on(1, 1, forward(1));
+            }
+            Instr::Operator(op) if inline_operator(op) => {
+                self.check_operators();
+                self.top();
+                self.integer(Reg::Rcx, Reg::Rdi, 0);
+                self.integer(Reg::Rax, Reg::Rdi, -SLOT_SIZE);
+                self.operate(op, Reg::Rdi, -SLOT_SIZE);
+                self.move_top(-1);
+                self.carry_on(1, 1, forward(1));
+            }
+            Instr::LocalLocalOperator { a, b, op } if inline_operator(op) => {
+                self.check_operators();
+                self.push_target();
+                self.load(Reg::Rsi, Reg::Rbx, LOCALS);
+                self.integer(Reg::Rax, Reg::Rsi, a as i32 * SLOT_SIZE);
+                self.integer(Reg::Rcx, Reg::Rsi, b as i32 * SLOT_SIZE);
+                self.operate(op, Reg::Rdi, 0);
+                self.move_top(1);
+                // counted as the three instructions it stands for
+                self.carry_on(3, 3, forward(3));
+            }
+            Instr::LocalIntegerOperator { local, op, integer } if inline_operator(op) => {
+                self.check_operators();
+                self.push_target();
+                self.load(Reg::Rsi, Reg::Rbx, LOCALS);
+                self.integer(Reg::Rax, Reg::Rsi, local as i32 * SLOT_SIZE);
+                // mov rcx, integer
+                self.bytes(&[0x48, 0xC7, 0xC1]);
+                self.bytes(&integer.to_le_bytes());
+                self.operate(op, Reg::Rdi, 0);
+                self.move_top(1);
+                self.carry_on(3, 3, forward(3));
+            }
+            Instr::Jump(delta) => self.carry_on(1, delta, forward(delta)),
+            Instr::CondJump(delta) => {
+                self.top();
+                self.condition(Reg::Rdi, 0);
+                self.move_top(-1);
+                // test eax, eax; jz not taken
+                self.bytes(&[0x85, 0xC0]);
+                let not_taken = self.forward_jump(&[0x0F, 0x84]);
+                self.carry_on(1, delta, forward(delta));
+                self.land(not_taken);
+                self.carry_on(1, 1, Label::Instruction(idx + 1));
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// rdi = the slot on top of the stack
+    fn top(&mut self) {
+        // movsxd rdi, [rbx + STACK_PTR]; shl rdi, log2(SLOT_SIZE); add rdi, [rbx + SLOTS]
+        self.memory(&[0x48, 0x63], Reg::Rdi as u8, Reg::Rbx, STACK_PTR);
+        self.bytes(&[0x48, 0xC1, 0xE7, SLOT_SIZE.trailing_zeros() as u8]);
+        self.memory(&[0x48, 0x03], Reg::Rdi as u8, Reg::Rbx, SLOTS);
+    }
+
+    /// rdi = the slot a push goes in, which run_native has checked there's room for
+    fn push_target(&mut self) {
+        self.top();
+        // add rdi, SLOT_SIZE
+        self.bytes(&[0x48, 0x83, 0xC7, SLOT_SIZE as u8]);
+        self.writable(Reg::Rdi, 0);
+    }
+
+    /// moves the top of the stack up `by` slots
+    fn move_top(&mut self, by: i8) {
+        // add dword [rbx + STACK_PTR], by
+        self.memory(&[0x83], 0, Reg::Rbx, STACK_PTR);
+        self.bytes(&[by as u8]);
+    }
+
+    /// bails if a script has rebound the operators, see `VM::integer_operator`
+    fn check_operators(&mut self) {
+        // cmp byte [rbx + OPERATORS_REBOUND], 0; jne bail
+        self.memory(&[0x80], 7, Reg::Rbx, OPERATORS_REBOUND);
+        self.bytes(&[0]);
+        self.bail(&[0x0F, 0x85]);
+    }
+
+    /// stores `rax op rcx` at `[base + disp]`, bailing if it overflows
+    fn operate(&mut self, op: Op, base: Reg, disp: i32) {
+        let setcc = match op {
+            Op::Equal => 0x94,
+            Op::LT => 0x9C,
+            Op::GTE => 0x9D,
+            Op::LTE => 0x9E,
+            Op::GT => 0x9F,
+            _ => {
+                match op {
+                    // add rax, rcx
+                    Op::Add => self.bytes(&[0x48, 0x01, 0xC8]),
+                    // sub rax, rcx
+                    Op::Sub => self.bytes(&[0x48, 0x29, 0xC8]),
+                    // imul rax, rcx
+                    Op::Mul => self.bytes(&[0x48, 0x0F, 0xAF, 0xC1]),
+                    op => unreachable!("{op:?} doesn't have a fast path"),
+                }
+                // jo bail
+                self.bail(&[0x0F, 0x80]);
+                self.store_integer(base, disp);
+                return;
+            }
+        };
+        // cmp rax, rcx; setcc al
+        self.bytes(&[0x48, 0x39, 0xC8, 0x0F, setcc, 0xC0]);
+        self.store_bool(base, disp);
+    }
+
+    /// counts `steps` instructions, moves `ip` on by `delta` and goes to `to`
+    fn carry_on(&mut self, steps: i8, delta: i32, to: Label) {
+        // add qword [rbx + STEPS], steps; add qword [rbx + IP], delta * INSTR_SIZE; jmp to
+        self.memory(&[0x48, 0x83], 0, Reg::Rbx, STEPS);
+        self.bytes(&[steps as u8]);
+        self.memory(&[0x48, 0x81], 0, Reg::Rbx, IP);
+        self.bytes(&(delta * INSTR_SIZE).to_le_bytes());
+        self.jump(&[0xE9], to);
+    }
+}
+
+// How values are laid out in slots. Without `nan_boxing`, a slot is a `SmallVal`, which is
+// `repr(u8)`, so it starts with a byte for its variant and what that holds comes after it.
+// Integers are at 8, bools at 1, and nothing owns anything.
+
+#[cfg(not(feature = "nan_boxing"))]
+const INTEGER: u8 = 0;
+#[cfg(not(feature = "nan_boxing"))]
+const BOOL: u8 = 2;
+#[cfg(not(feature = "nan_boxing"))]
+const NIL: u8 = 3;
+
+#[cfg(not(feature = "nan_boxing"))]
+impl Assembler {
+    /// dst = the integer at `[base + disp]`, bailing if it isn't one
+    fn integer(&mut self, dst: Reg, base: Reg, disp: i32) {
+        // cmp byte [base + disp], INTEGER; jne bail; mov dst, [base + disp + 8]
+        self.memory(&[0x80], 7, base, disp);
+        self.bytes(&[INTEGER]);
+        self.bail(&[0x0F, 0x85]);
+        self.load(dst, base, disp + 8);
+    }
+
+    /// stores the integer in rax at `[base + disp]`
+    fn store_integer(&mut self, base: Reg, disp: i32) {
+        // mov qword [base + disp], INTEGER; mov [base + disp + 8], rax
+        self.memory(&[0x48, 0xC7], 0, base, disp);
+        self.bytes(&(INTEGER as u32).to_le_bytes());
+        self.store(base, disp + 8, Reg::Rax);
+    }
+
+    /// stores the bool in al at `[base + disp]`
+    fn store_bool(&mut self, base: Reg, disp: i32) {
+        // movzx eax, al; shl eax, 8; or eax, BOOL; mov [base + disp], rax
+        self.bytes(&[0x0F, 0xB6, 0xC0, 0xC1, 0xE0, 0x08, 0x83, 0xC8, BOOL]);
+        self.store(base, disp, Reg::Rax);
+    }
+
+    /// bails if the slot at `[base + disp]` owns something, which nothing does
+    fn writable(&mut self, _base: Reg, _disp: i32) {}
+
+    fn copy(&mut self, from: Reg, from_disp: i32, to: Reg, to_disp: i32) {
+        self.load(Reg::Rax, from, from_disp);
+        self.load(Reg::Rdx, from, from_disp + 8);
+        self.store(to, to_disp, Reg::Rax);
+        self.store(to, to_disp + 8, Reg::Rdx);
+    }
+
+    /// eax = the bool at `[base + disp]`, bailing if it isn't one
+    fn condition(&mut self, base: Reg, disp: i32) {
+        // cmp byte [base + disp], BOOL; jne bail; movzx eax, byte [base + disp + 1]
+        self.memory(&[0x80], 7, base, disp);
+        self.bytes(&[BOOL]);
+        self.bail(&[0x0F, 0x85]);
+        self.memory(&[0x0F, 0xB6], 0, base, disp + 1);
+    }
+}
+
+/// the slot a constant is pushed as, in 8 byte words, if it doesn't need anything made for it
+#[cfg(not(feature = "nan_boxing"))]
+fn immediate(constant: &ConstantValue) -> Option<Vec<u64>> {
+    match constant {
+        ConstantValue::Integer(i) => Some(vec![INTEGER as u64, *i as u64]),
+        ConstantValue::Boolean(b) => Some(vec![BOOL as u64 | (*b as u64) << 8, 0]),
+        ConstantValue::Nil => Some(vec![NIL as u64, 0]),
+        _ => None,
+    }
+}
+
+// With `nan_boxing`, a slot is a `PackedVal`. Integers that don't fit in its payload are
+// boxed, and those slots own their box.
+
+/// the top 16 bits of a packed value with `tag`
+#[cfg(feature = "nan_boxing")]
+const fn tag_bits(tag: u64) -> u32 {
+    ((nanbox::BOXED | tag << nanbox::TAG_SHIFT) >> nanbox::TAG_SHIFT) as u32
+}
+
+#[cfg(feature = "nan_boxing")]
+impl Assembler {
+    /// bails if the value in `src` doesn't have `tag`, using rdx
+    fn expect_tag(&mut self, src: Reg, tag: u64) {
+        self.compare_tag(src, tag);
+        // jne bail
+        self.bail(&[0x0F, 0x85]);
+    }
+
+    /// bails if the value in `src` has `tag`, using rdx
+    fn refuse_tag(&mut self, src: Reg, tag: u64) {
+        self.compare_tag(src, tag);
+        // je bail
+        self.bail(&[0x0F, 0x84]);
+    }
+
+    fn compare_tag(&mut self, src: Reg, tag: u64) {
+        // mov rdx, src; shr rdx, TAG_SHIFT; cmp edx, tag_bits(tag)
+        self.bytes(&[0x48, 0x89, 0xC2 | (src as u8) << 3]);
+        self.bytes(&[0x48, 0xC1, 0xEA, nanbox::TAG_SHIFT as u8, 0x81, 0xFA]);
+        self.bytes(&tag_bits(tag).to_le_bytes());
+    }
+
+    /// dst = the integer at `[base + disp]`, bailing if it isn't one
+    fn integer(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.load(dst, base, disp);
+        self.expect_tag(dst, nanbox::INTEGER);
+        // shl dst, 16; sar dst, 16, which extends the payload's sign
+        let dst = dst as u8;
+        self.bytes(&[0x48, 0xC1, 0xE0 | dst, 16, 0x48, 0xC1, 0xF8 | dst, 16]);
+    }
+
+    /// stores the integer in rax at `[base + disp]`, bailing if it would have to be boxed
+    fn store_integer(&mut self, base: Reg, disp: i32) {
+        // mov rdx, rax; shl rdx, 16; sar rdx, 16; cmp rdx, rax; jne bail
+        self.bytes(&[0x48, 0x89, 0xC2, 0x48, 0xC1, 0xE2, 16, 0x48, 0xC1, 0xFA, 16]);
+        self.bytes(&[0x48, 0x39, 0xC2]);
+        self.bail(&[0x0F, 0x85]);
+        // shl rax, 16; shr rax, 16; mov rdx, tag; or rax, rdx
+        self.bytes(&[0x48, 0xC1, 0xE0, 16, 0x48, 0xC1, 0xE8, 16, 0x48, 0xBA]);
+        self.bytes(&(u64::from(tag_bits(nanbox::INTEGER)) << nanbox::TAG_SHIFT).to_le_bytes());
+        self.bytes(&[0x48, 0x09, 0xD0]);
+        self.store(base, disp, Reg::Rax);
+    }
+
+    /// stores the bool in al at `[base + disp]`
+    fn store_bool(&mut self, base: Reg, disp: i32) {
+        // movzx eax, al; mov rdx, tag; or rax, rdx
+        self.bytes(&[0x0F, 0xB6, 0xC0, 0x48, 0xBA]);
+        self.bytes(&(u64::from(tag_bits(nanbox::BOOL)) << nanbox::TAG_SHIFT).to_le_bytes());
+        self.bytes(&[0x48, 0x09, 0xD0]);
+        self.store(base, disp, Reg::Rax);
+    }
+
+    /// bails if the slot at `[base + disp]` owns a boxed integer, which would need dropping
+    fn writable(&mut self, base: Reg, disp: i32) {
+        self.load(Reg::Rdx, base, disp);
+        self.refuse_tag(Reg::Rdx, nanbox::BIG_INTEGER);
+    }
+
+    /// bails if the value is a boxed integer, which would need a box of its own
+    fn copy(&mut self, from: Reg, from_disp: i32, to: Reg, to_disp: i32) {
+        self.load(Reg::Rax, from, from_disp);
+        self.refuse_tag(Reg::Rax, nanbox::BIG_INTEGER);
+        self.store(to, to_disp, Reg::Rax);
+    }
+
+    /// eax = the bool at `[base + disp]`, bailing if it isn't one
+    fn condition(&mut self, base: Reg, disp: i32) {
+        self.load(Reg::Rax, base, disp);
+        self.expect_tag(Reg::Rax, nanbox::BOOL);
+        // and eax, 1
+        self.bytes(&[0x83, 0xE0, 0x01]);
+    }
+}
+
+/// the slot a constant is pushed as, in 8 byte words, if it doesn't need anything made for it
+#[cfg(feature = "nan_boxing")]
+fn immediate(constant: &ConstantValue) -> Option<Vec<u64>> {
+    let value = match constant {
+        ConstantValue::Integer(i) => SmallVal::Integer(*i),
+        ConstantValue::Boolean(b) => SmallVal::Bool(*b),
+        ConstantValue::Nil => SmallVal::Nil,
+        _ => return None,
+    };
+    let bits = PackedVal::pack(&value).to_bits();
+    let boxed = (bits >> nanbox::TAG_SHIFT) as u32 == tag_bits(nanbox::BIG_INTEGER);
+    (!boxed).then(|| vec![bits])
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// Memory holding machine code, which is never writable and executable at the same time
+struct ExecutableBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> Option<Self> {
+        let len = code.len();
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        // MAP_FAILED
+        if ptr as isize == -1 {
+            return None;
+        }
+        let buffer = ExecutableBuffer {
+            ptr: ptr as *mut u8,
+            len,
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), buffer.ptr, len);
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+        }
+        Some(buffer)
+    }
+}
+
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+/// Runs `handler` for native code, returning 0 if it carried on to the instruction `span`
+/// after the one it ran. Panics can't unwind through native code, so they're caught and
+/// resumed once it's returned.
+fn step(vm: *mut VM, span: isize, handler: impl FnOnce(&mut VM)) -> u32 {
+    let vm = unsafe { &mut *vm };
+    let expected = vm.ip.wrapping_offset(span);
+    // as the interpreter counts it, superinstructions count the rest themselves
+    vm.steps += 1;
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(vm))) {
+        vm.jit_panic = Some(payload);
+        return 1;
+    }
+    if vm.ip != expected {
+        return 1;
+    }
+    vm.jit_frame = NativeFrame::of(vm);
+    0
+}
+
+fn to_op(op: u32) -> Op {
+    Op::try_from(op as u8).expect("expected an operator")
+}
+
+extern "C" fn constant(vm: *mut VM, idx: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_constant(idx as u8))
+}
+
+/// a helper for each operator, so the op is known when it's compiled
+fn operator_helper(op: Op) -> Helper {
+    extern "C" fn operator<const OP: u8>(vm: *mut VM, _: u32, _: u32, _: u32) -> u32 {
+        step(vm, 1, |vm| vm.handle_operator(to_op(OP as u32)))
+    }
+    match op {
+        Op::Add => operator::<{ Op::Add as u8 }>,
+        Op::Sub => operator::<{ Op::Sub as u8 }>,
+        Op::Mul => operator::<{ Op::Mul as u8 }>,
+        Op::Div => operator::<{ Op::Div as u8 }>,
+        Op::Equal => operator::<{ Op::Equal as u8 }>,
+        Op::GT => operator::<{ Op::GT as u8 }>,
+        Op::LT => operator::<{ Op::LT as u8 }>,
+        Op::GTE => operator::<{ Op::GTE as u8 }>,
+        Op::LTE => operator::<{ Op::LTE as u8 }>,
+        op => unreachable!("{op:?} isn't an operator"),
+    }
+}
+
+extern "C" fn jump(vm: *mut VM, delta: u32, _: u32, _: u32) -> u32 {
+    step(vm, delta as i32 as isize, |vm| vm.handle_jump(delta as i32))
+}
+
+/// 0 if the jump wasn't taken, 1 if it was, and 2 if native code has to stop
+extern "C" fn cond_jump(vm: *mut VM, delta: u32, _: u32, _: u32) -> u32 {
+    match step(vm, 1, |vm| vm.handle_cond_jump(delta as i32)) {
+        0 => 0,
+        _ if unsafe { &*vm }.jit_panic.is_some() => 2,
+        _ => 1,
+    }
+}
+
+extern "C" fn func_call(vm: *mut VM, arity: u32, cache: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_func_call(arity as u8, cache))
+}
+
+extern "C" fn return_helper(vm: *mut VM, _: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_return())
+}
+
+extern "C" fn declare_global(vm: *mut VM, slot: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_declare_global(slot as usize))
+}
+
+extern "C" fn reference_global(vm: *mut VM, slot: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_reference_global(slot as usize))
+}
+
+extern "C" fn reference_local(vm: *mut VM, idx: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_reference_local(idx as u8))
+}
+
+extern "C" fn print(vm: *mut VM, _: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_print())
+}
+
+extern "C" fn define(vm: *mut VM, idx: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_local_define(idx as u8))
+}
+
+extern "C" fn reference_upvalue(vm: *mut VM, idx: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_reference_upvalue(idx as u8))
+}
+
+extern "C" fn set_upvalue(vm: *mut VM, idx: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_set_upvalue(idx as u8))
+}
+
+extern "C" fn closure(vm: *mut VM, constant: u32, captures: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_closure(constant as u8, captures))
+}
+
+extern "C" fn pop(vm: *mut VM, _: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_pop())
+}
+
+extern "C" fn set_local(vm: *mut VM, idx: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_set_local(idx as u8))
+}
+
+extern "C" fn push_handler(vm: *mut VM, delta: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_push_handler(delta as i32))
+}
+
+extern "C" fn pop_handler(vm: *mut VM, _: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_pop_handler())
+}
+
+extern "C" fn raise(vm: *mut VM, _: u32, _: u32, _: u32) -> u32 {
+    step(vm, 1, |vm| vm.handle_raise())
+}
+
+extern "C" fn local_local_operator(vm: *mut VM, a: u32, b: u32, op: u32) -> u32 {
+    step(vm, 3, |vm| {
+        vm.handle_local_local_operator(a as u8, b as u8, to_op(op))
+    })
+}
+
+extern "C" fn local_integer_operator(vm: *mut VM, local: u32, op: u32, integer: u32) -> u32 {
+    step(vm, 3, |vm| {
+        vm.handle_local_integer_operator(local as u8, to_op(op), integer as i32)
+    })
+}
+
+extern "C" fn constant_call(vm: *mut VM, constant: u32, arity: u32, cache: u32) -> u32 {
+    step(vm, 2, |vm| {
+        vm.handle_constant_call(constant as u8, arity as u8, cache)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::vm::SmallVal;
+
+    fn run(src: &str, threshold: Option<u32>) -> VM {
+        let mut vm = VM::default();
+        vm.set_jit_threshold(threshold);
+        vm.run(compile(&src.to_string()));
+        vm
+    }
+
+    fn global(vm: &VM, name: &str) -> SmallVal {
+        vm.globals.get(name).unwrap().clone()
+    }
+
+    #[test]
+    fn malformed_thresholds_fall_back_to_the_default() {
+        assert_eq!(threshold(Some("5")), Some(5));
+        assert_eq!(threshold(Some("off")), None);
+        assert_eq!(threshold(Some("lots")), Some(DEFAULT_THRESHOLD));
+        assert_eq!(threshold(None), Some(DEFAULT_THRESHOLD));
+    }
+
+    #[test]
+    fn jumps_are_patched() {
+        let mut asm = Assembler {
+            labels: vec![0, 7],
+            ..Default::default()
+        };
+        asm.jump(&[0xE9], Label::Instruction(1));
+        asm.exit = 9;
+        asm.jump(&[0x0F, 0x85], Label::Exit);
+        assert_eq!(
+            asm.finish(),
+            [0xE9, 2, 0, 0, 0, 0x0F, 0x85, 0xFE, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn stack_needs_follow_forward_paths() {
+        use Instr::*;
+        // (if a 1 (+ 2 3)), where the else branch needs more room
+        let instructions = [
+            ReferenceLocal(1),
+            CondJump(3),
+            Constant(0),
+            Jump(4),
+            Constant(1),
+            Constant(2),
+            Operator(Op::Add),
+            Return,
+            Invalid,
+        ];
+        assert_eq!(stack_needed(&instructions), [2, 1, 1, 0, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn hot_functions_are_compiled() {
+        let src = "(defun (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+                   (define x (fib 20))";
+        let vm = run(src, Some(10));
+        assert_eq!(global(&vm, "x"), SmallVal::Integer(6765));
+        let SmallVal::ObjectPtr(fib) = global(&vm, "fib") else {
+            panic!("expected fib to be a closure");
+        };
+        let crate::vm::ObjectValue::Closure(fib) = &unsafe { &*fib }.value else {
+            panic!("expected fib to be a closure");
+        };
+        assert!(fib.f.bytecode.decoded.jit.code().is_some());
+
+        let vm = run(src, None);
+        assert_eq!(global(&vm, "x"), SmallVal::Integer(6765));
+    }
+
+    #[test]
+    fn fast_paths_fall_back_to_their_helpers() {
+        // operands that aren't integers, integers that need boxing with `nan_boxing`, and
+        // conditions that aren't bools
+        let src = "(defun (f n)
+                     (cons (try (< n \"a\") (catch e (error-kind e)))
+                           (cons (* n 100000000000) (if n (- n 1) 2))))
+                   (define x (f 10000))
+                   (define + (fn (a b) (- a b)))
+                   (define y (f 10000))";
+        let interpreted = run(src, None);
+        let compiled = run(src, Some(0));
+        for name in ["x", "y"] {
+            let expected = global(&interpreted, name).to_string();
+            assert_eq!(global(&compiled, name).to_string(), expected);
+        }
+        assert_eq!(
+            global(&compiled, "x").to_string(),
+            "(type-error . (1000000000000000 . 9999))"
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "nan_boxing"))]
+    fn small_vals_are_laid_out_as_fast_paths_expect() {
+        let byte = |value: &SmallVal, at: usize| unsafe {
+            *(value as *const SmallVal as *const u8).add(at)
+        };
+        let integer = SmallVal::Integer(-7);
+        assert_eq!(byte(&integer, 0), INTEGER);
+        assert_eq!(
+            unsafe { *(&integer as *const SmallVal as *const i64).add(1) },
+            -7
+        );
+        assert_eq!(byte(&SmallVal::Bool(true), 0), BOOL);
+        assert_eq!(byte(&SmallVal::Bool(true), 1), 1);
+        assert_eq!(byte(&SmallVal::Nil, 0), NIL);
+    }
+
+    #[test]
+    fn errors_are_caught_in_native_code() {
+        let src = "(defun (f n) (try (car n) (catch e (error-kind e))))
+                   (f 1)
+                   (define x (f 2))";
+        let vm = run(src, Some(0));
+        assert_eq!(global(&vm, "x").to_string(), "'type-error");
+    }
+
+    #[test]
+    #[should_panic(expected = "uncaught error <type-error")]
+    fn uncaught_errors_unwind_past_native_code() {
+        run("(defun (f n) (car n)) (f 1)", Some(0));
+    }
+}
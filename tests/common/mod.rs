@@ -0,0 +1,125 @@
+//! What the tests that run the integration tests' programs through `ruspc` share.
+
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const RUSPC: &str = env!("CARGO_BIN_EXE_ruspc");
+/// some of the programs only stop when a limit stops them, which these runs don't set
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+/// lines the VM prints while it works with upvalues, and with the backtrace of an uncaught
+/// error, which aren't the program's output
+pub const VM_DEBUG_LINES: [&str; 5] = [
+    "in \"",
+    "found upvalue",
+    "capturing upvalue",
+    "inserting at start",
+    "inserting in middle",
+];
+
+/// The rusp source in every raw string and `run_code` call in the integration tests, named
+/// after the test it's in, with `#2`, `#3` and so on after the first of several.
+/// Some of them are assembly or aren't meant to compile.
+pub fn programs() -> Vec<(String, String)> {
+    let tests = include_str!("../integration_test.rs");
+    let demo = include_str!("../../demo/input.risp").to_string();
+    let mut programs = vec![("demo".to_string(), demo)];
+    let mut test = "";
+    let mut in_test = 0;
+    for line_start in tests.lines().filter(|line| !line.trim().starts_with("//")) {
+        let offset = line_start.as_ptr() as usize - tests.as_ptr() as usize;
+        let rest = &tests[offset..];
+        if let Some(name) = line_start.strip_prefix("fn ") {
+            test = &name[..name.find('(').unwrap()];
+            in_test = 0;
+        }
+        let source = if let Some(start) = line_start.find("r#\"") {
+            let source = &rest[start + 3..];
+            source[..source.find("\"#").unwrap()].to_string()
+        } else if let Some(start) = line_start.find("run_code(\"") {
+            unescape(&rest[start + 10..])
+        } else {
+            continue;
+        };
+        in_test += 1;
+        let name = match in_test {
+            1 => test.to_string(),
+            n => format!("{test}#{n}"),
+        };
+        programs.push((name, source));
+    }
+    programs
+}
+
+/// the contents of a string literal, up to its closing quote
+pub fn unescape(literal: &str) -> String {
+    let mut out = String::new();
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// runs `command`, or gives up on it after `TIMEOUT`. Its output is read as it's written, so a
+/// program that prints more than a pipe holds doesn't block until it times out.
+pub fn run(command: &mut Command) -> Option<Output> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = drain(child.stdout.take().unwrap());
+    let stderr = drain(child.stderr.take().unwrap());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    Some(Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    })
+}
+
+/// reads `pipe` to the end on another thread
+fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        pipe.read_to_end(&mut bytes).unwrap();
+        bytes
+    })
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !VM_DEBUG_LINES.iter().any(|debug| line.starts_with(debug)))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// the uncaught error a program stopped with
+pub fn error(output: &Output) -> Option<String> {
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .find(|line| line.starts_with("Runtime error: "))
+        .map(str::to_string)
+}
@@ -0,0 +1,147 @@
+//! Runs the programs in the integration tests (and the demo) through `ruspc emit-c` and the
+//! system C compiler, and checks they do what they do on the VM.
+
+mod common;
+
+use common::{error, programs, run, stdout, RUSPC};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// The programs `ruspc emit-c` turns down, and what it says it doesn't support.
+/// Anything else it fails on is a bug.
+const UNSUPPORTED: &[(&str, &str)] = &[
+    ("call_cc_early_exit", "CallCC"),
+    ("call_cc_returns_normally", "CallCC"),
+    ("call_cc_escapes_recursion", "CallCC"),
+    ("call_cc_reentry", "CallCC"),
+    ("call_cc_escaping_try_pops_handler", "CallCC"),
+    ("generator_yields_values_in_order", "generator"),
+    ("exhausted_generator_resumes_to_nil", "generator"),
+    ("coroutine_passes_values_both_ways", "coroutine"),
+    ("resuming_dead_coroutine_raises", "coroutine"),
+    ("coroutine_error_propagates_to_resumer", "coroutine"),
+    ("errors_caught_inside_coroutine", "coroutine"),
+    ("yield_outside_coroutine_raises", "Yield"),
+    ("nested_coroutines", "generator"),
+    ("coroutine_closes_over_its_locals", "Resume"),
+    ("spawned_task_sends_to_main", "chan"),
+    ("tasks_finish_after_main", "chan"),
+    ("tasks_ping_pong", "chan"),
+    ("tasks_are_preempted", "chan"),
+    ("recv_without_senders_deadlocks", "recv"),
+    ("blocked_tasks_deadlock", "chan"),
+    ("scheduling_is_deterministic_with_a_seed", "chan"),
+    ("heap_stats_builtin", "heap-stats"),
+    ("generator_stacks_grow", "generator"),
+];
+
+/// The programs in the integration tests that are assembly rather than rusp.
+const NOT_RUSP: &[&str] = &["golden_disassembly", "untrusted_bytecode_is_verified"];
+
+enum Compared {
+    Same,
+    /// emit-c doesn't support this, yet
+    Unsupported(String),
+}
+
+/// runs `command`, failing if it doesn't stop
+fn finish(command: &mut Command) -> Result<Output, String> {
+    run(command).ok_or_else(|| format!("{command:?} didn't stop"))
+}
+
+/// the feature `ruspc emit-c` said it doesn't support, if that's why it failed
+fn unsupported(emitted: &Output) -> Option<String> {
+    let stderr = String::from_utf8_lossy(&emitted.stderr);
+    let start = stderr.find("emit-c doesn't support ")? + "emit-c doesn't support ".len();
+    let feature = stderr[start..].lines().next().unwrap();
+    Some(feature.strip_suffix(" yet").unwrap_or(feature).to_string())
+}
+
+/// whether the C program built from `source` does the same as the VM
+fn compare(dir: &Path, idx: usize, source: &str) -> Result<Compared, String> {
+    let risp = dir.join(format!("program_{idx}.risp"));
+    let c = dir.join(format!("program_{idx}.c"));
+    let exe = dir.join(format!("program_{idx}"));
+    std::fs::write(&risp, source).unwrap();
+
+    let emitted = finish(Command::new(RUSPC).arg("emit-c").arg(&risp))?;
+    if !emitted.status.success() {
+        if let Some(feature) = unsupported(&emitted) {
+            return Ok(Compared::Unsupported(feature));
+        }
+        let stderr = String::from_utf8_lossy(&emitted.stderr);
+        return Err(format!("{} can't be emitted:\n{stderr}", risp.display()));
+    }
+    std::fs::write(&c, &emitted.stdout).unwrap();
+    let built = finish(
+        Command::new("cc")
+            .arg("-std=c99")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&c),
+    )?;
+    if !built.status.success() {
+        let stderr = String::from_utf8_lossy(&built.stderr);
+        return Err(format!("{} doesn't compile:\n{stderr}", c.display()));
+    }
+
+    let vm = finish(Command::new(RUSPC).arg(&risp))?;
+    let native = finish(&mut Command::new(&exe))?;
+    if vm.status.success() != native.status.success() {
+        return Err(format!(
+            "{} exited with {} on the VM but {} in C",
+            risp.display(),
+            vm.status,
+            native.status
+        ));
+    }
+    if stdout(&vm) != stdout(&native) {
+        return Err(format!(
+            "{} printed\n{}on the VM but\n{}in C",
+            risp.display(),
+            stdout(&vm),
+            stdout(&native)
+        ));
+    }
+    if !vm.status.success() && error(&vm) != error(&native) {
+        return Err(format!(
+            "{} failed with {:?} on the VM but {:?} in C",
+            risp.display(),
+            error(&vm),
+            error(&native)
+        ));
+    }
+    Ok(Compared::Same)
+}
+
+#[test]
+fn emitted_c_matches_the_vm() {
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("no C compiler, skipping");
+        return;
+    }
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("emit_c");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut unsupported = vec![];
+    let mut failures = vec![];
+    for (idx, (name, source)) in programs().iter().enumerate() {
+        if NOT_RUSP.contains(&name.as_str()) {
+            continue;
+        }
+        match compare(&dir, idx, source) {
+            Ok(Compared::Same) => {}
+            Ok(Compared::Unsupported(feature)) => unsupported.push((name.clone(), feature)),
+            Err(failure) => failures.push(format!("{name}: {failure}")),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+    let expected: Vec<_> = UNSUPPORTED
+        .iter()
+        .map(|&(name, feature)| (name.to_string(), feature.to_string()))
+        .collect();
+    assert_eq!(
+        unsupported, expected,
+        "emit-c supports different programs to UNSUPPORTED"
+    );
+}
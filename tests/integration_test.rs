@@ -1,5 +1,8 @@
+use rusp::assembler::assemble;
 use rusp::compiler::compile;
-use rusp::vm::VM;
+use rusp::disassembler::disassemble;
+use rusp::vm::{LimitExceeded, VM};
+use std::time::{Duration, Instant};
 
 #[test]
 fn actually_e2e() {
@@ -305,6 +308,638 @@ fn playground() {
     )
 }
 
+fn run_and_display_global(src: &str, name: &str) -> String {
+    let bc = compile(&src.to_string());
+    let mut vm = VM::default();
+    vm.run(bc);
+    // unwinding should always leave the stack balanced
+    assert_eq!(vm.stack.len(), 0);
+    format!(
+        "{}",
+        vm.globals.get(name).expect("expected global to be defined")
+    )
+}
+
+#[test]
+fn try_catch_builtin_error() {
+    let result = run_and_display_global(
+        r#"
+(define result (try (car 1)
+    (catch e (error-kind e))))
+"#,
+        "result",
+    );
+    assert_eq!(result, "'type-error");
+}
+
+#[test]
+fn error_payload_is_optional() {
+    let src = r#"
+(define payload (try (error 'oops "no payload") (catch e (error-payload e))))
+(define message (try (error 'oops "no payload") (catch e (error-message e))))
+(define too-few (try (error 'oops) (catch e (error-kind e))))
+"#;
+    assert_eq!(run_and_display_global(src, "payload"), "nil");
+    assert_eq!(run_and_display_global(src, "message"), "\"no payload\"");
+    assert_eq!(run_and_display_global(src, "too-few"), "'arity-error");
+}
+
+#[test]
+fn try_catch_unwinds_callframes() {
+    let result = run_and_display_global(
+        r#"
+(defun (deep n)
+    (if (= n 0)
+        (error 'bottomed-out "reached the bottom" n)
+        (+ 1 (deep (- n 1)))))
+
+(defun (safe-deep n)
+    (define before "local survives unwinding")
+    (define caught (try (deep n)
+        (catch e (error-payload e))))
+    (cons before caught))
+
+(define result (safe-deep 10))
+"#,
+        "result",
+    );
+    assert_eq!(result, "(\"local survives unwinding\" . 0)");
+}
+
+#[test]
+fn try_without_error_evaluates_body() {
+    let result = run_and_display_global(
+        r#"
+(define result (try (+ 1 2)
+    (catch e 0)))
+"#,
+        "result",
+    );
+    assert_eq!(result, "3");
+}
+
+#[test]
+fn try_catch_closes_upvalues() {
+    let result = run_and_display_global(
+        r#"
+(defun (f)
+    (define x "captured")
+    (try (car 1)
+        (catch e x)))
+
+(define result (f))
+"#,
+        "result",
+    );
+    assert_eq!(result, "\"captured\"");
+}
+
+#[test]
+fn try_finally_runs_on_both_paths() {
+    let src = r#"
+(define ok (try (+ 1 2)
+    (finally (print "cleanup"))))
+
+(define failed (try (try (car 1)
+        (finally (print "cleanup")))
+    (catch e (error-kind e))))
+"#;
+    let mut vm = VM::default();
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    assert_eq!(format!("{}", vm.globals.get("ok").unwrap()), "3");
+    assert_eq!(
+        format!("{}", vm.globals.get("failed").unwrap()),
+        "'type-error"
+    );
+}
+
+#[test]
+fn try_finally_runs_when_handler_raises() {
+    let result = run_and_display_global(
+        r#"
+(define result (try (try (car 1)
+        (catch e (raise "from handler"))
+        (finally (print "cleanup")))
+    (catch e (error-payload e))))
+"#,
+        "result",
+    );
+    assert_eq!(result, "\"from handler\"");
+}
+
+#[test]
+fn raise_rethrows_caught_error() {
+    let result = run_and_display_global(
+        r#"
+(define result (try (try (undefined-function 1)
+        (catch e (raise e)))
+    (catch e (error-message e))))
+"#,
+        "result",
+    );
+    assert_eq!(result, "\"undefined global variable: undefined-function\"");
+}
+
+#[test]
+#[should_panic(expected = "uncaught error <type-error")]
+fn uncaught_error_panics() {
+    run_code("(car 1)");
+}
+
+#[test]
+fn call_cc_early_exit() {
+    let result = run_and_display_global(
+        r#"
+(define result (+ 1 (call/cc (fn (k)
+    (+ 10 (k 5))))))
+"#,
+        "result",
+    );
+    assert_eq!(result, "6");
+}
+
+#[test]
+fn call_cc_returns_normally() {
+    let result = run_and_display_global(
+        r#"
+(define result (+ 1 (call/cc (fn (k) 10))))
+"#,
+        "result",
+    );
+    assert_eq!(result, "11");
+}
+
+#[test]
+fn call_cc_escapes_recursion() {
+    let result = run_and_display_global(
+        r#"
+(defun (search n found)
+    (if (= n 7)
+        (found n)
+        (search (+ n 1) found)))
+
+(define result (call/cc (fn (k) (search 0 k))))
+"#,
+        "result",
+    );
+    assert_eq!(result, "7");
+}
+
+#[test]
+fn call_cc_reentry() {
+    let src = r#"
+(define total 0)
+(define k (call/cc (fn (c) c)))
+(define total (+ total 1))
+(if (= total 1) (k 100) 0)
+"#;
+    let mut vm = VM::default();
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    assert_eq!(format!("{}", vm.globals.get("total").unwrap()), "2");
+    assert_eq!(format!("{}", vm.globals.get("k").unwrap()), "100");
+}
+
+#[test]
+#[should_panic(expected = "uncaught error <type-error")]
+fn call_cc_escaping_try_pops_handler() {
+    // the handler must not outlive the `try` that was escaped from
+    run_code(
+        r#"
+(call/cc (fn (k)
+    (try (k 1)
+        (catch e 2))))
+(car 1)
+"#,
+    );
+}
+
+#[test]
+fn generator_yields_values_in_order() {
+    let src = r#"
+(define count-from (fn (n)
+    (yield n)
+    (count-from (+ n 1))))
+(define gen (generator (fn () (count-from 1))))
+(define a (resume gen))
+(define b (resume gen))
+(define c (resume gen))
+(define total (+ a (+ b c)))
+"#;
+    assert_eq!(run_and_display_global(src, "total"), "6");
+}
+
+#[test]
+fn exhausted_generator_resumes_to_nil() {
+    let src = r#"
+(define gen (generator (fn ()
+    (yield 1)
+    (yield 2))))
+(define a (resume gen))
+(define b (resume gen))
+(define c (resume gen))
+(define d (resume gen))
+(define finished (done? gen))
+"#;
+    let mut vm = VM::default();
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    let get = |name: &str| format!("{}", vm.globals.get(name).unwrap());
+    assert_eq!(get("a"), "1");
+    assert_eq!(get("b"), "2");
+    assert_eq!(get("c"), "nil");
+    assert_eq!(get("d"), "nil");
+    assert_eq!(get("finished"), "true");
+}
+
+#[test]
+fn coroutine_passes_values_both_ways() {
+    // the first resume is the argument, later ones are what `yield` evaluates to
+    let src = r#"
+(define co (coroutine (fn (x)
+    (define y (yield (+ x 1)))
+    (define z (yield (+ y 10)))
+    (* z 2))))
+(define a (resume co 1))
+(define b (resume co 5))
+(define c (resume co 7))
+(define result (+ (* a 100) (+ (* b 10) c)))
+"#;
+    // a = 2, b = 15, c = 14
+    assert_eq!(run_and_display_global(src, "result"), "364");
+}
+
+#[test]
+fn resuming_dead_coroutine_raises() {
+    let src = r#"
+(define co (coroutine (fn () 1)))
+(resume co)
+(define result (try (resume co)
+    (catch e (error-kind e))))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "'dead-coroutine");
+}
+
+#[test]
+fn coroutine_error_propagates_to_resumer() {
+    let src = r#"
+(define co (coroutine (fn ()
+    (yield 1)
+    (car 1))))
+(resume co)
+(define result (try (resume co)
+    (catch e (error-kind e))))
+(define finished (done? co))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "'type-error");
+    assert_eq!(run_and_display_global(src, "finished"), "true");
+}
+
+#[test]
+fn errors_caught_inside_coroutine() {
+    let src = r#"
+(define co (coroutine (fn ()
+    (yield (try (car 1) (catch e 10)))
+    20)))
+(define a (resume co))
+(define b (resume co))
+(define result (+ a b))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "30");
+}
+
+#[test]
+fn yield_outside_coroutine_raises() {
+    let src = r#"
+(define result (try (yield 1)
+    (catch e (error-kind e))))
+"#;
+    assert_eq!(
+        run_and_display_global(src, "result"),
+        "'yield-outside-coroutine"
+    );
+}
+
+#[test]
+fn nested_coroutines() {
+    let src = r#"
+(define inner (generator (fn ()
+    (yield 1)
+    (yield 2))))
+(define outer (generator (fn ()
+    (yield (* 10 (resume inner)))
+    (yield (* 10 (resume inner))))))
+(define a (resume outer))
+(define b (resume outer))
+(define result (+ a b))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "30");
+}
+
+#[test]
+fn coroutine_closes_over_its_locals() {
+    let src = r#"
+(define make-counter (fn ()
+    (generator (fn ()
+        (define n 0)
+        (define step (fn () (+ n 1)))
+        (yield step)))))
+(define step (resume (make-counter)))
+(define result (step))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "1");
+}
+
+#[test]
+fn spawned_task_sends_to_main() {
+    let src = r#"
+(define c (chan))
+(spawn (fn () (send c (+ 1 2))))
+(define result (recv c))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "3");
+}
+
+#[test]
+fn tasks_finish_after_main() {
+    let src = r#"
+(define c (chan))
+(define t (spawn (fn () (send c 1))))
+(define started (done? t))
+"#;
+    let mut vm = VM::default();
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    assert_eq!(format!("{}", vm.globals.get("started").unwrap()), "false");
+    vm.run(compile(&"(define finished (done? t))".to_string()));
+    assert_eq!(format!("{}", vm.globals.get("finished").unwrap()), "true");
+}
+
+#[test]
+fn tasks_ping_pong() {
+    let src = r#"
+(define ping (chan))
+(define pong (chan))
+(define bounce (fn (n)
+    (send pong (+ (recv ping) 1))
+    (if (= n 1) 0 (bounce (- n 1)))))
+(spawn (fn () (bounce 3)))
+(send ping 0)
+(define a (recv pong))
+(send ping (* a 10))
+(define b (recv pong))
+(send ping (* b 10))
+(define result (recv pong))
+"#;
+    // 0 -> 1, 10 -> 11, 110 -> 111
+    assert_eq!(run_and_display_global(src, "result"), "111");
+}
+
+#[test]
+fn tasks_are_preempted() {
+    // without preemption the slow task would send first
+    let src = r#"
+(define c (chan))
+(define count (fn (n) (if (= n 0) 0 (+ 1 (count (- n 1))))))
+(spawn (fn () (count 500) (send c 'slow)))
+(spawn (fn () (send c 'fast)))
+(define first (recv c))
+(define second (recv c))
+"#;
+    let mut vm = VM::default();
+    vm.set_time_slice(10);
+    vm.run(compile(&src.to_string()));
+    assert_eq!(format!("{}", vm.globals.get("first").unwrap()), "'fast");
+    assert_eq!(format!("{}", vm.globals.get("second").unwrap()), "'slow");
+}
+
+#[test]
+fn recv_without_senders_deadlocks() {
+    let src = r#"
+(define result (try (recv (chan))
+    (catch e (error-kind e))))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "'deadlock");
+}
+
+#[test]
+fn blocked_tasks_deadlock() {
+    let src = r#"
+(define a (chan))
+(define b (chan))
+(spawn (fn () (send b (recv a))))
+(define result (try (recv b)
+    (catch e (error-kind e))))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "'deadlock");
+}
+
+#[test]
+fn scheduling_is_deterministic_with_a_seed() {
+    let src = r#"
+(define c (chan))
+(define produce (fn (tag n)
+    (send c tag)
+    (if (= n 1) 0 (produce tag (- n 1)))))
+(spawn (fn () (produce 1 20)))
+(spawn (fn () (produce 2 20)))
+(define collect (fn (n) (if (= n 0) 0 (cons (recv c) (collect (- n 1))))))
+(define result (collect 40))
+"#;
+    let run_with_seed = |seed| {
+        let mut vm = VM::default();
+        vm.set_time_slice(5);
+        vm.set_scheduler_seed(seed);
+        vm.run(compile(&src.to_string()));
+        format!("{}", vm.globals.get("result").unwrap())
+    };
+    let first = run_with_seed(42);
+    assert_eq!(first, run_with_seed(42));
+    // a different seed interleaves differently
+    assert_ne!(first, run_with_seed(1));
+    assert_eq!(first.matches('1').count(), 20);
+    assert_eq!(first.matches('2').count(), 20);
+}
+
+#[test]
+fn out_of_fuel_is_resumable() {
+    let src = r#"
+(define count (fn (n) (if (= n 0) 0 (+ 1 (count (- n 1))))))
+(define result (count 100))
+"#;
+    let mut vm = VM::default();
+    vm.set_fuel(Some(50));
+    assert_eq!(
+        vm.execute(compile(&src.to_string())),
+        Err(LimitExceeded::OutOfFuel)
+    );
+    assert_eq!(vm.fuel(), Some(0));
+    assert!(!vm.globals.contains_key("result"));
+
+    // still not enough
+    vm.add_fuel(50);
+    assert_eq!(vm.resume(), Err(LimitExceeded::OutOfFuel));
+
+    vm.add_fuel(100_000);
+    assert_eq!(vm.resume(), Ok(()));
+    assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "100");
+    assert_eq!(vm.stack.len(), 0);
+}
+
+#[test]
+fn fuel_is_only_spent_on_instructions_run() {
+    let mut vm = VM::default();
+    vm.set_fuel(Some(100));
+    assert_eq!(vm.execute(compile(&"(define x 1)".to_string())), Ok(()));
+    // Constant, DeclareGlobal, DebugEnd
+    assert_eq!(vm.fuel(), Some(97));
+}
+
+#[test]
+fn deadline_is_resumable() {
+    let src = r#"
+(define count (fn (n) (if (= n 0) 0 (+ 1 (count (- n 1))))))
+(define result (count 100))
+"#;
+    let mut vm = VM::default();
+    vm.set_deadline(Some(Instant::now()));
+    assert_eq!(
+        vm.execute(compile(&src.to_string())),
+        Err(LimitExceeded::DeadlineExceeded)
+    );
+
+    vm.set_deadline(Some(Instant::now() + Duration::from_secs(60)));
+    assert_eq!(vm.resume(), Ok(()));
+    assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "100");
+}
+
+#[test]
+#[should_panic(expected = "ran out of fuel")]
+fn run_panics_when_out_of_fuel() {
+    let mut vm = VM::default();
+    vm.set_fuel(Some(1));
+    vm.run(compile(&"(define x (+ 1 2))".to_string()));
+}
+
+#[test]
+fn heap_limit_raises_catchable_out_of_memory() {
+    let src = r#"
+(define build (fn (n) (if (= n 0) 0 (cons n (build (- n 1))))))
+(define result (try (build 500)
+    (catch e (error-kind e))))
+(define after (car (build 10)))
+"#;
+    let mut vm = VM::default();
+    let baseline = vm.heap_stats().live.bytes;
+    vm.set_heap_limit(Some(baseline + 20_000));
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    assert_eq!(
+        format!("{}", vm.globals.get("result").unwrap()),
+        "'out-of-memory"
+    );
+    // the partial list was collected, so there's room to keep going
+    assert_eq!(format!("{}", vm.globals.get("after").unwrap()), "10");
+    assert!(vm.heap_stats().collections > 0);
+}
+
+#[test]
+fn heap_limit_collects_garbage_first() {
+    let src = r#"
+(define garbage (fn (n)
+    (cons 1 2)
+    (if (= n 0) 0 (garbage (- n 1)))))
+(define churn (fn (n) (if (= n 0) 0 (+ (garbage 50) (churn (- n 1))))))
+(define result (churn 100))
+"#;
+    let mut vm = VM::default();
+    let baseline = vm.heap_stats().live.bytes;
+    vm.set_heap_limit(Some(baseline + 50_000));
+    vm.run(compile(&src.to_string()));
+    assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "0");
+}
+
+#[test]
+fn heap_stats_builtin() {
+    let src = r#"
+(define stats (heap-stats))
+(define total (car stats))
+(define label (car total))
+"#;
+    assert_eq!(run_and_display_global(src, "label"), "total");
+}
+
+#[test]
+fn unbounded_recursion_raises_stack_overflow() {
+    let src = r#"
+(define deep (fn (n) (+ 1 (deep (+ n 1)))))
+(define result (try (deep 0)
+    (catch e (error-message e))))
+"#;
+    assert_eq!(
+        run_and_display_global(src, "result"),
+        "\"stack overflow in deep\""
+    );
+}
+
+#[test]
+fn call_depth_limit() {
+    let src = r#"
+(define deep (fn (n) (if (= n 0) 0 (+ 1 (deep (- n 1))))))
+(define shallow (deep 40))
+(define result (try (deep 60)
+    (catch e (error-kind e))))
+"#;
+    let mut vm = VM::default();
+    vm.set_max_call_depth(50);
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    assert_eq!(format!("{}", vm.globals.get("shallow").unwrap()), "40");
+    assert_eq!(
+        format!("{}", vm.globals.get("result").unwrap()),
+        "'stack-overflow"
+    );
+}
+
+#[test]
+#[should_panic(expected = "uncaught error <stack-overflow: stack overflow in deep>")]
+fn uncaught_stack_overflow_panics() {
+    run_code("(define deep (fn (n) (+ 1 (deep n)))) (deep 0)");
+}
+
+#[test]
+fn growable_stack() {
+    // `g` keeps an open upvalue into `f`'s frame while the stack is reallocated
+    let src = r#"
+(define deep (fn (n) (if (= n 0) 0 (+ 1 (deep (- n 1))))))
+(define f (fn (x)
+    (define g (fn () x))
+    (+ (deep 5000) (g))))
+(define result (f 7))
+"#;
+    let mut vm = VM::default();
+    vm.set_max_call_depth(100_000);
+    vm.set_max_stack_slots(1_000_000);
+    vm.run(compile(&src.to_string()));
+    assert_eq!(vm.stack.len(), 0);
+    assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "5007");
+}
+
+#[test]
+fn generator_stacks_grow() {
+    // a generator's stack starts far smaller than the recursion inside it needs
+    let src = r#"
+(define deep (fn (n) (if (= n 0) 0 (+ 1 (deep (- n 1))))))
+(define gen (generator (fn ()
+    (define x 7)
+    (define g (fn () x))
+    (yield (+ (deep 200) (g))))))
+(define result (resume gen))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "207");
+}
+
 // #[test]
 // fn target_spec() {
 //     let src = r#"
@@ -398,3 +1033,116 @@ fn playground() {
 //     vm.run(bc);
 //     assert_eq!(fib(20), *vm.stack.at(0).unwrap().as_integer().unwrap());
 // }
+
+#[test]
+fn golden_disassembly() {
+    let src = "(defun (double x) (* x 2))\n(define y (double 21))".to_owned();
+    let expected = r#".func k0 "double" arity=1 locals=0 upvalues=0
+  .locals x
+  .const k0 2
+  .line 1
+    ReferenceLocal 1
+    Constant k0 ; 2
+    Mul
+    Return
+.end
+.const k1 "double"
+.const k2 21
+.const k3 "y"
+.line 1
+  Closure k0
+  DeclareGlobal k1 ; "double"
+.line 2
+  ReferenceGlobal k1 ; "double"
+  Constant k2 ; 21
+  FuncCall 1
+  DeclareGlobal k3 ; "y"
+  DebugEnd
+"#;
+    let bc = compile(&src);
+    assert_eq!(disassemble(&bc), Ok(expected.to_string()));
+
+    let reassembled = assemble(expected).unwrap();
+    assert_eq!(reassembled, bc);
+    let mut vm = VM::default();
+    vm.run(reassembled);
+    assert_eq!(vm.globals.get("y"), Some(&rusp::vm::SmallVal::Integer(42)));
+}
+
+#[test]
+fn untrusted_bytecode_is_verified() {
+    let mut vm = VM::default();
+    let bad = assemble("  Constant 1\n  Add\n  DebugEnd").unwrap();
+    assert_eq!(
+        vm.run_untrusted(bad),
+        Err("in <top level>, offset 2: Add pops 2 values, but the stack only has 1".to_string())
+    );
+
+    let good = assemble(
+        r#"
+.func double "double" arity=1
+  ReferenceLocal 1
+  ReferenceLocal 1
+  Add
+  Return
+.end
+  Closure double
+  Constant 21
+  FuncCall 1
+  DeclareGlobal "y"
+  DebugEnd"#,
+    )
+    .unwrap();
+    assert_eq!(vm.run_untrusted(good), Ok(()));
+    assert_eq!(vm.globals.get("y"), Some(&rusp::vm::SmallVal::Integer(42)));
+}
+
+#[test]
+fn operators_raise_the_builtins_errors() {
+    let src = r#"
+(define kind (try (+ 1 'a) (catch e (error-kind e))))
+(define message (try (/ 1 0) (catch e (error-message e))))
+"#;
+    assert_eq!(run_and_display_global(src, "kind"), "'type-error");
+    assert_eq!(
+        run_and_display_global(src, "message"),
+        "\"attempted to divide by zero\""
+    );
+}
+
+#[test]
+fn rebound_operators_are_respected() {
+    let src = r#"
+(defun (add-three a b c) (+ a (+ b c)))
+(define + (fn (a b) (* a b)))
+(define result (add-three 2 3 4))
+"#;
+    assert_eq!(run_and_display_global(src, "result"), "24");
+
+    let mut vm = VM::default();
+    vm.run(compile(&"(define = (fn (a b) 'equal))".to_string()));
+    vm.run(compile(&"(define result (= 1 2))".to_string()));
+    assert_eq!(format!("{}", vm.globals.get("result").unwrap()), "'equal");
+}
+
+#[test]
+fn symbols_are_interned() {
+    let src = r#"
+(define same (eq? 'abc (intern "abc")))
+(define different (eq? 'abc 'abd))
+(define name (symbol->string 'abc))
+(define kind (try (error 'oops "message") (catch e (eq? (error-kind e) 'oops))))
+"#;
+    assert_eq!(run_and_display_global(src, "same"), "true");
+    assert_eq!(run_and_display_global(src, "different"), "false");
+    assert_eq!(run_and_display_global(src, "name"), "\"abc\"");
+    assert_eq!(run_and_display_global(src, "kind"), "true");
+
+    // constant pools keep symbols by name, so chunks loaded separately share them
+    let mut vm = VM::default();
+    vm.run(compile(&"(define a 'abc)".to_string()));
+    let chunk = assemble("  Constant 'abc\n  DeclareGlobal \"b\"\n  DebugEnd").unwrap();
+    assert_eq!(vm.run_untrusted(chunk), Ok(()));
+    vm.run(compile(&"(define same (eq? a b))".to_string()));
+    assert_eq!(format!("{}", vm.globals.get("same").unwrap()), "true");
+}
@@ -0,0 +1,66 @@
+//! Runs the programs in the integration tests (and the demo) with every function compiled to
+//! machine code on its first call, and checks they do what they do on the interpreter.
+
+#![cfg(feature = "jit")]
+
+mod common;
+
+use common::{error, programs, run, stdout, RUSPC};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// whether `source` does the same compiled as interpreted, or `None` when it doesn't stop
+fn compare(dir: &Path, idx: usize, source: &str) -> Option<Result<(), String>> {
+    let risp = dir.join(format!("program_{idx}.risp"));
+    std::fs::write(&risp, source).unwrap();
+
+    let interpreted = run(Command::new(RUSPC)
+        .arg(&risp)
+        .env("RUSP_JIT_THRESHOLD", "off"))?;
+    let compiled = run(Command::new(RUSPC)
+        .arg(&risp)
+        .env("RUSP_JIT_THRESHOLD", "0"))?;
+    if interpreted.status.success() != compiled.status.success() {
+        return Some(Err(format!(
+            "{} exited with {} interpreted but {} compiled",
+            risp.display(),
+            interpreted.status,
+            compiled.status
+        )));
+    }
+    if stdout(&interpreted) != stdout(&compiled) {
+        return Some(Err(format!(
+            "{} printed\n{}interpreted but\n{}compiled",
+            risp.display(),
+            stdout(&interpreted),
+            stdout(&compiled)
+        )));
+    }
+    if error(&interpreted) != error(&compiled) {
+        return Some(Err(format!(
+            "{} failed with {:?} interpreted but {:?} compiled",
+            risp.display(),
+            error(&interpreted),
+            error(&compiled)
+        )));
+    }
+    Some(Ok(()))
+}
+
+#[test]
+fn compiled_functions_match_the_interpreter() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("jit");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut compared = 0;
+    let mut failures = vec![];
+    for (idx, (name, source)) in programs().iter().enumerate() {
+        match compare(&dir, idx, source) {
+            Some(Ok(())) => compared += 1,
+            Some(Err(failure)) => failures.push(format!("{name}: {failure}")),
+            None => {}
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+    assert!(compared >= 50, "only compared {compared} programs");
+}